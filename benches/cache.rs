@@ -3,22 +3,21 @@ extern crate criterion;
 
 use cache::q2;
 use cache::slru;
+use cache::workload::{Rng, Zipf};
 use criterion::Criterion;
-use rand::{self, Rng};
 
 fn q2_benchmark(c: &mut Criterion) {
     let size = 128;
     let mut cache = q2::Cache::new(size);
-    let mut rng = rand::thread_rng();
+    let mut keys = Zipf::new(512, 0.99, 1);
+    let mut rng = Rng::new(2);
 
     c.bench_function("q2-random-ops", move |b| {
         b.iter(|| {
             for _ in 0usize..1000 {
-                let mut key: i64 = rng.gen();
-                key %= 512;
-                let r: i64 = rng.gen();
+                let key = keys.next().unwrap();
 
-                match r % 3 {
+                match rng.below(3) {
                     0 => {
                         cache.add(key, key);
                     }
@@ -38,16 +37,15 @@ fn q2_benchmark(c: &mut Criterion) {
 fn slru_benchmark(c: &mut Criterion) {
     let size = 128;
     let mut cache = slru::Cache::new(size);
-    let mut rng = rand::thread_rng();
+    let mut keys = Zipf::new(512, 0.99, 1);
+    let mut rng = Rng::new(2);
 
     c.bench_function("slru-random-ops", move |b| {
         b.iter(|| {
             for _ in 0usize..1000 {
-                let mut key: i64 = rng.gen();
-                key %= 512;
-                let r: i64 = rng.gen();
+                let key = keys.next().unwrap();
 
-                match r % 3 {
+                match rng.below(3) {
                     0 => {
                         cache.add(key, key);
                     }
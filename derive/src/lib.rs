@@ -0,0 +1,183 @@
+extern crate proc_macro;
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote};
+use syn::spanned::Spanned;
+use syn::{
+    parse_macro_input, parse_quote, Data, DeriveInput, Error, Fields, FnArg, GenericParam, Index,
+    ItemFn, LitInt, LitStr, Pat, ReturnType,
+};
+
+#[proc_macro_derive(HeapSize)]
+pub fn derive_heap_size(input: TokenStream) -> TokenStream {
+    let mut input = parse_macro_input!(input as DeriveInput);
+
+    for param in &mut input.generics.params {
+        if let GenericParam::Type(ty) = param {
+            ty.bounds.push(parse_quote!(::cache::heap_size::HeapSize));
+        }
+    }
+
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let body = heap_size_body(&input.data);
+
+    let expanded = quote! {
+        impl #impl_generics ::cache::heap_size::HeapSize for #name #ty_generics #where_clause {
+            fn heap_size(&self) -> usize {
+                #body
+            }
+        }
+    };
+    TokenStream::from(expanded)
+}
+
+fn heap_size_body(data: &Data) -> TokenStream2 {
+    match data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => {
+                let names = fields.named.iter().map(|f| &f.ident);
+                quote! { 0 #(+ ::cache::heap_size::HeapSize::heap_size(&self.#names))* }
+            }
+            Fields::Unnamed(fields) => {
+                let indices = (0..fields.unnamed.len()).map(Index::from);
+                quote! { 0 #(+ ::cache::heap_size::HeapSize::heap_size(&self.#indices))* }
+            }
+            Fields::Unit => quote!(0),
+        },
+        Data::Enum(data) => {
+            let arms = data.variants.iter().map(|variant| {
+                let ident = &variant.ident;
+                match &variant.fields {
+                    Fields::Named(fields) => {
+                        let names: Vec<_> = fields.named.iter().map(|f| &f.ident).collect();
+                        quote! {
+                            Self::#ident { #(#names),* } => {
+                                0 #(+ ::cache::heap_size::HeapSize::heap_size(#names))*
+                            }
+                        }
+                    }
+                    Fields::Unnamed(fields) => {
+                        let names: Vec<_> = (0..fields.unnamed.len())
+                            .map(|i| format_ident!("f{}", i))
+                            .collect();
+                        quote! {
+                            Self::#ident(#(#names),*) => {
+                                0 #(+ ::cache::heap_size::HeapSize::heap_size(#names))*
+                            }
+                        }
+                    }
+                    Fields::Unit => quote! { Self::#ident => 0, },
+                }
+            });
+            quote! {
+                match self {
+                    #(#arms)*
+                }
+            }
+        }
+        Data::Union(_) => quote!(0),
+    }
+}
+
+/// Caches the results of a function by its arguments, in a cache of each
+/// thread. See `cache::memo`.
+#[proc_macro_attribute]
+pub fn cached(args: TokenStream, item: TokenStream) -> TokenStream {
+    let mut policy = None;
+    let mut size = None;
+    let parser = syn::meta::parser(|meta| {
+        if meta.path.is_ident("policy") {
+            policy = Some(meta.value()?.parse::<LitStr>()?);
+            Ok(())
+        } else if meta.path.is_ident("size") {
+            size = Some(meta.value()?.parse::<LitInt>()?);
+            Ok(())
+        } else {
+            Err(meta.error("expected `policy` or `size`"))
+        }
+    });
+    parse_macro_input!(args with parser);
+    let func = parse_macro_input!(item as ItemFn);
+    match cached_fn(policy, size, func) {
+        Ok(expanded) => TokenStream::from(expanded),
+        Err(err) => TokenStream::from(err.to_compile_error()),
+    }
+}
+
+fn cached_fn(
+    policy: Option<LitStr>,
+    size: Option<LitInt>,
+    func: ItemFn,
+) -> syn::Result<TokenStream2> {
+    let policy = match &policy {
+        Some(policy) => match policy.value().as_str() {
+            "lru" | "fifo" | "slru" | "q2" => format_ident!("{}", policy.value()),
+            _ => {
+                return Err(Error::new(
+                    policy.span(),
+                    "expected one of `lru`, `fifo`, `slru` and `q2`",
+                ))
+            }
+        },
+        None => format_ident!("lru"),
+    };
+    let size = match size {
+        Some(size) => size,
+        None => return Err(Error::new(func.sig.span(), "missing `size`")),
+    };
+    let sig = &func.sig;
+    if sig.asyncness.is_some() || !sig.generics.params.is_empty() {
+        return Err(Error::new(
+            sig.span(),
+            "only synchronous functions without generics can be cached",
+        ));
+    }
+    let mut names = Vec::new();
+    let mut types = Vec::new();
+    for arg in &sig.inputs {
+        match arg {
+            FnArg::Typed(arg) => match &*arg.pat {
+                Pat::Ident(pat) => {
+                    names.push(&pat.ident);
+                    types.push(&arg.ty);
+                }
+                pat => return Err(Error::new(pat.span(), "expected an argument name")),
+            },
+            FnArg::Receiver(arg) => {
+                return Err(Error::new(arg.span(), "methods cannot be cached"));
+            }
+        }
+    }
+    let output = match &sig.output {
+        ReturnType::Type(_, ty) => quote!(#ty),
+        ReturnType::Default => quote!(()),
+    };
+    let attrs = &func.attrs;
+    let vis = &func.vis;
+    let body = &func.block;
+
+    Ok(quote! {
+        #(#attrs)*
+        #vis #sig {
+            ::std::thread_local! {
+                static CACHE: ::std::cell::RefCell<
+                    ::cache::#policy::Cache<(#(#types,)*), #output>
+                > = ::std::cell::RefCell::new(::cache::#policy::Cache::new(#size));
+            }
+            let key = (#(::std::clone::Clone::clone(&#names),)*);
+            let cached = CACHE.with(|cache| {
+                cache.borrow_mut().get(&key).map(::std::clone::Clone::clone)
+            });
+            if let ::std::option::Option::Some(v) = cached {
+                return v;
+            }
+            let v = (move || -> #output #body)();
+            CACHE.with(|cache| {
+                cache.borrow_mut().add(key, ::std::clone::Clone::clone(&v));
+            });
+            v
+        }
+    })
+}
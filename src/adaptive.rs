@@ -0,0 +1,297 @@
+//! Adjusting the size of a cache to its workload.
+//!
+//! A `Controller` is ticked periodically with the cache it manages. On each
+//! tick it looks at the hit rate since the previous decision and moves
+//! `max_size` one step within the bounds it was given:
+//!
+//! - down, while a memory pressure signal is raised;
+//! - up, while the hit rate is below the target;
+//! - down otherwise, to give back the memory the target does not need.
+//!
+//! ```
+//! use cache::adaptive::Controller;
+//! use cache::lru;
+//!
+//! let mut cache: lru::Cache<u32, String> = lru::Cache::new(100);
+//! let mut controller = Controller::new(10, 1000);
+//! controller.set_min_lookups(10);
+//! for k in 0..100 {
+//!     cache.get(&k);
+//! }
+//! assert_eq!(controller.tick(&mut cache), Some(110));
+//! ```
+//!
+//! Independently of its size limit, a cache given an `IdleShrink` policy
+//! gives back the memory it held at a past peak once it has stayed well
+//! below its capacity for a while.
+
+use std::hash::{BuildHasher, Hash};
+use std::time::Duration;
+
+use super::any::AnyCache;
+use super::clock::{Clock, DefaultClock};
+use super::core::{self, ReplacementPolicy};
+use super::stats::Info;
+
+/// A cache whose size can be changed at runtime.
+pub trait Resizable {
+    fn max_size(&self) -> usize;
+
+    fn resize(&mut self, max_size: usize);
+
+    fn stat(&self) -> Info;
+}
+
+impl<K: Hash + Eq, V, S: BuildHasher + Clone> Resizable for AnyCache<K, V, S> {
+    fn max_size(&self) -> usize {
+        AnyCache::max_size(self)
+    }
+
+    fn resize(&mut self, max_size: usize) {
+        AnyCache::resize(self, max_size)
+    }
+
+    fn stat(&self) -> Info {
+        AnyCache::stat(self)
+    }
+}
+
+impl<K, V, P, S> Resizable for core::Cache<K, V, P, S>
+where
+    K: Hash + Eq,
+    S: BuildHasher,
+    P: ReplacementPolicy<K, V, S>,
+{
+    fn max_size(&self) -> usize {
+        core::Cache::max_size(self)
+    }
+
+    fn resize(&mut self, max_size: usize) {
+        core::Cache::resize(self, max_size)
+    }
+
+    fn stat(&self) -> Info {
+        core::Cache::stat(self)
+    }
+}
+
+pub const DEFAULT_TARGET_HIT_RATE: f64 = 0.9;
+pub const DEFAULT_STEP: f64 = 0.1;
+pub const DEFAULT_MIN_LOOKUPS: usize = 1000;
+
+pub struct Controller {
+    min_size: usize,
+    max_size: usize,
+    target_hit_rate: f64,
+    step: f64,
+    min_lookups: usize,
+    pressure: Option<Box<dyn FnMut() -> bool>>,
+    // the counters of the cache at the previous decision
+    hit_count: usize,
+    miss_count: usize,
+}
+
+impl Controller {
+    /// Creates a controller keeping the size within `min_size..=max_size`.
+    pub fn new(min_size: usize, max_size: usize) -> Controller {
+        let min_size = min_size.max(1);
+        Controller {
+            min_size,
+            max_size: max_size.max(min_size),
+            target_hit_rate: DEFAULT_TARGET_HIT_RATE,
+            step: DEFAULT_STEP,
+            min_lookups: DEFAULT_MIN_LOOKUPS,
+            pressure: None,
+            hit_count: 0,
+            miss_count: 0,
+        }
+    }
+
+    pub fn set_target_hit_rate(&mut self, target: f64) {
+        self.target_hit_rate = target;
+    }
+
+    /// Sets the fraction of the current size added or removed in one step.
+    /// A step is never less than one entry.
+    pub fn set_step(&mut self, step: f64) {
+        self.step = step;
+    }
+
+    /// Sets the number of lookups a hit rate must be measured over before
+    /// the size is changed because of it.
+    pub fn set_min_lookups(&mut self, lookups: usize) {
+        self.min_lookups = lookups;
+    }
+
+    /// Registers a signal polled on every tick; while it returns `true` the
+    /// cache shrinks, whatever its hit rate.
+    pub fn set_memory_pressure<F>(&mut self, pressure: F)
+    where
+        F: FnMut() -> bool + 'static,
+    {
+        self.pressure = Some(Box::new(pressure));
+    }
+
+    /// Resizes `cache` if needed and returns its new size if it changed.
+    pub fn tick<C: Resizable>(&mut self, cache: &mut C) -> Option<usize> {
+        let info = cache.stat();
+        let size = cache.max_size();
+        let under_pressure = self.pressure.as_mut().is_some_and(|pressure| pressure());
+
+        // counters that went backwards were reset in between
+        let hits = info
+            .hit_count
+            .checked_sub(self.hit_count)
+            .unwrap_or(info.hit_count);
+        let misses = info
+            .miss_count
+            .checked_sub(self.miss_count)
+            .unwrap_or(info.miss_count);
+        let lookups = hits + misses;
+        if !under_pressure && lookups < self.min_lookups {
+            return self.apply(cache, size, size);
+        }
+        self.hit_count = info.hit_count;
+        self.miss_count = info.miss_count;
+
+        let step = ((size as f64 * self.step) as usize).max(1);
+        let target = if under_pressure {
+            size.saturating_sub(step)
+        } else if lookups > 0 && (hits as f64 / lookups as f64) < self.target_hit_rate {
+            size.saturating_add(step)
+        } else {
+            size.saturating_sub(step)
+        };
+        self.apply(cache, size, target)
+    }
+
+    fn apply<C: Resizable>(&self, cache: &mut C, size: usize, target: usize) -> Option<usize> {
+        let target = target.clamp(self.min_size, self.max_size);
+        if target == size {
+            return None;
+        }
+        cache.resize(target);
+        Some(target)
+    }
+}
+
+/// Decides when a cache that has stayed below a fraction of its capacity
+/// long enough shrinks its storage, freeing the unused node chunks and
+/// table slots left over from a peak.
+pub struct IdleShrink {
+    threshold: f64,
+    after: Duration,
+    clock: Box<dyn Clock>,
+    // when the cache last went below the threshold, or last shrank since
+    below_since: Option<Duration>,
+}
+
+impl IdleShrink {
+    /// Shrinks a cache that has held fewer than `threshold` times its
+    /// maximum number of entries for `after`, and again every `after` it
+    /// stays there.
+    pub fn new(threshold: f64, after: Duration) -> IdleShrink {
+        IdleShrink::with_clock(threshold, after, DefaultClock::default())
+    }
+
+    pub fn with_clock<C>(threshold: f64, after: Duration, clock: C) -> IdleShrink
+    where
+        C: Clock + 'static,
+    {
+        IdleShrink {
+            threshold,
+            after,
+            clock: Box::new(clock),
+            below_since: None,
+        }
+    }
+
+    /// Notes the fill of a cache and returns whether it should shrink now.
+    pub(crate) fn due(&mut self, len: usize, max_size: usize) -> bool {
+        if len as f64 >= self.threshold * max_size as f64 {
+            self.below_since = None;
+            return false;
+        }
+        let now = self.clock.now();
+        match self.below_since {
+            Some(since) if now.saturating_sub(since) >= self.after => {
+                self.below_since = Some(now);
+                true
+            }
+            Some(_) => false,
+            None => {
+                self.below_since = Some(now);
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::lru;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_controller() {
+        let mut cache = AnyCache::new(crate::any::Policy::q2(), 10);
+        let mut controller = Controller::new(20, 100);
+        controller.set_min_lookups(100);
+        // brought within bounds at once
+        assert_eq!(controller.tick(&mut cache), Some(20));
+
+        // grows until the 50 keys fit
+        for _ in 0..40 {
+            for k in 0..50 {
+                if cache.get(&k).is_none() {
+                    cache.add(k, ());
+                }
+            }
+            controller.tick(&mut cache);
+        }
+        assert!(cache.max_size() >= 50 && cache.max_size() <= 100);
+
+        let pressure = Rc::new(Cell::new(true));
+        let signal = pressure.clone();
+        controller.set_memory_pressure(move || signal.get());
+        let before = cache.max_size();
+        assert!(controller.tick(&mut cache).unwrap() < before);
+        for _ in 0..20 {
+            controller.tick(&mut cache);
+        }
+        assert_eq!(cache.max_size(), 20);
+        assert!(cache.len() <= 20);
+        pressure.set(false);
+        assert_eq!(controller.tick(&mut cache), None);
+    }
+
+    #[test]
+    fn test_idle_shrink() {
+        let clock = ManualClock::new();
+        let mut cache = lru::Cache::new(1000);
+        cache.set_idle_shrink(IdleShrink::with_clock(
+            0.25,
+            Duration::from_secs(10),
+            clock.clone(),
+        ));
+        for k in 0..1000 {
+            cache.add(k, k);
+        }
+        for k in 10..1000 {
+            cache.remove(&k);
+        }
+        let peak = cache.stat().memory.total();
+
+        cache.add(0, 0);
+        clock.advance(Duration::from_secs(9));
+        cache.add(0, 0);
+        assert_eq!(cache.stat().memory.total(), peak);
+        clock.advance(Duration::from_secs(1));
+        cache.add(0, 0);
+        assert!(cache.stat().memory.total() < peak / 10);
+        assert_eq!(cache.len(), 10);
+    }
+}
@@ -0,0 +1,371 @@
+//! Admission policies, which decide whether a new key is worth evicting an
+//! existing entry for.
+//!
+//! A cache with an admission policy reports every key it is asked for to
+//! `record`. When a new key arrives while the cache is full, the policy is
+//! shown the key and the entry that would be evicted first; if it turns the
+//! key down, the new entry is handed to the eviction callback instead, just
+//! like one heavier than the whole cache.
+
+use std::time::Duration;
+
+use super::bloom::Bloom;
+use super::clock::{Clock, DefaultClock};
+use super::sketch::CountMinSketch;
+
+/// Decides whether a new key may displace the next eviction victim. Keys
+/// are identified by their hash under the cache's hasher.
+pub trait AdmissionPolicy {
+    /// Notes a lookup or insertion of the key with hash `hash`.
+    fn record(&mut self, hash: u64);
+
+    /// Returns whether the key with hash `candidate` may take the place of
+    /// the key with hash `victim`.
+    fn admit(&mut self, candidate: u64, victim: u64) -> bool;
+}
+
+pub(crate) type Admission = Box<dyn AdmissionPolicy>;
+
+const DEPTH: usize = 4;
+const SEEDS: [u64; 3] = [
+    0x9e37_79b9_7f4a_7c15,
+    0xc2b2_ae3d_27d4_eb4f,
+    0x1656_67b1_9e37_79f9,
+];
+/// Counters saturate here, as TinyLFU's 4-bit counters do; the sketch
+/// stores them in a byte each.
+const MAX_COUNT: u32 = 15;
+
+fn index(hash: u64, i: usize, mask: usize) -> usize {
+    ((hash ^ SEEDS[i]).wrapping_mul(SEEDS[i + 1]) >> 32) as usize & mask
+}
+
+/// The TinyLFU admission policy: a new key is admitted only if it has been
+/// requested more often than the victim.
+///
+/// Frequencies are estimated with a count-min sketch fronted by a
+/// "doorkeeper" bit set, so that keys seen once only cost a bit. Every
+/// decay period, by default ten times `capacity` accesses, the estimates are
+/// aged: all counts are halved and the doorkeeper is cleared, which lets
+/// keys that were hot once but no longer are be evicted.
+pub struct TinyLfu {
+    sketch: CountMinSketch,
+    doorkeeper: Vec<u64>,
+    mask: usize,
+    additions: usize,
+    sample_size: usize,
+}
+
+impl TinyLfu {
+    /// Creates a policy sized for a cache of `capacity` entries.
+    pub fn new(capacity: usize) -> TinyLfu {
+        let width = capacity.max(16).next_power_of_two();
+        let mut sketch = CountMinSketch::new(width, DEPTH);
+        sketch.set_max_count(MAX_COUNT);
+        TinyLfu {
+            sketch,
+            doorkeeper: vec![0; width / 64 + 1],
+            mask: width - 1,
+            additions: 0,
+            sample_size: width * 10,
+        }
+    }
+
+    /// Sets the number of accesses after which the estimates are aged, or
+    /// disables aging altogether if `accesses` is 0.
+    pub fn set_decay_period(&mut self, accesses: usize) {
+        self.sample_size = accesses;
+        self.additions = 0;
+    }
+
+    /// Returns the estimated number of accesses to the key with hash `hash`
+    /// since the last halving.
+    pub fn frequency(&self, hash: u64) -> u32 {
+        self.sketch.estimate(hash) + u32::from(self.in_doorkeeper(hash))
+    }
+
+    fn doorkeeper_bits(&self, hash: u64) -> [usize; 2] {
+        [
+            index(hash, 0, self.mask),
+            index(hash.rotate_left(32), 1, self.mask),
+        ]
+    }
+
+    fn in_doorkeeper(&self, hash: u64) -> bool {
+        self.doorkeeper_bits(hash)
+            .iter()
+            .all(|&bit| self.doorkeeper[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    /// Ages the estimates at once, as happens at the end of every decay
+    /// period.
+    pub fn age(&mut self) {
+        self.sketch.age();
+        for word in self.doorkeeper.iter_mut() {
+            *word = 0;
+        }
+        self.additions /= 2;
+    }
+}
+
+impl AdmissionPolicy for TinyLfu {
+    fn record(&mut self, hash: u64) {
+        if self.in_doorkeeper(hash) {
+            self.sketch.increment(hash);
+        } else {
+            for &bit in self.doorkeeper_bits(hash).iter() {
+                self.doorkeeper[bit / 64] |= 1 << (bit % 64);
+            }
+        }
+
+        if self.sample_size > 0 {
+            self.additions += 1;
+            if self.additions >= self.sample_size {
+                self.age();
+            }
+        }
+    }
+
+    fn admit(&mut self, candidate: u64, victim: u64) -> bool {
+        self.frequency(candidate) > self.frequency(victim)
+    }
+}
+
+/// Keeps one-hit wonders out of a cache: the first time a key is added it is
+/// only recorded, in a bloom filter, and the entry is turned down; it is
+/// cached from its second addition on. The filter forgets all keys once
+/// `capacity` have been recorded, so a key must come back within that many
+/// others to be let in.
+pub struct Doorkeeper {
+    filter: Bloom,
+    capacity: usize,
+    recorded: usize,
+}
+
+impl Doorkeeper {
+    /// Creates a doorkeeper remembering up to `capacity` keys, with about one
+    /// percent of false positives.
+    pub fn new(capacity: usize) -> Doorkeeper {
+        let capacity = capacity.max(1);
+        Doorkeeper {
+            filter: Bloom::new(capacity),
+            capacity,
+            recorded: 0,
+        }
+    }
+
+    /// Returns whether the key with hash `hash` was recorded before.
+    pub fn contains(&self, hash: u64) -> bool {
+        self.filter.contains(hash)
+    }
+
+    /// Records the key with hash `hash` and returns whether it was recorded
+    /// before.
+    pub fn record(&mut self, hash: u64) -> bool {
+        if self.filter.insert(hash) {
+            return true;
+        }
+        self.recorded += 1;
+        if self.recorded >= self.capacity {
+            self.clear();
+        }
+        false
+    }
+
+    pub fn clear(&mut self) {
+        self.filter.clear();
+        self.recorded = 0;
+    }
+
+    pub(crate) fn heap_bytes(&self) -> usize {
+        self.filter.heap_bytes()
+    }
+}
+
+enum Interval {
+    Operations {
+        per_interval: usize,
+        in_interval: usize,
+    },
+    Time {
+        per_interval: Duration,
+        start: Duration,
+        clock: Box<dyn Clock>,
+    },
+}
+
+/// Bounds the number of promotions into the protected segment of an SLRU or
+/// 2Q cache per interval. A burst of new keys, such as a batch job reading
+/// its data more than once, then cannot flush the working set: the keys over
+/// the limit stay in the probationary segment, where they go first.
+pub struct PromotionLimit {
+    max: usize,
+    promoted: usize,
+    interval: Interval,
+}
+
+impl PromotionLimit {
+    /// Allows `max` promotions every `ops` lookups and insertions.
+    pub fn per_operations(max: usize, ops: usize) -> PromotionLimit {
+        PromotionLimit::with_interval(
+            max,
+            Interval::Operations {
+                per_interval: ops.max(1),
+                in_interval: 0,
+            },
+        )
+    }
+
+    /// Allows `max` promotions every `span` of time.
+    pub fn per_duration(max: usize, span: Duration) -> PromotionLimit {
+        PromotionLimit::per_duration_with_clock(max, span, DefaultClock::default())
+    }
+
+    pub fn per_duration_with_clock<C>(max: usize, span: Duration, clock: C) -> PromotionLimit
+    where
+        C: Clock + 'static,
+    {
+        let start = clock.now();
+        PromotionLimit::with_interval(
+            max,
+            Interval::Time {
+                per_interval: span,
+                start,
+                clock: Box::new(clock),
+            },
+        )
+    }
+
+    fn with_interval(max: usize, interval: Interval) -> PromotionLimit {
+        PromotionLimit {
+            max,
+            promoted: 0,
+            interval,
+        }
+    }
+
+    /// Returns the number of promotions left in the current interval.
+    pub fn remaining(&self) -> usize {
+        self.max.saturating_sub(self.promoted)
+    }
+
+    /// Notes a lookup or insertion, which ends an interval counted in
+    /// operations.
+    pub(crate) fn record(&mut self) {
+        if let Interval::Operations {
+            per_interval,
+            in_interval,
+        } = &mut self.interval
+        {
+            *in_interval += 1;
+            if *in_interval >= *per_interval {
+                *in_interval = 0;
+                self.promoted = 0;
+            }
+        }
+    }
+
+    /// Starts a new interval if the current one, counted in time, has run
+    /// out.
+    pub(crate) fn roll(&mut self) {
+        if let Interval::Time {
+            per_interval,
+            start,
+            clock,
+        } = &mut self.interval
+        {
+            let now = clock.now();
+            if now.saturating_sub(*start) >= *per_interval {
+                *start = now;
+                self.promoted = 0;
+            }
+        }
+    }
+
+    /// Takes a promotion out of the current interval, if any is left.
+    pub(crate) fn allow(&mut self) -> bool {
+        self.roll();
+        if self.promoted >= self.max {
+            return false;
+        }
+        self.promoted += 1;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tiny_lfu() {
+        let mut tiny_lfu = TinyLfu::new(16);
+        for _ in 0..5 {
+            tiny_lfu.record(1);
+        }
+        tiny_lfu.record(2);
+        assert_eq!(tiny_lfu.frequency(1), 5);
+        assert_eq!(tiny_lfu.frequency(2), 1);
+        assert_eq!(tiny_lfu.frequency(3), 0);
+        assert!(tiny_lfu.admit(1, 2));
+        assert!(!tiny_lfu.admit(2, 1));
+        assert!(!tiny_lfu.admit(3, 2));
+
+        // the 160th access halves the counts
+        for _ in 0..154 {
+            tiny_lfu.record(2);
+        }
+        assert_eq!(tiny_lfu.frequency(1), 2);
+        assert_eq!(tiny_lfu.frequency(2), 7);
+
+        tiny_lfu.age();
+        assert_eq!(tiny_lfu.frequency(1), 1);
+        assert_eq!(tiny_lfu.frequency(2), 3);
+
+        tiny_lfu.set_decay_period(0);
+        for _ in 0..1000 {
+            tiny_lfu.record(3);
+        }
+        assert_eq!(tiny_lfu.frequency(3), 16);
+        assert_eq!(tiny_lfu.frequency(2), 3);
+    }
+
+    #[test]
+    fn test_doorkeeper() {
+        let mut doorkeeper = Doorkeeper::new(100);
+        assert!(!doorkeeper.record(1));
+        assert!(doorkeeper.contains(1));
+        assert!(doorkeeper.record(1));
+        // forgets everything once full
+        let false_positives = (2..101).filter(|&hash| doorkeeper.record(hash)).count();
+        assert!(false_positives < 5);
+        assert!(!doorkeeper.contains(1));
+    }
+
+    #[test]
+    fn test_promotion_limit() {
+        let mut limit = PromotionLimit::per_operations(2, 4);
+        assert!(limit.allow());
+        assert!(limit.allow());
+        assert!(!limit.allow());
+        assert_eq!(limit.remaining(), 0);
+        for _ in 0..4 {
+            limit.record();
+        }
+        assert_eq!(limit.remaining(), 2);
+
+        let clock = crate::clock::ManualClock::new();
+        let mut limit =
+            PromotionLimit::per_duration_with_clock(1, Duration::from_secs(1), clock.clone());
+        assert!(limit.allow());
+        limit.record();
+        assert!(!limit.allow());
+        clock.advance(Duration::from_secs(1));
+        assert!(limit.allow());
+        assert!(!limit.allow());
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(limit.remaining(), 0);
+        limit.roll();
+        assert_eq!(limit.remaining(), 1);
+    }
+}
@@ -0,0 +1,736 @@
+//! A cache whose replacement policy is chosen at runtime.
+//!
+//! `AnyCache` is an enum over the policy modules, so calls are dispatched
+//! with a `match` rather than through a trait object.
+
+use std::borrow::Borrow;
+use std::error::Error;
+use std::fmt;
+use std::hash::{BuildHasher, Hash};
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use super::adaptive::IdleShrink;
+use super::admission::AdmissionPolicy;
+use super::core::PriorityClass;
+use super::error::ConfigError;
+use super::hash::{DefaultState, SeededState};
+use super::heap_size::HeapSize;
+use super::stats::{
+    AgeTracking, EntryEvent, EntryMeta, HitWindow, Info, LatencySampling, Removal, StatsRecorder,
+};
+use super::{fifo, lru, q2, slru};
+
+pub use super::core::{IntoIter, Iter, IterFrom};
+
+/// A replacement policy together with its parameters.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Policy {
+    #[default]
+    Lru,
+    Fifo,
+    Slru {
+        main_cache_factor: f64,
+    },
+    Q2 {
+        main_cache_factor: f64,
+        out_cache_factor: f64,
+    },
+}
+
+impl Policy {
+    /// SLRU with the default segment sizes.
+    pub fn slru() -> Policy {
+        Policy::Slru {
+            main_cache_factor: slru::DEFAULT_MAIN_CF,
+        }
+    }
+
+    /// 2Q with the default segment and ghost queue sizes.
+    pub fn q2() -> Policy {
+        Policy::Q2 {
+            main_cache_factor: q2::DEFAULT_MAIN_CF,
+            out_cache_factor: q2::DEFAULT_OUT_CF,
+        }
+    }
+}
+
+#[cfg(feature = "json")]
+impl Policy {
+    /// Returns the name of the policy and its parameters as JSON.
+    pub fn to_json(&self) -> serde_json::Value {
+        match *self {
+            Policy::Lru | Policy::Fifo => serde_json::json!({ "name": self.to_string() }),
+            Policy::Slru { main_cache_factor } => serde_json::json!({
+                "name": self.to_string(),
+                "main_cache_factor": main_cache_factor,
+            }),
+            Policy::Q2 {
+                main_cache_factor,
+                out_cache_factor,
+            } => serde_json::json!({
+                "name": self.to_string(),
+                "main_cache_factor": main_cache_factor,
+                "out_cache_factor": out_cache_factor,
+            }),
+        }
+    }
+}
+
+/// Shows the name of the policy, as accepted by `from_str`.
+impl fmt::Display for Policy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Policy::Lru => "lru",
+            Policy::Fifo => "fifo",
+            Policy::Slru { .. } => "slru",
+            Policy::Q2 { .. } => "q2",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParsePolicyError(String);
+
+impl fmt::Display for ParsePolicyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "unknown policy {:?}, expected lru, fifo, slru or q2",
+            self.0
+        )
+    }
+}
+
+impl Error for ParsePolicyError {}
+
+/// Parses a policy name, giving it the default parameters.
+impl FromStr for Policy {
+    type Err = ParsePolicyError;
+
+    fn from_str(s: &str) -> Result<Policy, ParsePolicyError> {
+        match s {
+            "lru" => Ok(Policy::Lru),
+            "fifo" => Ok(Policy::Fifo),
+            "slru" => Ok(Policy::slru()),
+            "q2" | "2q" => Ok(Policy::q2()),
+            _ => Err(ParsePolicyError(s.to_owned())),
+        }
+    }
+}
+
+// every variant holds a few kilobytes of statistics; q2 is only larger by
+// its ghost queue, so boxing it would buy nothing
+#[allow(clippy::large_enum_variant)]
+pub enum AnyCache<K, V, S = DefaultState> {
+    Lru(lru::Cache<K, V, S>),
+    Fifo(fifo::Cache<K, V, S>),
+    Slru(slru::Cache<K, V, S>),
+    Q2(q2::Cache<K, V, S>),
+}
+
+macro_rules! dispatch {
+    ($self:expr, $cache:ident => $e:expr) => {
+        match $self {
+            AnyCache::Lru($cache) => $e,
+            AnyCache::Fifo($cache) => $e,
+            AnyCache::Slru($cache) => $e,
+            AnyCache::Q2($cache) => $e,
+        }
+    };
+}
+
+impl<K: Hash + Eq, V> AnyCache<K, V, DefaultState> {
+    pub fn new(policy: Policy, size: usize) -> AnyCache<K, V, DefaultState> {
+        AnyCache::with_hasher(policy, size, Default::default())
+    }
+}
+
+impl<K: Hash + Eq, V> AnyCache<K, V, SeededState> {
+    /// Creates a cache that hashes, and so behaves, the same on every run
+    /// given the same `seed`.
+    pub fn with_seed(policy: Policy, size: usize, seed: u64) -> AnyCache<K, V, SeededState> {
+        AnyCache::with_hasher(policy, size, SeededState::new(seed))
+    }
+}
+
+impl<K, V, S> AnyCache<K, V, S>
+where
+    K: Hash + Eq,
+    S: BuildHasher + Clone,
+{
+    pub fn with_hasher(policy: Policy, size: usize, hash_builder: S) -> AnyCache<K, V, S> {
+        match policy {
+            Policy::Lru => AnyCache::Lru(lru::Cache::with_hasher(size, hash_builder)),
+            Policy::Fifo => AnyCache::Fifo(fifo::Cache::with_hasher(size, hash_builder)),
+            Policy::Slru { main_cache_factor } => AnyCache::Slru(
+                slru::Cache::with_param_and_hasher(size, main_cache_factor, hash_builder),
+            ),
+            Policy::Q2 {
+                main_cache_factor,
+                out_cache_factor,
+            } => AnyCache::Q2(q2::Cache::with_param_and_hasher(
+                size,
+                main_cache_factor,
+                out_cache_factor,
+                hash_builder,
+            )),
+        }
+    }
+
+    /// Returns the policy of the cache and its current parameters.
+    pub fn policy(&self) -> Policy {
+        match self {
+            AnyCache::Lru(_) => Policy::Lru,
+            AnyCache::Fifo(_) => Policy::Fifo,
+            AnyCache::Slru(cache) => Policy::Slru {
+                main_cache_factor: cache.main_cache_factor(),
+            },
+            AnyCache::Q2(cache) => Policy::Q2 {
+                main_cache_factor: cache.main_cache_factor(),
+                out_cache_factor: cache.out_cache_factor(),
+            },
+        }
+    }
+
+    pub fn set_eviction_callback<C>(&mut self, cb: C)
+    where
+        C: FnMut(K, V) + 'static,
+    {
+        dispatch!(self, cache => cache.set_eviction_callback(cb))
+    }
+
+    pub fn set_batch_eviction_callback<C>(&mut self, batch_size: usize, cb: C)
+    where
+        C: FnMut(Vec<(K, V)>) + 'static,
+    {
+        dispatch!(self, cache => cache.set_batch_eviction_callback(batch_size, cb))
+    }
+
+    pub fn set_event_queue(&mut self, capacity: usize)
+    where
+        K: Clone,
+    {
+        dispatch!(self, cache => cache.set_event_queue(capacity))
+    }
+
+    pub fn take_events(&mut self) -> Vec<EntryEvent<K, V>> {
+        dispatch!(self, cache => cache.take_events())
+    }
+
+    pub fn set_eviction_observer<O>(&mut self, observer: O)
+    where
+        O: FnMut(&K, &V) + 'static,
+    {
+        dispatch!(self, cache => cache.set_eviction_observer(observer))
+    }
+
+    pub fn set_eviction_filter<F>(&mut self, filter: F)
+    where
+        F: Fn(&K, &V) -> bool + 'static,
+    {
+        dispatch!(self, cache => cache.set_eviction_filter(filter))
+    }
+
+    pub fn set_admission_policy<A>(&mut self, admission: A)
+    where
+        A: AdmissionPolicy + 'static,
+    {
+        dispatch!(self, cache => cache.set_admission_policy(admission))
+    }
+
+    pub fn set_weigher<W>(&mut self, max_weight: usize, weigher: W)
+    where
+        W: Fn(&K, &V) -> u32 + 'static,
+    {
+        dispatch!(self, cache => cache.set_weigher(max_weight, weigher))
+    }
+
+    pub fn set_max_heap_size(&mut self, max_bytes: usize)
+    where
+        K: HeapSize + 'static,
+        V: HeapSize + 'static,
+    {
+        dispatch!(self, cache => cache.set_max_heap_size(max_bytes))
+    }
+
+    pub fn resize(&mut self, max_size: usize) {
+        dispatch!(self, cache => cache.resize(max_size))
+    }
+
+    pub fn contains_key<Q>(&self, k: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        dispatch!(self, cache => cache.contains_key(k))
+    }
+
+    pub fn get<Q>(&mut self, k: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        dispatch!(self, cache => cache.get(k))
+    }
+
+    pub fn get_many<'a, Q, I>(&mut self, keys: I) -> Vec<Option<&V>>
+    where
+        K: Borrow<Q>,
+        Q: 'a + ?Sized + Eq + Hash,
+        I: IntoIterator<Item = &'a Q>,
+    {
+        dispatch!(self, cache => cache.get_many(keys))
+    }
+
+    pub fn peek<Q>(&self, k: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        dispatch!(self, cache => cache.peek(k))
+    }
+
+    pub fn peek_many<'a, Q, I>(&self, keys: I) -> Vec<bool>
+    where
+        K: Borrow<Q>,
+        Q: 'a + ?Sized + Eq + Hash,
+        I: IntoIterator<Item = &'a Q>,
+    {
+        dispatch!(self, cache => cache.peek_many(keys))
+    }
+
+    pub fn remove<Q>(&mut self, k: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        dispatch!(self, cache => cache.remove(k))
+    }
+
+    pub fn remove_if<Q, F>(&mut self, k: &Q, f: F) -> Removal<V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+        F: FnOnce(&K, &V) -> bool,
+    {
+        dispatch!(self, cache => cache.remove_if(k, f))
+    }
+
+    pub fn split_by<F>(&mut self, f: F) -> AnyCache<K, V, S>
+    where
+        F: FnMut(&K, &V) -> bool,
+    {
+        match self {
+            AnyCache::Lru(cache) => AnyCache::Lru(cache.split_by(f)),
+            AnyCache::Fifo(cache) => AnyCache::Fifo(cache.split_by(f)),
+            AnyCache::Slru(cache) => AnyCache::Slru(cache.split_by(f)),
+            AnyCache::Q2(cache) => AnyCache::Q2(cache.split_by(f)),
+        }
+    }
+
+    /// Absorbs the entries of `other`. Caches of different policies are
+    /// merged by adding the entries of `other`, least recent first, which
+    /// replace these for the keys in both.
+    pub fn merge(&mut self, other: AnyCache<K, V, S>) {
+        match (self, other) {
+            (AnyCache::Lru(cache), AnyCache::Lru(other)) => cache.merge(other),
+            (AnyCache::Fifo(cache), AnyCache::Fifo(other)) => cache.merge(other),
+            (AnyCache::Slru(cache), AnyCache::Slru(other)) => cache.merge(other),
+            (AnyCache::Q2(cache), AnyCache::Q2(other)) => cache.merge(other),
+            (cache, other) => {
+                let entries: Vec<_> = other.into_iter().collect();
+                for (k, v) in entries.into_iter().rev() {
+                    cache.add(k, v);
+                }
+            }
+        }
+    }
+
+    pub fn add(&mut self, k: K, v: V) -> Option<V> {
+        dispatch!(self, cache => cache.add(k, v))
+    }
+
+    pub fn version<Q>(&self, k: &Q) -> Option<u64>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        dispatch!(self, cache => cache.version(k))
+    }
+
+    pub fn given_version<Q>(&self, k: &Q) -> Option<u64>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        dispatch!(self, cache => cache.given_version(k))
+    }
+
+    pub fn add_if_version(&mut self, k: K, v: V, expected: Option<u64>) -> Result<Option<u64>, V>
+    where
+        K: Clone,
+    {
+        dispatch!(self, cache => cache.add_if_version(k, v, expected))
+    }
+
+    pub fn add_with_version(&mut self, k: K, v: V, version: u64) -> Result<(), V>
+    where
+        K: Clone,
+    {
+        dispatch!(self, cache => cache.add_with_version(k, v, version))
+    }
+
+    pub fn add_with_priority(&mut self, k: K, v: V, priority: u32) -> Option<V>
+    where
+        K: Clone,
+    {
+        dispatch!(self, cache => cache.add_with_priority(k, v, priority))
+    }
+
+    pub fn priority<Q>(&self, k: &Q) -> Option<u32>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        dispatch!(self, cache => cache.priority(k))
+    }
+
+    pub fn add_priority_class(&mut self, class: PriorityClass) -> Result<(), ConfigError>
+    where
+        K: Clone,
+    {
+        dispatch!(self, cache => cache.add_priority_class(class))
+    }
+
+    pub fn priority_class_lens(&self) -> Vec<(&str, usize)> {
+        dispatch!(self, cache => cache.priority_class_lens())
+    }
+
+    pub fn push(&mut self, k: K, v: V) -> (Option<V>, Vec<(K, V)>) {
+        dispatch!(self, cache => cache.push(k, v))
+    }
+
+    pub fn add_many<I>(&mut self, iter: I) -> Vec<(K, V)>
+    where
+        I: IntoIterator<Item = (K, V)>,
+    {
+        dispatch!(self, cache => cache.add_many(iter))
+    }
+
+    pub fn warm_from_iter<I>(&mut self, iter: I) -> usize
+    where
+        I: IntoIterator<Item = (K, V)>,
+    {
+        dispatch!(self, cache => cache.warm_from_iter(iter))
+    }
+
+    pub fn len(&self) -> usize {
+        dispatch!(self, cache => cache.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        dispatch!(self, cache => cache.is_empty())
+    }
+
+    pub fn max_size(&self) -> usize {
+        dispatch!(self, cache => cache.max_size())
+    }
+
+    pub fn is_disabled(&self) -> bool {
+        dispatch!(self, cache => cache.is_disabled())
+    }
+
+    pub fn max_weight(&self) -> usize {
+        dispatch!(self, cache => cache.max_weight())
+    }
+
+    pub fn weighted_size(&self) -> usize {
+        dispatch!(self, cache => cache.weighted_size())
+    }
+
+    pub fn capacity(&self) -> usize {
+        dispatch!(self, cache => cache.capacity())
+    }
+
+    pub fn iter(&self) -> Iter<'_, K, V> {
+        dispatch!(self, cache => cache.iter())
+    }
+
+    pub fn iter_from<Q>(&self, k: &Q) -> Option<IterFrom<'_, K, V>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        dispatch!(self, cache => cache.iter_from(k))
+    }
+
+    pub fn to_vec(&self) -> Vec<(K, V)>
+    where
+        K: Clone,
+        V: Clone,
+    {
+        dispatch!(self, cache => cache.to_vec())
+    }
+
+    pub fn export(self) -> Vec<(K, V)> {
+        dispatch!(self, cache => cache.export())
+    }
+
+    pub fn purge(&mut self) {
+        dispatch!(self, cache => cache.purge())
+    }
+
+    pub fn shrink_to_fit(&mut self) {
+        dispatch!(self, cache => cache.shrink_to_fit())
+    }
+
+    pub fn set_low_water_mark(&mut self, low: Option<usize>) -> Result<(), ConfigError> {
+        dispatch!(self, cache => cache.set_low_water_mark(low))
+    }
+
+    pub fn low_water_mark(&self) -> Option<usize> {
+        dispatch!(self, cache => cache.low_water_mark())
+    }
+
+    pub fn set_idle_shrink(&mut self, policy: IdleShrink) {
+        dispatch!(self, cache => cache.set_idle_shrink(policy))
+    }
+
+    pub fn expire_where<F>(&mut self, f: F) -> usize
+    where
+        F: FnMut(&K, &V) -> bool,
+    {
+        dispatch!(self, cache => cache.expire_where(f))
+    }
+
+    pub fn expire<Q>(&mut self, k: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        dispatch!(self, cache => cache.expire(k))
+    }
+
+    pub fn run_pending_tasks(&mut self) {
+        dispatch!(self, cache => cache.run_pending_tasks())
+    }
+
+    pub fn stat(&self) -> Info {
+        dispatch!(self, cache => cache.stat())
+    }
+
+    /// Returns the configuration of the cache and its statistics as JSON.
+    #[cfg(feature = "json")]
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "policy": self.policy().to_json(),
+            "max_size": self.max_size(),
+            "max_weight": self.max_weight(),
+            "stats": self.stat().to_json(),
+        })
+    }
+
+    pub fn stat_delta(&mut self) -> Info {
+        dispatch!(self, cache => cache.stat_delta())
+    }
+
+    pub fn reset_stats(&mut self) {
+        dispatch!(self, cache => cache.reset_stats())
+    }
+
+    pub fn set_hit_window(&mut self, window: HitWindow) {
+        dispatch!(self, cache => cache.set_hit_window(window))
+    }
+
+    pub fn recent_hit_rate(&self) -> Option<f64> {
+        dispatch!(self, cache => cache.recent_hit_rate())
+    }
+
+    pub fn set_age_tracking(&mut self, ages: AgeTracking) {
+        dispatch!(self, cache => cache.set_age_tracking(ages))
+    }
+
+    pub fn set_frequency_tracking(&mut self, enabled: bool) {
+        dispatch!(self, cache => cache.set_frequency_tracking(enabled))
+    }
+
+    pub fn frequency_distribution(&self) -> Option<Vec<(RangeInclusive<u32>, usize)>> {
+        dispatch!(self, cache => cache.frequency_distribution())
+    }
+
+    pub fn metadata<Q>(&self, k: &Q) -> Option<EntryMeta>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        dispatch!(self, cache => cache.metadata(k))
+    }
+
+    pub fn set_hot_key_tracking(&mut self, capacity: usize, window: usize)
+    where
+        K: Clone,
+    {
+        dispatch!(self, cache => cache.set_hot_key_tracking(capacity, window))
+    }
+
+    pub fn hot_keys(&self, n: usize) -> Vec<(K, u64)> {
+        dispatch!(self, cache => cache.hot_keys(n))
+    }
+
+    pub fn set_latency_sampling(&mut self, latency: LatencySampling) {
+        dispatch!(self, cache => cache.set_latency_sampling(latency))
+    }
+
+    pub fn set_stats_recorder<R>(&mut self, recorder: R)
+    where
+        R: StatsRecorder + 'static,
+    {
+        dispatch!(self, cache => cache.set_stats_recorder(recorder))
+    }
+
+    #[cfg(feature = "metrics")]
+    pub fn flush_metrics(&mut self, name: &str) {
+        dispatch!(self, cache => cache.flush_metrics(name))
+    }
+}
+
+/// The `Arc` methods of [`core::Cache`](crate::core::Cache).
+impl<K, V, S> AnyCache<K, Arc<V>, S>
+where
+    K: Hash + Eq,
+    S: BuildHasher + Clone,
+{
+    /// See [`core::Cache::get_arc`](crate::core::Cache::get_arc).
+    pub fn get_arc<Q>(&mut self, k: &Q) -> Option<Arc<V>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        self.get(k).cloned()
+    }
+
+    /// See [`core::Cache::peek_arc`](crate::core::Cache::peek_arc).
+    pub fn peek_arc<Q>(&self, k: &Q) -> Option<Arc<V>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        self.peek(k).cloned()
+    }
+
+    /// See [`core::Cache::get_many_arc`](crate::core::Cache::get_many_arc).
+    pub fn get_many_arc<'a, Q, I>(&mut self, keys: I) -> Vec<Option<Arc<V>>>
+    where
+        K: Borrow<Q>,
+        Q: 'a + ?Sized + Eq + Hash,
+        I: IntoIterator<Item = &'a Q>,
+    {
+        keys.into_iter().map(|k| self.get(k).cloned()).collect()
+    }
+
+    /// See [`core::Cache::add_arc`](crate::core::Cache::add_arc).
+    pub fn add_arc(&mut self, k: K, v: V) -> Option<Arc<V>> {
+        self.add(k, Arc::new(v))
+    }
+}
+
+impl<K, V, S> From<lru::Cache<K, V, S>> for AnyCache<K, V, S> {
+    fn from(cache: lru::Cache<K, V, S>) -> AnyCache<K, V, S> {
+        AnyCache::Lru(cache)
+    }
+}
+
+impl<K, V, S> From<fifo::Cache<K, V, S>> for AnyCache<K, V, S> {
+    fn from(cache: fifo::Cache<K, V, S>) -> AnyCache<K, V, S> {
+        AnyCache::Fifo(cache)
+    }
+}
+
+impl<K, V, S> From<slru::Cache<K, V, S>> for AnyCache<K, V, S> {
+    fn from(cache: slru::Cache<K, V, S>) -> AnyCache<K, V, S> {
+        AnyCache::Slru(cache)
+    }
+}
+
+impl<K, V, S> From<q2::Cache<K, V, S>> for AnyCache<K, V, S> {
+    fn from(cache: q2::Cache<K, V, S>) -> AnyCache<K, V, S> {
+        AnyCache::Q2(cache)
+    }
+}
+
+impl<K, V, S> IntoIterator for AnyCache<K, V, S> {
+    type Item = (K, V);
+    type IntoIter = IntoIter<K, V, S>;
+
+    fn into_iter(self) -> IntoIter<K, V, S> {
+        dispatch!(self, cache => cache.into_iter())
+    }
+}
+
+impl<'a, K, V, S> IntoIterator for &'a AnyCache<K, V, S>
+where
+    K: Hash + Eq,
+    S: BuildHasher + Clone,
+{
+    type Item = (&'a K, &'a V);
+    type IntoIter = Iter<'a, K, V>;
+
+    fn into_iter(self) -> Iter<'a, K, V> {
+        self.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_policies() {
+        for &policy in &[Policy::Lru, Policy::Fifo, Policy::slru(), Policy::q2()] {
+            let mut cache = AnyCache::new(policy, 8);
+            assert_eq!(cache.policy(), policy);
+            for i in 0usize..16 {
+                cache.add(i, i);
+            }
+            assert_eq!(cache.len(), 8);
+            assert_eq!(cache.iter().count(), 8);
+            assert_eq!(cache.get(&15), Some(&15));
+            assert_eq!(cache.remove(&15), Some(15));
+            assert!(!cache.contains_key(&15));
+            assert_eq!(cache.stat().insert_count, 16);
+            assert_eq!(cache.into_iter().count(), 7);
+        }
+    }
+
+    #[test]
+    fn test_from() {
+        let mut cache: AnyCache<usize, usize> = lru::Cache::new(2).into();
+        cache.add(1, 1);
+        cache.add(2, 2);
+        cache.get(&1);
+        cache.add(3, 3);
+        assert!(cache.contains_key(&1));
+        assert!(!cache.contains_key(&2));
+        assert_eq!(cache.policy(), Policy::Lru);
+    }
+
+    #[test]
+    fn test_priority_class() {
+        for &policy in &[Policy::Lru, Policy::Fifo, Policy::slru(), Policy::q2()] {
+            let mut cache = AnyCache::new(policy, 10);
+            let class = PriorityClass::new("a", 0..=1, 0.3).unwrap();
+            assert_eq!(cache.add_priority_class(class), Ok(()));
+            for k in 0..5 {
+                cache.add_with_priority(k, "a", 1);
+            }
+            // the flood of a higher priority takes all but the share of "a"
+            for k in 100..120 {
+                cache.add_with_priority(k, "b", 5);
+            }
+            assert_eq!(cache.priority_class_lens(), vec![("a", 3)], "{:?}", policy);
+            assert_eq!(cache.len(), 10);
+        }
+    }
+}
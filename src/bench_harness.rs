@@ -0,0 +1,240 @@
+//! Side-by-side evaluation of the policies.
+//!
+//! `compare` runs one recorded sequence of operations against a fresh cache
+//! of every policy, all of the same size, and tabulates how each did:
+//!
+//! ```
+//! use cache::bench_harness::{self, Op};
+//! use cache::workload::Zipf;
+//!
+//! let ops = Op::accesses(Zipf::new(1000, 0.9, 1).take(10_000));
+//! let table = bench_harness::compare(&ops, 100);
+//! assert_eq!(table.rows.len(), 4);
+//! println!("{}", table);
+//! ```
+
+use std::fmt;
+use std::time::Duration;
+
+use super::any::{AnyCache, Policy};
+use super::clock::{Clock, DefaultClock};
+use super::stats::MemoryUsage;
+
+/// One operation of a recorded sequence.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Get(u64),
+    Add(u64),
+    Remove(u64),
+    /// A read-through lookup: a `Get`, followed by an `Add` on a miss.
+    Access(u64),
+    /// Drops every entry.
+    Purge,
+}
+
+impl Op {
+    /// Turns a stream of keys, such as a trace or a `workload` generator,
+    /// into read-through lookups.
+    pub fn accesses<I: IntoIterator<Item = u64>>(keys: I) -> Vec<Op> {
+        keys.into_iter().map(Op::Access).collect()
+    }
+}
+
+/// The outcome of one policy.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Row {
+    pub policy: Policy,
+    pub ops: usize,
+    pub hits: usize,
+    pub misses: usize,
+    pub evictions: usize,
+    pub elapsed: Duration,
+    /// The memory held by the cache at the end of the run.
+    pub memory: MemoryUsage,
+}
+
+impl Row {
+    pub fn hit_ratio(&self) -> f64 {
+        let lookups = self.hits + self.misses;
+        if lookups == 0 {
+            return 0.0;
+        }
+        self.hits as f64 / lookups as f64
+    }
+
+    /// Returns the number of operations run per second.
+    pub fn throughput(&self) -> f64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs == 0.0 {
+            return 0.0;
+        }
+        self.ops as f64 / secs
+    }
+
+    #[cfg(feature = "json")]
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "policy": self.policy.to_json(),
+            "ops": self.ops,
+            "hits": self.hits,
+            "misses": self.misses,
+            "hit_ratio": self.hit_ratio(),
+            "evictions": self.evictions,
+            "elapsed_secs": self.elapsed.as_secs_f64(),
+            "ops_per_sec": self.throughput(),
+            "memory": serde_json::to_value(self.memory).expect("memory usage serializes to JSON"),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Comparison {
+    pub size: usize,
+    pub rows: Vec<Row>,
+}
+
+impl Comparison {
+    /// Returns the row with the highest hit ratio.
+    pub fn best(&self) -> Option<&Row> {
+        self.rows
+            .iter()
+            .max_by(|a, b| a.hit_ratio().total_cmp(&b.hit_ratio()))
+    }
+
+    #[cfg(feature = "json")]
+    pub fn to_json(&self) -> serde_json::Value {
+        let rows: Vec<_> = self.rows.iter().map(Row::to_json).collect();
+        serde_json::json!({ "size": self.size, "results": rows })
+    }
+}
+
+impl fmt::Display for Comparison {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{:<8}{:>10}{:>12}{:>12}{:>12}{:>12}{:>14}{:>14}",
+            "policy",
+            "hit ratio",
+            "hits",
+            "misses",
+            "evictions",
+            "time (ms)",
+            "ops/s",
+            "memory (B)"
+        )?;
+        for row in &self.rows {
+            writeln!(
+                f,
+                "{:<8}{:>10.4}{:>12}{:>12}{:>12}{:>12.3}{:>14.0}{:>14}",
+                row.policy.to_string(),
+                row.hit_ratio(),
+                row.hits,
+                row.misses,
+                row.evictions,
+                row.elapsed.as_secs_f64() * 1000.0,
+                row.throughput(),
+                row.memory.total()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Runs `ops` against every policy with its default parameters.
+pub fn compare(ops: &[Op], size: usize) -> Comparison {
+    compare_policies(
+        &[Policy::Lru, Policy::Fifo, Policy::slru(), Policy::q2()],
+        ops,
+        size,
+    )
+}
+
+pub fn compare_policies(policies: &[Policy], ops: &[Op], size: usize) -> Comparison {
+    Comparison {
+        size,
+        rows: policies.iter().map(|&p| run(p, ops, size)).collect(),
+    }
+}
+
+/// Runs `ops` against a fresh cache of `policy` and `size`. The time, read
+/// from the `DefaultClock`, includes the bookkeeping of the cache
+/// statistics.
+pub fn run(policy: Policy, ops: &[Op], size: usize) -> Row {
+    let mut cache = AnyCache::new(policy, size);
+    let clock = DefaultClock::default();
+    let start = clock.now();
+    for &op in ops {
+        match op {
+            Op::Get(k) => {
+                cache.get(&k);
+            }
+            Op::Add(k) => {
+                cache.add(k, k);
+            }
+            Op::Remove(k) => {
+                cache.remove(&k);
+            }
+            Op::Access(k) => {
+                if cache.get(&k).is_none() {
+                    cache.add(k, k);
+                }
+            }
+            Op::Purge => cache.purge(),
+        }
+    }
+    let elapsed = clock.now() - start;
+    let info = cache.stat();
+    Row {
+        policy,
+        ops: ops.len(),
+        hits: info.hit_count,
+        misses: info.miss_count,
+        evictions: info.eviction_count,
+        elapsed,
+        memory: info.memory,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compare() {
+        // a hot set revisited between one-off scans, which flush LRU and
+        // FIFO but not the protected segments of SLRU and 2Q
+        let mut keys = Vec::new();
+        for round in 0..20 {
+            for _ in 0..3 {
+                keys.extend(0..8);
+            }
+            keys.extend(1000 * (round + 1)..1000 * (round + 1) + 20);
+        }
+        let mut ops = Op::accesses(keys);
+        ops.push(Op::Remove(0));
+        ops.push(Op::Get(0));
+
+        let table = compare(&ops, 16);
+        let lru = &table.rows[0];
+        assert_eq!(lru.policy, Policy::Lru);
+        assert_eq!(lru.misses, 20 * 28 + 1);
+        assert!(table.best().unwrap().hit_ratio() > lru.hit_ratio());
+        assert_eq!(table.to_string().lines().count(), 5);
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_to_json() {
+        let ops = Op::accesses(vec![1, 2, 1, 3]);
+        let json = compare_policies(&[Policy::q2()], &ops, 2).to_json();
+        let row = &json["results"][0];
+        assert_eq!(json["size"], 2);
+        assert_eq!(row["policy"]["name"], "q2");
+        assert_eq!(row["policy"]["out_cache_factor"], 0.5);
+        assert_eq!(
+            (row["hits"].clone(), row["misses"].clone()),
+            (1.into(), 3.into())
+        );
+        assert_eq!(row["hit_ratio"], 0.25);
+    }
+}
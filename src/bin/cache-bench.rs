@@ -0,0 +1,116 @@
+//! Runs a synthetic workload or a trace against the policies and prints
+//! hit ratio, throughput and memory, as a table or as JSON.
+//!
+//! Every lookup reads through: a miss adds the key.
+
+use std::collections::HashMap;
+use std::env;
+use std::error::Error;
+use std::fs::File;
+use std::io::BufReader;
+use std::process;
+
+use cache::any::Policy;
+use cache::bench_harness::{self, Op};
+use cache::trace::{self, Format};
+use cache::workload::{Hotspot, ScanMixed, Uniform, Zipf};
+
+const USAGE: &str = "usage: cache-bench [options]
+
+    --policy <lru|fifo|slru|q2|all>      policies to run (default: all)
+    --size <n>                           entries per cache (default: 1000)
+    --workload <zipf|uniform|hotspot|scan>
+                                         key distribution (default: zipf)
+    --keys <n>                           distinct keys (default: 10 x size)
+    --exponent <f>                       zipf exponent (default: 0.99)
+    --trace <file>                       replay a trace instead
+    --format <arc|lirs|csv>              format of the trace (default: lirs)
+    --ops <n>                            operations to run (default: 1000000)
+    --seed <n>                           seed of the workload (default: 0)
+    --json                               print JSON instead of a table";
+
+fn parse_args(args: &[String]) -> Result<(HashMap<String, String>, bool), Box<dyn Error>> {
+    let mut options = HashMap::new();
+    let mut json = false;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--json" => json = true,
+            "--help" | "-h" => return Err(USAGE.into()),
+            name if name.starts_with("--") => {
+                let value = args
+                    .next()
+                    .ok_or_else(|| format!("missing value for {}", name))?;
+                options.insert(name[2..].to_owned(), value.clone());
+            }
+            _ => return Err(format!("unexpected argument {:?}\n\n{}", arg, USAGE).into()),
+        }
+    }
+    Ok((options, json))
+}
+
+fn option<T>(options: &HashMap<String, String>, name: &str, default: T) -> Result<T, Box<dyn Error>>
+where
+    T: std::str::FromStr,
+    T::Err: Error + 'static,
+{
+    match options.get(name) {
+        Some(value) => value
+            .parse()
+            .map_err(|err| format!("--{}: {}", name, err).into()),
+        None => Ok(default),
+    }
+}
+
+fn keys(options: &HashMap<String, String>, size: usize) -> Result<Vec<u64>, Box<dyn Error>> {
+    let ops: usize = option(options, "ops", 1_000_000)?;
+    if let Some(path) = options.get("trace") {
+        let format: Format = option(options, "format", Format::Lirs)?;
+        let file = File::open(path)?;
+        let keys = trace::read(BufReader::new(file), format).take(ops);
+        return Ok(keys.collect::<Result<_, _>>()?);
+    }
+    let n: u64 = option(options, "keys", 10 * size as u64)?;
+    let seed: u64 = option(options, "seed", 0)?;
+    let exponent: f64 = option(options, "exponent", 0.99)?;
+    let workload = options.get("workload").map_or("zipf", String::as_str);
+    let keys = match workload {
+        "zipf" => Zipf::new(n, exponent, seed).take(ops).collect(),
+        "uniform" => Uniform::new(n, seed).take(ops).collect(),
+        "hotspot" => Hotspot::new(n, 0.2, 0.8, seed).take(ops).collect(),
+        "scan" => {
+            let zipf = Zipf::new(n, exponent, seed);
+            ScanMixed::new(zipf, size as u64, 0.001, seed)
+                .take(ops)
+                .collect()
+        }
+        _ => return Err(format!("unknown workload {:?}", workload).into()),
+    };
+    Ok(keys)
+}
+
+fn run(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let (options, json) = parse_args(args)?;
+    let size: usize = option(&options, "size", 1000)?;
+    let policies = match options.get("policy").map(String::as_str) {
+        None | Some("all") => vec![Policy::Lru, Policy::Fifo, Policy::slru(), Policy::q2()],
+        Some(name) => vec![name.parse()?],
+    };
+
+    let ops = Op::accesses(keys(&options, size)?);
+    let table = bench_harness::compare_policies(&policies, &ops, size);
+    if json {
+        println!("{}", serde_json::to_string_pretty(&table.to_json())?);
+    } else {
+        print!("{}", table);
+    }
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if let Err(err) = run(&args) {
+        eprintln!("cache-bench: {}", err);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,47 @@
+//! Replays a trace file against a cache and prints the outcome.
+//!
+//! Usage: cache-trace <arc|lirs|csv> <lru|fifo|slru|q2|all> <size> <file>
+//!
+//! With `all`, every policy replays the trace and a comparison table is
+//! printed instead.
+
+use std::env;
+use std::error::Error;
+use std::fs::File;
+use std::io::BufReader;
+use std::process;
+
+use cache::any::{AnyCache, Policy};
+use cache::bench_harness::{self, Op};
+use cache::trace::{self, Format};
+
+const USAGE: &str = "usage: cache-trace <arc|lirs|csv> <lru|fifo|slru|q2|all> <size> <file>";
+
+fn run(args: &[String]) -> Result<(), Box<dyn Error>> {
+    if args.len() != 4 {
+        return Err(USAGE.into());
+    }
+    let format: Format = args[0].parse()?;
+    let size: usize = args[2].parse()?;
+    let file = File::open(&args[3])?;
+
+    if args[1] == "all" {
+        let keys = trace::read(BufReader::new(file), format).collect::<Result<Vec<_>, _>>()?;
+        print!("{}", bench_harness::compare(&Op::accesses(keys), size));
+        return Ok(());
+    }
+    let policy: Policy = args[1].parse()?;
+
+    let mut cache = AnyCache::new(policy, size);
+    let report = trace::replay(&mut cache, trace::read(BufReader::new(file), format))?;
+    println!("{}", report);
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if let Err(err) = run(&args) {
+        eprintln!("cache-trace: {}", err);
+        process::exit(1);
+    }
+}
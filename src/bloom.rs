@@ -0,0 +1,159 @@
+use std::mem;
+
+/// The number of bits set per key.
+const PROBES: u64 = 4;
+/// Bits per key the filter is sized for, which gives about one percent of
+/// false positives with four probes.
+const BITS_PER_KEY: usize = 10;
+
+/// A bloom filter over the hashes of keys.
+#[derive(Clone)]
+pub(crate) struct Bloom {
+    words: Vec<u64>,
+    mask: u64,
+}
+
+impl Bloom {
+    /// Creates a filter sized for `capacity` keys.
+    pub(crate) fn new(capacity: usize) -> Bloom {
+        let bits = Bloom::bits_for(capacity);
+        Bloom {
+            words: vec![0; bits / 64],
+            mask: bits as u64 - 1,
+        }
+    }
+
+    fn bits_for(capacity: usize) -> usize {
+        (capacity.max(1) * BITS_PER_KEY).next_power_of_two().max(64)
+    }
+
+    fn fits(&self, capacity: usize) -> bool {
+        self.mask == Bloom::bits_for(capacity) as u64 - 1
+    }
+
+    /// Returns a filter holding the keys of both, as large as the smaller.
+    /// Their sizes are powers of two, so the bits of the larger fold onto
+    /// the smaller exactly.
+    fn union(&self, other: &Bloom) -> Bloom {
+        let (small, large) = if self.words.len() <= other.words.len() {
+            (self, other)
+        } else {
+            (other, self)
+        };
+        let mut union = small.clone();
+        let len = union.words.len();
+        for (i, word) in large.words.iter().enumerate() {
+            union.words[i % len] |= word;
+        }
+        union
+    }
+
+    // double hashing from the two halves of the key hash
+    fn bits(&self, hash: u64) -> impl Iterator<Item = u64> {
+        let (low, high) = (hash, hash.rotate_left(32) | 1);
+        let mask = self.mask;
+        (0..PROBES).map(move |i| low.wrapping_add(i.wrapping_mul(high)) & mask)
+    }
+
+    pub(crate) fn contains(&self, hash: u64) -> bool {
+        self.bits(hash)
+            .all(|bit| self.words[(bit / 64) as usize] & (1 << (bit % 64)) != 0)
+    }
+
+    /// Adds the key with hash `hash` and returns whether it was already in.
+    pub(crate) fn insert(&mut self, hash: u64) -> bool {
+        let mut present = true;
+        for bit in self.bits(hash) {
+            let word = &mut self.words[(bit / 64) as usize];
+            present &= *word & (1 << (bit % 64)) != 0;
+            *word |= 1 << (bit % 64);
+        }
+        present
+    }
+
+    pub(crate) fn clear(&mut self) {
+        for word in self.words.iter_mut() {
+            *word = 0;
+        }
+    }
+
+    pub(crate) fn heap_bytes(&self) -> usize {
+        self.words.capacity() * mem::size_of::<u64>()
+    }
+}
+
+/// Two bloom filters used in turn: once `capacity` keys were added to the
+/// current one, the other is cleared and takes its place. The last
+/// `capacity` to `2 * capacity` keys added are remembered.
+pub(crate) struct RotatingBloom {
+    current: Bloom,
+    previous: Bloom,
+    capacity: usize,
+    // the number of keys added to each filter
+    current_len: usize,
+    previous_len: usize,
+}
+
+impl RotatingBloom {
+    pub(crate) fn new(capacity: usize) -> RotatingBloom {
+        RotatingBloom {
+            current: Bloom::new(capacity),
+            previous: Bloom::new(capacity),
+            capacity,
+            current_len: 0,
+            previous_len: 0,
+        }
+    }
+
+    /// Returns the number of keys in the filters, counting a key in both
+    /// twice.
+    pub(crate) fn len(&self) -> usize {
+        self.current_len + self.previous_len
+    }
+
+    pub(crate) fn contains(&self, hash: u64) -> bool {
+        self.current.contains(hash) || self.previous.contains(hash)
+    }
+
+    pub(crate) fn insert(&mut self, hash: u64) {
+        if self.capacity == 0 || self.current.insert(hash) {
+            return;
+        }
+        self.current_len += 1;
+        if self.current_len >= self.capacity {
+            mem::swap(&mut self.current, &mut self.previous);
+            if self.current.fits(self.capacity) {
+                self.current.clear();
+            } else {
+                self.current = Bloom::new(self.capacity);
+            }
+            self.previous_len = self.current_len;
+            self.current_len = 0;
+        }
+    }
+
+    /// Sizes the filters for `capacity` keys. The keys already added are
+    /// kept, both filters merged into the previous one, which is replaced
+    /// at the next turn.
+    pub(crate) fn resize(&mut self, capacity: usize) {
+        if capacity == self.capacity {
+            return;
+        }
+        self.previous = self.current.union(&self.previous);
+        self.current = Bloom::new(capacity);
+        self.previous_len += self.current_len;
+        self.current_len = 0;
+        self.capacity = capacity;
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.current.clear();
+        self.previous.clear();
+        self.current_len = 0;
+        self.previous_len = 0;
+    }
+
+    pub(crate) fn heap_bytes(&self) -> usize {
+        self.current.heap_bytes() + self.previous.heap_bytes()
+    }
+}
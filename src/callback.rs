@@ -0,0 +1,140 @@
+use std::any::Any;
+use std::collections::VecDeque;
+use std::hash::{BuildHasher, Hash};
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+
+use super::map::LinkedHashMap;
+use super::stats::{EntryEvent, EvictionReason};
+
+/// How many entries from the back of a segment are offered to an eviction
+/// filter before giving up on finding a victim.
+pub(crate) const FILTER_SCAN: usize = 16;
+
+/// Receives ownership of every entry that leaves a cache by eviction.
+pub(crate) type Callback<K, V> = Box<dyn FnMut(K, V)>;
+
+/// Receives the entries evicted by one operation, a batch at a time.
+pub(crate) type BatchCallback<K, V> = Box<dyn FnMut(Vec<(K, V)>)>;
+
+/// Sees every evicted entry before its ownership is handed on.
+pub(crate) type Observer<K, V> = Box<dyn FnMut(&K, &V)>;
+
+/// Decides whether an entry may be chosen as an eviction victim.
+pub(crate) type Filter<K, V> = Box<dyn Fn(&K, &V) -> bool>;
+
+/// The payload of a panic raised by a user callback.
+pub(crate) type Panic = Box<dyn Any + Send>;
+
+/// Runs the user callback `f`, catching its panic into `panic` so that the
+/// operation that invoked it can finish, leaving the cache consistent,
+/// before `resume` raises it again. Only the first panic of an operation
+/// is kept.
+pub(crate) fn guard<F: FnOnce()>(panic: &mut Option<Panic>, f: F) {
+    if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(f)) {
+        panic.get_or_insert(payload);
+    }
+}
+
+/// Raises again the panic caught by `guard`, if any.
+pub(crate) fn resume(panic: &mut Option<Panic>) {
+    if let Some(payload) = panic.take() {
+        panic::resume_unwind(payload);
+    }
+}
+
+/// Pops the least recent entry of `map` that passes `filter`, never its
+/// front one if `spare_front` is set. Returns `None` if no entry within
+/// `FILTER_SCAN` of the back may be evicted, in which case the cache stays
+/// over its limit until a later insertion retries.
+pub(crate) fn pop_victim<K, V, S>(
+    map: &mut LinkedHashMap<K, V, S>,
+    filter: &Option<Filter<K, V>>,
+    spare_front: bool,
+) -> Option<(K, V)>
+where
+    K: Hash + Eq,
+    S: BuildHasher,
+{
+    // a scan from the back reaches the front entry last
+    let len = map.len().saturating_sub(spare_front as usize);
+    if len == 0 {
+        return None;
+    }
+    match filter {
+        Some(filter) => map.pop_back_matching(FILTER_SCAN.min(len), |k, v| filter(k, v)),
+        None => map.pop_back(),
+    }
+}
+
+/// Collects evicted entries for a `BatchCallback`, which is called once
+/// `size` of them are pending and again when the evicting operation ends.
+pub(crate) struct Batch<K, V> {
+    callback: BatchCallback<K, V>,
+    size: usize,
+    pending: Vec<(K, V)>,
+}
+
+impl<K, V> Batch<K, V> {
+    pub(crate) fn new(size: usize, callback: BatchCallback<K, V>) -> Batch<K, V> {
+        let size = size.max(1);
+        Batch {
+            callback,
+            size,
+            pending: Vec::new(),
+        }
+    }
+
+    pub(crate) fn push(&mut self, k: K, v: V) {
+        self.pending.push((k, v));
+        if self.pending.len() >= self.size {
+            self.flush();
+        }
+    }
+
+    pub(crate) fn flush(&mut self) {
+        if !self.pending.is_empty() {
+            let batch = mem::take(&mut self.pending);
+            (self.callback)(batch);
+        }
+    }
+}
+
+/// Buffers the entry events of a cache until they are taken, dropping the
+/// oldest once `capacity` of them are pending.
+pub(crate) struct EventQueue<K, V> {
+    events: VecDeque<EntryEvent<K, V>>,
+    capacity: usize,
+    // inserted keys are moved into the cache, so the queue keeps a copy
+    clone_key: fn(&K) -> K,
+}
+
+impl<K, V> EventQueue<K, V> {
+    pub(crate) fn new(capacity: usize, clone_key: fn(&K) -> K) -> EventQueue<K, V> {
+        EventQueue {
+            events: VecDeque::new(),
+            capacity: capacity.max(1),
+            clone_key,
+        }
+    }
+
+    pub(crate) fn inserted(&mut self, k: &K) {
+        let k = (self.clone_key)(k);
+        self.push(EntryEvent::Inserted(k));
+    }
+
+    pub(crate) fn evicted(&mut self, key: K, value: V, reason: EvictionReason) {
+        self.push(EntryEvent::Evicted { key, value, reason });
+    }
+
+    pub(crate) fn take(&mut self) -> Vec<EntryEvent<K, V>> {
+        self.events.drain(..).collect()
+    }
+
+    fn push(&mut self, event: EntryEvent<K, V>) {
+        if self.events.len() >= self.capacity {
+            self.events.pop_front();
+        }
+        self.events.push_back(event);
+    }
+}
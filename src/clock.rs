@@ -0,0 +1,123 @@
+//! Time sources for the time-based features of the caches.
+//!
+//! A clock reports the time elapsed since an origin of its own choosing;
+//! only differences between readings are meaningful.
+//!
+//! `SystemClock` relies on `Instant`, which panics on
+//! `wasm32-unknown-unknown`. There the `wasm` feature provides `JsClock`,
+//! which becomes the `DefaultClock` used by the caches when no clock is
+//! given.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+pub trait Clock {
+    fn now(&self) -> Duration;
+}
+
+/// The clock the caches use unless given one.
+#[cfg(not(all(target_arch = "wasm32", feature = "wasm")))]
+pub type DefaultClock = SystemClock;
+
+/// The clock the caches use unless given one.
+#[cfg(all(target_arch = "wasm32", feature = "wasm"))]
+pub type DefaultClock = JsClock;
+
+/// Reads the monotonic system clock.
+#[derive(Debug, Clone, Copy)]
+pub struct SystemClock {
+    origin: Instant,
+}
+
+impl SystemClock {
+    pub fn new() -> SystemClock {
+        SystemClock {
+            origin: Instant::now(),
+        }
+    }
+}
+
+impl Default for SystemClock {
+    fn default() -> SystemClock {
+        SystemClock::new()
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        self.origin.elapsed()
+    }
+}
+
+/// A clock that only moves when told to. Clones share the same time, so a
+/// test can keep one handle and give another to a cache.
+#[derive(Debug, Clone, Default)]
+pub struct ManualClock {
+    nanos: Arc<AtomicU64>,
+}
+
+impl ManualClock {
+    pub fn new() -> ManualClock {
+        Default::default()
+    }
+
+    pub fn advance(&self, by: Duration) {
+        self.nanos.fetch_add(by.as_nanos() as u64, Ordering::SeqCst);
+    }
+
+    pub fn set(&self, to: Duration) {
+        self.nanos.store(to.as_nanos() as u64, Ordering::SeqCst);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Duration {
+        Duration::from_nanos(self.nanos.load(Ordering::SeqCst))
+    }
+}
+
+/// Reads JavaScript's `Date.now()`, in milliseconds. Readings never go
+/// backwards, even if the wall clock does.
+#[cfg(feature = "wasm")]
+#[derive(Debug)]
+pub struct JsClock {
+    origin: f64,
+    last: AtomicU64,
+}
+
+#[cfg(feature = "wasm")]
+impl JsClock {
+    pub fn new() -> JsClock {
+        JsClock {
+            origin: js_sys::Date::now(),
+            last: AtomicU64::new(0),
+        }
+    }
+}
+
+#[cfg(feature = "wasm")]
+impl Default for JsClock {
+    fn default() -> JsClock {
+        JsClock::new()
+    }
+}
+
+#[cfg(feature = "wasm")]
+impl Clone for JsClock {
+    fn clone(&self) -> JsClock {
+        JsClock {
+            origin: self.origin,
+            last: AtomicU64::new(self.last.load(Ordering::SeqCst)),
+        }
+    }
+}
+
+#[cfg(feature = "wasm")]
+impl Clock for JsClock {
+    fn now(&self) -> Duration {
+        let millis = (js_sys::Date::now() - self.origin).max(0.0) as u64;
+        let millis = self.last.fetch_max(millis, Ordering::SeqCst).max(millis);
+        Duration::from_millis(millis)
+    }
+}
@@ -0,0 +1,203 @@
+//! The API of the `lru` crate's `LruCache`, backed by `lru::Cache`.
+//!
+//! Code written against `lru` can switch to this crate by changing its
+//! import, and move to another policy later on.
+//!
+//! ```
+//! use std::num::NonZeroUsize;
+//! use cache::compat::LruCache;
+//!
+//! let mut cache = LruCache::new(NonZeroUsize::new(2).unwrap());
+//! cache.put("apple", 3);
+//! cache.put("banana", 2);
+//! assert_eq!(cache.get(&"apple"), Some(&3));
+//! cache.put("pear", 4);
+//! assert!(!cache.contains(&"banana"));
+//! assert_eq!(cache.pop_lru(), Some(("apple", 3)));
+//! ```
+
+use std::borrow::Borrow;
+use std::convert::TryFrom;
+use std::hash::{BuildHasher, Hash};
+use std::num::NonZeroUsize;
+
+use super::core;
+use super::hash::DefaultState;
+use super::lru;
+
+pub struct LruCache<K, V, S = DefaultState> {
+    inner: lru::Cache<K, V, S>,
+}
+
+impl<K: Hash + Eq, V> LruCache<K, V, DefaultState> {
+    pub fn new(cap: NonZeroUsize) -> LruCache<K, V, DefaultState> {
+        LruCache::with_hasher(cap, Default::default())
+    }
+}
+
+impl<K, V, S> LruCache<K, V, S>
+where
+    K: Hash + Eq,
+    S: BuildHasher,
+{
+    pub fn with_hasher(cap: NonZeroUsize, hash_builder: S) -> LruCache<K, V, S> {
+        LruCache {
+            inner: lru::Cache::with_hasher_nonzero(cap, hash_builder),
+        }
+    }
+
+    /// Caches `v` under `k`, returning the value it replaces.
+    pub fn put(&mut self, k: K, v: V) -> Option<V> {
+        self.inner.add(k, v)
+    }
+
+    /// Caches `v` under `k`, returning the entry it replaces or else the
+    /// one evicted to make room for it.
+    pub fn push(&mut self, k: K, v: V) -> Option<(K, V)> {
+        let (old, mut evicted) = self.inner.push_entry(k, v);
+        old.or_else(|| evicted.pop())
+    }
+
+    pub fn get<Q>(&mut self, k: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        self.inner.get(k)
+    }
+
+    pub fn peek<Q>(&self, k: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        self.inner.peek(k)
+    }
+
+    /// Returns the least recently used entry without touching it.
+    pub fn peek_lru(&self) -> Option<(&K, &V)> {
+        self.inner.iter().next_back()
+    }
+
+    pub fn contains<Q>(&self, k: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        self.inner.contains_key(k)
+    }
+
+    pub fn pop<Q>(&mut self, k: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        self.inner.remove(k)
+    }
+
+    pub fn pop_entry<Q>(&mut self, k: &Q) -> Option<(K, V)>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        self.inner.remove_entry(k)
+    }
+
+    pub fn pop_lru(&mut self) -> Option<(K, V)> {
+        self.inner.remove_lru()
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+
+    pub fn cap(&self) -> NonZeroUsize {
+        NonZeroUsize::new(self.inner.max_size()).unwrap()
+    }
+
+    /// Changes the capacity, dropping the least recently used entries that
+    /// no longer fit.
+    pub fn resize(&mut self, cap: NonZeroUsize) {
+        self.inner.resize(cap.get());
+    }
+
+    pub fn clear(&mut self) {
+        self.inner.purge();
+    }
+
+    /// Iterates from the most to the least recently used entry.
+    pub fn iter(&self) -> core::Iter<'_, K, V> {
+        self.inner.iter()
+    }
+
+    pub fn inner(&self) -> &lru::Cache<K, V, S> {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> lru::Cache<K, V, S> {
+        self.inner
+    }
+}
+
+/// Wraps a cache of nonzero size; a disabled one, which has no capacity
+/// `cap` could report, is handed back.
+impl<K, V, S> TryFrom<lru::Cache<K, V, S>> for LruCache<K, V, S>
+where
+    K: Hash + Eq,
+    S: BuildHasher,
+{
+    type Error = lru::Cache<K, V, S>;
+
+    fn try_from(inner: lru::Cache<K, V, S>) -> Result<LruCache<K, V, S>, lru::Cache<K, V, S>> {
+        if inner.max_size() == 0 {
+            return Err(inner);
+        }
+        Ok(LruCache { inner })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lru_semantics() {
+        let mut cache = LruCache::new(NonZeroUsize::new(2).unwrap());
+        assert_eq!(cache.put(1, "a"), None);
+        assert_eq!(cache.put(1, "b"), Some("a"));
+        assert_eq!(cache.push(2, "c"), None);
+        assert_eq!(cache.push(2, "d"), Some((2, "c")));
+        assert_eq!(cache.push(3, "e"), Some((1, "b")));
+        assert_eq!(cache.peek_lru(), Some((&2, &"d")));
+
+        cache.resize(NonZeroUsize::new(1).unwrap());
+        assert_eq!(cache.cap().get(), 1);
+        assert_eq!(cache.iter().collect::<Vec<_>>(), vec![(&3, &"e")]);
+        assert_eq!(cache.pop_lru(), Some((3, "e")));
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn test_push_replace() {
+        let mut cache = LruCache::new(NonZeroUsize::new(2).unwrap());
+        cache.push(1, "a");
+        assert_eq!(cache.push(1, "b"), Some((1, "a")));
+        let stat = cache.inner().stat();
+        assert_eq!((stat.insert_count, stat.replace_count), (1, 1));
+    }
+
+    #[test]
+    fn test_try_from() {
+        let cache: lru::Cache<u32, u32> = lru::Cache::new(3);
+        let cap = LruCache::try_from(cache)
+            .ok()
+            .map(|cache| cache.cap().get());
+        assert_eq!(cap, Some(3));
+        let disabled: lru::Cache<u32, u32> = lru::Cache::disabled();
+        assert!(LruCache::try_from(disabled).is_err());
+    }
+}
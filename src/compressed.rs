@@ -0,0 +1,311 @@
+//! An LRU cache with a compressed victim cache.
+//!
+//! Entries evicted from the main cache are encoded, compressed and kept in
+//! a second LRU cache bounded by their compressed size. A hit there
+//! decompresses the entry and moves it back to the main cache. Entries reach
+//! the eviction callback once they leave the victim cache too.
+//!
+//! The compressors are behind the `lz4` and `zstd` features.
+//!
+//! ```
+//! # #[cfg(feature = "lz4")]
+//! # {
+//! use cache::compressed::{Cache, Lz4};
+//!
+//! let mut cache = Cache::new(1, 1 << 20, Lz4);
+//! cache.add(1, "a".repeat(1000));
+//! cache.add(2, "b".repeat(1000));
+//! assert_eq!(cache.victim_len(), 1);
+//! assert!(cache.compressed_bytes() < 100);
+//! assert_eq!(cache.get(&1).map(String::len), Some(1000));
+//! # }
+//! ```
+
+use std::borrow::Borrow;
+use std::hash::{BuildHasher, Hash};
+use std::io;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use super::callback::Callback;
+use super::hash::DefaultState;
+use super::lru;
+use super::stats::Info;
+
+pub trait Compressor {
+    fn compress(&self, data: &[u8]) -> io::Result<Vec<u8>>;
+
+    fn decompress(&self, data: &[u8]) -> io::Result<Vec<u8>>;
+}
+
+/// LZ4 block compression: fast, with a moderate ratio.
+#[cfg(feature = "lz4")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Lz4;
+
+#[cfg(feature = "lz4")]
+impl Compressor for Lz4 {
+    fn compress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        Ok(lz4_flex::compress_prepend_size(data))
+    }
+
+    fn decompress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        lz4_flex::decompress_size_prepended(data)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+}
+
+/// Zstandard compression at the given level; higher levels trade speed for
+/// ratio.
+#[cfg(feature = "zstd")]
+#[derive(Debug, Clone, Copy)]
+pub struct Zstd {
+    pub level: i32,
+}
+
+#[cfg(feature = "zstd")]
+impl Default for Zstd {
+    fn default() -> Zstd {
+        Zstd {
+            level: zstd::DEFAULT_COMPRESSION_LEVEL,
+        }
+    }
+}
+
+#[cfg(feature = "zstd")]
+impl Compressor for Zstd {
+    fn compress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        zstd::bulk::compress(data, self.level)
+    }
+
+    fn decompress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        zstd::stream::decode_all(data)
+    }
+}
+
+pub struct Cache<K, V, C, S = DefaultState> {
+    main: lru::Cache<K, V, S>,
+    victims: lru::Cache<K, Vec<u8>>,
+    compressor: C,
+    callback: Option<Callback<K, V>>,
+}
+
+impl<K, V, C> Cache<K, V, C, DefaultState>
+where
+    K: Hash + Eq,
+    V: Serialize + DeserializeOwned,
+    C: Compressor,
+{
+    pub fn new(max_size: usize, max_compressed_bytes: usize, compressor: C) -> Self {
+        Cache::with_hasher(
+            max_size,
+            max_compressed_bytes,
+            compressor,
+            Default::default(),
+        )
+    }
+}
+
+impl<K, V, C, S> Cache<K, V, C, S>
+where
+    K: Hash + Eq,
+    V: Serialize + DeserializeOwned,
+    C: Compressor,
+    S: BuildHasher,
+{
+    /// Creates a cache holding `max_size` entries uncompressed, and up to
+    /// `max_compressed_bytes` of compressed values besides.
+    pub fn with_hasher(
+        max_size: usize,
+        max_compressed_bytes: usize,
+        compressor: C,
+        hash_builder: S,
+    ) -> Self {
+        let mut victims = lru::Cache::new(1);
+        victims.set_weigher(max_compressed_bytes, |_, v: &Vec<u8>| v.len() as u32);
+        Cache {
+            main: lru::Cache::with_hasher(max_size, hash_builder),
+            victims,
+            compressor,
+            callback: None,
+        }
+    }
+
+    /// Registers the callback of entries that leave the victim cache, or
+    /// that could not be compressed. Entries whose compressed form fails to
+    /// decode are dropped without it.
+    pub fn set_eviction_callback<F>(&mut self, cb: F)
+    where
+        F: FnMut(K, V) + 'static,
+    {
+        self.callback = Some(Box::new(cb));
+    }
+
+    fn compress(&self, v: &V) -> io::Result<Vec<u8>> {
+        let bytes = bincode::serialize(v).map_err(io::Error::other)?;
+        self.compressor.compress(&bytes)
+    }
+
+    fn decompress(&self, data: &[u8]) -> Option<V> {
+        let bytes = self.compressor.decompress(data).ok()?;
+        bincode::deserialize(&bytes).ok()
+    }
+
+    pub fn contains_key<Q>(&self, k: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        self.main.contains_key(k) || self.victims.contains_key(k)
+    }
+
+    /// Looks `k` up in the main cache, then in the victim cache, from which
+    /// a hit is decompressed and moved back to the main cache.
+    pub fn get<Q>(&mut self, k: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        if !self.main.contains_key(k) {
+            // looked up first to be counted in the victim statistics
+            if self.victims.get(k).is_none() {
+                return self.main.get(k);
+            }
+            let (k, data) = self.victims.remove_entry(k)?;
+            let v = self.decompress(&data)?;
+            let (_, displaced) = self.main.push(k, v);
+            self.demote(displaced);
+        }
+        self.main.get(k)
+    }
+
+    /// Caches `v` under `k` uncompressed and returns the value it replaces,
+    /// from either cache.
+    pub fn add(&mut self, k: K, v: V) -> Option<V> {
+        let old_v = self
+            .victims
+            .remove(&k)
+            .and_then(|data| self.decompress(&data));
+        let (main_v, displaced) = self.main.push(k, v);
+        self.demote(displaced);
+        main_v.or(old_v)
+    }
+
+    pub fn remove<Q>(&mut self, k: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        if let Some(v) = self.main.remove(k) {
+            return Some(v);
+        }
+        let data = self.victims.remove(k)?;
+        self.decompress(&data)
+    }
+
+    fn demote(&mut self, displaced: Vec<(K, V)>) {
+        for (k, v) in displaced {
+            let data = match self.compress(&v) {
+                Ok(data) => data,
+                Err(_) => {
+                    self.evict(k, v);
+                    continue;
+                }
+            };
+            let (_, evicted) = self.victims.push(k, data);
+            if self.callback.is_none() {
+                continue;
+            }
+            for (k, data) in evicted {
+                if let Some(v) = self.decompress(&data) {
+                    self.evict(k, v);
+                }
+            }
+        }
+    }
+
+    fn evict(&mut self, k: K, v: V) {
+        if let Some(cb) = &mut self.callback {
+            cb(k, v);
+        }
+    }
+
+    /// Returns the number of entries in both caches.
+    pub fn len(&self) -> usize {
+        self.main.len() + self.victims.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.main.is_empty() && self.victims.is_empty()
+    }
+
+    pub fn main_len(&self) -> usize {
+        self.main.len()
+    }
+
+    pub fn victim_len(&self) -> usize {
+        self.victims.len()
+    }
+
+    /// Returns the total size of the compressed values.
+    pub fn compressed_bytes(&self) -> usize {
+        self.victims.weighted_size()
+    }
+
+    pub fn max_size(&self) -> usize {
+        self.main.max_size()
+    }
+
+    pub fn purge(&mut self) {
+        self.main.purge();
+        self.victims.purge();
+    }
+
+    /// Returns the statistics of the main cache.
+    pub fn stat(&self) -> Info {
+        self.main.stat()
+    }
+
+    /// Returns the statistics of the victim cache, whose hits are the
+    /// entries decompressed and whose misses the lookups that missed both
+    /// caches.
+    pub fn victim_stat(&self) -> Info {
+        self.victims.stat()
+    }
+}
+
+#[cfg(all(test, feature = "lz4"))]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_victim_cache() {
+        let evicted = Rc::new(RefCell::new(Vec::new()));
+        let sink = evicted.clone();
+        let mut cache = Cache::new(2, 64, Lz4);
+        cache.set_eviction_callback(move |k, _: String| sink.borrow_mut().push(k));
+        for i in 0..4 {
+            cache.add(i, i.to_string().repeat(500));
+        }
+        assert_eq!((cache.main_len(), cache.victim_len()), (2, 2));
+
+        assert_eq!(cache.get(&0).map(String::len), Some(500));
+        assert_eq!((cache.main_len(), cache.victim_len()), (2, 2));
+        assert!(!cache.victims.contains_key(&0));
+        assert_eq!(cache.victim_stat().hit_count, 1);
+        assert_eq!(cache.get(&10), None);
+        assert_eq!(cache.victim_stat().miss_count, 1);
+        assert_eq!(cache.add(1, "x".to_string()).map(|v| v.len()), Some(500));
+        assert!(evicted.as_ref().borrow().is_empty());
+
+        for i in 4..10 {
+            cache.add(i, i.to_string().repeat(500));
+        }
+        assert!(cache.compressed_bytes() <= 64);
+        assert!(!evicted.as_ref().borrow().is_empty());
+        assert_eq!(cache.len(), cache.main_len() + cache.victim_len());
+    }
+}
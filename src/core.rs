@@ -0,0 +1,2610 @@
+//! A cache that is generic over its replacement policy.
+//!
+//! `Cache` owns the statistics, callbacks and trackers, while a
+//! `ReplacementPolicy` only decides where entries go in the ordered `Store`
+//! and which one leaves when the cache is full. By convention the back of
+//! the store is the next victim: that is where `Store::pop_victim`, which
+//! honours the eviction filter and the priorities, and the admission policy
+//! look. `lru::Cache` and `fifo::Cache` are this cache with the `Lru` and
+//! `Fifo` policies; `slru::Cache` and `q2::Cache` are this cache with the
+//! `Slru` and `Q2` policies, whose store keeps a protected segment ahead of
+//! a probationary one.
+//!
+//! ```
+//! use std::borrow::Borrow;
+//! use std::hash::{BuildHasher, Hash};
+//!
+//! use cache::core::{Cache, ReplacementPolicy, Store};
+//!
+//! /// Evicts the most recently read entry, keeping new ones until they are
+//! /// read.
+//! struct Mru;
+//!
+//! impl<K: Hash + Eq, V, S: BuildHasher> ReplacementPolicy<K, V, S> for Mru {
+//!     fn on_hit<'a, Q>(&mut self, store: &'a mut Store<K, V, S>, k: &Q) -> Option<(&'a K, &'a V)>
+//!     where
+//!         K: Borrow<Q>,
+//!         Q: ?Sized + Eq + Hash,
+//!     {
+//!         store.move_to_back(k);
+//!         store.get_key_value(k)
+//!     }
+//!
+//!     fn on_insert(&mut self, store: &mut Store<K, V, S>, k: K, v: V) -> Option<(K, V)> {
+//!         store.upsert_front(k, v, false)
+//!     }
+//! }
+//!
+//! let mut cache = Cache::with_policy(Mru, 2);
+//! cache.add(1, "a");
+//! cache.add(2, "b");
+//! cache.get(&1);
+//! cache.add(3, "c");
+//! assert!(!cache.contains_key(&1));
+//! assert!(cache.contains_key(&2));
+//! ```
+
+use std::borrow::Borrow;
+use std::collections::btree_map::{self, BTreeMap};
+use std::hash::{BuildHasher, Hash};
+use std::iter::{Chain, Flatten};
+use std::num::NonZeroUsize;
+use std::ops::RangeInclusive;
+use std::option;
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::adaptive::IdleShrink;
+use super::admission::{Admission, AdmissionPolicy, PromotionLimit};
+use super::callback::{self, Batch, Callback, EventQueue, Filter, Observer, Panic};
+use super::error::{self, ConfigError};
+use super::hash::{DefaultState, SeededState};
+use super::heap_size::{self, HeapSize};
+use super::map::{self, Dropped, LinkedHashMap};
+use super::stats::{
+    AgeTracking, Counters, EntryEvent, EntryMeta, EvictionReason, GhostCounts, HitWindow, HotKeys,
+    Info, LatencySampling, MemoryUsage, Operation, Removal, Segment, Stats, StatsRecorder,
+};
+use super::version::Versions;
+use super::weight::Weigher;
+
+/// What `Cache::push_entry` returns: the replaced entry, then the displaced
+/// ones.
+type Pushed<K, V> = (Option<(K, V)>, Vec<(K, V)>);
+
+/// The entries taken out of a `Store`: those of the protected segment, then
+/// those on probation.
+type Drained<K, V> = (Vec<(K, V)>, Vec<(K, V)>);
+
+/// Iterates over the entries of a cache from the most protected one to the
+/// next victim, the protected segment of a segmented cache first.
+pub type Iter<'a, K, V> = Chain<map::Iter<'a, K, V>, map::Iter<'a, K, V>>;
+
+/// Iterates like `Iter` from a given entry on, as returned by
+/// `Cache::iter_from`.
+pub type IterFrom<'a, K, V> = Chain<map::IterFrom<'a, K, V>, map::IterFrom<'a, K, V>>;
+
+/// Moves the entries out of a cache in the order of `Iter`.
+pub type IntoIter<K, V, S = DefaultState> =
+    Chain<Flatten<option::IntoIter<LinkedHashMap<K, V, S>>>, map::IntoIter<K, V, S>>;
+
+/// Decides the order of the entries in a cache's store.
+///
+/// The hooks are only called for keys that are in the store, except for
+/// `on_remove` and for `admits` and `on_insert`, which must add the entry if
+/// its key is new.
+pub trait ReplacementPolicy<K: Hash + Eq, V, S: BuildHasher> {
+    /// The smallest size of a cache of this policy; smaller sizes are raised
+    /// to it.
+    const MIN_SIZE: usize = 1;
+
+    /// Looks up `k` for a read, moving its entry as a hit calls for.
+    fn on_hit<'a, Q>(&mut self, store: &'a mut Store<K, V, S>, k: &Q) -> Option<(&'a K, &'a V)>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash;
+
+    /// Stores `v` under `k`: a new key with `Store::upsert_front`, which
+    /// lets the cache find its entry, or in place of the value of a cached
+    /// one, whose entry is then returned.
+    fn on_insert(&mut self, store: &mut Store<K, V, S>, k: K, v: V) -> Option<(K, V)>;
+
+    /// Returns whether `k` may be stored, before the admission policy of the
+    /// cache is asked about a new key. Keys turned down are handed to the
+    /// eviction callback.
+    fn admits(&mut self, _store: &Store<K, V, S>, _k: &K) -> bool {
+        true
+    }
+
+    /// Called with the hash of a key that left the store other than through
+    /// `choose_victim`, or that was removed while not cached.
+    fn on_remove(&mut self, _hash: u64) {}
+
+    /// Removes and returns the entry to evict, if any. Every eviction goes
+    /// through here; a policy that picks its own victim should take it with
+    /// `Store::pop_victim`, which honours the eviction filter and the
+    /// priorities.
+    fn choose_victim(&mut self, store: &mut Store<K, V, S>) -> Option<(K, V)> {
+        store.pop_victim()
+    }
+
+    /// Called when the limits of the cache change, before it evicts down to
+    /// them.
+    fn on_resize(&mut self, _store: &mut Store<K, V, S>, _max_size: usize, _max_weight: usize) {}
+
+    /// Called after `purge` emptied the store.
+    fn on_purge(&mut self) {}
+
+    /// Called after `merge` took in the entries of the cache that `other`
+    /// was the policy of, before it evicts down to the limits.
+    fn on_merge(&mut self, _store: &mut Store<K, V, S>, _other: Self)
+    where
+        Self: Sized,
+    {
+    }
+
+    /// Returns the segment that `warm_from_iter` adds an entry of `weight`
+    /// to, at its back, or `None` to skip the entry.
+    fn warm_segment(&mut self, _store: &Store<K, V, S>, _weight: usize) -> Option<Segment> {
+        Some(Segment::Single)
+    }
+
+    /// Returns the memory the policy holds besides the store.
+    fn memory_usage(&self) -> MemoryUsage {
+        MemoryUsage::default()
+    }
+
+    /// Frees the spare capacity of what the policy holds.
+    fn shrink_to_fit(&mut self) {}
+}
+
+/// The entries of a `Cache` in the order its policy keeps them, the next
+/// victim at the back. The store of a segmented cache keeps a protected
+/// segment ahead of the probationary one: victims are taken from the
+/// probationary one first, and entries only move between the two when the
+/// policy protects or demotes them.
+pub struct Store<K, V, S> {
+    // the probationary segment, or the whole store without segments
+    map: LinkedHashMap<K, V, S>,
+    protected: Option<LinkedHashMap<K, V, S>>,
+    weigher: Weigher<K, V>,
+    weight: usize,
+    protected_weight: usize,
+    filter: Option<Filter<K, V>>,
+    // kept in the order of the segments by the methods below
+    priorities: Option<Priorities<K>>,
+    demotion: Option<Observer<K, V>>,
+    promotion_limit: Option<PromotionLimit>,
+    // the panic of a callback, held until the operation that ran it ends
+    panic: Option<Panic>,
+    // while an insertion stores a new key, whether its entry is at the front
+    // of the protected segment rather than of `map`; it is never a victim
+    spared: Option<bool>,
+    ghosts: GhostCounts,
+}
+
+/// Returns the protected segment of a store if `protected` is set and the
+/// store has one, or else `map`.
+fn segment_mut<'a, K, V, S>(
+    map: &'a mut LinkedHashMap<K, V, S>,
+    protected: &'a mut Option<LinkedHashMap<K, V, S>>,
+    in_protected: bool,
+) -> &'a mut LinkedHashMap<K, V, S> {
+    match protected {
+        Some(protected) if in_protected => protected,
+        _ => map,
+    }
+}
+
+impl<K: Hash + Eq, V, S: BuildHasher> Store<K, V, S> {
+    fn new(
+        map: LinkedHashMap<K, V, S>,
+        protected: Option<LinkedHashMap<K, V, S>>,
+    ) -> Store<K, V, S> {
+        Store {
+            map,
+            protected,
+            weigher: Weigher::unit(),
+            weight: 0,
+            protected_weight: 0,
+            filter: None,
+            priorities: None,
+            demotion: None,
+            promotion_limit: None,
+            panic: None,
+            spared: None,
+            ghosts: GhostCounts::default(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len() + self.protected.as_ref().map_or(0, LinkedHashMap::len)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn contains_key<Q>(&self, k: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        let hash = self.map.hash(k);
+        self.in_protected(hash, k) || self.map.contains_key_hashed(hash, k)
+    }
+
+    pub fn get_key_value<Q>(&self, k: &Q) -> Option<(&K, &V)>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        let hash = self.map.hash(k);
+        let protected = self.protected.as_ref();
+        let found = protected.and_then(|protected| protected.get_key_value_hashed(hash, k));
+        found.or_else(|| self.map.get_key_value_hashed(hash, k))
+    }
+
+    /// Moves the entry of `k` to the front of its segment and returns it.
+    pub fn get_refresh<Q>(&mut self, k: &Q) -> Option<(&K, &V)>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        let hash = self.map.hash(k);
+        let protected = self.in_protected(hash, k);
+        let segment = segment_mut(&mut self.map, &mut self.protected, protected);
+        let found = segment.get_refresh_hashed(hash, k);
+        if let (Some(priorities), Some(_)) = (&mut self.priorities, &found) {
+            priorities.moved(hash, k, protected, true);
+        }
+        found
+    }
+
+    /// Moves the entry of `k` to the front of its segment.
+    pub fn move_to_front<Q>(&mut self, k: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        let hash = self.map.hash(k);
+        let protected = self.in_protected(hash, k);
+        let segment = segment_mut(&mut self.map, &mut self.protected, protected);
+        let moved = segment.move_to_front_hashed(hash, k);
+        if let (Some(priorities), true) = (&mut self.priorities, moved) {
+            priorities.moved(hash, k, protected, true);
+        }
+        moved
+    }
+
+    /// Moves the entry of `k` to the back of its segment.
+    pub fn move_to_back<Q>(&mut self, k: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        let hash = self.map.hash(k);
+        let protected = self.in_protected(hash, k);
+        let segment = segment_mut(&mut self.map, &mut self.protected, protected);
+        let moved = segment.move_to_back_hashed(hash, k);
+        if let (Some(priorities), true) = (&mut self.priorities, moved) {
+            priorities.moved(hash, k, protected, false);
+        }
+        moved
+    }
+
+    /// Stores `v` under `k`, at the front of the store, or of its
+    /// probationary segment, if `k` is new. The value of a cached key is
+    /// replaced in place, its entry moved to the front of its segment if
+    /// `refresh` is set, and returned with the old value.
+    pub fn upsert_front(&mut self, k: K, v: V, refresh: bool) -> Option<(K, V)> {
+        let hash = self.map.hash(&k);
+        let weight = self.weigher.weigh(&k, &v);
+        let protected = self.in_protected(hash, &k);
+        let segment = segment_mut(&mut self.map, &mut self.protected, protected);
+        let replaced = segment.upsert_front_hashed(hash, k, v, refresh);
+        self.add_weight(weight, protected);
+        match &replaced {
+            Some((k, old_v)) => {
+                self.sub_weight(self.weigher.weigh(k, old_v), protected);
+                if let Some(priorities) = &mut self.priorities {
+                    priorities.replaced(hash, k, protected, refresh);
+                }
+            }
+            None => {
+                self.spared = Some(false);
+                if let (Some(priorities), Some((k, _))) = (&mut self.priorities, self.map.front()) {
+                    priorities.insert(hash, k, false, true);
+                }
+            }
+        }
+        replaced
+    }
+
+    pub fn front(&self) -> Option<(&K, &V)> {
+        let protected = self.protected.as_ref().and_then(LinkedHashMap::front);
+        protected.or_else(|| self.map.front())
+    }
+
+    pub fn back(&self) -> Option<(&K, &V)> {
+        let protected = self.protected.as_ref();
+        let back = self.map.back();
+        back.or_else(|| protected.and_then(LinkedHashMap::back))
+    }
+
+    pub fn pop_front(&mut self) -> Option<(K, V)> {
+        let protected = self.protected.as_ref().is_some_and(|p| !p.is_empty());
+        let segment = segment_mut(&mut self.map, &mut self.protected, protected);
+        let (k, v) = segment.pop_front()?;
+        self.removed(&k, &v, protected);
+        Some((k, v))
+    }
+
+    pub fn pop_back(&mut self) -> Option<(K, V)> {
+        let protected = self.map.is_empty();
+        let segment = segment_mut(&mut self.map, &mut self.protected, protected);
+        let (k, v) = segment.pop_back()?;
+        self.removed(&k, &v, protected);
+        Some((k, v))
+    }
+
+    /// Removes and returns the next victim: the entry nearest the back of
+    /// the store that the eviction filter lets go, of the lowest priority
+    /// once entries have priorities, taken from the probationary segment
+    /// before the protected one. The entry of the key an insertion is
+    /// storing is never taken. `None` if the filter lets none go.
+    pub fn pop_victim(&mut self) -> Option<(K, V)> {
+        if self.priorities.is_some() {
+            return self.pop_by_priority(&[false, true]);
+        }
+        self.pop_victim_in(false)
+            .or_else(|| self.pop_victim_in(true))
+    }
+
+    /// Like `pop_victim`, but only takes from `segment`. `None` if the store
+    /// has no such segment.
+    pub(crate) fn pop_victim_from(&mut self, segment: Segment) -> Option<(K, V)> {
+        let protected = self.is_protected(segment)?;
+        if self.priorities.is_some() {
+            return self.pop_by_priority(&[protected]);
+        }
+        self.pop_victim_in(protected)
+    }
+
+    /// Pops the next victim of the protected segment if `protected` is set,
+    /// or else of `map`, without priorities.
+    fn pop_victim_in(&mut self, protected: bool) -> Option<(K, V)> {
+        let spare_front = self.spared == Some(protected);
+        let segment = if protected {
+            self.protected.as_mut()?
+        } else {
+            &mut self.map
+        };
+        let (k, v) = callback::pop_victim(segment, &self.filter, spare_front)?;
+        self.sub_weight(self.weigher.weigh(&k, &v), protected);
+        Some((k, v))
+    }
+
+    /// Removes the entry nearest the back of the lowest priority that the
+    /// filter lets go, taking from the priorities whose class is at its
+    /// share only if no other has one and looking in `segments` in turn,
+    /// true standing for the protected one. `None` if priorities are not
+    /// kept.
+    fn pop_by_priority(&mut self, segments: &[bool]) -> Option<(K, V)> {
+        let priorities = self.priorities.as_mut()?;
+        let (map, protected, filter, spared) =
+            (&self.map, &self.protected, &self.filter, self.spared);
+        let len = map.len() + protected.as_ref().map_or(0, LinkedHashMap::len);
+        let (in_protected, victim) = priorities.victim_order(len).find_map(|priority| {
+            let lists = priorities.lists.get(&priority)?;
+            segments.iter().find_map(|&in_protected| {
+                let entries = if in_protected {
+                    protected.as_ref()?
+                } else {
+                    map
+                };
+                // the front entry is that of the key being stored, if any
+                let front = entries.front().map(|(k, _)| k);
+                let newest = front.filter(|_| spared == Some(in_protected));
+                let list = lists[in_protected as usize].iter().rev().map(|(k, _)| k);
+                let victim = list
+                    .filter(|&k| Some(k) != newest)
+                    .find(|&k| match filter {
+                        Some(filter) => entries.get_key_value(k).is_some_and(|(k, v)| filter(k, v)),
+                        None => true,
+                    })?;
+                Some((in_protected, victim))
+            })
+        })?;
+        let segment = segment_mut(&mut self.map, &mut self.protected, in_protected);
+        let hash = segment.hash(victim);
+        let (k, v) = segment.remove_entry_hashed(hash, victim)?;
+        priorities.remove(hash, &k);
+        self.sub_weight(self.weigher.weigh(&k, &v), in_protected);
+        Some((k, v))
+    }
+
+    pub(crate) fn hash<Q>(&self, k: &Q) -> u64
+    where
+        Q: ?Sized + Hash,
+    {
+        self.map.hash(k)
+    }
+
+    pub(crate) fn hasher(&self) -> &S {
+        self.map.hasher()
+    }
+
+    /// Returns whether the store keeps a protected segment.
+    pub(crate) fn is_segmented(&self) -> bool {
+        self.protected.is_some()
+    }
+
+    /// Returns whether the entries weigh one each.
+    pub(crate) fn is_unweighted(&self) -> bool {
+        self.weigher.is_unit()
+    }
+
+    /// Returns the segment of the entry of `k`, or `None` if `k` is not
+    /// stored.
+    pub(crate) fn segment_of<Q>(&self, k: &Q) -> Option<Segment>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        let hash = self.map.hash(k);
+        if self.in_protected(hash, k) {
+            Some(Segment::Main)
+        } else if !self.map.contains_key_hashed(hash, k) {
+            None
+        } else if self.protected.is_some() {
+            Some(Segment::In)
+        } else {
+            Some(Segment::Single)
+        }
+    }
+
+    pub(crate) fn segment_len(&self, segment: Segment) -> usize {
+        self.segment(segment).map_or(0, LinkedHashMap::len)
+    }
+
+    pub(crate) fn segment_weight(&self, segment: Segment) -> usize {
+        match self.is_protected(segment) {
+            Some(true) => self.protected_weight,
+            Some(false) => self.weight - self.protected_weight,
+            None => 0,
+        }
+    }
+
+    /// Iterates over `segment` from its front to its back.
+    pub(crate) fn iter_segment(&self, segment: Segment) -> map::Iter<'_, K, V> {
+        self.segment(segment)
+            .map(LinkedHashMap::iter)
+            .unwrap_or_default()
+    }
+
+    /// Stores `v` under `k` at the front of the protected segment, moving
+    /// the entry of a key on probation there. The old entry of a cached key
+    /// is returned, with the key passed in. Without a protected segment,
+    /// this is `upsert_front` with `refresh` set.
+    pub(crate) fn upsert_protected(&mut self, k: K, v: V) -> Option<(K, V)> {
+        let protected = match &mut self.protected {
+            Some(protected) => protected,
+            None => return self.upsert_front(k, v, true),
+        };
+        let hash = self.map.hash(&k);
+        let weight = self.weigher.weigh(&k, &v);
+        let old = match protected.remove_entry_hashed(hash, &k) {
+            Some(entry) => Some((entry, true)),
+            None => self
+                .map
+                .remove_entry_hashed(hash, &k)
+                .map(|entry| (entry, false)),
+        };
+        self.weight += weight;
+        self.protected_weight += weight;
+        match old {
+            Some(((old_k, old_v), was_protected)) => {
+                let old_weight = self.weigher.weigh(&old_k, &old_v);
+                self.weight = self.weight.saturating_sub(old_weight);
+                if was_protected {
+                    self.protected_weight = self.protected_weight.saturating_sub(old_weight);
+                }
+                protected.push_front_hashed(hash, old_k, v);
+                if let Some(priorities) = &mut self.priorities {
+                    priorities.replaced(hash, &k, true, true);
+                }
+                Some((k, old_v))
+            }
+            None => {
+                protected.push_front_hashed(hash, k, v);
+                self.spared = Some(true);
+                if let (Some(priorities), Some((k, _))) = (&mut self.priorities, protected.front())
+                {
+                    priorities.insert(hash, k, true, true);
+                }
+                None
+            }
+        }
+    }
+
+    /// Moves the entry of `k` from the probationary segment to the front of
+    /// the protected one. Returns whether it was on probation.
+    pub(crate) fn protect<Q>(&mut self, k: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        let protected = match &mut self.protected {
+            Some(protected) => protected,
+            None => return false,
+        };
+        let hash = self.map.hash(k);
+        let (key, v) = match self.map.remove_entry_hashed(hash, k) {
+            Some(entry) => entry,
+            None => return false,
+        };
+        self.protected_weight += self.weigher.weigh(&key, &v);
+        protected.push_front_hashed(hash, key, v);
+        if let Some(priorities) = &mut self.priorities {
+            priorities.moved(hash, k, true, true);
+        }
+        true
+    }
+
+    /// Moves the entry at the back of the protected segment to the front of
+    /// the probationary one, showing it to the demotion listener. Returns
+    /// whether there was one.
+    pub(crate) fn demote(&mut self) -> bool {
+        let (k, v) = match self.protected.as_mut().and_then(LinkedHashMap::pop_back) {
+            Some(entry) => entry,
+            None => return false,
+        };
+        let hash = self.map.hash(&k);
+        let weight = self.weigher.weigh(&k, &v);
+        self.protected_weight = self.protected_weight.saturating_sub(weight);
+        if let Some(listener) = &mut self.demotion {
+            callback::guard(&mut self.panic, || listener(&k, &v));
+        }
+        if let Some(priorities) = &mut self.priorities {
+            priorities.moved(hash, &k, false, true);
+        }
+        self.map.push_front_hashed(hash, k, v);
+        true
+    }
+
+    /// Takes a promotion out of the promotion limit, if there is one.
+    pub(crate) fn may_promote(&mut self) -> bool {
+        match &mut self.promotion_limit {
+            Some(limit) => limit.allow(),
+            None => true,
+        }
+    }
+
+    pub(crate) fn set_promotion_limit(&mut self, limit: PromotionLimit) {
+        self.promotion_limit = Some(limit);
+    }
+
+    pub(crate) fn promotion_limit(&self) -> Option<&PromotionLimit> {
+        self.promotion_limit.as_ref()
+    }
+
+    pub(crate) fn set_demotion_listener(&mut self, listener: Observer<K, V>) {
+        self.demotion = Some(listener);
+    }
+
+    /// Counts an insertion whose key was found in a ghost queue of the
+    /// policy, `age` ghost insertions after the key entered it.
+    pub(crate) fn count_ghost_hit(&mut self, age: usize) {
+        self.ghosts.hit(age);
+    }
+
+    /// Counts a ghost the policy forgot without it ever being hit.
+    pub(crate) fn count_ghost_drop(&mut self) {
+        self.ghosts.drop_one();
+    }
+
+    /// Returns whether `segment` is the protected one, or `None` if the
+    /// store has no such segment.
+    fn is_protected(&self, segment: Segment) -> Option<bool> {
+        match (segment, self.protected.is_some()) {
+            (Segment::Main, true) => Some(true),
+            (Segment::In, true) | (Segment::Single, false) => Some(false),
+            _ => None,
+        }
+    }
+
+    fn segment(&self, segment: Segment) -> Option<&LinkedHashMap<K, V, S>> {
+        if self.is_protected(segment)? {
+            self.protected.as_ref()
+        } else {
+            Some(&self.map)
+        }
+    }
+
+    /// Returns whether `k` is in the protected segment.
+    fn in_protected<Q>(&self, hash: u64, k: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        let protected = self.protected.as_ref();
+        protected.is_some_and(|protected| protected.contains_key_hashed(hash, k))
+    }
+
+    fn add_weight(&mut self, weight: usize, protected: bool) {
+        self.weight += weight;
+        if protected {
+            self.protected_weight += weight;
+        }
+    }
+
+    fn sub_weight(&mut self, weight: usize, protected: bool) {
+        self.weight = self.weight.saturating_sub(weight);
+        if protected {
+            self.protected_weight = self.protected_weight.saturating_sub(weight);
+        }
+    }
+
+    /// Accounts for an entry that left the store other than through
+    /// `pop_by_priority`.
+    fn removed(&mut self, k: &K, v: &V, protected: bool) {
+        self.sub_weight(self.weigher.weigh(k, v), protected);
+        if let Some(priorities) = &mut self.priorities {
+            priorities.remove(self.map.hash(k), k);
+        }
+    }
+
+    /// Removes the entry of `k`.
+    fn take<Q>(&mut self, k: &Q) -> Option<(K, V)>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        let hash = self.map.hash(k);
+        let protected = self.in_protected(hash, k);
+        let segment = segment_mut(&mut self.map, &mut self.protected, protected);
+        let (k, v) = segment.remove_entry_hashed(hash, k)?;
+        self.removed(&k, &v, protected);
+        Some((k, v))
+    }
+
+    /// Removes the entries for which `f` returns true.
+    fn drain_where<F>(&mut self, mut f: F) -> Drained<K, V>
+    where
+        F: FnMut(&K, &V) -> bool,
+    {
+        let protected = match &mut self.protected {
+            Some(protected) => protected.drain_where(&mut f),
+            None => Vec::new(),
+        };
+        let probation = self.map.drain_where(f);
+        for (k, v) in &protected {
+            self.removed(k, v, true);
+        }
+        for (k, v) in &probation {
+            self.removed(k, v, false);
+        }
+        (protected, probation)
+    }
+
+    /// Adds the entry of a key that is not stored yet at the back of the
+    /// protected segment if `protected` is set and there is one, or else of
+    /// the probationary one.
+    fn push_back(&mut self, k: K, v: V, protected: bool) {
+        let protected = protected && self.protected.is_some();
+        let hash = self.map.hash(&k);
+        self.add_weight(self.weigher.weigh(&k, &v), protected);
+        if let Some(priorities) = &mut self.priorities {
+            priorities.insert(hash, &k, protected, false);
+        }
+        let segment = segment_mut(&mut self.map, &mut self.protected, protected);
+        segment.push_back_hashed(hash, k, v);
+    }
+
+    /// Returns the key of the entry an insertion is storing, while it is.
+    fn newest(&self) -> Option<&K> {
+        let segment = if self.spared? {
+            self.protected.as_ref()?
+        } else {
+            &self.map
+        };
+        segment.front().map(|(k, _)| k)
+    }
+
+    fn get_mut<Q>(&mut self, k: &Q) -> Option<&mut V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        let hash = self.map.hash(k);
+        let protected = self.in_protected(hash, k);
+        segment_mut(&mut self.map, &mut self.protected, protected).get_mut_hashed(hash, k)
+    }
+
+    /// Merges the entries of `other` into these, each segment into its
+    /// counterpart, a key on probation in one store giving way to a
+    /// protected one of the other. Returns the entries dropped for one of
+    /// the same key: ours, then theirs.
+    fn merge(&mut self, other: Store<K, V, S>) -> Dropped<K, V> {
+        let Store {
+            map,
+            protected,
+            priorities: theirs,
+            ..
+        } = other;
+        let (mine, dropped) = match &mut self.protected {
+            Some(ours) => {
+                let in_theirs = |k: &K| protected.as_ref().is_some_and(|p| p.contains_key(k));
+                let mut mine = self.map.drain_where(|k, _| in_theirs(k));
+                let (probation, mut dropped): (Vec<_>, Vec<_>) =
+                    map.into_iter().partition(|(k, _)| !ours.contains_key(k));
+                let protected = protected.into_iter().flatten().collect();
+                for (segment, entries) in [(ours, protected), (&mut self.map, probation)] {
+                    let (ours_dropped, theirs_dropped) = segment.merge(entries);
+                    mine.extend(ours_dropped);
+                    dropped.extend(theirs_dropped);
+                }
+                (mine, dropped)
+            }
+            None => {
+                let entries = protected.into_iter().flatten().chain(map).collect();
+                self.map.merge(entries)
+            }
+        };
+        if let Some(priorities) = &mut self.priorities {
+            priorities.refile(&self.map, self.protected.as_ref(), theirs.as_ref());
+        }
+        self.reweigh();
+        (mine, dropped)
+    }
+
+    /// Sums the weights of the entries again.
+    fn reweigh(&mut self) {
+        let weigher = &self.weigher;
+        let sum = |segment: &LinkedHashMap<K, V, S>| -> usize {
+            segment.iter().map(|(k, v)| weigher.weigh(k, v)).sum()
+        };
+        self.protected_weight = self.protected.as_ref().map_or(0, &sum);
+        self.weight = self.protected_weight + sum(&self.map);
+    }
+
+    fn clear(&mut self) {
+        self.map.clear();
+        if let Some(protected) = &mut self.protected {
+            protected.clear();
+        }
+        if let Some(priorities) = &mut self.priorities {
+            priorities.clear();
+        }
+        self.weight = 0;
+        self.protected_weight = 0;
+    }
+
+    fn released(&self) -> usize {
+        let protected = self.protected.as_ref().map_or(0, LinkedHashMap::released);
+        self.map.released().wrapping_add(protected)
+    }
+
+    fn capacity(&self) -> usize {
+        self.map.capacity() + self.protected.as_ref().map_or(0, LinkedHashMap::capacity)
+    }
+
+    fn shrink_to_fit(&mut self) {
+        self.map.shrink_to_fit();
+        if let Some(protected) = &mut self.protected {
+            protected.shrink_to_fit();
+        }
+    }
+
+    fn memory_usage(&self) -> MemoryUsage {
+        let protected = self.protected.as_ref().map(LinkedHashMap::memory_usage);
+        self.map.memory_usage() + protected.unwrap_or_default()
+    }
+
+    fn iter(&self) -> Iter<'_, K, V> {
+        let protected = self.protected.as_ref().map(LinkedHashMap::iter);
+        protected.unwrap_or_default().chain(self.map.iter())
+    }
+
+    fn iter_from<Q>(&self, k: &Q) -> Option<IterFrom<'_, K, V>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        let protected = self.protected.as_ref();
+        if let Some(from) = protected.and_then(|protected| protected.iter_from(k)) {
+            let map = &self.map;
+            let probation = map.front().and_then(|(k, _)| map.iter_from::<K>(k));
+            return Some(from.chain(probation.unwrap_or_default()));
+        }
+        let from = self.map.iter_from(k)?;
+        Some(map::IterFrom::default().chain(from))
+    }
+}
+
+/// The eviction priorities of the entries of a `Store`. The keys of each
+/// priority are also kept in lists of their own, one per segment, in the
+/// order of the store, so that the victim of the lowest priority is found
+/// without a walk. The tables are looked up by the hashes of the store;
+/// their own hashers are never used.
+struct Priorities<K> {
+    of: LinkedHashMap<K, u32, DefaultState>,
+    // the keys on probation, or all of them without segments, then the
+    // protected ones
+    lists: BTreeMap<u32, [LinkedHashMap<K, (), DefaultState>; 2]>,
+    classes: Vec<PriorityClass>,
+    // the priority `add_with_priority` gives the entry it stores
+    next: Option<u32>,
+    // copies the keys, which `add` takes without a `K: Clone` bound
+    clone: fn(&K) -> K,
+}
+
+impl<K: Hash + Eq> Priorities<K> {
+    fn new(clone: fn(&K) -> K) -> Priorities<K> {
+        Priorities {
+            of: LinkedHashMap::with_hasher(Default::default()),
+            lists: BTreeMap::new(),
+            classes: Vec::new(),
+            next: None,
+            clone,
+        }
+    }
+
+    fn lists() -> [LinkedHashMap<K, (), DefaultState>; 2] {
+        [
+            LinkedHashMap::with_hasher(Default::default()),
+            LinkedHashMap::with_hasher(Default::default()),
+        ]
+    }
+
+    fn get<Q>(&self, hash: u64, k: &Q) -> Option<u32>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        self.of.get_hashed(hash, k).copied()
+    }
+
+    /// Files the key of a new entry at the front or back of the list of
+    /// its priority in its segment, the protected one if `protected` is
+    /// set.
+    fn insert(&mut self, hash: u64, k: &K, protected: bool, front: bool) {
+        let priority = self.next.take().unwrap_or(0);
+        self.set(hash, k, priority, protected, front);
+    }
+
+    /// Refiles the key of a replaced entry: at the front of the list of its
+    /// new priority if it was given one, or where the store moved it.
+    fn replaced(&mut self, hash: u64, k: &K, protected: bool, refresh: bool) {
+        match self.next.take() {
+            Some(priority) => self.set(hash, k, priority, protected, true),
+            None if refresh => self.moved(hash, k, protected, true),
+            None => {}
+        }
+    }
+
+    fn set(&mut self, hash: u64, k: &K, priority: u32, protected: bool, front: bool) {
+        self.remove(hash, k);
+        self.of.push_back_hashed(hash, (self.clone)(k), priority);
+        let lists = self.lists.entry(priority).or_insert_with(Priorities::lists);
+        let list = &mut lists[protected as usize];
+        if front {
+            list.push_front_hashed(hash, (self.clone)(k), ());
+        } else {
+            list.push_back_hashed(hash, (self.clone)(k), ());
+        }
+    }
+
+    /// Follows an entry the store moved to the front or back of its
+    /// segment, the protected one if `protected` is set, from either one.
+    fn moved<Q>(&mut self, hash: u64, k: &Q, protected: bool, front: bool)
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        let priority = match self.of.get_hashed(hash, k) {
+            Some(priority) => priority,
+            None => return,
+        };
+        let [probation, protected_keys] = match self.lists.get_mut(priority) {
+            Some(lists) => lists,
+            None => return,
+        };
+        let (to, from) = if protected {
+            (protected_keys, probation)
+        } else {
+            (probation, protected_keys)
+        };
+        let moved = if front {
+            to.move_to_front_hashed(hash, k)
+        } else {
+            to.move_to_back_hashed(hash, k)
+        };
+        if let (false, Some((key, ()))) = (moved, from.remove_entry_hashed(hash, k)) {
+            if front {
+                to.push_front_hashed(hash, key, ());
+            } else {
+                to.push_back_hashed(hash, key, ());
+            }
+        }
+    }
+
+    fn remove<Q>(&mut self, hash: u64, k: &Q)
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        let priority = match self.of.remove_entry_hashed(hash, k) {
+            Some((_, priority)) => priority,
+            None => return,
+        };
+        if let btree_map::Entry::Occupied(mut lists) = self.lists.entry(priority) {
+            for list in lists.get_mut().iter_mut() {
+                if list.remove_entry_hashed(hash, k).is_some() {
+                    break;
+                }
+            }
+            if lists.get().iter().all(LinkedHashMap::is_empty) {
+                lists.remove();
+            }
+        }
+    }
+
+    /// Files the keys of the segments again in their order, after entries
+    /// were added to them other than through the store. Keys known to
+    /// neither these priorities nor `theirs` get priority 0.
+    fn refile<V, S>(
+        &mut self,
+        map: &LinkedHashMap<K, V, S>,
+        protected: Option<&LinkedHashMap<K, V, S>>,
+        theirs: Option<&Priorities<K>>,
+    ) where
+        S: BuildHasher,
+    {
+        let mut of = LinkedHashMap::with_hasher(Default::default());
+        let mut lists = BTreeMap::new();
+        let segments = [Some(map), protected];
+        for (index, segment) in segments.iter().enumerate() {
+            for (k, _) in segment.iter().flat_map(|segment| segment.iter()) {
+                let hash = map.hash(k);
+                let priority = self
+                    .get(hash, k)
+                    .or_else(|| theirs.and_then(|theirs| theirs.get(hash, k)))
+                    .unwrap_or(0);
+                of.push_back_hashed(hash, (self.clone)(k), priority);
+                lists.entry(priority).or_insert_with(Priorities::lists)[index].push_back_hashed(
+                    hash,
+                    (self.clone)(k),
+                    (),
+                );
+            }
+        }
+        self.of = of;
+        self.lists = lists;
+    }
+
+    fn clear(&mut self) {
+        self.of.clear();
+        self.lists.clear();
+    }
+
+    fn class_len(&self, class: &PriorityClass) -> usize {
+        self.lists
+            .range(class.priorities.clone())
+            .flat_map(|(_, lists)| lists.iter().map(LinkedHashMap::len))
+            .sum()
+    }
+
+    /// Returns whether evicting an entry of `priority` from a cache of
+    /// `len` entries would take its class below its share.
+    fn protects(&self, priority: u32, len: usize) -> bool {
+        let class = self
+            .classes
+            .iter()
+            .find(|class| class.priorities.contains(&priority));
+        class.is_some_and(|class| {
+            let left = self.class_len(class).saturating_sub(1);
+            (left as f64) < class.min_share * len.saturating_sub(1) as f64
+        })
+    }
+
+    /// Returns the priorities of the cached entries in the order victims
+    /// are taken from them: lowest first, those whose class is at its
+    /// share after all others.
+    fn victim_order(&self, len: usize) -> impl Iterator<Item = u32> + '_ {
+        let priorities = self.lists.keys().copied();
+        priorities
+            .clone()
+            .filter(move |&priority| !self.protects(priority, len))
+            .chain(priorities.filter(move |&priority| self.protects(priority, len)))
+    }
+}
+
+/// Evicts the least recently used entry.
+#[derive(Debug, Clone, Copy)]
+pub struct Lru {
+    refresh_interval: u32,
+    // hits since an entry was last moved to the front
+    unrefreshed_hits: u32,
+}
+
+impl Lru {
+    /// See `lru::Cache::set_refresh_interval`.
+    pub fn set_refresh_interval(&mut self, every: u32) {
+        self.refresh_interval = every.max(1);
+        self.unrefreshed_hits = 0;
+    }
+
+    pub fn refresh_interval(&self) -> u32 {
+        self.refresh_interval
+    }
+}
+
+impl Default for Lru {
+    fn default() -> Lru {
+        Lru {
+            refresh_interval: 1,
+            unrefreshed_hits: 0,
+        }
+    }
+}
+
+impl<K: Hash + Eq, V, S: BuildHasher> ReplacementPolicy<K, V, S> for Lru {
+    fn on_hit<'a, Q>(&mut self, store: &'a mut Store<K, V, S>, k: &Q) -> Option<(&'a K, &'a V)>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        if self.unrefreshed_hits + 1 >= self.refresh_interval {
+            let found = store.get_refresh(k);
+            if found.is_some() {
+                self.unrefreshed_hits = 0;
+            }
+            found
+        } else {
+            let found = store.get_key_value(k);
+            if found.is_some() {
+                self.unrefreshed_hits += 1;
+            }
+            found
+        }
+    }
+
+    fn on_insert(&mut self, store: &mut Store<K, V, S>, k: K, v: V) -> Option<(K, V)> {
+        store.upsert_front(k, v, true)
+    }
+}
+
+/// Evicts the oldest entry, regardless of how often it is read.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Fifo;
+
+impl<K: Hash + Eq, V, S: BuildHasher> ReplacementPolicy<K, V, S> for Fifo {
+    fn on_hit<'a, Q>(&mut self, store: &'a mut Store<K, V, S>, k: &Q) -> Option<(&'a K, &'a V)>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        store.get_key_value(k)
+    }
+
+    fn on_insert(&mut self, store: &mut Store<K, V, S>, k: K, v: V) -> Option<(K, V)> {
+        store.upsert_front(k, v, false)
+    }
+}
+
+/// A named range of eviction priorities whose entries are guaranteed a
+/// share of a cache: they are only chosen as victims while they make up at
+/// least `min_share` of the entries left after the eviction, unless every
+/// class is down to its share.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PriorityClass {
+    pub name: String,
+    pub priorities: RangeInclusive<u32>,
+    pub min_share: f64,
+}
+
+impl PriorityClass {
+    /// Fails if `min_share` is not within [0, 1].
+    pub fn new<N: Into<String>>(
+        name: N,
+        priorities: RangeInclusive<u32>,
+        min_share: f64,
+    ) -> Result<PriorityClass, ConfigError> {
+        error::check_share(min_share)?;
+        Ok(PriorityClass {
+            name: name.into(),
+            priorities,
+            min_share,
+        })
+    }
+}
+
+pub struct Cache<K, V, P, S = DefaultState> {
+    max_size: usize,
+    max_weight: usize,
+
+    stats: Stats,
+
+    callback: Option<Callback<K, V>>,
+    batch: Option<Batch<K, V>>,
+    events: Option<EventQueue<K, V>>,
+    observer: Option<Observer<K, V>>,
+    admission: Option<Admission>,
+    displaced: Option<Vec<(K, V)>>,
+    idle_shrink: Option<IdleShrink>,
+    low_water_mark: Option<usize>,
+    hot_keys: Option<HotKeys<K>>,
+    versions: Option<Versions<K>>,
+
+    policy: P,
+    store: Store<K, V, S>,
+}
+
+impl<K, V, P> Cache<K, V, P, DefaultState>
+where
+    K: Hash + Eq,
+    P: ReplacementPolicy<K, V, DefaultState> + Default,
+{
+    pub fn new(max_size: usize) -> Cache<K, V, P, DefaultState> {
+        Self::with_hasher(max_size, Default::default())
+    }
+
+    pub fn new_nonzero(max_size: NonZeroUsize) -> Cache<K, V, P, DefaultState> {
+        Self::with_hasher_nonzero(max_size, Default::default())
+    }
+
+    pub fn disabled() -> Cache<K, V, P, DefaultState> {
+        Self::disabled_with_hasher(Default::default())
+    }
+}
+
+impl<K, V, P> Cache<K, V, P, SeededState>
+where
+    K: Hash + Eq,
+    P: ReplacementPolicy<K, V, SeededState> + Default,
+{
+    /// Creates a cache that hashes, and so behaves, the same on every run
+    /// given the same `seed`.
+    pub fn with_seed(max_size: usize, seed: u64) -> Cache<K, V, P, SeededState> {
+        Self::with_hasher(max_size, SeededState::new(seed))
+    }
+}
+
+impl<K, V, P> Cache<K, V, P, DefaultState>
+where
+    K: Hash + Eq,
+    P: ReplacementPolicy<K, V, DefaultState>,
+{
+    /// Creates a cache whose entries are ordered by `policy`.
+    pub fn with_policy(policy: P, max_size: usize) -> Cache<K, V, P, DefaultState> {
+        Cache::with_policy_and_hasher(policy, max_size, Default::default())
+    }
+}
+
+impl<K, V, P, S> Cache<K, V, P, S>
+where
+    K: Hash + Eq,
+    S: BuildHasher,
+    P: ReplacementPolicy<K, V, S> + Default,
+{
+    pub fn with_hasher(max_size: usize, hash_builder: S) -> Cache<K, V, P, S> {
+        Cache::with_policy_and_hasher(P::default(), max_size, hash_builder)
+    }
+
+    /// Creates a cache with a capacity of zero: every `add` hands the entry
+    /// straight to the eviction callback and every `get` misses.
+    pub fn disabled_with_hasher(hash_builder: S) -> Cache<K, V, P, S> {
+        Cache::with_max_size(P::default(), 0, hash_builder)
+    }
+
+    /// Like `with_hasher`, but a zero size is ruled out by the type instead
+    /// of being silently raised to one.
+    pub fn with_hasher_nonzero(max_size: NonZeroUsize, hash_builder: S) -> Cache<K, V, P, S> {
+        Self::with_hasher(max_size.get(), hash_builder)
+    }
+}
+
+impl<K, V, P, S> Cache<K, V, P, S>
+where
+    K: Hash + Eq,
+    S: BuildHasher + Clone,
+    P: ReplacementPolicy<K, V, S> + Clone,
+{
+    /// Moves the entries for which `f` returns true into a new cache and
+    /// returns it, keeping their relative order in both caches. The new
+    /// cache has the limits, weigher and hasher of this one, but none of its
+    /// callbacks, policies or trackers.
+    pub fn split_by<F>(&mut self, f: F) -> Cache<K, V, P, S>
+    where
+        F: FnMut(&K, &V) -> bool,
+    {
+        let policy = self.policy.clone();
+        self.split_with(policy, f)
+    }
+}
+
+impl<K, V, P, S> Cache<K, V, P, S>
+where
+    K: Hash + Eq,
+    S: BuildHasher,
+    P: ReplacementPolicy<K, V, S>,
+{
+    pub fn with_policy_and_hasher(
+        policy: P,
+        max_size: usize,
+        hash_builder: S,
+    ) -> Cache<K, V, P, S> {
+        Cache::with_max_size(policy, max_size.max(P::MIN_SIZE), hash_builder)
+    }
+
+    fn with_max_size(policy: P, max_size: usize, hash_builder: S) -> Cache<K, V, P, S> {
+        let map = LinkedHashMap::with_capacity_and_hasher(max_size, hash_builder);
+        Cache::with_store(policy, max_size, Store::new(map, None))
+    }
+
+    /// Creates a cache whose store keeps a protected segment ahead of the
+    /// probationary one, with room for `probation` and `protected` entries
+    /// before they reallocate. A `max_size` of zero makes it disabled.
+    pub(crate) fn with_segments(
+        policy: P,
+        max_size: usize,
+        (probation, protected): (usize, usize),
+        hash_builder: S,
+    ) -> Cache<K, V, P, S>
+    where
+        S: Clone,
+    {
+        let store = Store::new(
+            LinkedHashMap::with_capacity_and_hasher(probation, hash_builder.clone()),
+            Some(LinkedHashMap::with_capacity_and_hasher(
+                protected,
+                hash_builder,
+            )),
+        );
+        Cache::with_store(policy, max_size, store)
+    }
+
+    fn with_store(policy: P, max_size: usize, store: Store<K, V, S>) -> Cache<K, V, P, S> {
+        Cache {
+            max_size,
+            max_weight: max_size,
+            stats: Stats::default(),
+            callback: None,
+            batch: None,
+            events: None,
+            observer: None,
+            admission: None,
+            displaced: None,
+            idle_shrink: None,
+            low_water_mark: None,
+            hot_keys: None,
+            versions: None,
+            policy,
+            store,
+        }
+    }
+
+    pub fn policy(&self) -> &P {
+        &self.policy
+    }
+
+    pub fn policy_mut(&mut self) -> &mut P {
+        &mut self.policy
+    }
+
+    pub(crate) fn store(&self) -> &Store<K, V, S> {
+        &self.store
+    }
+
+    pub(crate) fn store_mut(&mut self) -> &mut Store<K, V, S> {
+        &mut self.store
+    }
+
+    /// Returns the policy and the store, for a policy setting that moves
+    /// entries.
+    pub(crate) fn parts_mut(&mut self) -> (&mut P, &mut Store<K, V, S>) {
+        (&mut self.policy, &mut self.store)
+    }
+
+    pub fn hasher(&self) -> &S {
+        self.store.hasher()
+    }
+
+    pub fn set_eviction_callback<C>(&mut self, cb: C)
+    where
+        C: FnMut(K, V) + 'static,
+    {
+        self.batch = None;
+        self.events = None;
+        self.callback = Some(Box::new(cb));
+    }
+
+    /// Hands evicted entries to `cb` in batches of up to `batch_size`
+    /// instead of one call per entry. An operation that evicts fewer still
+    /// delivers them before it returns. Replaces the eviction callback.
+    pub fn set_batch_eviction_callback<C>(&mut self, batch_size: usize, cb: C)
+    where
+        C: FnMut(Vec<(K, V)>) + 'static,
+    {
+        self.callback = None;
+        self.events = None;
+        self.batch = Some(Batch::new(batch_size, Box::new(cb)));
+    }
+
+    /// Buffers insertion and eviction events, evicted entries included,
+    /// until they are taken with `take_events`. Once `capacity` events are
+    /// pending the oldest are dropped. Replaces the eviction callbacks.
+    pub fn set_event_queue(&mut self, capacity: usize)
+    where
+        K: Clone,
+    {
+        self.callback = None;
+        self.batch = None;
+        self.events = Some(EventQueue::new(capacity, K::clone));
+    }
+
+    /// Returns the events buffered since the last call, oldest first.
+    pub fn take_events(&mut self) -> Vec<EntryEvent<K, V>> {
+        self.events.as_mut().map_or_else(Vec::new, EventQueue::take)
+    }
+
+    /// Registers an observer that sees every evicted entry by reference,
+    /// including those returned by `push`, before ownership moves on to the
+    /// eviction callback or the caller.
+    pub fn set_eviction_observer<O>(&mut self, observer: O)
+    where
+        O: FnMut(&K, &V) + 'static,
+    {
+        self.observer = Some(Box::new(observer));
+    }
+
+    /// Registers a predicate consulted when choosing eviction victims.
+    /// Entries for which it returns false are skipped, scanning a bounded
+    /// number of entries; if none qualifies the cache stays over its limit
+    /// until a later insertion. Once entries have priorities, the victim is
+    /// the first entry the filter lets go in the order priorities and
+    /// priority classes call for, however many entries that takes.
+    pub fn set_eviction_filter<F>(&mut self, filter: F)
+    where
+        F: Fn(&K, &V) -> bool + 'static,
+    {
+        self.store.filter = Some(Box::new(filter));
+    }
+
+    /// Installs a policy that decides whether a new key may displace the
+    /// next eviction victim once the cache is full. Keys it turns down are
+    /// handed to the eviction callback.
+    pub fn set_admission_policy<A>(&mut self, admission: A)
+    where
+        A: AdmissionPolicy + 'static,
+    {
+        self.admission = Some(Box::new(admission));
+    }
+
+    /// Bounds the cache by the total weight of its entries instead of their
+    /// count. Entries heavier than `max_weight` are never stored; adding one
+    /// hands it straight to the eviction callback.
+    pub fn set_weigher<W>(&mut self, max_weight: usize, weigher: W)
+    where
+        W: Fn(&K, &V) -> u32 + 'static,
+    {
+        self.store.weigher = Weigher::new(weigher);
+        self.store.reweigh();
+        self.max_weight = max_weight;
+        self.policy
+            .on_resize(&mut self.store, self.max_size, max_weight);
+        self.trim();
+    }
+
+    /// Bounds the cache by the approximate number of bytes its entries
+    /// occupy, as reported by `heap_size::entry_size`.
+    pub fn set_max_heap_size(&mut self, max_bytes: usize)
+    where
+        K: HeapSize + 'static,
+        V: HeapSize + 'static,
+    {
+        self.set_weigher(max_bytes, heap_size::entry_weight::<K, V>);
+        self.store.weigher.in_bytes = true;
+    }
+
+    /// Changes the entry limit, evicting the entries that no longer fit. A
+    /// weight budget set with `set_weigher` is left alone.
+    pub fn resize(&mut self, max_size: usize) {
+        self.max_size = max_size.max(P::MIN_SIZE);
+        if self.store.is_unweighted() {
+            self.max_weight = self.max_size;
+        }
+        self.policy
+            .on_resize(&mut self.store, self.max_size, self.max_weight);
+        self.trim();
+    }
+
+    pub fn contains_key<Q>(&self, k: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        self.store.contains_key(k)
+    }
+
+    pub fn get<Q>(&mut self, k: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        let sample = self.stats.start_sample();
+        if let Some(admission) = &mut self.admission {
+            admission.record(self.store.hash(k));
+        }
+        if let Some(limit) = &mut self.store.promotion_limit {
+            limit.record();
+        }
+        // stamps are only kept for cached keys, so a miss leaves them alone
+        let store = &self.store;
+        self.stats.stamp_access(|| store.hash(k));
+        if self.store.is_segmented() {
+            return self.get_segmented(k, sample);
+        }
+        if let Some((k, v)) = self.policy.on_hit(&mut self.store, k) {
+            self.stats.hit();
+            if let Some(hot_keys) = &mut self.hot_keys {
+                hot_keys.record(k);
+            }
+            self.stats.end_sample(Operation::Get, sample);
+            return Some(v);
+        }
+        self.stats.miss();
+        self.stats.end_sample(Operation::Get, sample);
+        None
+    }
+
+    /// The rest of `get` for a segmented store, whose hits are counted by
+    /// the segment they were found in.
+    fn get_segmented<Q>(&mut self, k: &Q, sample: Option<Duration>) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        let segment = self.store.segment_of(k);
+        match self.policy.on_hit(&mut self.store, k) {
+            Some((k, _)) => {
+                if segment == Some(Segment::Main) {
+                    self.stats.hit_main();
+                } else {
+                    self.stats.hit_in();
+                }
+                if let Some(hot_keys) = &mut self.hot_keys {
+                    hot_keys.record(k);
+                }
+            }
+            None => self.stats.miss(),
+        }
+        // the hit may have run the demotion listener
+        self.flush_evicted();
+        self.stats.end_sample(Operation::Get, sample);
+        self.peek(k)
+    }
+
+    /// Looks up the keys of `keys` in order, each like `get`, and returns
+    /// what was found for each. An entry displaced by a later lookup of the
+    /// batch is reported missing.
+    pub fn get_many<'a, Q, I>(&mut self, keys: I) -> Vec<Option<&V>>
+    where
+        K: Borrow<Q>,
+        Q: 'a + ?Sized + Eq + Hash,
+        I: IntoIterator<Item = &'a Q>,
+    {
+        map::get_many(self, keys, Self::get, Self::peek, |cache| {
+            cache.store.released()
+        })
+    }
+
+    /// Like `get`, but returns the value mutably. The entry moves as a hit
+    /// calls for, but its weight is not recomputed; changes that affect it
+    /// should go through `add`. Like a write, it gives a versioned entry a
+    /// new version.
+    pub fn get_mut<Q>(&mut self, k: &Q) -> Option<&mut V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        self.get(k)?;
+        self.bump_version(k);
+        self.store.get_mut(k)
+    }
+
+    pub fn peek<Q>(&self, k: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        self.store.get_key_value(k).map(|(_, v)| v)
+    }
+
+    /// Returns whether each key of `keys` is cached, without touching the
+    /// order of the entries or the statistics.
+    pub fn peek_many<'a, Q, I>(&self, keys: I) -> Vec<bool>
+    where
+        K: Borrow<Q>,
+        Q: 'a + ?Sized + Eq + Hash,
+        I: IntoIterator<Item = &'a Q>,
+    {
+        keys.into_iter().map(|k| self.contains_key(k)).collect()
+    }
+
+    /// Like `peek`, but returns the value mutably. Neither the position of
+    /// the entry nor its weight change, but like a write it gives a
+    /// versioned entry a new version.
+    pub fn peek_mut<Q>(&mut self, k: &Q) -> Option<&mut V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        self.bump_version(k);
+        self.store.get_mut(k)
+    }
+
+    /// Gives the entry of `k` a new version, if entries are versioned, for
+    /// a write through a mutable reference.
+    fn bump_version<Q>(&mut self, k: &Q)
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        if let Some(versions) = &mut self.versions {
+            if let Some((k, _)) = self.store.get_key_value(k) {
+                versions.bump(self.store.hash(k), k);
+            }
+        }
+    }
+
+    pub fn remove<Q>(&mut self, k: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        self.remove_entry(k).map(|(_, v)| v)
+    }
+
+    /// Removes the entry of `k` only if `f` holds for it, deciding and
+    /// removing in one call.
+    pub fn remove_if<Q, F>(&mut self, k: &Q, f: F) -> Removal<V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+        F: FnOnce(&K, &V) -> bool,
+    {
+        let remove = match self.store.get_key_value(k) {
+            Some((k, v)) => f(k, v),
+            None => return Removal::Missing,
+        };
+        if !remove {
+            return Removal::Kept;
+        }
+        match self.remove(k) {
+            Some(v) => Removal::Removed(v),
+            None => Removal::Missing,
+        }
+    }
+
+    /// Like `split_by`, giving the new cache `policy`, which starts out
+    /// knowing nothing of the entries moved to it.
+    pub(crate) fn split_with<F>(&mut self, policy: P, f: F) -> Cache<K, V, P, S>
+    where
+        F: FnMut(&K, &V) -> bool,
+        S: Clone,
+    {
+        let (protected, probation) = self.store.drain_where(f);
+        let hash_builder = self.store.hasher();
+        let store = Store::new(
+            LinkedHashMap::with_capacity_and_hasher(probation.len(), hash_builder.clone()),
+            self.store.protected.as_ref().map(|_| {
+                LinkedHashMap::with_capacity_and_hasher(protected.len(), hash_builder.clone())
+            }),
+        );
+        let mut other = Cache::with_store(policy, self.max_size, store);
+        other.max_weight = self.max_weight;
+        other.store.weigher = self.store.weigher.clone();
+        for (k, v) in protected {
+            self.forget(&k);
+            other.store.push_back(k, v, true);
+        }
+        for (k, v) in probation {
+            self.forget(&k);
+            other.store.push_back(k, v, false);
+        }
+        other
+    }
+
+    /// Absorbs the entries of `other`, interleaved with these by their
+    /// position in their own cache, so that the most protected entries of
+    /// both end up the most protected, then evicts down to the limits. Of a
+    /// key in both caches, the protected or else more protected entry is
+    /// kept and the other one evicted as `Superseded`.
+    pub fn merge(&mut self, other: Cache<K, V, P, S>) {
+        let Cache { policy, store, .. } = other;
+        for (k, _) in store.iter() {
+            if !self.store.contains_key(k) {
+                self.absorb(k);
+            }
+        }
+        let (mine, theirs) = self.store.merge(store);
+        for (k, v) in mine {
+            self.supersede(k, v, true);
+        }
+        for (k, v) in theirs {
+            self.supersede(k, v, false);
+        }
+        self.policy.on_merge(&mut self.store, policy);
+        self.trim();
+    }
+
+    pub fn remove_entry<Q>(&mut self, k: &Q) -> Option<(K, V)>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        let (k, v) = match self.store.take(k) {
+            Some(entry) => entry,
+            None => {
+                // the policy may remember keys it no longer caches
+                self.policy.on_remove(self.store.hash(k));
+                return None;
+            }
+        };
+        self.forget(&k);
+        Some((k, v))
+    }
+
+    /// Keeps only the entries for which `f` returns true. The others are
+    /// dropped without going through the eviction callback; their number
+    /// is returned.
+    pub fn retain<F>(&mut self, mut f: F) -> usize
+    where
+        F: FnMut(&K, &V) -> bool,
+    {
+        let (protected, probation) = self.store.drain_where(|k, v| !f(k, v));
+        for (k, _) in protected.iter().chain(&probation) {
+            self.forget(k);
+        }
+        protected.len() + probation.len()
+    }
+
+    /// Removes the entries for which `f` returns true as expired. Unlike
+    /// `retain`, they leave through the eviction path, counted as
+    /// expirations and handed over with the `Expired` reason. Returns their
+    /// number.
+    pub fn expire_where<F>(&mut self, f: F) -> usize
+    where
+        F: FnMut(&K, &V) -> bool,
+    {
+        let (protected, probation) = self.store.drain_where(f);
+        let count = protected.len() + probation.len();
+        for (k, v) in protected.into_iter().chain(probation) {
+            self.policy.on_remove(self.store.hash(&k));
+            self.evict(k, v, EvictionReason::Expired);
+        }
+        self.flush_evicted();
+        count
+    }
+
+    /// Removes the entry of `k` as expired, through the eviction path like
+    /// `expire_where`. Returns whether `k` was cached.
+    pub fn expire<Q>(&mut self, k: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        let (k, v) = match self.store.take(k) {
+            Some(entry) => entry,
+            None => return false,
+        };
+        self.policy.on_remove(self.store.hash(&k));
+        self.evict(k, v, EvictionReason::Expired);
+        self.flush_evicted();
+        true
+    }
+
+    /// Removes and returns the entry at the back of the store, without
+    /// passing it to the eviction callback.
+    pub(crate) fn remove_back(&mut self) -> Option<(K, V)> {
+        let (k, v) = self.store.pop_back()?;
+        self.forget(&k);
+        Some((k, v))
+    }
+
+    /// Lets the policy and the trackers forget `k`, whose entry left the
+    /// store without being evicted.
+    fn forget(&mut self, k: &K) {
+        self.policy.on_remove(self.store.hash(k));
+        let store = &self.store;
+        self.stats.unstamp(|| store.hash(k));
+        if let Some(versions) = &mut self.versions {
+            versions.remove(store.hash(k), k);
+        }
+    }
+
+    pub fn add(&mut self, k: K, v: V) -> Option<V> {
+        self.add_entry(k, v).map(|(_, v)| v)
+    }
+
+    /// Like `add`, but a replaced entry is returned whole, with the key
+    /// passed in.
+    fn add_entry(&mut self, k: K, v: V) -> Option<(K, V)> {
+        let sample = self.stats.start_sample();
+        let old = self.insert(k, v);
+        self.shrink_if_idle();
+        // covers an entry rejected by the insertion itself
+        self.flush_evicted();
+        self.stats.end_sample(Operation::Add, sample);
+        old
+    }
+
+    /// Stores `v` with an eviction priority. Once a priority was given, the
+    /// victim is the entry nearest the back of the lowest priority, so that
+    /// entries costly to recompute outlive cheaper ones. Entries stored by
+    /// `add` have priority 0, or keep theirs if their key was cached. An
+    /// entry given a new priority counts as the most recent of it.
+    pub fn add_with_priority(&mut self, k: K, v: V, priority: u32) -> Option<V>
+    where
+        K: Clone,
+    {
+        self.enable_priorities();
+        if let Some(priorities) = &mut self.store.priorities {
+            priorities.next = Some(priority);
+        }
+        let old_v = self.add(k, v);
+        // left over if the entry was rejected
+        if let Some(priorities) = &mut self.store.priorities {
+            priorities.next = None;
+        }
+        old_v
+    }
+
+    /// Returns the eviction priority of the entry of `k`, or `None` if `k`
+    /// is not cached or no priority was ever given.
+    pub fn priority<Q>(&self, k: &Q) -> Option<u32>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        let priorities = self.store.priorities.as_ref()?;
+        priorities.get(self.store.hash(k), k)
+    }
+
+    /// Guarantees the entries whose priority is in `class` a minimum share
+    /// of the cache, whatever their priority and recency. Fails, adding
+    /// nothing, if its share is not within [0, 1] or the shares of all
+    /// classes would add up to more than 1.
+    pub fn add_priority_class(&mut self, class: PriorityClass) -> Result<(), ConfigError>
+    where
+        K: Clone,
+    {
+        error::check_share(class.min_share)?;
+        let total = self.store.priorities.as_ref().map_or(0.0, |priorities| {
+            priorities.classes.iter().map(|class| class.min_share).sum()
+        }) + class.min_share;
+        if total > 1.0 {
+            return Err(ConfigError::SharesOverOne { total });
+        }
+        self.enable_priorities();
+        if let Some(priorities) = &mut self.store.priorities {
+            priorities.classes.push(class);
+        }
+        Ok(())
+    }
+
+    /// Returns the name and number of entries of each priority class.
+    pub fn priority_class_lens(&self) -> Vec<(&str, usize)> {
+        let priorities = match &self.store.priorities {
+            Some(priorities) => priorities,
+            None => return Vec::new(),
+        };
+        priorities
+            .classes
+            .iter()
+            .map(|class| (class.name.as_str(), priorities.class_len(class)))
+            .collect()
+    }
+
+    /// Starts keeping the priorities of the entries, those already cached
+    /// at priority 0.
+    fn enable_priorities(&mut self)
+    where
+        K: Clone,
+    {
+        if self.store.priorities.is_none() {
+            let mut priorities = Priorities::new(K::clone);
+            priorities.refile(&self.store.map, self.store.protected.as_ref(), None);
+            self.store.priorities = Some(priorities);
+        }
+    }
+
+    /// Returns the version of the entry of `k`, or `None` if `k` is not
+    /// cached. Entries have version 0 until they are written after the
+    /// first versioned write.
+    pub fn version<Q>(&self, k: &Q) -> Option<u64>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        if !self.store.contains_key(k) {
+            return None;
+        }
+        let versions = self.versions.as_ref();
+        Some(versions.map_or(0, |versions| versions.get(self.store.hash(k), k)))
+    }
+
+    /// Returns the version last given to the entry of `k` by
+    /// `add_with_version`, or `None` if it was given none or `k` is not
+    /// cached.
+    pub fn given_version<Q>(&self, k: &Q) -> Option<u64>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        let versions = self.versions.as_ref()?;
+        versions.given(self.store.hash(k), k)
+    }
+
+    /// Stores `v` only if the entry of `k` still has version `expected`,
+    /// `None` standing for a missing entry, so that a writer that started
+    /// from an older value cannot clobber a newer one. From the first
+    /// versioned write on, every write gives the entry a new, higher
+    /// version. Returns the version of the entry after the write, `None` if
+    /// it was not stored, or `v` back if the version did not match.
+    pub fn add_if_version(&mut self, k: K, v: V, expected: Option<u64>) -> Result<Option<u64>, V>
+    where
+        K: Clone,
+    {
+        if self.version(&k) != expected {
+            return Err(v);
+        }
+        self.enable_versioning();
+        let key = k.clone();
+        self.add(k, v);
+        Ok(self.version(&key))
+    }
+
+    /// Stores `v` with a version of the caller's choosing, only if it is
+    /// higher than the one last given to the entry of `k`, as returned by
+    /// `given_version`. The given versions are kept apart from those of
+    /// `version`, which the write moves on as any other. Returns `v` back
+    /// if the version is not higher.
+    pub fn add_with_version(&mut self, k: K, v: V, version: u64) -> Result<(), V>
+    where
+        K: Clone,
+    {
+        if self.given_version(&k).is_some_and(|given| version <= given) {
+            return Err(v);
+        }
+        self.enable_versioning();
+        let key = k.clone();
+        self.add(k, v);
+        let hash = self.store.hash(&key);
+        if let Some(versions) = &mut self.versions {
+            versions.give(hash, &key, version);
+        }
+        Ok(())
+    }
+
+    /// Starts versioning the entries stored from now on.
+    fn enable_versioning(&mut self)
+    where
+        K: Clone,
+    {
+        if self.versions.is_none() {
+            self.versions = Some(Versions::new(K::clone));
+        }
+    }
+
+    fn insert(&mut self, k: K, v: V) -> Option<(K, V)> {
+        if let Some(hot_keys) = &mut self.hot_keys {
+            hot_keys.record(&k);
+        }
+        if let Some(admission) = &mut self.admission {
+            admission.record(self.store.hash(&k));
+        }
+        if let Some(limit) = &mut self.store.promotion_limit {
+            limit.record();
+        }
+        let weight = self.store.weigher.weigh(&k, &v);
+        if self.is_disabled() || weight > self.max_weight {
+            let old = self.remove_entry(&k);
+            self.evict(k, v, EvictionReason::Rejected);
+            return old;
+        }
+
+        // the admission policy is only asked about keys not cached yet
+        if !self.policy.admits(&self.store, &k)
+            || (self.admission.is_some()
+                && !self.store.contains_key(&k)
+                && !self.admits(&k, weight))
+        {
+            self.evict(k, v, EvictionReason::Rejected);
+            return None;
+        }
+
+        if let Some((k, old_v)) = self.policy.on_insert(&mut self.store, k, v) {
+            self.stats.replace();
+            let hash = self.store.hash(&k);
+            self.stats.stamp_replace(|| hash);
+            if let Some(versions) = &mut self.versions {
+                versions.bump(hash, &k);
+            }
+            self.trim();
+            return Some((k, old_v));
+        }
+
+        self.stats.insert();
+        if let Some(k) = self.store.newest() {
+            let hash = self.store.hash(k);
+            self.stats.stamp_insert(|| hash);
+            if let Some(versions) = &mut self.versions {
+                versions.bump(hash, k);
+            }
+            if let Some(events) = &mut self.events {
+                events.inserted(k);
+            }
+        }
+        self.trim();
+        None
+    }
+
+    /// Asks the admission policy, if any, whether `k` may be stored when
+    /// that means evicting an entry.
+    fn admits(&mut self, k: &K, weight: usize) -> bool {
+        let admission = match &mut self.admission {
+            Some(admission) => admission,
+            None => return true,
+        };
+        if self.store.weight + weight <= self.max_weight {
+            return true;
+        }
+        match self.store.back() {
+            Some((victim, _)) => admission.admit(self.store.hash(k), self.store.hash(victim)),
+            None => true,
+        }
+    }
+
+    /// Returns the weight to evict down to: the capacity, or the low-water
+    /// mark once the capacity is exceeded.
+    fn eviction_limit(&self) -> usize {
+        match self.low_water_mark {
+            Some(low) if self.store.weight > self.max_weight => low.min(self.max_weight),
+            _ => self.max_weight,
+        }
+    }
+
+    fn trim(&mut self) {
+        let limit = self.eviction_limit();
+        while self.store.weight > limit {
+            match self.policy.choose_victim(&mut self.store) {
+                Some((k, v)) => self.evict(k, v, EvictionReason::Capacity),
+                None => break,
+            }
+        }
+        self.store.spared = None;
+        self.flush_evicted();
+    }
+
+    /// Inserts like `add`, but instead of going through the eviction
+    /// callback every entry displaced by the insertion is returned, together
+    /// with the replaced value if the key was already cached.
+    pub fn push(&mut self, k: K, v: V) -> (Option<V>, Vec<(K, V)>) {
+        let (old, displaced) = self.push_entry(k, v);
+        (old.map(|(_, v)| v), displaced)
+    }
+
+    /// Like `push`, but a replaced entry is returned whole, with the key
+    /// passed in.
+    pub(crate) fn push_entry(&mut self, k: K, v: V) -> Pushed<K, V> {
+        self.displaced = Some(Vec::new());
+        let old = self.add_entry(k, v);
+        (old, self.displaced.take().unwrap_or_default())
+    }
+
+    /// Inserts the entries of `iter` in order, each like `push`, and
+    /// returns every entry displaced on the way. Values replaced by the
+    /// insertions are dropped.
+    pub fn add_many<I>(&mut self, iter: I) -> Vec<(K, V)>
+    where
+        I: IntoIterator<Item = (K, V)>,
+    {
+        self.displaced = Some(Vec::new());
+        for (k, v) in iter {
+            self.add(k, v);
+        }
+        self.displaced.take().unwrap_or_default()
+    }
+
+    /// Fills the cache from `iter`, which yields the entries to keep first:
+    /// the first one ends up the most protected, behind any entries already
+    /// cached. A segmented cache fills its protected segment first, the
+    /// rest going on probation rather than all of them passing through it.
+    /// Keys already cached and entries that no longer fit are skipped.
+    /// Returns the number of entries inserted.
+    pub fn warm_from_iter<I>(&mut self, iter: I) -> usize
+    where
+        I: IntoIterator<Item = (K, V)>,
+    {
+        let mut inserted = 0;
+        for (k, v) in iter {
+            if self.store.weight >= self.max_weight {
+                break;
+            }
+            let weight = self.store.weigher.weigh(&k, &v);
+            if self.store.weight + weight > self.max_weight || self.store.contains_key(&k) {
+                continue;
+            }
+            let segment = match self.policy.warm_segment(&self.store, weight) {
+                Some(segment) => segment,
+                None => continue,
+            };
+            self.stats.insert();
+            let hash = self.store.hash(&k);
+            self.stats.stamp_insert(|| hash);
+            if let Some(versions) = &mut self.versions {
+                versions.bump(hash, &k);
+            }
+            if let Some(events) = &mut self.events {
+                events.inserted(&k);
+            }
+            self.store.push_back(k, v, segment == Segment::Main);
+            inserted += 1;
+        }
+        inserted
+    }
+
+    fn evict(&mut self, k: K, v: V, reason: EvictionReason) {
+        self.stats.evict(reason);
+        let hash = self.store.hash(&k);
+        self.stats.evict_stamped(|| hash);
+        if let Some(versions) = &mut self.versions {
+            versions.remove(hash, &k);
+        }
+        self.hand_over(k, v, reason);
+    }
+
+    /// Counts the key of an entry `merge` takes from the other cache as
+    /// inserted, before it is stored.
+    fn absorb(&mut self, k: &K) {
+        self.stats.insert();
+        let hash = self.store.hash(k);
+        self.stats.stamp_insert(|| hash);
+        if let Some(versions) = &mut self.versions {
+            versions.bump(hash, k);
+        }
+        if let Some(events) = &mut self.events {
+            events.inserted(k);
+        }
+    }
+
+    /// Lets go of an entry `merge` dropped for the one of the same key in
+    /// the other cache: `mine` if it was stored here, in which case the key
+    /// counts as replaced.
+    fn supersede(&mut self, k: K, v: V, mine: bool) {
+        if mine {
+            self.stats.replace();
+            let hash = self.store.hash(&k);
+            self.stats.stamp_replace(|| hash);
+            if let Some(versions) = &mut self.versions {
+                versions.bump(hash, &k);
+            }
+        }
+        self.hand_over(k, v, EvictionReason::Superseded);
+    }
+
+    /// Passes an entry the cache let go of to whoever takes them.
+    fn hand_over(&mut self, k: K, v: V, reason: EvictionReason) {
+        let panic = &mut self.store.panic;
+        if let Some(observer) = &mut self.observer {
+            callback::guard(panic, || observer(&k, &v));
+        }
+        if let Some(displaced) = &mut self.displaced {
+            displaced.push((k, v));
+        } else if let Some(batch) = &mut self.batch {
+            callback::guard(panic, || batch.push(k, v));
+        } else if let Some(events) = &mut self.events {
+            events.evicted(k, v, reason);
+        } else if let Some(cb) = &mut self.callback {
+            callback::guard(panic, || cb(k, v));
+        }
+    }
+
+    /// Ends an operation that may have evicted: takes over the ghost counts
+    /// of the policy, delivers the pending batch, then raises again the
+    /// panic of a callback run along the way, now that the cache is
+    /// consistent.
+    pub(crate) fn flush_evicted(&mut self) {
+        self.stats.add_ghosts(&mut self.store.ghosts);
+        if let Some(batch) = &mut self.batch {
+            callback::guard(&mut self.store.panic, || batch.flush());
+        }
+        if self.store.panic.is_some() {
+            // a `push` that panics has no one to return its entries to
+            self.displaced = None;
+        }
+        callback::resume(&mut self.store.panic);
+    }
+
+    pub fn len(&self) -> usize {
+        self.store.len()
+    }
+
+    pub fn max_size(&self) -> usize {
+        self.max_size
+    }
+
+    pub fn is_disabled(&self) -> bool {
+        self.max_weight == 0
+    }
+
+    pub fn max_weight(&self) -> usize {
+        self.max_weight
+    }
+
+    /// Returns the total weight of the cached entries, which is their count
+    /// unless a weigher has been set.
+    pub fn weighted_size(&self) -> usize {
+        self.store.weight
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.store.capacity()
+    }
+
+    pub fn iter(&self) -> Iter<'_, K, V> {
+        self.store.iter()
+    }
+
+    /// Iterates in the order of `iter`, starting at the entry of `k`, or
+    /// returns `None` if `k` is not cached. Starting again from the last
+    /// key of a page continues a paginated dump.
+    pub fn iter_from<Q>(&self, k: &Q) -> Option<IterFrom<'_, K, V>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        self.store.iter_from(k)
+    }
+
+    /// Returns clones of the entries from the next eviction victim to the
+    /// most protected entry, the reverse of the order `warm_from_iter`
+    /// takes.
+    pub fn to_vec(&self) -> Vec<(K, V)>
+    where
+        K: Clone,
+        V: Clone,
+    {
+        self.iter()
+            .rev()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect()
+    }
+
+    /// Like `to_vec`, but moves the entries out of the cache.
+    pub fn export(self) -> Vec<(K, V)> {
+        self.into_iter().rev().collect()
+    }
+
+    pub fn purge(&mut self) {
+        for (k, _) in self.store.iter() {
+            self.policy.on_remove(self.store.hash(k));
+        }
+        self.policy.on_purge();
+        self.stats.unstamp_all();
+        if let Some(versions) = &mut self.versions {
+            versions.clear();
+        }
+        self.store.clear();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.store.is_empty()
+    }
+
+    pub fn shrink_to_fit(&mut self) {
+        self.store.shrink_to_fit();
+        self.policy.shrink_to_fit();
+    }
+
+    /// Lets the cache fill up to its capacity, then evicts down to `low`, in
+    /// the units of the capacity, in one go, rather than one entry per
+    /// insertion. The evictions come in bursts that a batch callback
+    /// receives together, and most insertions evict nothing. `None`, the
+    /// default, evicts just enough for the capacity. A mark above the
+    /// capacity is rejected.
+    pub fn set_low_water_mark(&mut self, low: Option<usize>) -> Result<(), ConfigError> {
+        error::check_low_water_mark(low, self.max_weight)?;
+        self.low_water_mark = low;
+        Ok(())
+    }
+
+    pub fn low_water_mark(&self) -> Option<usize> {
+        self.low_water_mark
+    }
+
+    /// Shrinks the storage of the cache whenever `policy` finds it has been
+    /// idle, which is checked on every insertion.
+    pub fn set_idle_shrink(&mut self, policy: IdleShrink) {
+        self.idle_shrink = Some(policy);
+    }
+
+    /// Does the work otherwise left for later operations to notice: shrinks
+    /// the storage if the idle policy calls for it, starts a new interval of
+    /// the promotion limit if the current one has run out and delivers the
+    /// pending batch of evicted entries. Meant to be called from the
+    /// embedder's own periodic tick. Expired entries are swept by the
+    /// `layer::Ttl` wrapper that knows their deadlines.
+    pub fn run_pending_tasks(&mut self) {
+        self.shrink_if_idle();
+        if let Some(limit) = &mut self.store.promotion_limit {
+            limit.roll();
+        }
+        self.flush_evicted();
+    }
+
+    fn shrink_if_idle(&mut self) {
+        let (len, max_size) = (self.len(), self.max_size);
+        if let Some(policy) = &mut self.idle_shrink {
+            if policy.due(len, max_size) {
+                self.shrink_to_fit();
+            }
+        }
+    }
+
+    pub fn stat(&self) -> Info {
+        self.info(self.stats.counters)
+    }
+
+    /// Returns the counters accumulated since the previous call, or since
+    /// the cache was created or its stats reset. `len` is the current length.
+    pub fn stat_delta(&mut self) -> Info {
+        let delta = self.stats.take_delta();
+        self.info(delta)
+    }
+
+    pub fn reset_stats(&mut self) {
+        self.stats.reset();
+        if let Some(hot_keys) = &mut self.hot_keys {
+            hot_keys.clear();
+        }
+    }
+
+    /// Starts timing a sample of the `get` and `add` calls, reported by
+    /// `stat` as latency histograms.
+    pub fn set_latency_sampling(&mut self, latency: LatencySampling) {
+        self.stats.set_latency_sampling(Some(latency));
+    }
+
+    /// Sends every statistics event to `recorder` as well as to the
+    /// built-in counters.
+    pub fn set_stats_recorder<R>(&mut self, recorder: R)
+    where
+        R: StatsRecorder + 'static,
+    {
+        self.stats.set_recorder(Some(Box::new(recorder)));
+    }
+
+    /// Starts tracking the most frequently read and written keys, keeping
+    /// counts for up to `capacity` keys and halving them every `window`
+    /// accesses so that the ranking follows recent traffic.
+    pub fn set_hot_key_tracking(&mut self, capacity: usize, window: usize)
+    where
+        K: Clone,
+    {
+        self.hot_keys = Some(HotKeys::new(capacity, window));
+    }
+
+    /// Returns up to `n` of the hottest keys with their approximate access
+    /// counts, most accessed first. Empty unless hot key tracking is set.
+    pub fn hot_keys(&self, n: usize) -> Vec<(K, u64)> {
+        self.hot_keys
+            .as_ref()
+            .map_or_else(Vec::new, |hot_keys| hot_keys.top(n))
+    }
+
+    /// Reports the counters accumulated since the previous flush, and the
+    /// current size, to the installed `metrics` recorder. Every metric is
+    /// labelled with `cache = name`.
+    #[cfg(feature = "metrics")]
+    pub fn flush_metrics(&mut self, name: &str) {
+        let delta = self.stats.take_flushed();
+        super::stats::metrics::flush(name, &delta, self.len(), self.weighted_size());
+    }
+
+    /// Starts tracking the hit rate over a sliding window of lookups.
+    pub fn set_hit_window(&mut self, window: HitWindow) {
+        self.stats.set_hit_window(Some(window));
+    }
+
+    /// Returns the hit rate within the window set by `set_hit_window`, or
+    /// `None` if there is no window or no lookups fell into it.
+    pub fn recent_hit_rate(&self) -> Option<f64> {
+        self.stats.recent_hit_rate()
+    }
+
+    /// Starts recording the age and idle time of evicted entries, reported
+    /// by `stat`. Only entries stored from now on are recorded.
+    pub fn set_age_tracking(&mut self, ages: AgeTracking) {
+        self.stats.set_age_tracking(Some(ages));
+    }
+
+    /// Starts counting how often each entry stored from now on is read or
+    /// replaced, or stops if `enabled` is false.
+    pub fn set_frequency_tracking(&mut self, enabled: bool) {
+        self.stats.set_frequency_tracking(enabled);
+    }
+
+    /// Returns how many tracked entries fall into each power-of-two range of
+    /// access counts, or `None` if frequency tracking is disabled.
+    pub fn frequency_distribution(&self) -> Option<Vec<(RangeInclusive<u32>, usize)>> {
+        self.stats.frequency_distribution()
+    }
+
+    /// Returns the segment of the entry of `k` and what the age and
+    /// frequency tracking know about it, or `None` if `k` is not cached.
+    pub fn metadata<Q>(&self, k: &Q) -> Option<EntryMeta>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        let segment = self.store.segment_of(k)?;
+        Some(self.stats.entry_meta(self.store.hash(k), segment))
+    }
+
+    /// Adds an entry read back by a deserializer at the back of the
+    /// protected segment if `protected` is set, or else of the
+    /// probationary one. `restored` evicts down to the limits once all are
+    /// added.
+    #[cfg(feature = "serde")]
+    pub(crate) fn restore(&mut self, k: K, v: V, protected: bool) {
+        self.store.push_back(k, v, protected);
+    }
+
+    #[cfg(feature = "serde")]
+    pub(crate) fn restored(&mut self) {
+        // a key read twice is stored once
+        self.store.reweigh();
+        let (max_size, max_weight) = (self.max_size, self.max_weight);
+        self.policy.on_resize(&mut self.store, max_size, max_weight);
+        self.trim();
+    }
+
+    fn info(&self, stats: Counters) -> Info {
+        let mut memory = self.store.memory_usage() + self.policy.memory_usage();
+        memory.heap_bytes = self.store.weigher.heap_bytes(self.store.weight, self.len());
+        Info::new(&stats, self.len(), memory, &self.stats)
+    }
+}
+
+/// Caches of shared values, whose lookups can hand out an owned handle
+/// instead of a borrow of the cache. The same methods of the other caches
+/// are documented here.
+impl<K, V, P, S> Cache<K, Arc<V>, P, S>
+where
+    K: Hash + Eq,
+    S: BuildHasher,
+    P: ReplacementPolicy<K, Arc<V>, S>,
+{
+    /// Like `get`, but returns a clone of the `Arc`, which the caller can
+    /// hold on to while the cache is used or mutated.
+    pub fn get_arc<Q>(&mut self, k: &Q) -> Option<Arc<V>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        self.get(k).cloned()
+    }
+
+    /// Like `peek`, but returns a clone of the `Arc`.
+    pub fn peek_arc<Q>(&self, k: &Q) -> Option<Arc<V>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        self.peek(k).cloned()
+    }
+
+    /// Like `get_many`, but returns clones of the `Arc`s, taken as each key
+    /// is looked up: an entry displaced later in the batch is still returned.
+    pub fn get_many_arc<'a, Q, I>(&mut self, keys: I) -> Vec<Option<Arc<V>>>
+    where
+        K: Borrow<Q>,
+        Q: 'a + ?Sized + Eq + Hash,
+        I: IntoIterator<Item = &'a Q>,
+    {
+        keys.into_iter().map(|k| self.get(k).cloned()).collect()
+    }
+
+    /// Caches `v` behind a new `Arc`, returning the value it replaces.
+    pub fn add_arc(&mut self, k: K, v: V) -> Option<Arc<V>> {
+        self.add(k, Arc::new(v))
+    }
+}
+
+impl<K, V, P, S> IntoIterator for Cache<K, V, P, S> {
+    type Item = (K, V);
+    type IntoIter = IntoIter<K, V, S>;
+
+    fn into_iter(self) -> IntoIter<K, V, S> {
+        let Store { map, protected, .. } = self.store;
+        protected.into_iter().flatten().chain(map)
+    }
+}
+
+impl<'a, K, V, P, S> IntoIterator for &'a Cache<K, V, P, S>
+where
+    K: Hash + Eq,
+    S: BuildHasher,
+{
+    type Item = (&'a K, &'a V);
+    type IntoIter = Iter<'a, K, V>;
+
+    fn into_iter(self) -> Iter<'a, K, V> {
+        self.store.iter()
+    }
+}
+
+/// Serializes the entries of a segment of a store like a `LinkedHashMap`,
+/// for the caches that serialize their segments apart.
+#[cfg(feature = "serde")]
+pub(crate) struct SegmentEntries<'a, K, V, S>(pub(crate) &'a Store<K, V, S>, pub(crate) Segment);
+
+#[cfg(feature = "serde")]
+impl<'a, K, V, S> serde::Serialize for SegmentEntries<'a, K, V, S>
+where
+    K: Hash + Eq + serde::Serialize,
+    V: serde::Serialize,
+    S: BuildHasher,
+{
+    fn serialize<T: serde::Serializer>(&self, serializer: T) -> Result<T::Ok, T::Error> {
+        serializer.collect_seq(self.0.iter_segment(self.1))
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<K, V, P, S> serde::Serialize for Cache<K, V, P, S>
+where
+    K: serde::Serialize,
+    V: serde::Serialize,
+    P: Default,
+{
+    /// Serializes the size and the entries in the order of `iter`.
+    /// Callbacks, weighers, statistics and the state of the policy are not
+    /// serialized.
+    fn serialize<T: serde::Serializer>(&self, serializer: T) -> Result<T::Ok, T::Error> {
+        use serde::ser::SerializeStruct;
+
+        let mut state = serializer.serialize_struct("Cache", 2)?;
+        state.serialize_field("max_size", &self.max_size)?;
+        state.serialize_field("entries", &self.store.map)?;
+        state.end()
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de, K, V, P, S> serde::Deserialize<'de> for Cache<K, V, P, S>
+where
+    K: Hash + Eq + serde::Deserialize<'de>,
+    V: serde::Deserialize<'de>,
+    S: BuildHasher + Default,
+    P: ReplacementPolicy<K, V, S> + Default,
+{
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(serde::Deserialize)]
+        struct Repr<K, V> {
+            max_size: usize,
+            entries: Vec<(K, V)>,
+        }
+
+        let repr = Repr::deserialize(deserializer)?;
+        let mut cache = Cache::with_max_size(P::default(), repr.max_size, S::default());
+        for (k, v) in repr.entries {
+            cache.restore(k, v, false);
+        }
+        cache.restored();
+        Ok(cache)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Evicts the most recently read entry, keeping new ones until they are
+    /// read.
+    struct Mru;
+
+    impl<K: Hash + Eq, V, S: BuildHasher> ReplacementPolicy<K, V, S> for Mru {
+        fn on_hit<'a, Q>(&mut self, store: &'a mut Store<K, V, S>, k: &Q) -> Option<(&'a K, &'a V)>
+        where
+            K: Borrow<Q>,
+            Q: ?Sized + Eq + Hash,
+        {
+            store.move_to_back(k);
+            store.get_key_value(k)
+        }
+
+        fn on_insert(&mut self, store: &mut Store<K, V, S>, k: K, v: V) -> Option<(K, V)> {
+            store.upsert_front(k, v, false)
+        }
+    }
+
+    #[test]
+    fn test_policies() {
+        let mut lru = Cache::<_, _, Lru>::new(2);
+        let mut fifo = Cache::<_, _, Fifo>::new(2);
+        for &k in [1, 2].iter() {
+            lru.add(k, k);
+            fifo.add(k, k);
+        }
+        lru.get(&1);
+        fifo.get(&1);
+        lru.add(3, 3);
+        fifo.add(3, 3);
+        assert!(lru.contains_key(&1) && !lru.contains_key(&2));
+        assert!(!fifo.contains_key(&1) && fifo.contains_key(&2));
+        assert_eq!(lru.stat().eviction_count, 1);
+        assert_eq!(fifo.stat().hit_count, 1);
+    }
+
+    /// An `Lru` that counts the victims it is asked for.
+    #[derive(Default)]
+    struct Counting {
+        lru: Lru,
+        victims: usize,
+    }
+
+    impl<K: Hash + Eq, V, S: BuildHasher> ReplacementPolicy<K, V, S> for Counting {
+        fn on_hit<'a, Q>(&mut self, store: &'a mut Store<K, V, S>, k: &Q) -> Option<(&'a K, &'a V)>
+        where
+            K: Borrow<Q>,
+            Q: ?Sized + Eq + Hash,
+        {
+            self.lru.on_hit(store, k)
+        }
+
+        fn on_insert(&mut self, store: &mut Store<K, V, S>, k: K, v: V) -> Option<(K, V)> {
+            self.lru.on_insert(store, k, v)
+        }
+
+        fn choose_victim(&mut self, store: &mut Store<K, V, S>) -> Option<(K, V)> {
+            self.victims += 1;
+            store.pop_victim()
+        }
+    }
+
+    #[test]
+    fn test_victims_through_policy() {
+        let mut cache = Cache::<_, _, Counting>::new(2);
+        cache.set_eviction_filter(|&k, _| k != 1);
+        for k in 1..4 {
+            cache.add(k, k);
+        }
+        assert!(cache.contains_key(&1) && !cache.contains_key(&2));
+        assert_eq!(cache.policy().victims, 1);
+
+        cache.add_with_priority(4, 4, 1);
+        assert!(!cache.contains_key(&3));
+        assert_eq!(cache.policy().victims, 2);
+        assert_eq!(cache.stat().eviction_count, 2);
+    }
+
+    #[test]
+    fn test_custom_policy() {
+        let mut cache = Cache::with_policy(Mru, 2);
+        cache.add(1, 1);
+        cache.add(2, 2);
+        cache.get(&1);
+        cache.get(&2);
+        let (old, evicted) = cache.push(3, 3);
+        assert_eq!(old, None);
+        assert_eq!(evicted, vec![(2, 2)]);
+        assert_eq!(cache.remove(&1), Some(1));
+        assert_eq!(cache.len(), 1);
+    }
+}
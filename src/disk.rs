@@ -0,0 +1,415 @@
+//! An LRU cache that spills to disk.
+//!
+//! Entries evicted from memory are appended to a log file instead of being
+//! dropped, and are read back and cached in memory again when they are next
+//! accessed. The log is bounded in bytes; the entries that have been on disk
+//! the longest leave it first, and only then reach the eviction callback.
+//!
+//! The log is scratch space: it is truncated when the cache is created and
+//! compacted once more than half of it belongs to entries that have since
+//! been reloaded or removed.
+//!
+//! ```no_run
+//! use cache::disk::Cache;
+//!
+//! let mut cache = Cache::new(1024, "/tmp/cache.log", 1 << 30).unwrap();
+//! cache.add(1u64, vec![0u8; 4096]).unwrap();
+//! assert!(cache.get(&1).unwrap().is_some());
+//! ```
+
+use std::borrow::Borrow;
+use std::error;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::hash::{BuildHasher, Hash};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use super::callback::Callback;
+use super::hash::DefaultState;
+use super::lru;
+use super::map::LinkedHashMap;
+use super::stats::Info;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Encoding(bincode::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "disk tier i/o failed: {}", err),
+            Error::Encoding(err) => write!(f, "invalid disk tier record: {}", err),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            Error::Encoding(err) => Some(err),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
+    }
+}
+
+/// Where an entry lies in the log.
+#[derive(Debug, Clone, Copy)]
+struct Record {
+    offset: u64,
+    len: u64,
+}
+
+struct Log {
+    path: PathBuf,
+    file: File,
+    end: u64,
+    live: u64,
+}
+
+impl Log {
+    fn create(path: PathBuf) -> io::Result<Log> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)?;
+        Ok(Log {
+            path,
+            file,
+            end: 0,
+            live: 0,
+        })
+    }
+
+    fn append<K: Serialize, V: Serialize>(&mut self, k: &K, v: &V) -> Result<Record, Error> {
+        let bytes = bincode::serialize(&(k, v)).map_err(Error::Encoding)?;
+        self.file.seek(SeekFrom::Start(self.end))?;
+        self.file.write_all(&bytes)?;
+        let record = Record {
+            offset: self.end,
+            len: bytes.len() as u64,
+        };
+        self.end += record.len;
+        self.live += record.len;
+        Ok(record)
+    }
+
+    fn read<K: DeserializeOwned, V: DeserializeOwned>(
+        &mut self,
+        record: Record,
+    ) -> Result<(K, V), Error> {
+        let mut bytes = vec![0; record.len as usize];
+        self.file.seek(SeekFrom::Start(record.offset))?;
+        self.file.read_exact(&mut bytes)?;
+        bincode::deserialize(&bytes).map_err(Error::Encoding)
+    }
+
+    fn release(&mut self, record: Record) {
+        self.live -= record.len;
+    }
+}
+
+pub struct Cache<K, V, S = DefaultState> {
+    memory: lru::Cache<K, V, S>,
+    index: LinkedHashMap<K, Record>,
+    log: Log,
+    max_disk_bytes: u64,
+    callback: Option<Callback<K, V>>,
+}
+
+impl<K, V> Cache<K, V, DefaultState>
+where
+    K: Hash + Eq + Serialize + DeserializeOwned,
+    V: Serialize + DeserializeOwned,
+{
+    pub fn new<P: AsRef<Path>>(
+        max_size: usize,
+        path: P,
+        max_disk_bytes: u64,
+    ) -> Result<Cache<K, V, DefaultState>, Error> {
+        Cache::with_hasher(max_size, path, max_disk_bytes, Default::default())
+    }
+}
+
+impl<K, V, S> Cache<K, V, S>
+where
+    K: Hash + Eq + Serialize + DeserializeOwned,
+    V: Serialize + DeserializeOwned,
+    S: BuildHasher,
+{
+    /// Creates a cache holding `max_size` entries in memory and up to
+    /// `max_disk_bytes` of encoded entries in the log at `path`.
+    pub fn with_hasher<P: AsRef<Path>>(
+        max_size: usize,
+        path: P,
+        max_disk_bytes: u64,
+        hash_builder: S,
+    ) -> Result<Cache<K, V, S>, Error> {
+        Ok(Cache {
+            memory: lru::Cache::with_hasher(max_size, hash_builder),
+            index: LinkedHashMap::new(),
+            log: Log::create(path.as_ref().to_owned())?,
+            max_disk_bytes,
+            callback: None,
+        })
+    }
+
+    /// Registers the callback of entries that leave the disk tier, or that
+    /// could not be written to it.
+    pub fn set_eviction_callback<C>(&mut self, cb: C)
+    where
+        C: FnMut(K, V) + 'static,
+    {
+        self.callback = Some(Box::new(cb));
+    }
+
+    pub fn contains_key<Q>(&self, k: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        self.memory.contains_key(k) || self.index.contains_key(k)
+    }
+
+    /// Returns whether `k` is cached in memory.
+    pub fn in_memory<Q>(&self, k: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        self.memory.contains_key(k)
+    }
+
+    /// Looks `k` up in memory, then on disk. An entry found on disk is
+    /// cached in memory again, which may spill others.
+    pub fn get<Q>(&mut self, k: &Q) -> Result<Option<&V>, Error>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        if !self.memory.contains_key(k) {
+            let record = match self.index.get(k) {
+                Some(&record) => record,
+                None => return Ok(self.memory.get(k)),
+            };
+            // an entry that fails to read stays on disk
+            let (_, v): (K, V) = self.log.read(record)?;
+            let (k, _) = self.index.remove_entry(k).expect("indexed above");
+            self.log.release(record);
+            let (_, displaced) = self.memory.push(k, v);
+            self.spill(displaced)?;
+        }
+        Ok(self.memory.get(k))
+    }
+
+    /// Caches `v` under `k` in memory, spilling the entries it displaces,
+    /// and returns the value it replaces from either tier.
+    pub fn add(&mut self, k: K, v: V) -> Result<Option<V>, Error> {
+        let on_disk = match self.index.remove(&k) {
+            Some(record) => {
+                self.log.release(record);
+                Some(self.log.read::<K, V>(record).map(|(_, v)| v))
+            }
+            None => None,
+        };
+        let (old_v, displaced) = self.memory.push(k, v);
+        self.spill(displaced)?;
+        match on_disk {
+            Some(v) => v.map(Some),
+            None => Ok(old_v),
+        }
+    }
+
+    pub fn remove<Q>(&mut self, k: &Q) -> Result<Option<V>, Error>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        if let Some(v) = self.memory.remove(k) {
+            return Ok(Some(v));
+        }
+        match self.index.remove(k) {
+            Some(record) => {
+                self.log.release(record);
+                let (_, v): (K, V) = self.log.read(record)?;
+                self.compact_if_sparse()?;
+                Ok(Some(v))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn spill(&mut self, displaced: Vec<(K, V)>) -> Result<(), Error> {
+        let mut result = Ok(());
+        for (k, v) in displaced {
+            if result.is_err() {
+                self.evict(k, v);
+                continue;
+            }
+            match self.log.append(&k, &v) {
+                Ok(record) => {
+                    self.index.push_front(k, record);
+                }
+                Err(err) => {
+                    self.evict(k, v);
+                    result = Err(err);
+                }
+            }
+        }
+        result?;
+        while self.log.live > self.max_disk_bytes {
+            let (k, record) = match self.index.pop_back() {
+                Some(entry) => entry,
+                None => break,
+            };
+            self.log.release(record);
+            if self.callback.is_some() {
+                let (_, v): (K, V) = self.log.read(record)?;
+                self.evict(k, v);
+            }
+        }
+        self.compact_if_sparse()
+    }
+
+    fn evict(&mut self, k: K, v: V) {
+        if let Some(cb) = &mut self.callback {
+            cb(k, v);
+        }
+    }
+
+    /// Rewrites the log without its dead records once they make up more
+    /// than half of it.
+    fn compact_if_sparse(&mut self) -> Result<(), Error> {
+        if self.log.end - self.log.live <= self.log.live {
+            return Ok(());
+        }
+        let mut path = self.log.path.clone().into_os_string();
+        path.push(".compact");
+        let mut compacted = Log::create(PathBuf::from(path))?;
+        let mut bytes = Vec::new();
+        // the index keeps pointing into the old log until it is replaced
+        let mut offsets = Vec::with_capacity(self.index.len());
+        for (_, record) in self.index.iter() {
+            bytes.resize(record.len as usize, 0);
+            self.log.file.seek(SeekFrom::Start(record.offset))?;
+            self.log.file.read_exact(&mut bytes)?;
+            compacted.file.write_all(&bytes)?;
+            offsets.push(compacted.end);
+            compacted.end += record.len;
+            compacted.live += record.len;
+        }
+        fs::rename(&compacted.path, &self.log.path)?;
+        for ((_, record), offset) in self.index.iter_mut().zip(offsets) {
+            record.offset = offset;
+        }
+        compacted.path = self.log.path.clone();
+        self.log = compacted;
+        Ok(())
+    }
+
+    /// Returns the number of entries in both tiers.
+    pub fn len(&self) -> usize {
+        self.memory.len() + self.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.memory.is_empty() && self.index.is_empty()
+    }
+
+    pub fn memory_len(&self) -> usize {
+        self.memory.len()
+    }
+
+    pub fn disk_len(&self) -> usize {
+        self.index.len()
+    }
+
+    /// Returns the encoded size of the entries on disk.
+    pub fn disk_bytes(&self) -> u64 {
+        self.log.live
+    }
+
+    pub fn max_size(&self) -> usize {
+        self.memory.max_size()
+    }
+
+    /// Drops the entries of both tiers and truncates the log.
+    pub fn purge(&mut self) -> Result<(), Error> {
+        self.memory.purge();
+        self.index.clear();
+        self.log = Log::create(self.log.path.clone())?;
+        Ok(())
+    }
+
+    /// Returns the statistics of the memory tier.
+    pub fn stat(&self) -> Info {
+        self.memory.stat()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::env;
+    use std::process;
+    use std::rc::Rc;
+
+    fn log_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("cache-disk-{}-{}.log", name, process::id()))
+    }
+
+    #[test]
+    fn test_spill_and_reload() {
+        let path = log_path("reload");
+        let mut cache = Cache::new(2, &path, 1 << 20).unwrap();
+        for i in 0..5u32 {
+            cache.add(i, i.to_string()).unwrap();
+        }
+        assert_eq!((cache.memory_len(), cache.disk_len()), (2, 3));
+        assert_eq!(cache.get(&0).unwrap().map(String::as_str), Some("0"));
+        assert!(cache.in_memory(&0));
+        assert!(!cache.in_memory(&3));
+        assert_eq!(
+            cache.add(1, "one".to_string()).unwrap().as_deref(),
+            Some("1")
+        );
+        assert_eq!(cache.remove(&2).unwrap().as_deref(), Some("2"));
+        assert_eq!(cache.len(), 4);
+        for (k, v) in [(0, "0"), (1, "one"), (3, "3"), (4, "4")].iter() {
+            assert_eq!(cache.get(k).unwrap().map(String::as_str), Some(*v));
+        }
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_disk_bound() {
+        let path = log_path("bound");
+        let evicted = Rc::new(RefCell::new(Vec::new()));
+        let sink = evicted.clone();
+        let record_len = bincode::serialized_size(&(0u32, 0u64)).unwrap();
+        let mut cache = Cache::new(1, &path, 2 * record_len).unwrap();
+        cache.set_eviction_callback(move |k, v| sink.borrow_mut().push((k, v)));
+        for i in 0..5u32 {
+            cache.add(i, u64::from(i)).unwrap();
+        }
+        assert_eq!(*evicted.as_ref().borrow(), vec![(0, 0), (1, 1)]);
+        assert_eq!(cache.disk_bytes(), 2 * record_len);
+        assert!(!cache.contains_key(&1));
+        assert_eq!(cache.get(&2).unwrap(), Some(&2));
+        fs::remove_file(&path).unwrap();
+    }
+}
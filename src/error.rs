@@ -0,0 +1,122 @@
+use std::error::Error;
+use std::fmt;
+use std::mem;
+
+/// Returned by the `try_*` constructors when a cache configuration is
+/// rejected instead of being clamped into range.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConfigError {
+    /// The requested size is below the minimum the policy supports.
+    SizeTooSmall { size: usize, min: usize },
+    /// A segment factor lies outside of the open interval (0, 1).
+    FactorOutOfRange { name: &'static str, value: f64 },
+    /// The factors leave one of the segments without room for an entry.
+    EmptySegment { segment: &'static str },
+    /// A share of the cache lies outside of [0, 1].
+    ShareOutOfRange { value: f64 },
+    /// The shares guaranteed to the priority classes add up to more than
+    /// the whole cache.
+    SharesOverOne { total: f64 },
+    /// The low-water mark lies above the capacity.
+    LowWaterMarkOverCapacity { low: usize, capacity: usize },
+    /// A backoff factor below one, which would shrink the backoffs.
+    BackoffFactorBelowOne { factor: f64 },
+    /// A sketch error bound so tight that its counters would not fit in
+    /// memory.
+    SketchTooWide { epsilon: f64 },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::SizeTooSmall { size, min } => {
+                write!(f, "cache size {} is below the minimum of {}", size, min)
+            }
+            ConfigError::FactorOutOfRange { name, value } => {
+                write!(f, "{} must be within (0, 1), got {}", name, value)
+            }
+            ConfigError::EmptySegment { segment } => {
+                write!(f, "the {} segment would have a size of zero", segment)
+            }
+            ConfigError::ShareOutOfRange { value } => {
+                write!(f, "a share must be within [0, 1], got {}", value)
+            }
+            ConfigError::SharesOverOne { total } => {
+                write!(f, "the shares add up to {}, more than the cache", total)
+            }
+            ConfigError::LowWaterMarkOverCapacity { low, capacity } => write!(
+                f,
+                "low-water mark {} is above the capacity of {}",
+                low, capacity
+            ),
+            ConfigError::BackoffFactorBelowOne { factor } => {
+                write!(f, "the backoff factor must be at least 1, got {}", factor)
+            }
+            ConfigError::SketchTooWide { epsilon } => write!(
+                f,
+                "an epsilon of {} needs more sketch counters than fit in memory",
+                epsilon
+            ),
+        }
+    }
+}
+
+impl Error for ConfigError {}
+
+pub(crate) fn check_size(size: usize, min: usize) -> Result<(), ConfigError> {
+    if size < min {
+        return Err(ConfigError::SizeTooSmall { size, min });
+    }
+    Ok(())
+}
+
+pub(crate) fn check_factor(name: &'static str, value: f64) -> Result<(), ConfigError> {
+    if !(value > 0.0 && value < 1.0) {
+        return Err(ConfigError::FactorOutOfRange { name, value });
+    }
+    Ok(())
+}
+
+pub(crate) fn check_segment(segment: &'static str, len: usize) -> Result<(), ConfigError> {
+    if len == 0 {
+        return Err(ConfigError::EmptySegment { segment });
+    }
+    Ok(())
+}
+
+pub(crate) fn check_low_water_mark(low: Option<usize>, capacity: usize) -> Result<(), ConfigError> {
+    match low {
+        Some(low) if low > capacity => Err(ConfigError::LowWaterMarkOverCapacity { low, capacity }),
+        _ => Ok(()),
+    }
+}
+
+pub(crate) fn check_backoff_factor(factor: f64) -> Result<(), ConfigError> {
+    if !(1.0..).contains(&factor) {
+        return Err(ConfigError::BackoffFactorBelowOne { factor });
+    }
+    Ok(())
+}
+
+/// Checks that a sketch of `depth` rows of `width` counters, the width
+/// rounded up to a power of two, can be allocated.
+pub(crate) fn check_sketch_size(
+    epsilon: f64,
+    width: usize,
+    depth: usize,
+) -> Result<(), ConfigError> {
+    let counters = width
+        .checked_next_power_of_two()
+        .and_then(|width| width.checked_mul(depth));
+    if counters.is_none_or(|counters| counters > isize::MAX as usize / mem::size_of::<u32>()) {
+        return Err(ConfigError::SketchTooWide { epsilon });
+    }
+    Ok(())
+}
+
+pub(crate) fn check_share(value: f64) -> Result<(), ConfigError> {
+    if !(0.0..=1.0).contains(&value) {
+        return Err(ConfigError::ShareOutOfRange { value });
+    }
+    Ok(())
+}
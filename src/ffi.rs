@@ -0,0 +1,289 @@
+//! A C interface to the LRU and 2Q caches, for keys and values that are
+//! byte strings.
+//!
+//! Each cache is an opaque handle created by `cache_<policy>_new` and
+//! released with `cache_<policy>_free`. Keys and values are copied in, so
+//! the caller keeps ownership of its buffers. The declarations can be
+//! generated with cbindgen; linking from C or C++ needs the crate built as
+//! a `staticlib` or `cdylib`.
+//!
+//! ```c
+//! CacheLru *cache = cache_lru_new(1024);
+//! cache_lru_put(cache, "k", 1, "v", 1);
+//! const uint8_t *v;
+//! size_t len;
+//! if (cache_lru_get(cache, "k", 1, &v, &len) == 1) {
+//!     /* v points to len bytes */
+//! }
+//! cache_lru_free(cache);
+//! ```
+//!
+//! No panic unwinds into the caller: a function that panics returns
+//! `CACHE_ERROR`, or null for `new`, after which the cache should only be
+//! freed.
+
+use std::os::raw::c_int;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::slice;
+
+use super::{lru, q2};
+
+type Bytes = Box<[u8]>;
+
+/// Returned by a function that panicked.
+pub const CACHE_ERROR: c_int = -1;
+
+/// An LRU cache of byte strings.
+pub struct CacheLru(lru::Cache<Bytes, Bytes>);
+
+/// A 2Q cache of byte strings.
+pub struct CacheQ2(q2::Cache<Bytes, Bytes>);
+
+unsafe fn bytes<'a>(data: *const u8, len: usize) -> &'a [u8] {
+    if len == 0 {
+        &[]
+    } else {
+        slice::from_raw_parts(data, len)
+    }
+}
+
+/// Runs `f`, turning a panic into `error`.
+fn catch<T>(error: T, f: impl FnOnce() -> T) -> T {
+    panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or(error)
+}
+
+/// Stores the pointer and length of `found`, if any, and returns 1, or 0
+/// if there is none.
+unsafe fn found(found: Option<&Bytes>, value: *mut *const u8, value_len: *mut usize) -> c_int {
+    let v = match found {
+        Some(v) => v,
+        None => return 0,
+    };
+    if !value.is_null() {
+        *value = v.as_ptr();
+    }
+    if !value_len.is_null() {
+        *value_len = v.len();
+    }
+    1
+}
+
+/// Creates an LRU cache holding up to `max_size` entries. Release it with
+/// `cache_lru_free`.
+#[no_mangle]
+pub extern "C" fn cache_lru_new(max_size: usize) -> *mut CacheLru {
+    catch(ptr::null_mut(), || {
+        Box::into_raw(Box::new(CacheLru(lru::Cache::new(max_size))))
+    })
+}
+
+/// Releases an LRU cache. Does nothing if `cache` is null.
+///
+/// # Safety
+///
+/// `cache` must be null or a handle returned by `cache_lru_new`, not
+/// already freed.
+#[no_mangle]
+pub unsafe extern "C" fn cache_lru_free(cache: *mut CacheLru) {
+    if !cache.is_null() {
+        catch((), || drop(Box::from_raw(cache)));
+    }
+}
+
+/// Looks up a key. Returns 1 and stores a pointer to its value in `value`
+/// and the value's length in `value_len` if it is cached, 0 otherwise. The
+/// pointer is valid until the cache is next modified or freed.
+///
+/// # Safety
+///
+/// `cache` must be a live handle, `key` must point to `key_len` readable
+/// bytes unless `key_len` is zero, and `value` and `value_len` must be null
+/// or writable.
+#[no_mangle]
+pub unsafe extern "C" fn cache_lru_get(
+    cache: *mut CacheLru,
+    key: *const u8,
+    key_len: usize,
+    value: *mut *const u8,
+    value_len: *mut usize,
+) -> c_int {
+    catch(CACHE_ERROR, || {
+        found((*cache).0.get(bytes(key, key_len)), value, value_len)
+    })
+}
+
+/// Caches a copy of the value under a copy of the key. Returns 1 if a
+/// value was replaced, 0 otherwise.
+///
+/// # Safety
+///
+/// `cache` must be a live handle, and `key` and `value` must point to
+/// `key_len` and `value_len` readable bytes unless those are zero.
+#[no_mangle]
+pub unsafe extern "C" fn cache_lru_put(
+    cache: *mut CacheLru,
+    key: *const u8,
+    key_len: usize,
+    value: *const u8,
+    value_len: usize,
+) -> c_int {
+    catch(CACHE_ERROR, || {
+        let k = Bytes::from(bytes(key, key_len));
+        let v = Bytes::from(bytes(value, value_len));
+        (*cache).0.add(k, v).is_some() as c_int
+    })
+}
+
+/// Removes a key. Returns 1 if it was cached, 0 otherwise.
+///
+/// # Safety
+///
+/// `cache` must be a live handle and `key` must point to `key_len`
+/// readable bytes unless `key_len` is zero.
+#[no_mangle]
+pub unsafe extern "C" fn cache_lru_remove(
+    cache: *mut CacheLru,
+    key: *const u8,
+    key_len: usize,
+) -> c_int {
+    catch(CACHE_ERROR, || {
+        (*cache).0.remove(bytes(key, key_len)).is_some() as c_int
+    })
+}
+
+/// Stores the number of cached entries in `len` and returns 0.
+///
+/// # Safety
+///
+/// `cache` must be a live handle and `len` writable.
+#[no_mangle]
+pub unsafe extern "C" fn cache_lru_len(cache: *const CacheLru, len: *mut usize) -> c_int {
+    catch(CACHE_ERROR, || {
+        *len = (*cache).0.len();
+        0
+    })
+}
+
+/// Creates a 2Q cache holding up to `max_size` entries. Release it with
+/// `cache_q2_free`.
+#[no_mangle]
+pub extern "C" fn cache_q2_new(max_size: usize) -> *mut CacheQ2 {
+    catch(ptr::null_mut(), || {
+        Box::into_raw(Box::new(CacheQ2(q2::Cache::new(max_size))))
+    })
+}
+
+/// Releases a 2Q cache. Does nothing if `cache` is null.
+///
+/// # Safety
+///
+/// `cache` must be null or a handle returned by `cache_q2_new`, not
+/// already freed.
+#[no_mangle]
+pub unsafe extern "C" fn cache_q2_free(cache: *mut CacheQ2) {
+    if !cache.is_null() {
+        catch((), || drop(Box::from_raw(cache)));
+    }
+}
+
+/// Looks up a key, like `cache_lru_get`.
+///
+/// # Safety
+///
+/// As for `cache_lru_get`, with a handle from `cache_q2_new`.
+#[no_mangle]
+pub unsafe extern "C" fn cache_q2_get(
+    cache: *mut CacheQ2,
+    key: *const u8,
+    key_len: usize,
+    value: *mut *const u8,
+    value_len: *mut usize,
+) -> c_int {
+    catch(CACHE_ERROR, || {
+        found((*cache).0.get(bytes(key, key_len)), value, value_len)
+    })
+}
+
+/// Caches a copy of the value under a copy of the key, like
+/// `cache_lru_put`.
+///
+/// # Safety
+///
+/// As for `cache_lru_put`, with a handle from `cache_q2_new`.
+#[no_mangle]
+pub unsafe extern "C" fn cache_q2_put(
+    cache: *mut CacheQ2,
+    key: *const u8,
+    key_len: usize,
+    value: *const u8,
+    value_len: usize,
+) -> c_int {
+    catch(CACHE_ERROR, || {
+        let k = Bytes::from(bytes(key, key_len));
+        let v = Bytes::from(bytes(value, value_len));
+        (*cache).0.add(k, v).is_some() as c_int
+    })
+}
+
+/// Removes a key, like `cache_lru_remove`.
+///
+/// # Safety
+///
+/// As for `cache_lru_remove`, with a handle from `cache_q2_new`.
+#[no_mangle]
+pub unsafe extern "C" fn cache_q2_remove(
+    cache: *mut CacheQ2,
+    key: *const u8,
+    key_len: usize,
+) -> c_int {
+    catch(CACHE_ERROR, || {
+        (*cache).0.remove(bytes(key, key_len)).is_some() as c_int
+    })
+}
+
+/// Stores the number of cached entries in `len` and returns 0.
+///
+/// # Safety
+///
+/// `cache` must be a live handle from `cache_q2_new` and `len` writable.
+#[no_mangle]
+pub unsafe extern "C" fn cache_q2_len(cache: *const CacheQ2, len: *mut usize) -> c_int {
+    catch(CACHE_ERROR, || {
+        *len = (*cache).0.len();
+        0
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        unsafe {
+            let cache = cache_q2_new(4);
+            assert_eq!(cache_q2_put(cache, b"k".as_ptr(), 1, b"v1".as_ptr(), 2), 0);
+            assert_eq!(cache_q2_put(cache, b"k".as_ptr(), 1, b"v2".as_ptr(), 2), 1);
+            assert_eq!(cache_q2_put(cache, ptr::null(), 0, ptr::null(), 0), 0);
+
+            let (mut v, mut len) = (ptr::null(), 0);
+            assert_eq!(cache_q2_get(cache, b"k".as_ptr(), 1, &mut v, &mut len), 1);
+            assert_eq!(slice::from_raw_parts(v, len), b"v2");
+            assert_eq!(cache_q2_get(cache, b"x".as_ptr(), 1, &mut v, &mut len), 0);
+            assert_eq!(cache_q2_len(cache, &mut len), 0);
+            assert_eq!(len, 2);
+
+            assert_eq!(cache_q2_remove(cache, b"k".as_ptr(), 1), 1);
+            assert_eq!(cache_q2_remove(cache, b"k".as_ptr(), 1), 0);
+            cache_q2_free(cache);
+            cache_lru_free(ptr::null_mut());
+        }
+    }
+
+    #[test]
+    fn test_catch() {
+        let panicked = catch(CACHE_ERROR, || panic!("in the cache"));
+        assert_eq!(panicked, CACHE_ERROR);
+    }
+}
@@ -1,135 +1,91 @@
-use std::borrow::Borrow;
-use std::collections::hash_map::RandomState;
+#[cfg(feature = "persistence")]
 use std::hash::{BuildHasher, Hash};
-use std::ptr;
 
-use super::map::LinkedHashMap;
+use super::core::{self, Fifo};
+use super::hash::DefaultState;
+#[cfg(feature = "persistence")]
+use super::persistence::{self, SnapshotError};
+pub use super::stats::Info;
 
-pub struct Cache<K, V, S = RandomState> {
-    max_size: usize,
+/// Evicts the oldest entry, regardless of how often it is read: a
+/// `core::Cache` with the `Fifo` policy.
+pub type Cache<K, V, S = DefaultState> = core::Cache<K, V, Fifo, S>;
 
-    hit_count: usize,
-    miss_count: usize,
-
-    callback: Option<Box<dyn Fn(K, V)>>,
-
-    l_map: LinkedHashMap<K, V, S>,
-}
-
-impl<K: Hash + Eq, V> Cache<K, V, RandomState> {
-    pub fn new(max_size: usize) -> Cache<K, V, RandomState> {
-        Cache::with_hasher(max_size, Default::default())
-    }
-}
-
-impl<K: Hash + Eq, V, S> Cache<K, V, S>
+#[cfg(feature = "persistence")]
+impl<K, V, S> Cache<K, V, S>
 where
     K: Hash + Eq,
     S: BuildHasher,
 {
-    pub fn with_hasher(max_size: usize, hash_builder: S) -> Cache<K, V, S> {
-        let max_size = if max_size < 1 { 1 } else { max_size };
-        Cache {
-            max_size,
-            hit_count: 0,
-            miss_count: 0,
-            callback: None,
-            l_map: LinkedHashMap::with_capacity_and_hasher(max_size, hash_builder),
-        }
-    }
-
-    pub fn contains_key<Q: ?Sized>(&self, k: &Q) -> bool
+    /// Writes a versioned snapshot of the cache to `writer`. Only what
+    /// serde serializes is saved.
+    pub fn save_to<W>(&self, writer: W) -> Result<(), SnapshotError>
     where
-        K: Borrow<Q>,
-        Q: Eq + Hash,
+        K: serde::Serialize,
+        V: serde::Serialize,
+        W: std::io::Write,
     {
-        self.l_map.contains_key(k)
+        persistence::save(persistence::Kind::Fifo, self, writer)
     }
 
-    pub fn set_eviction_callback<C>(&mut self, cb: C)
+    /// Reads a cache written by `save_to`.
+    pub fn load_from<R>(reader: R) -> Result<Cache<K, V, S>, SnapshotError>
     where
-        C: Fn(K, V) + 'static,
+        K: serde::de::DeserializeOwned,
+        V: serde::de::DeserializeOwned,
+        S: Default,
+        R: std::io::Read,
     {
-        self.callback = Some(Box::new(cb));
+        persistence::load(persistence::Kind::Fifo, reader)
     }
+}
 
-    pub fn get<Q: ?Sized>(&mut self, k: &Q) -> Option<&V>
-    where
-        K: Borrow<Q>,
-        Q: Eq + Hash,
-    {
-        if let Some(v) = self.l_map.get(k) {
-            self.hit_count += 1;
-            return Some(&v);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_low_water_mark() {
+        let mut cache = Cache::new(8);
+        cache.set_low_water_mark(Some(5)).unwrap();
+        for k in 0..8 {
+            cache.add(k, k);
         }
-        self.miss_count += 1;
-        None
-    }
-
-    pub fn peek<Q: ?Sized>(&mut self, k: &Q) -> Option<&V>
-    where
-        K: Borrow<Q>,
-        Q: Eq + Hash,
-    {
-        self.l_map.get(k)
+        // the oldest go first, however recently they were read
+        assert_eq!(cache.get(&0), Some(&0));
+        cache.add(8, 8);
+        let keys: Vec<_> = cache.iter().map(|(&k, _)| k).collect();
+        assert_eq!(keys, vec![8, 7, 6, 5, 4]);
+        cache.add(9, 9);
+        assert_eq!(cache.len(), 6);
+        assert!(cache.set_low_water_mark(Some(9)).is_err());
     }
 
-    pub fn remove<Q: ?Sized>(&mut self, k: &Q) -> Option<V>
-    where
-        K: Borrow<Q>,
-        Q: Eq + Hash,
-    {
-        self.l_map.remove(k)
-    }
-
-    pub fn remove_entry<Q: ?Sized>(&mut self, k: &Q) -> Option<(K, V)>
-    where
-        K: Borrow<Q>,
-        Q: Eq + Hash,
-    {
-        self.l_map.remove_entry(k)
-    }
-
-    pub fn add(&mut self, k: K, v: V) -> Option<V> {
-        if let Some(val) = self.l_map.get_mut(&k) {
-            let old_v = unsafe { ptr::replace(val, v) };
-            return Some(old_v);
+    #[test]
+    fn test_merge() {
+        let mut cache = Cache::new(4);
+        let mut other = Cache::new(4);
+        for k in 0..3 {
+            cache.add(k, "mine");
         }
-
-        if self.len() + 1 > self.max_size {
-            self.l_map
-                .pop_back()
-                .map(|(k, v)| self.callback.as_ref().map(|cb| cb(k, v)));
+        for k in 2..4 {
+            other.add(k, "theirs");
         }
-        self.l_map.push_front(k, v);
-        None
-    }
-
-    pub fn len(&self) -> usize {
-        self.l_map.len()
-    }
-
-    pub fn purge(&mut self) {
-        self.l_map.clear()
+        let evicted = Rc::new(RefCell::new(Vec::new()));
+        let log = evicted.clone();
+        cache.set_eviction_callback(move |k, v| log.borrow_mut().push((k, v)));
+
+        cache.merge(other);
+        let entries: Vec<_> = cache.iter().map(|(&k, &v)| (k, v)).collect();
+        assert_eq!(
+            entries,
+            vec![(2, "mine"), (3, "theirs"), (1, "mine"), (0, "mine")]
+        );
+        assert_eq!(*evicted.as_ref().borrow(), vec![(2, "theirs")]);
+        let info = cache.stat();
+        assert_eq!((info.insert_count, info.replace_count), (4, 0));
+        assert_eq!(info.eviction_count, 0);
     }
-
-    pub fn is_empty(&self) -> bool {
-        self.l_map.is_empty()
-    }
-
-    pub fn shrink_to_fit(&mut self) {
-        self.l_map.shrink_to_fit();
-    }
-
-    pub fn stat(&self) -> Info {
-        Info {
-            hit_count: self.hit_count,
-            miss_count: self.miss_count,
-        }
-    }
-}
-
-pub struct Info {
-    pub hit_count: usize,
-    pub miss_count: usize,
 }
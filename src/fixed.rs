@@ -0,0 +1,307 @@
+//! An LRU cache that never allocates.
+//!
+//! `LruCache<K, V, N>` keeps up to `N` entries in an inline array, linked by
+//! `u16` indices, and only needs `core`: with the default `std` feature
+//! disabled this is the one module of the crate, usable on targets without
+//! an allocator. Keys are found by a scan from the most recently used entry,
+//! so lookups take time linear in `N` and keys need only be `Eq`.
+//!
+//! ```
+//! use cache::fixed::LruCache;
+//!
+//! let mut cache: LruCache<u8, u32, 2> = LruCache::new();
+//! cache.add(1, 10);
+//! cache.add(2, 20);
+//! cache.get(&1);
+//! assert_eq!(cache.push(3, 30), Some((2, 20)));
+//! ```
+
+use ::core::array;
+use ::core::borrow::Borrow;
+use ::core::mem;
+
+const NIL: u16 = u16::MAX;
+
+struct Node<K, V> {
+    key: K,
+    value: V,
+    prev: u16,
+    next: u16,
+}
+
+pub struct LruCache<K, V, const N: usize> {
+    nodes: [Option<Node<K, V>>; N],
+    // most recently used
+    head: u16,
+    // least recently used
+    tail: u16,
+    len: u16,
+}
+
+impl<K: Eq, V, const N: usize> LruCache<K, V, N> {
+    const VALID_CAPACITY: () = assert!(
+        N > 0 && N < NIL as usize,
+        "the capacity of a fixed cache must be within 1..65535"
+    );
+
+    pub fn new() -> LruCache<K, V, N> {
+        #[allow(clippy::let_unit_value)]
+        let () = Self::VALID_CAPACITY;
+        LruCache {
+            nodes: array::from_fn(|_| None),
+            head: NIL,
+            tail: NIL,
+            len: 0,
+        }
+    }
+
+    fn node(&self, i: u16) -> &Node<K, V> {
+        self.nodes[i as usize].as_ref().unwrap()
+    }
+
+    fn node_mut(&mut self, i: u16) -> &mut Node<K, V> {
+        self.nodes[i as usize].as_mut().unwrap()
+    }
+
+    fn find<Q>(&self, k: &Q) -> Option<u16>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        let mut i = self.head;
+        while i != NIL {
+            let node = self.node(i);
+            if node.key.borrow() == k {
+                return Some(i);
+            }
+            i = node.next;
+        }
+        None
+    }
+
+    fn unlink(&mut self, i: u16) {
+        let (prev, next) = {
+            let node = self.node(i);
+            (node.prev, node.next)
+        };
+        if prev == NIL {
+            self.head = next;
+        } else {
+            self.node_mut(prev).next = next;
+        }
+        if next == NIL {
+            self.tail = prev;
+        } else {
+            self.node_mut(next).prev = prev;
+        }
+    }
+
+    fn link_front(&mut self, i: u16) {
+        let head = self.head;
+        {
+            let node = self.node_mut(i);
+            node.prev = NIL;
+            node.next = head;
+        }
+        if head == NIL {
+            self.tail = i;
+        } else {
+            self.node_mut(head).prev = i;
+        }
+        self.head = i;
+    }
+
+    fn touch(&mut self, i: u16) {
+        if self.head != i {
+            self.unlink(i);
+            self.link_front(i);
+        }
+    }
+
+    pub fn contains_key<Q>(&self, k: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        self.find(k).is_some()
+    }
+
+    pub fn get<Q>(&mut self, k: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        let i = self.find(k)?;
+        self.touch(i);
+        Some(&self.node(i).value)
+    }
+
+    pub fn get_mut<Q>(&mut self, k: &Q) -> Option<&mut V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        let i = self.find(k)?;
+        self.touch(i);
+        Some(&mut self.node_mut(i).value)
+    }
+
+    pub fn peek<Q>(&self, k: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        self.find(k).map(|i| &self.node(i).value)
+    }
+
+    /// Caches `v` under `k` and returns the value it replaces. An entry
+    /// evicted to make room is dropped; use `push` to get it back.
+    pub fn add(&mut self, k: K, v: V) -> Option<V> {
+        match self.find(&k) {
+            Some(i) => {
+                self.touch(i);
+                Some(mem::replace(&mut self.node_mut(i).value, v))
+            }
+            None => {
+                self.insert(k, v);
+                None
+            }
+        }
+    }
+
+    /// Caches `v` under `k` and returns the entry it displaces: the previous
+    /// value of `k`, or else the least recently used entry if the cache was
+    /// full.
+    pub fn push(&mut self, k: K, v: V) -> Option<(K, V)> {
+        match self.find(&k) {
+            Some(i) => {
+                self.touch(i);
+                let old_v = mem::replace(&mut self.node_mut(i).value, v);
+                Some((k, old_v))
+            }
+            None => self.insert(k, v),
+        }
+    }
+
+    fn insert(&mut self, k: K, v: V) -> Option<(K, V)> {
+        let evicted = if self.len as usize == N {
+            self.remove_lru()
+        } else {
+            None
+        };
+        let i = self.nodes.iter().position(Option::is_none).unwrap() as u16;
+        self.nodes[i as usize] = Some(Node {
+            key: k,
+            value: v,
+            prev: NIL,
+            next: NIL,
+        });
+        self.link_front(i);
+        self.len += 1;
+        evicted
+    }
+
+    pub fn remove<Q>(&mut self, k: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        let i = self.find(k)?;
+        self.take(i).map(|(_, v)| v)
+    }
+
+    /// Removes and returns the least recently used entry.
+    pub fn remove_lru(&mut self) -> Option<(K, V)> {
+        match self.tail {
+            NIL => None,
+            i => self.take(i),
+        }
+    }
+
+    fn take(&mut self, i: u16) -> Option<(K, V)> {
+        self.unlink(i);
+        self.len -= 1;
+        self.nodes[i as usize]
+            .take()
+            .map(|node| (node.key, node.value))
+    }
+
+    pub fn len(&self) -> usize {
+        self.len as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn capacity(&self) -> usize {
+        N
+    }
+
+    pub fn purge(&mut self) {
+        for node in self.nodes.iter_mut() {
+            *node = None;
+        }
+        self.head = NIL;
+        self.tail = NIL;
+        self.len = 0;
+    }
+
+    /// Iterates from the most to the least recently used entry.
+    pub fn iter(&self) -> Iter<'_, K, V, N> {
+        Iter {
+            nodes: &self.nodes,
+            next: self.head,
+        }
+    }
+}
+
+impl<K: Eq, V, const N: usize> Default for LruCache<K, V, N> {
+    fn default() -> LruCache<K, V, N> {
+        LruCache::new()
+    }
+}
+
+pub struct Iter<'a, K, V, const N: usize> {
+    nodes: &'a [Option<Node<K, V>>; N],
+    next: u16,
+}
+
+impl<'a, K, V, const N: usize> Iterator for Iter<'a, K, V, N> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<(&'a K, &'a V)> {
+        if self.next == NIL {
+            return None;
+        }
+        let node = self.nodes[self.next as usize].as_ref().unwrap();
+        self.next = node.next;
+        Some((&node.key, &node.value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fixed_lru() {
+        let mut cache: LruCache<u32, u32, 3> = LruCache::new();
+        for i in 0..3 {
+            assert_eq!(cache.push(i, i), None);
+        }
+        assert_eq!(cache.get(&0), Some(&0));
+        assert_eq!(cache.push(3, 3), Some((1, 1)));
+        assert_eq!(cache.remove(&2), Some(2));
+        assert_eq!(cache.add(4, 4), None);
+        assert_eq!(cache.add(0, 10), Some(0));
+        let entries: Vec<_> = cache.iter().map(|(&k, &v)| (k, v)).collect();
+        assert_eq!(entries, vec![(0, 10), (4, 4), (3, 3)]);
+
+        assert_eq!(cache.push(5, 5), Some((3, 3)));
+        assert_eq!(cache.remove_lru(), Some((4, 4)));
+        assert_eq!(cache.len(), 2);
+        cache.purge();
+        assert!(cache.is_empty() && cache.peek(&0).is_none());
+    }
+}
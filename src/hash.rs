@@ -0,0 +1,101 @@
+//! Hash builders for the caches.
+//!
+//! The caches default to `RandomState`, whose keys come from the operating
+//! system. Where no randomness source is available, or when runs must be
+//! reproducible, pass a `FixedState` to the `with_hasher` constructors:
+//!
+//! ```
+//! use cache::hash::FixedState;
+//! use cache::q2::Cache;
+//!
+//! let mut cache: Cache<u32, u32, FixedState> = Cache::with_hasher(16, FixedState::default());
+//! cache.add(1, 1);
+//! assert_eq!(cache.get(&1), Some(&1));
+//! ```
+//!
+//! # Deterministic mode
+//!
+//! The hash of a key decides which entries collide and, for `q2`, which
+//! keys the ghost queue remembers, so two runs over the same operations
+//! only behave alike if they hash alike. The `with_seed` constructors build
+//! a cache on a `SeededState`, for which that holds given the same seed.
+//! The `new` constructors always use `DefaultState`: determinism is asked
+//! for cache by cache, so that no other crate of the build can turn it on
+//! for all of them.
+//!
+//! Hashes stay stable across runs of the same build only: the algorithm
+//! behind `DefaultHasher` may change between Rust releases.
+//!
+//! ```
+//! use cache::q2::Cache;
+//!
+//! let run = |seed| {
+//!     let mut cache = Cache::with_seed(4, seed);
+//!     for k in 0..16u32 {
+//!         cache.add(k % 7, k);
+//!     }
+//!     cache.iter().map(|(&k, _)| k).collect::<Vec<_>>()
+//! };
+//! assert_eq!(run(42), run(42));
+//! ```
+
+use std::collections::hash_map::{DefaultHasher, RandomState};
+use std::hash::{BuildHasher, BuildHasherDefault, Hasher};
+
+/// Builds SipHash hashers with fixed keys, so that every run hashes alike.
+/// Unlike `RandomState` it offers no protection against keys chosen to
+/// collide.
+pub type FixedState = BuildHasherDefault<DefaultHasher>;
+
+/// The hash builder of the caches created without one.
+pub type DefaultState = RandomState;
+
+/// Builds SipHash hashers keyed by a seed: hashers built from equal seeds
+/// hash alike, on every run. The seed is no secret, so like `FixedState` it
+/// offers no protection against keys chosen to collide.
+#[derive(Debug, Clone)]
+pub struct SeededState {
+    seed: u64,
+    // a hasher that has already been fed the seed
+    hasher: DefaultHasher,
+}
+
+impl SeededState {
+    pub fn new(seed: u64) -> SeededState {
+        let mut hasher = DefaultHasher::new();
+        hasher.write_u64(seed);
+        SeededState { seed, hasher }
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+}
+
+/// Seed zero.
+impl Default for SeededState {
+    fn default() -> SeededState {
+        SeededState::new(0)
+    }
+}
+
+impl BuildHasher for SeededState {
+    type Hasher = DefaultHasher;
+
+    fn build_hasher(&self) -> DefaultHasher {
+        self.hasher.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seeded_state() {
+        let (a, b) = (SeededState::new(1), SeededState::new(1));
+        assert_eq!(a.hash_one("key"), b.hash_one("key"));
+        assert_ne!(a.hash_one("key"), SeededState::new(2).hash_one("key"));
+        assert_eq!(SeededState::default().seed(), 0);
+    }
+}
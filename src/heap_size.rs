@@ -0,0 +1,205 @@
+//! Approximate heap usage of cached keys and values.
+//!
+//! `HeapSize` reports the bytes a value owns on the heap, not counting its
+//! own inline size. Caches combine it with `size_of` to bound themselves by
+//! resident bytes through `set_max_heap_size`. With the `derive` feature,
+//! `#[derive(HeapSize)]` sums the heap sizes of all fields.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::mem;
+
+#[cfg(feature = "derive")]
+pub use cache_derive::HeapSize;
+
+pub trait HeapSize {
+    /// Returns the number of bytes owned on the heap by this value.
+    fn heap_size(&self) -> usize;
+}
+
+/// Returns the approximate number of bytes a cached entry occupies: the
+/// inline size of the key and value plus whatever they own on the heap.
+pub fn entry_size<K: HeapSize, V: HeapSize>(k: &K, v: &V) -> usize {
+    mem::size_of::<K>() + mem::size_of::<V>() + k.heap_size() + v.heap_size()
+}
+
+pub(crate) fn entry_weight<K: HeapSize, V: HeapSize>(k: &K, v: &V) -> u32 {
+    let size = entry_size(k, v);
+    if size > u32::MAX as usize {
+        u32::MAX
+    } else {
+        size as u32
+    }
+}
+
+macro_rules! impl_inline {
+    ($($t:ty),*) => {
+        $(
+            impl HeapSize for $t {
+                #[inline]
+                fn heap_size(&self) -> usize {
+                    0
+                }
+            }
+        )*
+    };
+}
+
+impl_inline!(
+    (),
+    bool,
+    char,
+    u8,
+    u16,
+    u32,
+    u64,
+    u128,
+    usize,
+    i8,
+    i16,
+    i32,
+    i64,
+    i128,
+    isize,
+    f32,
+    f64
+);
+
+impl HeapSize for String {
+    fn heap_size(&self) -> usize {
+        self.capacity()
+    }
+}
+
+impl<T: HeapSize> HeapSize for Box<T> {
+    fn heap_size(&self) -> usize {
+        mem::size_of::<T>() + (**self).heap_size()
+    }
+}
+
+impl HeapSize for Box<str> {
+    fn heap_size(&self) -> usize {
+        self.len()
+    }
+}
+
+impl<T: HeapSize> HeapSize for Box<[T]> {
+    fn heap_size(&self) -> usize {
+        mem::size_of::<T>() * self.len() + self.iter().map(HeapSize::heap_size).sum::<usize>()
+    }
+}
+
+impl<T: HeapSize> HeapSize for Option<T> {
+    fn heap_size(&self) -> usize {
+        self.as_ref().map_or(0, HeapSize::heap_size)
+    }
+}
+
+impl<T: HeapSize> HeapSize for Vec<T> {
+    fn heap_size(&self) -> usize {
+        mem::size_of::<T>() * self.capacity() + self.iter().map(HeapSize::heap_size).sum::<usize>()
+    }
+}
+
+impl<T: HeapSize> HeapSize for VecDeque<T> {
+    fn heap_size(&self) -> usize {
+        mem::size_of::<T>() * self.capacity() + self.iter().map(HeapSize::heap_size).sum::<usize>()
+    }
+}
+
+impl<K: HeapSize, V: HeapSize, S> HeapSize for HashMap<K, V, S> {
+    fn heap_size(&self) -> usize {
+        (mem::size_of::<K>() + mem::size_of::<V>()) * self.capacity()
+            + self
+                .iter()
+                .map(|(k, v)| k.heap_size() + v.heap_size())
+                .sum::<usize>()
+    }
+}
+
+impl<K: HeapSize, V: HeapSize> HeapSize for BTreeMap<K, V> {
+    fn heap_size(&self) -> usize {
+        self.iter()
+            .map(|(k, v)| mem::size_of::<K>() + mem::size_of::<V>() + k.heap_size() + v.heap_size())
+            .sum()
+    }
+}
+
+impl<T: HeapSize, const N: usize> HeapSize for [T; N] {
+    fn heap_size(&self) -> usize {
+        self.iter().map(HeapSize::heap_size).sum()
+    }
+}
+
+macro_rules! impl_tuple {
+    ($($name:ident),+) => {
+        impl<$($name: HeapSize),+> HeapSize for ($($name,)+) {
+            #[allow(non_snake_case)]
+            fn heap_size(&self) -> usize {
+                let ($($name,)+) = self;
+                0 $(+ $name.heap_size())+
+            }
+        }
+    };
+}
+
+impl_tuple!(A);
+impl_tuple!(A, B);
+impl_tuple!(A, B, C);
+impl_tuple!(A, B, C, D);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_heap_size() {
+        assert_eq!(1u64.heap_size(), 0);
+
+        let s = String::with_capacity(10);
+        assert_eq!(s.heap_size(), 10);
+
+        let v: Vec<String> = vec![String::with_capacity(4), String::with_capacity(6)];
+        assert_eq!(v.heap_size(), 2 * mem::size_of::<String>() + 10);
+
+        let t = (1u8, String::with_capacity(3), Some(Box::new(7u32)));
+        assert_eq!(t.heap_size(), 3 + 4);
+
+        assert_eq!(
+            entry_size(&1u64, &String::with_capacity(8)),
+            8 + mem::size_of::<String>() + 8
+        );
+    }
+
+    #[cfg(feature = "derive")]
+    #[test]
+    fn test_derive() {
+        #[derive(HeapSize)]
+        struct Page {
+            id: u64,
+            body: String,
+            tags: Vec<u8>,
+        }
+
+        #[derive(HeapSize)]
+        enum Blob<T> {
+            Empty,
+            Inline(u64),
+            Heap { data: Vec<T> },
+        }
+
+        let page = Page {
+            id: 1,
+            body: String::with_capacity(16),
+            tags: Vec::with_capacity(4),
+        };
+        assert_eq!(page.heap_size(), 20);
+        assert_eq!(page.id, 1);
+
+        assert_eq!(Blob::<u16>::Empty.heap_size(), 0);
+        assert_eq!(Blob::<u16>::Inline(3).heap_size(), 0);
+        let blob = Blob::Heap {
+            data: Vec::<u16>::with_capacity(8),
+        };
+        assert_eq!(blob.heap_size(), 16);
+    }
+}
@@ -0,0 +1,135 @@
+//! A bounded interner.
+//!
+//! `Interner` hands out one shared `Arc` per distinct value, so equal values
+//! share their allocation and compare by pointer. Past its bound it forgets
+//! the least recently interned value; handles to it stay valid, but
+//! interning an equal value afterwards allocates a new one.
+//!
+//! ```
+//! use std::sync::Arc;
+//! use cache::interner::Interner;
+//!
+//! let mut names: Interner<str> = Interner::new(1024);
+//! let a = names.intern("alice");
+//! let b = names.intern(&String::from("alice"));
+//! assert!(Arc::ptr_eq(&a, &b));
+//! ```
+
+use std::hash::{BuildHasher, Hash};
+use std::sync::Arc;
+
+use super::hash::DefaultState;
+use super::map::LinkedHashMap;
+
+pub struct Interner<T: ?Sized, S = DefaultState> {
+    max_size: usize,
+    map: LinkedHashMap<Arc<T>, (), S>,
+}
+
+impl<T: ?Sized + Hash + Eq> Interner<T, DefaultState> {
+    pub fn new(max_size: usize) -> Interner<T, DefaultState> {
+        Interner::with_hasher(max_size, Default::default())
+    }
+}
+
+impl<T, S> Interner<T, S>
+where
+    T: ?Sized + Hash + Eq,
+    S: BuildHasher,
+{
+    pub fn with_hasher(max_size: usize, hash_builder: S) -> Interner<T, S> {
+        let max_size = if max_size < 1 { 1 } else { max_size };
+        Interner {
+            max_size,
+            map: LinkedHashMap::with_capacity_and_hasher(max_size, hash_builder),
+        }
+    }
+
+    /// Returns the shared copy of `value`, allocating it the first time.
+    pub fn intern(&mut self, value: &T) -> Arc<T>
+    where
+        for<'a> Arc<T>: From<&'a T>,
+    {
+        if let Some(interned) = self.touch(value) {
+            return interned;
+        }
+        self.insert(Arc::from(value))
+    }
+
+    /// Like `intern`, but takes the value by ownership so that a first
+    /// occurrence is moved instead of copied.
+    pub fn intern_owned(&mut self, value: T) -> Arc<T>
+    where
+        T: Sized,
+    {
+        if let Some(interned) = self.touch(&value) {
+            return interned;
+        }
+        self.insert(Arc::new(value))
+    }
+
+    fn touch(&mut self, value: &T) -> Option<Arc<T>> {
+        if !self.map.move_to_front(value) {
+            return None;
+        }
+        self.map.front().map(|(interned, _)| interned.clone())
+    }
+
+    fn insert(&mut self, interned: Arc<T>) -> Arc<T> {
+        self.map.push_front(interned.clone(), ());
+        while self.map.len() > self.max_size {
+            self.map.pop_back();
+        }
+        interned
+    }
+
+    /// Returns the shared copy of `value` if it is interned, without
+    /// counting it as a use.
+    pub fn get(&self, value: &T) -> Option<Arc<T>> {
+        self.map
+            .get_key_value(value)
+            .map(|(interned, _)| interned.clone())
+    }
+
+    pub fn contains(&self, value: &T) -> bool {
+        self.map.contains_key(value)
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    pub fn max_size(&self) -> usize {
+        self.max_size
+    }
+
+    pub fn clear(&mut self) {
+        self.map.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bounded() {
+        let mut interner = Interner::new(2);
+        let a = interner.intern_owned("a".to_string());
+        interner.intern_owned("b".to_string());
+        assert!(Arc::ptr_eq(&a, &interner.intern_owned("a".to_string())));
+        interner.intern_owned("c".to_string());
+        assert!(!interner.contains(&"b".to_string()));
+        assert_eq!(interner.len(), 2);
+
+        let mut bytes: Interner<[u8]> = Interner::new(1);
+        let x = bytes.intern(b"x");
+        bytes.intern(b"y");
+        assert!(!Arc::ptr_eq(&x, &bytes.intern(b"x")));
+        assert_eq!(bytes.get(b"x").as_deref(), Some(&b"x"[..]));
+    }
+}
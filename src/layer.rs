@@ -0,0 +1,970 @@
+//! Wrappers that add a feature on top of any cache.
+//!
+//! Every wrapper implements `Cache` by delegating to the cache it wraps, so
+//! they compose: a `Metered<Loading<lru::Cache<K, V>, F, K>>` counts the hits
+//! and misses of a read-through LRU cache.
+//!
+//! ```
+//! use cache::layer::{Cache, Loading, Metered};
+//! use cache::lru;
+//!
+//! let inner = lru::Cache::new(16);
+//! let mut cache = Metered::new(Loading::new(inner, |k: &u32| Some(k * 2)));
+//! assert_eq!(cache.get(&4), Some(&8));
+//! assert_eq!(cache.stat().hit_count, 1);
+//! ```
+
+use std::borrow::Borrow;
+#[cfg(feature = "log")]
+use std::cell::RefCell;
+use std::collections::HashMap;
+#[cfg(feature = "log")]
+use std::fmt::Debug;
+use std::hash::{BuildHasher, Hash};
+#[cfg(feature = "log")]
+use std::rc::Rc;
+use std::thread;
+use std::time::Duration;
+
+use super::any::AnyCache;
+use super::clock::{Clock, DefaultClock};
+use super::core::{self, ReplacementPolicy};
+use super::error::{self, ConfigError};
+use super::hash::DefaultState;
+use super::stats::{Info, MemoryUsage, Stats};
+
+/// The operations shared by every cache and wrapper.
+pub trait Cache<K, V> {
+    fn get(&mut self, k: &K) -> Option<&V>;
+
+    /// Looks up `k` without counting as an access.
+    fn peek(&self, k: &K) -> Option<&V>;
+
+    fn contains_key(&self, k: &K) -> bool;
+
+    /// Stores `v` under `k`, returning the value it replaced.
+    fn add(&mut self, k: K, v: V) -> Option<V>;
+
+    fn remove(&mut self, k: &K) -> Option<V>;
+
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn purge(&mut self);
+
+    /// Removes the entry of `k` as expired, through the eviction path with
+    /// the `Expired` reason, and returns whether `k` was cached. Defaults to
+    /// a plain `remove`.
+    fn expire(&mut self, k: &K) -> bool {
+        self.remove(k).is_some()
+    }
+
+    /// Removes the entries for which `expired` returns true through the
+    /// eviction path, with the `Expired` reason, and returns their number.
+    /// Caches that cannot go over their entries remove none, the default.
+    fn expire_where(&mut self, _expired: &mut dyn FnMut(&K, &V) -> bool) -> usize {
+        0
+    }
+
+    /// Does the maintenance the cache defers to later operations, such as
+    /// shrinking an idle cache or sweeping out expired entries. Wrappers
+    /// pass it on to the cache they wrap.
+    fn run_pending_tasks(&mut self) {}
+}
+
+impl<K: Hash + Eq, V, S: BuildHasher + Clone> Cache<K, V> for AnyCache<K, V, S> {
+    fn get(&mut self, k: &K) -> Option<&V> {
+        AnyCache::get(self, k)
+    }
+
+    fn peek(&self, k: &K) -> Option<&V> {
+        AnyCache::peek(self, k)
+    }
+
+    fn contains_key(&self, k: &K) -> bool {
+        AnyCache::contains_key(self, k)
+    }
+
+    fn add(&mut self, k: K, v: V) -> Option<V> {
+        AnyCache::add(self, k, v)
+    }
+
+    fn remove(&mut self, k: &K) -> Option<V> {
+        AnyCache::remove(self, k)
+    }
+
+    fn len(&self) -> usize {
+        AnyCache::len(self)
+    }
+
+    fn purge(&mut self) {
+        AnyCache::purge(self)
+    }
+
+    fn expire(&mut self, k: &K) -> bool {
+        AnyCache::expire(self, k)
+    }
+
+    fn expire_where(&mut self, expired: &mut dyn FnMut(&K, &V) -> bool) -> usize {
+        AnyCache::expire_where(self, expired)
+    }
+
+    fn run_pending_tasks(&mut self) {
+        AnyCache::run_pending_tasks(self)
+    }
+}
+
+impl<K, V, P, S> Cache<K, V> for core::Cache<K, V, P, S>
+where
+    K: Hash + Eq,
+    S: BuildHasher,
+    P: ReplacementPolicy<K, V, S>,
+{
+    fn get(&mut self, k: &K) -> Option<&V> {
+        core::Cache::get(self, k)
+    }
+
+    fn peek(&self, k: &K) -> Option<&V> {
+        core::Cache::peek(self, k)
+    }
+
+    fn contains_key(&self, k: &K) -> bool {
+        core::Cache::contains_key(self, k)
+    }
+
+    fn add(&mut self, k: K, v: V) -> Option<V> {
+        core::Cache::add(self, k, v)
+    }
+
+    fn remove(&mut self, k: &K) -> Option<V> {
+        core::Cache::remove(self, k)
+    }
+
+    fn len(&self) -> usize {
+        core::Cache::len(self)
+    }
+
+    fn purge(&mut self) {
+        core::Cache::purge(self)
+    }
+
+    fn expire(&mut self, k: &K) -> bool {
+        core::Cache::expire(self, k)
+    }
+
+    fn expire_where(&mut self, expired: &mut dyn FnMut(&K, &V) -> bool) -> usize {
+        core::Cache::expire_where(self, expired)
+    }
+
+    fn run_pending_tasks(&mut self) {
+        core::Cache::run_pending_tasks(self)
+    }
+}
+
+/// Counts the lookups and insertions that go through it, independently of
+/// the statistics of the wrapped cache.
+pub struct Metered<C> {
+    inner: C,
+    stats: Stats,
+}
+
+impl<C> Metered<C> {
+    pub fn new(inner: C) -> Metered<C> {
+        Metered {
+            inner,
+            stats: Stats::default(),
+        }
+    }
+
+    pub fn inner(&self) -> &C {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> C {
+        self.inner
+    }
+
+    /// Returns the counters; the memory usage is left empty.
+    pub fn stat<K, V>(&self) -> Info
+    where
+        C: Cache<K, V>,
+    {
+        let len = self.inner.len();
+        Info::new(
+            &self.stats.counters,
+            len,
+            MemoryUsage::default(),
+            &self.stats,
+        )
+    }
+
+    pub fn reset_stats(&mut self) {
+        self.stats.reset();
+    }
+}
+
+impl<K, V, C: Cache<K, V>> Cache<K, V> for Metered<C> {
+    fn get(&mut self, k: &K) -> Option<&V> {
+        let v = self.inner.get(k);
+        if v.is_some() {
+            self.stats.hit();
+        } else {
+            self.stats.miss();
+        }
+        v
+    }
+
+    fn peek(&self, k: &K) -> Option<&V> {
+        self.inner.peek(k)
+    }
+
+    fn contains_key(&self, k: &K) -> bool {
+        self.inner.contains_key(k)
+    }
+
+    fn add(&mut self, k: K, v: V) -> Option<V> {
+        let old_v = self.inner.add(k, v);
+        if old_v.is_some() {
+            self.stats.replace();
+        } else {
+            self.stats.insert();
+        }
+        old_v
+    }
+
+    fn remove(&mut self, k: &K) -> Option<V> {
+        self.inner.remove(k)
+    }
+
+    fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    fn purge(&mut self) {
+        self.inner.purge()
+    }
+
+    fn expire(&mut self, k: &K) -> bool {
+        self.inner.expire(k)
+    }
+
+    fn expire_where(&mut self, expired: &mut dyn FnMut(&K, &V) -> bool) -> usize {
+        self.inner.expire_where(expired)
+    }
+
+    fn run_pending_tasks(&mut self) {
+        self.inner.run_pending_tasks()
+    }
+}
+
+/// Expires entries a fixed time after they were added.
+///
+/// The wrapped cache stores each value with its deadline. Expired entries
+/// are dropped when they are next looked up, or by `run_pending_tasks` if
+/// the wrapped cache implements `expire_where`, and count towards `len`
+/// until then. Either way they leave through its eviction path, with the
+/// `Expired` reason.
+pub struct Ttl<C> {
+    inner: C,
+    ttl: Duration,
+    clock: Box<dyn Clock>,
+}
+
+impl<C> Ttl<C> {
+    pub fn new(inner: C, ttl: Duration) -> Ttl<C> {
+        Ttl::with_clock(inner, ttl, DefaultClock::default())
+    }
+
+    pub fn with_clock<T>(inner: C, ttl: Duration, clock: T) -> Ttl<C>
+    where
+        T: Clock + 'static,
+    {
+        Ttl {
+            inner,
+            ttl,
+            clock: Box::new(clock),
+        }
+    }
+
+    pub fn inner(&self) -> &C {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> C {
+        self.inner
+    }
+}
+
+impl<K, V, C: Cache<K, (V, Duration)>> Cache<K, V> for Ttl<C> {
+    fn get(&mut self, k: &K) -> Option<&V> {
+        let now = self.clock.now();
+        if let Some(&(_, deadline)) = self.inner.peek(k) {
+            if deadline <= now {
+                self.inner.expire(k);
+            }
+        }
+        self.inner.get(k).map(|(v, _)| v)
+    }
+
+    fn peek(&self, k: &K) -> Option<&V> {
+        let now = self.clock.now();
+        match self.inner.peek(k) {
+            Some((v, deadline)) if *deadline > now => Some(v),
+            _ => None,
+        }
+    }
+
+    fn contains_key(&self, k: &K) -> bool {
+        self.peek(k).is_some()
+    }
+
+    fn add(&mut self, k: K, v: V) -> Option<V> {
+        let deadline = self.clock.now() + self.ttl;
+        self.inner.add(k, (v, deadline)).map(|(v, _)| v)
+    }
+
+    fn remove(&mut self, k: &K) -> Option<V> {
+        self.inner.remove(k).map(|(v, _)| v)
+    }
+
+    fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    fn purge(&mut self) {
+        self.inner.purge()
+    }
+
+    fn expire(&mut self, k: &K) -> bool {
+        self.inner.expire(k)
+    }
+
+    fn expire_where(&mut self, expired: &mut dyn FnMut(&K, &V) -> bool) -> usize {
+        self.inner.expire_where(&mut |k, (v, _)| expired(k, v))
+    }
+
+    /// Sweeps out the entries past their deadline, then passes the call on.
+    fn run_pending_tasks(&mut self) {
+        let now = self.clock.now();
+        self.inner
+            .expire_where(&mut |_, &(_, deadline)| deadline <= now);
+        self.inner.run_pending_tasks()
+    }
+}
+
+/// A read-through cache: a missing key is loaded with `load` and stored
+/// before `get` returns. Keys for which `load` returns `None` stay missing.
+pub struct Loading<C, F, K> {
+    inner: C,
+    load: F,
+    retry: Option<RetryPolicy<K>>,
+}
+
+impl<C, F, K> Loading<C, F, K> {
+    pub fn new(inner: C, load: F) -> Loading<C, F, K> {
+        Loading {
+            inner,
+            load,
+            retry: None,
+        }
+    }
+
+    /// Retries the loads that fail according to `policy`.
+    pub fn set_retry_policy(&mut self, policy: RetryPolicy<K>) {
+        self.retry = Some(policy);
+    }
+
+    pub fn retry_policy(&self) -> Option<&RetryPolicy<K>> {
+        self.retry.as_ref()
+    }
+
+    pub fn inner(&self) -> &C {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> C {
+        self.inner
+    }
+}
+
+impl<K, V, C, F> Cache<K, V> for Loading<C, F, K>
+where
+    K: Clone + Eq + Hash,
+    C: Cache<K, V>,
+    F: FnMut(&K) -> Option<V>,
+{
+    fn get(&mut self, k: &K) -> Option<&V> {
+        if self.inner.get(k).is_none() {
+            let v = match &mut self.retry {
+                Some(retry) => retry.load(k, &mut self.load)?,
+                None => (self.load)(k)?,
+            };
+            self.inner.add(k.clone(), v);
+        }
+        self.inner.peek(k)
+    }
+
+    fn peek(&self, k: &K) -> Option<&V> {
+        self.inner.peek(k)
+    }
+
+    fn contains_key(&self, k: &K) -> bool {
+        self.inner.contains_key(k)
+    }
+
+    fn add(&mut self, k: K, v: V) -> Option<V> {
+        self.inner.add(k, v)
+    }
+
+    fn remove(&mut self, k: &K) -> Option<V> {
+        self.inner.remove(k)
+    }
+
+    fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    fn purge(&mut self) {
+        self.inner.purge()
+    }
+
+    fn expire(&mut self, k: &K) -> bool {
+        self.inner.expire(k)
+    }
+
+    fn expire_where(&mut self, expired: &mut dyn FnMut(&K, &V) -> bool) -> usize {
+        self.inner.expire_where(expired)
+    }
+
+    fn run_pending_tasks(&mut self) {
+        self.inner.run_pending_tasks()
+    }
+}
+
+/// How `Loading` retries a load that fails, that is, returns `None`. Keys
+/// that are merely missing are retried like any other.
+///
+/// A failed attempt is retried after a backoff that grows exponentially, up
+/// to a limit; the thread sleeps in between, unless given a sleeper. Once
+/// every attempt at a key has failed, the key can be put on a cooldown,
+/// during which it is reported missing without calling the loader, so that
+/// a failing backend is not asked again on every miss.
+pub struct RetryPolicy<K> {
+    attempts: usize,
+    initial_backoff: Duration,
+    factor: f64,
+    max_backoff: Duration,
+    cooldown: Duration,
+    clock: Box<dyn Clock>,
+    sleep: Sleeper,
+    // the end of the cooldown of each failing key
+    failed: HashMap<K, Duration, DefaultState>,
+    // the number of failing keys at which the expired cooldowns are dropped
+    prune_at: usize,
+}
+
+type Sleeper = Box<dyn FnMut(Duration)>;
+
+impl<K> RetryPolicy<K> {
+    /// Makes up to `attempts` attempts at each load, at least one, without
+    /// backoff or cooldown.
+    pub fn new(attempts: usize) -> RetryPolicy<K> {
+        RetryPolicy::with_clock(attempts, DefaultClock::default())
+    }
+
+    pub fn with_clock<T>(attempts: usize, clock: T) -> RetryPolicy<K>
+    where
+        T: Clock + 'static,
+    {
+        RetryPolicy {
+            attempts: attempts.max(1),
+            initial_backoff: Duration::ZERO,
+            factor: 1.0,
+            max_backoff: Duration::ZERO,
+            cooldown: Duration::ZERO,
+            clock: Box::new(clock),
+            sleep: Box::new(thread::sleep),
+            failed: HashMap::default(),
+            prune_at: 16,
+        }
+    }
+
+    /// Waits `initial` before the first retry, and `factor` times longer
+    /// before each next one, up to `max`. The factor must be at least one.
+    pub fn set_backoff(
+        &mut self,
+        initial: Duration,
+        factor: f64,
+        max: Duration,
+    ) -> Result<(), ConfigError> {
+        error::check_backoff_factor(factor)?;
+        self.initial_backoff = initial;
+        self.factor = factor;
+        self.max_backoff = max;
+        Ok(())
+    }
+
+    /// Waits out the backoffs with `sleep` instead of putting the thread to
+    /// sleep, for instance to yield to a scheduler or to advance a
+    /// `ManualClock`.
+    pub fn set_sleeper<T>(&mut self, sleep: T)
+    where
+        T: FnMut(Duration) + 'static,
+    {
+        self.sleep = Box::new(sleep);
+    }
+
+    /// Reports a key missing without loading it for `cooldown` after every
+    /// attempt at loading it failed.
+    pub fn set_cooldown(&mut self, cooldown: Duration) {
+        self.cooldown = cooldown;
+    }
+
+    /// Returns the wait before retry number `retry`, counted from 1.
+    pub fn backoff(&self, retry: usize) -> Duration {
+        if retry == 0 {
+            return Duration::ZERO;
+        }
+        let secs = self.initial_backoff.as_secs_f64() * self.factor.powi(retry as i32 - 1);
+        Duration::from_secs_f64(secs.min(self.max_backoff.as_secs_f64()))
+    }
+}
+
+impl<K: Hash + Eq> RetryPolicy<K> {
+    /// Returns whether `k` is on a cooldown after a failure.
+    pub fn is_cooling_down<Q>(&self, k: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        let now = self.clock.now();
+        self.failed.get(k).is_some_and(|&until| now < until)
+    }
+
+    fn load<V, F>(&mut self, k: &K, load: &mut F) -> Option<V>
+    where
+        K: Clone,
+        F: FnMut(&K) -> Option<V>,
+    {
+        if let Some(&until) = self.failed.get(k) {
+            if self.clock.now() < until {
+                return None;
+            }
+            self.failed.remove(k);
+        }
+        for attempt in 0..self.attempts {
+            let backoff = self.backoff(attempt);
+            if backoff > Duration::ZERO {
+                (self.sleep)(backoff);
+            }
+            if let Some(v) = load(k) {
+                return Some(v);
+            }
+        }
+        if self.cooldown > Duration::ZERO {
+            let now = self.clock.now();
+            if self.failed.len() >= self.prune_at {
+                self.failed.retain(|_, &mut until| now < until);
+                self.prune_at = (2 * self.failed.len()).max(16);
+            }
+            self.failed.insert(k.clone(), now + self.cooldown);
+        }
+        None
+    }
+}
+
+#[cfg(feature = "log")]
+type Sink = Box<dyn FnMut(&str)>;
+
+/// How much a `Logged` cache describes.
+#[cfg(feature = "log")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Verbosity {
+    /// Adds, removes, purges, expirations and evictions.
+    Writes,
+    /// Also the lookups that count as accesses, the default.
+    Accesses,
+    /// Also `peek` and `contains_key`.
+    All,
+}
+
+/// Describes every operation, with its key and outcome, to a sink such as
+/// `|line| eprintln!("{}", line)`. Available with the `log` feature, which
+/// also provides a sink writing to the `log` facade.
+///
+/// The victims the wrapped cache chooses are described too once the logger
+/// returned by `victim_logger` is its eviction callback. The logger passes
+/// each victim on to the callback it was given:
+///
+/// ```
+/// use cache::layer::{Cache, Logged};
+/// use cache::lru;
+///
+/// let mut cache = Logged::new(lru::Cache::new(1), |line: &str| println!("{}", line));
+/// let victims = cache.victim_logger(|_, v: &str| println!("dropping {}", v));
+/// cache.inner_mut().set_eviction_callback(victims);
+/// cache.add(1, "one");
+/// // prints `evict 1`, `dropping one` then `add 2: insert`
+/// cache.add(2, "two");
+/// ```
+#[cfg(feature = "log")]
+pub struct Logged<C> {
+    inner: C,
+    // shared with the victim loggers, and borrowed by the lookups through
+    // `&self`
+    sink: Rc<RefCell<Sink>>,
+    verbosity: Verbosity,
+}
+
+#[cfg(feature = "log")]
+impl<C> Logged<C> {
+    pub fn new<L>(inner: C, sink: L) -> Logged<C>
+    where
+        L: FnMut(&str) + 'static,
+    {
+        Logged {
+            inner,
+            sink: Rc::new(RefCell::new(Box::new(sink))),
+            verbosity: Verbosity::Accesses,
+        }
+    }
+
+    /// Describes the operations to the `log` facade, at the debug level
+    /// and with the `cache` target.
+    pub fn with_log(inner: C) -> Logged<C> {
+        Logged::new(inner, |line: &str| log::debug!(target: "cache", "{}", line))
+    }
+
+    pub fn set_verbosity(&mut self, verbosity: Verbosity) {
+        self.verbosity = verbosity;
+    }
+
+    pub fn verbosity(&self) -> Verbosity {
+        self.verbosity
+    }
+
+    /// Returns an eviction callback for the wrapped cache that describes
+    /// each victim as it is evicted, at any verbosity, then passes it on to
+    /// `callback`, so that the logger does not replace it.
+    pub fn victim_logger<K, V, F>(&self, mut callback: F) -> impl FnMut(K, V) + 'static
+    where
+        K: Debug,
+        F: FnMut(K, V) + 'static,
+    {
+        let sink = self.sink.clone();
+        move |k, v| {
+            (sink.borrow_mut())(&format!("evict {:?}", k));
+            callback(k, v);
+        }
+    }
+
+    pub fn inner(&self) -> &C {
+        &self.inner
+    }
+
+    pub fn inner_mut(&mut self) -> &mut C {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> C {
+        self.inner
+    }
+}
+
+#[cfg(feature = "log")]
+fn outcome<T>(found: &Option<T>, some: &'static str, none: &'static str) -> &'static str {
+    if found.is_some() {
+        some
+    } else {
+        none
+    }
+}
+
+#[cfg(feature = "log")]
+impl<K: Debug, V, C: Cache<K, V>> Cache<K, V> for Logged<C> {
+    fn get(&mut self, k: &K) -> Option<&V> {
+        let v = self.inner.get(k);
+        if self.verbosity >= Verbosity::Accesses {
+            (self.sink.borrow_mut())(&format!("get {:?}: {}", k, outcome(&v, "hit", "miss")));
+        }
+        v
+    }
+
+    fn peek(&self, k: &K) -> Option<&V> {
+        let v = self.inner.peek(k);
+        if self.verbosity >= Verbosity::All {
+            (self.sink.borrow_mut())(&format!("peek {:?}: {}", k, outcome(&v, "hit", "miss")));
+        }
+        v
+    }
+
+    fn contains_key(&self, k: &K) -> bool {
+        let found = self.inner.contains_key(k);
+        if self.verbosity >= Verbosity::All {
+            (self.sink.borrow_mut())(&format!("contains {:?}: {}", k, found));
+        }
+        found
+    }
+
+    fn add(&mut self, k: K, v: V) -> Option<V> {
+        let line = format!("add {:?}", k);
+        let old_v = self.inner.add(k, v);
+        (self.sink.borrow_mut())(&format!(
+            "{}: {}",
+            line,
+            outcome(&old_v, "replace", "insert")
+        ));
+        old_v
+    }
+
+    fn remove(&mut self, k: &K) -> Option<V> {
+        let v = self.inner.remove(k);
+        (self.sink.borrow_mut())(&format!(
+            "remove {:?}: {}",
+            k,
+            outcome(&v, "removed", "absent")
+        ));
+        v
+    }
+
+    fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    fn purge(&mut self) {
+        self.inner.purge();
+        (self.sink.borrow_mut())("purge");
+    }
+
+    fn expire(&mut self, k: &K) -> bool {
+        let expired = self.inner.expire(k);
+        let outcome = if expired { "expired" } else { "absent" };
+        (self.sink.borrow_mut())(&format!("expire {:?}: {}", k, outcome));
+        expired
+    }
+
+    fn expire_where(&mut self, expired: &mut dyn FnMut(&K, &V) -> bool) -> usize {
+        let mut lines = Vec::new();
+        let count = self.inner.expire_where(&mut |k, v| {
+            let expire = expired(k, v);
+            if expire {
+                lines.push(format!("expire {:?}: expired", k));
+            }
+            expire
+        });
+        for line in lines {
+            (self.sink.borrow_mut())(&line);
+        }
+        count
+    }
+
+    fn run_pending_tasks(&mut self) {
+        self.inner.run_pending_tasks()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::{fifo, lru, slru};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_run_pending_tasks() {
+        let clock = ManualClock::new();
+        let mut inner = slru::Cache::new(1000);
+        inner.set_idle_shrink(crate::adaptive::IdleShrink::with_clock(
+            0.5,
+            Duration::from_secs(1),
+            clock.clone(),
+        ));
+        let mut cache = Metered::new(inner);
+        for k in 0..1000 {
+            Cache::add(&mut cache, k, k);
+        }
+        Cache::purge(&mut cache);
+        let peak = cache.inner().stat().memory.total();
+        // the first tick notices the cache is idle, the next shrinks it
+        Cache::run_pending_tasks(&mut cache);
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(cache.inner().stat().memory.total(), peak);
+        Cache::run_pending_tasks(&mut cache);
+        assert!(cache.inner().stat().memory.total() < peak);
+    }
+
+    #[test]
+    fn test_metered() {
+        let mut cache = Metered::new(lru::Cache::new(1));
+        Cache::add(&mut cache, 1, 1);
+        Cache::add(&mut cache, 1, 2);
+        Cache::add(&mut cache, 2, 2);
+        assert_eq!(Cache::get(&mut cache, &1), None);
+        assert_eq!(Cache::get(&mut cache, &2), Some(&2));
+        let info = cache.stat();
+        assert_eq!((info.hit_count, info.miss_count), (1, 1));
+        assert_eq!((info.insert_count, info.replace_count), (2, 1));
+        assert_eq!(info.len, 1);
+    }
+
+    #[test]
+    fn test_ttl() {
+        let clock = ManualClock::new();
+        let mut cache = Ttl::with_clock(lru::Cache::new(2), Duration::from_secs(10), clock.clone());
+        cache.add(1, "a");
+        clock.advance(Duration::from_secs(5));
+        cache.add(2, "b");
+        assert_eq!(cache.get(&1), Some(&"a"));
+
+        clock.advance(Duration::from_secs(5));
+        assert!(!cache.contains_key(&1));
+        assert_eq!(cache.peek(&2), Some(&"b"));
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(&1), None);
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.inner().stat().expiration_count, 1);
+
+        // the sweep drops the expired entries that were not looked up
+        cache.add(3, "c");
+        clock.advance(Duration::from_secs(5));
+        cache.run_pending_tasks();
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.peek(&3), Some(&"c"));
+        assert_eq!(cache.inner().stat().expiration_count, 2);
+    }
+
+    #[test]
+    fn test_loading() {
+        let loads = Rc::new(RefCell::new(0));
+        let counter = loads.clone();
+        let mut cache = Loading::new(fifo::Cache::new(2), move |&k: &u32| {
+            *counter.borrow_mut() += 1;
+            if k < 10 {
+                Some(k + 1)
+            } else {
+                None
+            }
+        });
+        assert_eq!(cache.get(&1), Some(&2));
+        assert_eq!(cache.get(&1), Some(&2));
+        assert_eq!(cache.get(&10), None);
+        assert_eq!(*loads.as_ref().borrow(), 2);
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn test_retry_policy() {
+        let clock = ManualClock::new();
+        let loads = Rc::new(RefCell::new(0));
+        let counter = loads.clone();
+        let mut cache = Loading::new(lru::Cache::new(4), move |&k: &u32| {
+            *counter.borrow_mut() += 1;
+            // key 1 loads on the third attempt, key 2 never
+            if k == 1 && *counter.as_ref().borrow() == 3 {
+                Some(10)
+            } else {
+                None
+            }
+        });
+        let mut policy = RetryPolicy::with_clock(3, clock.clone());
+        policy.set_cooldown(Duration::from_secs(5));
+        cache.set_retry_policy(policy);
+
+        assert_eq!(cache.get(&1), Some(&10));
+        assert_eq!(cache.get(&2), None);
+        assert_eq!(*loads.as_ref().borrow(), 6);
+        assert!(cache.retry_policy().unwrap().is_cooling_down(&2));
+        // not loaded again until the cooldown is over
+        assert_eq!(cache.get(&2), None);
+        assert_eq!(*loads.as_ref().borrow(), 6);
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(cache.get(&2), None);
+        assert_eq!(*loads.as_ref().borrow(), 9);
+
+        let mut policy = RetryPolicy::<u32>::new(5);
+        let ms = Duration::from_millis;
+        policy.set_backoff(ms(10), 2.0, ms(50)).unwrap();
+        let backoffs: Vec<_> = (0..5).map(|retry| policy.backoff(retry)).collect();
+        assert_eq!(backoffs, vec![ms(0), ms(10), ms(20), ms(40), ms(50)]);
+        assert_eq!(
+            policy.set_backoff(ms(10), 0.5, ms(50)),
+            Err(ConfigError::BackoffFactorBelowOne { factor: 0.5 })
+        );
+
+        // the backoffs go to the sleeper, here a clock that jumps ahead
+        let mut policy = RetryPolicy::with_clock(3, clock.clone());
+        policy.set_backoff(ms(10), 2.0, ms(50)).unwrap();
+        let sleeper = clock.clone();
+        policy.set_sleeper(move |backoff| sleeper.advance(backoff));
+        let mut cache = Loading::new(lru::Cache::new(4), |_: &u32| None::<u32>);
+        cache.set_retry_policy(policy);
+        let start = clock.now();
+        assert_eq!(cache.get(&1), None);
+        assert_eq!(clock.now() - start, ms(30));
+    }
+
+    #[cfg(feature = "log")]
+    #[test]
+    fn test_logged() {
+        let lines = Rc::new(RefCell::new(Vec::new()));
+        let sink = lines.clone();
+        let inner = AnyCache::new(crate::any::Policy::q2(), 4);
+        let mut cache = Logged::new(inner, move |line: &str| {
+            sink.borrow_mut().push(line.to_owned())
+        });
+        cache.add("x", 1);
+        cache.add("x", 2);
+        cache.get(&"y");
+        cache.remove(&"x");
+        assert_eq!(
+            *lines.as_ref().borrow(),
+            vec![
+                "add \"x\": insert",
+                "add \"x\": replace",
+                "get \"y\": miss",
+                "remove \"x\": removed",
+            ]
+        );
+
+        lines.borrow_mut().clear();
+        let evicted = Rc::new(RefCell::new(Vec::new()));
+        let sink = evicted.clone();
+        let victims = cache.victim_logger(move |k, _| sink.borrow_mut().push(k));
+        cache.inner_mut().set_eviction_callback(victims);
+        cache.set_verbosity(Verbosity::Writes);
+        for k in &["a", "b", "c", "d", "e"] {
+            cache.add(k, 0);
+        }
+        cache.get(&"e");
+        cache.set_verbosity(Verbosity::All);
+        cache.peek(&"e");
+        assert!(cache.contains_key(&"e"));
+        assert_eq!(
+            lines.as_ref().borrow()[4..],
+            [
+                "evict \"a\"",
+                "add \"e\": insert",
+                "peek \"e\": hit",
+                "contains \"e\": true",
+            ]
+        );
+        assert_eq!(*evicted.as_ref().borrow(), vec!["a"]);
+
+        lines.borrow_mut().clear();
+        assert!(cache.expire(&"b"));
+        assert_eq!(cache.expire_where(&mut |&k, _| k == "c"), 1);
+        assert_eq!(
+            *lines.as_ref().borrow(),
+            vec![
+                "evict \"b\"",
+                "expire \"b\": expired",
+                "evict \"c\"",
+                "expire \"c\": expired",
+            ]
+        );
+    }
+}
@@ -1,5 +1,84 @@
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+// lets the derive and attribute macros refer to `::cache` from within this crate
+#[cfg(any(feature = "derive", feature = "macros"))]
+extern crate self as cache;
+
+#[cfg(feature = "std")]
+pub mod adaptive;
+#[cfg(feature = "std")]
+pub mod admission;
+#[cfg(feature = "std")]
+pub mod any;
+#[cfg(feature = "std")]
+pub mod bench_harness;
+#[cfg(feature = "std")]
+pub mod clock;
+#[cfg(feature = "std")]
+pub mod compat;
+#[cfg(any(feature = "lz4", feature = "zstd"))]
+pub mod compressed;
+#[cfg(feature = "std")]
+pub mod core;
+#[cfg(feature = "disk")]
+pub mod disk;
+#[cfg(feature = "std")]
+pub mod error;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(feature = "std")]
 pub mod fifo;
+pub mod fixed;
+#[cfg(feature = "std")]
+pub mod hash;
+#[cfg(feature = "std")]
+pub mod heap_size;
+#[cfg(feature = "std")]
+pub mod interner;
+#[cfg(feature = "std")]
+pub mod layer;
+#[cfg(feature = "std")]
 pub mod lru;
+#[cfg(feature = "std")]
 pub mod map;
+#[cfg(feature = "std")]
+pub mod memo;
+#[cfg(feature = "std")]
+pub mod mrc;
+#[cfg(feature = "std")]
+pub mod namespaced;
+#[cfg(feature = "persistence")]
+pub mod persistence;
+#[cfg(feature = "std")]
+pub mod pool;
+#[cfg(feature = "python")]
+pub mod python;
+#[cfg(feature = "std")]
 pub mod q2;
+#[cfg(feature = "std")]
+pub mod shadow;
+#[cfg(feature = "std")]
+pub mod sketch;
+#[cfg(feature = "std")]
 pub mod slru;
+#[cfg(feature = "std")]
+pub mod stats;
+#[cfg(all(feature = "std", any(test, feature = "testing")))]
+pub mod testing;
+#[cfg(feature = "std")]
+pub mod trace;
+#[cfg(feature = "std")]
+pub mod weak;
+#[cfg(feature = "std")]
+pub mod workload;
+#[cfg(feature = "std")]
+pub mod writeback;
+
+#[cfg(feature = "std")]
+mod bloom;
+#[cfg(feature = "std")]
+mod callback;
+#[cfg(feature = "std")]
+mod version;
+#[cfg(feature = "std")]
+mod weight;
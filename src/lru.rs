@@ -1,145 +1,103 @@
-use std::borrow::Borrow;
-use std::collections::hash_map::RandomState;
 use std::hash::{BuildHasher, Hash};
-use std::ptr;
 
-use super::map::LinkedHashMap;
+use super::core::{self, Lru};
+use super::hash::DefaultState;
+#[cfg(feature = "persistence")]
+use super::persistence::{self, SnapshotError};
+pub use super::stats::Info;
 
-pub struct Cache<K, V, S = RandomState> {
-    max_size: usize,
+/// Evicts the least recently used entry: a `core::Cache` with the `Lru`
+/// policy.
+pub type Cache<K, V, S = DefaultState> = core::Cache<K, V, Lru, S>;
 
-    hit_count: usize,
-    miss_count: usize,
-
-    callback: Option<Box<dyn Fn(K, V)>>,
-
-    l_map: LinkedHashMap<K, V, S>,
-}
-
-impl<K: Hash + Eq, V> Cache<K, V, RandomState> {
-    pub fn new(max_size: usize) -> Cache<K, V, RandomState> {
-        Cache::with_hasher(max_size, Default::default())
-    }
-}
-
-impl<K: Hash + Eq, V, S> Cache<K, V, S>
+impl<K, V, S> Cache<K, V, S>
 where
     K: Hash + Eq,
     S: BuildHasher,
 {
-    pub fn with_hasher(max_size: usize, hash_builder: S) -> Cache<K, V, S> {
-        let max_size = if max_size < 1 { 1 } else { max_size };
-        Cache {
-            max_size,
-            hit_count: 0,
-            miss_count: 0,
-            callback: None,
-            l_map: LinkedHashMap::with_capacity_and_hasher(max_size, hash_builder),
-        }
-    }
-
-    pub fn set_eviction_callback<C>(&mut self, cb: C)
-    where
-        C: Fn(K, V) + 'static,
-    {
-        self.callback = Some(Box::new(cb));
+    /// Moves an entry to the front on only one hit in `every`, sparing the
+    /// relinking on the others. Hot entries of a skewed workload are still
+    /// refreshed often enough to stay cached. The default of 1 refreshes on
+    /// every hit.
+    pub fn set_refresh_interval(&mut self, every: u32) {
+        self.policy_mut().set_refresh_interval(every);
     }
 
-    pub fn contains_key<Q: ?Sized>(&self, k: &Q) -> bool
-    where
-        K: Borrow<Q>,
-        Q: Eq + Hash,
-    {
-        self.l_map.contains_key(k)
+    pub fn refresh_interval(&self) -> u32 {
+        self.policy().refresh_interval()
     }
 
-    pub fn get<Q: ?Sized>(&mut self, k: &Q) -> Option<&V>
-    where
-        K: Borrow<Q>,
-        Q: Eq + Hash,
-    {
-        if self.l_map.contains_key(k) {
-            self.l_map.move_to_front(k);
-            return self.l_map.get(k);
-        }
-        None
+    /// Removes and returns the least recently used entry, without passing
+    /// it to the eviction callback.
+    pub fn remove_lru(&mut self) -> Option<(K, V)> {
+        self.remove_back()
     }
 
-    pub fn peek<Q: ?Sized>(&mut self, k: &Q) -> Option<&V>
+    /// Writes a versioned snapshot of the cache to `writer`. Only what
+    /// serde serializes is saved.
+    #[cfg(feature = "persistence")]
+    pub fn save_to<W>(&self, writer: W) -> Result<(), SnapshotError>
     where
-        K: Borrow<Q>,
-        Q: Eq + Hash,
+        K: serde::Serialize,
+        V: serde::Serialize,
+        W: std::io::Write,
     {
-        self.l_map.get(k)
+        persistence::save(persistence::Kind::Lru, self, writer)
     }
 
-    pub fn remove<Q: ?Sized>(&mut self, k: &Q) -> Option<V>
+    /// Reads a cache written by `save_to`.
+    #[cfg(feature = "persistence")]
+    pub fn load_from<R>(reader: R) -> Result<Cache<K, V, S>, SnapshotError>
     where
-        K: Borrow<Q>,
-        Q: Eq + Hash,
+        K: serde::de::DeserializeOwned,
+        V: serde::de::DeserializeOwned,
+        S: Default,
+        R: std::io::Read,
     {
-        self.l_map.remove(k)
+        persistence::load(persistence::Kind::Lru, reader)
     }
+}
 
-    pub fn remove_entry<Q: ?Sized>(&mut self, k: &Q) -> Option<(K, V)>
-    where
-        K: Borrow<Q>,
-        Q: Eq + Hash,
-    {
-        self.l_map.remove_entry(k)
-    }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::admission::TinyLfu;
+    use crate::clock::ManualClock;
+    use crate::core::PriorityClass;
+    use crate::error::ConfigError;
+    use crate::stats::{
+        AgeTracking, EntryEvent, Event, EvictionReason, HitWindow, LatencySampling, Removal,
+        Segment, StatsRecorder,
+    };
+    use std::cell::RefCell;
+    use std::hash::{BuildHasherDefault, Hasher};
+    use std::num::NonZeroUsize;
+    use std::rc::Rc;
+    use std::sync::Arc;
+    use std::time::Duration;
 
-    pub fn add(&mut self, k: K, v: V) -> Option<V> {
-        if let Some(val) = self.l_map.get_mut(&k) {
-            let old_v = unsafe { ptr::replace(val, v) };
-            self.l_map.move_to_front(&k);
-            return Some(old_v);
-        }
+    /// Hashes every key to 0, for tests of colliding keys.
+    #[derive(Default)]
+    struct Zero;
 
-        self.l_map.push_front(k, v);
-        if self.len() > self.max_size {
-            self.l_map
-                .pop_back()
-                .map(|(k, v)| self.callback.as_ref().map(|cb| cb(k, v)));
-            return None;
+    impl Hasher for Zero {
+        fn finish(&self) -> u64 {
+            0
         }
-        None
-    }
-
-    pub fn len(&self) -> usize {
-        self.l_map.len()
-    }
 
-    pub fn purge(&mut self) {
-        self.l_map.clear()
+        fn write(&mut self, _: &[u8]) {}
     }
 
-    pub fn is_empty(&self) -> bool {
-        self.l_map.is_empty()
-    }
-
-    pub fn shrink_to_fit(&mut self) {
-        self.l_map.shrink_to_fit();
-    }
-
-    pub fn stat(&self) -> Info {
-        Info {
-            hit_count: self.hit_count,
-            miss_count: self.miss_count,
-        }
+    #[test]
+    fn test_arc_values() {
+        let mut cache = Cache::new(1);
+        cache.add_arc(1, "a".to_string());
+        let v = cache.get_arc(&1).unwrap();
+        cache.add_arc(2, "b".to_string());
+        assert!(!cache.contains_key(&1));
+        assert_eq!(*v, "a");
+        assert_eq!(cache.peek_arc(&2).as_deref().map(String::as_str), Some("b"));
     }
-}
-
-pub struct Info {
-    pub hit_count: usize,
-    pub miss_count: usize,
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::cell::RefCell;
-    use std::rc::Rc;
 
     #[test]
     fn test_cache() {
@@ -169,10 +127,10 @@ mod tests {
             assert!(cache.get(&i).is_none());
         }
 
-        let (last, _) = cache.l_map.back().unwrap();
+        let (last, _) = cache.iter().next_back().unwrap();
         let last = *last;
         cache.get(&last);
-        let (last_after_get, _) = cache.l_map.back().unwrap();
+        let (last_after_get, _) = cache.iter().next_back().unwrap();
         assert!(*last_after_get != last);
 
         cache.purge();
@@ -180,6 +138,16 @@ mod tests {
         assert!(cache.get(&200).is_none());
     }
 
+    #[test]
+    fn test_max_size() {
+        let cache: Cache<usize, usize> = Cache::new(0);
+        assert_eq!(cache.max_size(), 1);
+
+        let cache: Cache<usize, usize> = Cache::new_nonzero(NonZeroUsize::new(16).unwrap());
+        assert_eq!(cache.max_size(), 16);
+        assert!(cache.capacity() >= 16);
+    }
+
     #[test]
     fn test_contains() {
         let mut cache = Cache::new(2);
@@ -201,4 +169,874 @@ mod tests {
         cache.add(3, 3);
         assert!(!cache.contains_key(&1));
     }
+
+    #[test]
+    fn test_into_iter() {
+        let mut cache = Cache::new(3);
+        cache.add(1, 1);
+        cache.add(2, 2);
+        cache.add(3, 3);
+        cache.get(&1);
+
+        let keys: Vec<i32> = (&cache).into_iter().map(|(k, _)| *k).collect();
+        assert_eq!(keys, vec![1, 3, 2]);
+
+        let entries: Vec<(i32, i32)> = cache.into_iter().collect();
+        assert_eq!(entries, vec![(1, 1), (3, 3), (2, 2)]);
+    }
+
+    #[test]
+    fn test_stat() {
+        let mut cache = Cache::new(2);
+        cache.add(1, 1);
+        cache.add(2, 2);
+
+        assert!(cache.get(&1).is_some());
+        assert!(cache.get(&3).is_none());
+        assert!(cache.peek(&2).is_some());
+        assert!(cache.peek(&3).is_none());
+
+        cache.add(2, 3);
+        cache.add(3, 3);
+
+        let info = cache.stat();
+        assert_eq!(info.hit_count, 1);
+        assert_eq!(info.miss_count, 1);
+        assert_eq!(info.insert_count, 3);
+        assert_eq!(info.replace_count, 1);
+        assert_eq!(info.eviction_count, 1);
+        assert_eq!(info.expiration_count, 0);
+        assert_eq!(info.len, 2);
+        assert_eq!(info.hit_ratio(), 0.5);
+    }
+
+    #[test]
+    fn test_disabled() {
+        let mut cache = Cache::disabled();
+
+        let evicted = Rc::new(RefCell::new(Vec::new()));
+        let e = evicted.clone();
+        cache.set_eviction_callback(move |k, v| {
+            e.borrow_mut().push((k, v));
+        });
+
+        assert!(cache.is_disabled());
+        assert_eq!(cache.add(1, 10), None);
+        assert_eq!(cache.add(2, 20), None);
+        assert!(cache.get(&1).is_none());
+        assert!(cache.is_empty());
+        assert_eq!(*evicted.as_ref().borrow(), vec![(1, 10), (2, 20)]);
+    }
+
+    #[test]
+    fn test_weigher() {
+        let mut cache = Cache::new(100);
+        cache.set_weigher(10, |_, v: &u32| *v);
+
+        let evicted = Rc::new(RefCell::new(Vec::new()));
+        let e = evicted.clone();
+        cache.set_eviction_callback(move |k, v| {
+            e.borrow_mut().push((k, v));
+        });
+
+        cache.add(1, 4);
+        cache.add(2, 4);
+        assert_eq!(cache.weighted_size(), 8);
+        cache.add(3, 4);
+        assert_eq!(cache.weighted_size(), 8);
+        assert!(!cache.contains_key(&1));
+
+        assert_eq!(cache.add(4, 11), None);
+        assert!(!cache.contains_key(&4));
+
+        assert_eq!(cache.add(2, 9), Some(4));
+        assert_eq!(cache.weighted_size(), 9);
+        assert_eq!(cache.len(), 1);
+        assert_eq!(*evicted.as_ref().borrow(), vec![(1, 4), (4, 11), (3, 4)]);
+
+        cache.remove(&2);
+        assert_eq!(cache.weighted_size(), 0);
+    }
+
+    #[test]
+    fn test_push() {
+        let mut cache = Cache::new(100);
+        cache.set_weigher(10, |_, v: &u32| *v);
+
+        let e_count = Rc::new(RefCell::new(0));
+        let count = e_count.clone();
+        cache.set_eviction_callback(move |_, _| {
+            *count.borrow_mut() += 1;
+        });
+
+        assert_eq!(cache.push(1, 3), (None, vec![]));
+        assert_eq!(cache.push(2, 3), (None, vec![]));
+        assert_eq!(cache.push(3, 3), (None, vec![]));
+        assert_eq!(cache.push(4, 8), (None, vec![(1, 3), (2, 3), (3, 3)]));
+        assert_eq!(cache.push(4, 2), (Some(8), vec![]));
+        assert_eq!(cache.push(5, 11), (None, vec![(5, 11)]));
+        assert_eq!(*e_count.as_ref().borrow(), 0);
+    }
+
+    #[test]
+    fn test_get_many() {
+        let mut cache = Cache::new(3);
+        cache.add_many((0..3).map(|i| (i, i * 10)));
+        assert_eq!(cache.get_many(&[0, 5, 1]), vec![Some(&0), None, Some(&10)]);
+        assert_eq!((cache.stat().hit_count, cache.stat().miss_count), (2, 1));
+        cache.add(3, 30);
+        assert!(!cache.contains_key(&2));
+
+        let mut cache = Cache::new(2);
+        cache.add_arc(0, "a");
+        let values = cache.get_many_arc(&[0, 1]);
+        cache.purge();
+        assert_eq!(values, vec![Some(Arc::new("a")), None]);
+    }
+
+    #[test]
+    fn test_peek_many() {
+        let mut cache = Cache::new(2);
+        cache.add(0, 0);
+        cache.add(1, 1);
+        assert_eq!(cache.peek_many(&[0, 2, 1]), vec![true, false, true]);
+        let info = cache.stat();
+        assert_eq!((info.hit_count, info.miss_count), (0, 0));
+        // 0 is still the least recently used
+        cache.add(2, 2);
+        assert!(!cache.contains_key(&0));
+    }
+
+    #[test]
+    fn test_add_many() {
+        let mut cache = Cache::new(3);
+        let e_count = Rc::new(RefCell::new(0));
+        let count = e_count.clone();
+        cache.set_eviction_callback(move |_, _| {
+            *count.borrow_mut() += 1;
+        });
+
+        cache.add(0, 0);
+        let evicted = cache.add_many((1..5).map(|i| (i, i)).chain(Some((3, 30))));
+        assert_eq!(evicted, vec![(0, 0), (1, 1)]);
+        let keys: Vec<_> = cache.iter().map(|(&k, &v)| (k, v)).collect();
+        assert_eq!(keys, vec![(3, 30), (4, 4), (2, 2)]);
+        assert_eq!(*e_count.as_ref().borrow(), 0);
+        cache.add(5, 5);
+        assert_eq!(*e_count.as_ref().borrow(), 1);
+    }
+
+    #[test]
+    fn test_max_heap_size() {
+        let mut cache: Cache<u64, String> = Cache::new(100);
+        let entry = 8 + std::mem::size_of::<String>();
+        cache.set_max_heap_size(2 * entry + 30);
+
+        cache.add(1, String::with_capacity(10));
+        cache.add(2, String::with_capacity(10));
+        assert_eq!(cache.weighted_size(), 2 * entry + 20);
+        cache.add(3, String::with_capacity(15));
+        assert_eq!(cache.len(), 2);
+        assert!(!cache.contains_key(&1));
+
+        let memory = cache.stat().memory;
+        assert_eq!(memory.heap_bytes, Some(25));
+        assert!(memory.total() > 2 * entry + 25);
+    }
+
+    #[test]
+    fn test_stateful_callback() {
+        let mut cache = Cache::new(1);
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        let mut last = None;
+        cache.set_eviction_callback(move |k, _| {
+            if let Some(prev) = last.replace(k) {
+                tx.send(prev + k).unwrap();
+            }
+        });
+
+        for i in 0..4 {
+            cache.add(i, i);
+        }
+        assert_eq!(rx.try_iter().collect::<Vec<_>>(), vec![1, 3]);
+    }
+
+    #[test]
+    fn test_refresh_interval() {
+        let mut cache = Cache::new(3);
+        cache.set_refresh_interval(2);
+        assert_eq!(cache.refresh_interval(), 2);
+        for i in 1..4 {
+            cache.add(i, i);
+        }
+        let keys = |cache: &Cache<i32, i32>| cache.iter().map(|(&k, _)| k).collect::<Vec<_>>();
+
+        assert_eq!(cache.get(&1), Some(&1));
+        assert_eq!(keys(&cache), vec![3, 2, 1]);
+        // a miss does not count
+        assert_eq!(cache.get(&4), None);
+        assert_eq!(cache.get(&1), Some(&1));
+        assert_eq!(keys(&cache), vec![1, 3, 2]);
+        assert_eq!(cache.stat().hit_count, 2);
+
+        cache.set_refresh_interval(0);
+        assert_eq!(cache.get(&2), Some(&2));
+        assert_eq!(keys(&cache), vec![2, 1, 3]);
+    }
+
+    #[test]
+    fn test_panicking_callback() {
+        use std::panic::{self, AssertUnwindSafe};
+
+        let mut cache = Cache::new(4);
+        let evicted = Rc::new(RefCell::new(Vec::new()));
+        let log = evicted.clone();
+        cache.set_eviction_callback(move |k, _| {
+            assert_ne!(k, 1, "callback panic");
+            log.borrow_mut().push(k);
+        });
+        for i in 0..4 {
+            cache.add(i, i);
+        }
+        let result = panic::catch_unwind(AssertUnwindSafe(|| cache.resize(1)));
+        assert!(result.is_err());
+        // the evictions after the panic still went through
+        assert_eq!(*evicted.as_ref().borrow(), vec![0, 2]);
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.weighted_size(), 1);
+        assert_eq!(cache.stat().eviction_count, 3);
+        cache.add(4, 4);
+        assert_eq!(cache.iter().map(|(&k, _)| k).collect::<Vec<_>>(), vec![4]);
+
+        // a panic in `push` does not leave later evictions collected
+        cache.set_eviction_observer(|&k, _| assert_ne!(k, 4, "observer panic"));
+        let result = panic::catch_unwind(AssertUnwindSafe(|| cache.push(5, 5)));
+        assert!(result.is_err());
+        cache.add(6, 6);
+        assert_eq!(*evicted.as_ref().borrow(), vec![0, 2, 3, 5]);
+    }
+
+    #[test]
+    fn test_event_queue() {
+        let mut cache = Cache::new(2);
+        cache.set_event_queue(4);
+        cache.add(1, "a");
+        cache.add(2, "b");
+        cache.add(2, "c");
+        cache.add(3, "d");
+        assert_eq!(
+            cache.take_events(),
+            vec![
+                EntryEvent::Inserted(1),
+                EntryEvent::Inserted(2),
+                EntryEvent::Inserted(3),
+                EntryEvent::Evicted {
+                    key: 1,
+                    value: "a",
+                    reason: EvictionReason::Capacity
+                },
+            ]
+        );
+        assert!(cache.take_events().is_empty());
+
+        // the oldest events give way
+        for k in 4..8 {
+            cache.add(k, "e");
+        }
+        let events = cache.take_events();
+        assert_eq!(events.len(), 4);
+        assert_eq!(events[0], EntryEvent::Inserted(6));
+
+        // expired entries leave through the queue too
+        assert!(cache.expire(&7));
+        assert!(!cache.expire(&7));
+        assert_eq!(
+            cache.take_events(),
+            vec![EntryEvent::Evicted {
+                key: 7,
+                value: "e",
+                reason: EvictionReason::Expired
+            }]
+        );
+    }
+
+    #[test]
+    fn test_batch_eviction_callback() {
+        let mut cache = Cache::new(10);
+        let batches = Rc::new(RefCell::new(Vec::new()));
+        let b = batches.clone();
+        cache.set_batch_eviction_callback(4, move |batch: Vec<(i32, i32)>| {
+            b.borrow_mut()
+                .push(batch.into_iter().map(|(k, _)| k).collect::<Vec<_>>());
+        });
+        for i in 0..11 {
+            cache.add(i, i);
+        }
+        assert_eq!(*batches.as_ref().borrow(), vec![vec![0]]);
+
+        cache.resize(1);
+        assert_eq!(
+            *batches.as_ref().borrow(),
+            vec![vec![0], vec![1, 2, 3, 4], vec![5, 6, 7, 8], vec![9]]
+        );
+        assert_eq!(cache.len(), 1);
+
+        // a plain callback takes over again
+        cache.set_eviction_callback(|_, _| {});
+        cache.add(11, 11);
+        assert_eq!(batches.as_ref().borrow().len(), 4);
+    }
+
+    #[test]
+    fn test_eviction_observer() {
+        let mut cache = Cache::new(2);
+
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let s = seen.clone();
+        cache.set_eviction_observer(move |k: &i32, v: &i32| {
+            s.borrow_mut().push(k + v);
+        });
+
+        cache.add(1, 10);
+        cache.add(2, 20);
+        cache.add(3, 30);
+        assert_eq!(cache.push(4, 40), (None, vec![(2, 20)]));
+        assert_eq!(*seen.as_ref().borrow(), vec![11, 22]);
+    }
+
+    #[test]
+    fn test_eviction_filter() {
+        let mut cache = Cache::new(3);
+        cache.set_eviction_filter(|k: &i32, _: &i32| *k != 1);
+
+        cache.add(1, 1);
+        cache.add(2, 2);
+        cache.add(3, 3);
+        cache.add(4, 4);
+        assert!(cache.contains_key(&1));
+        assert!(!cache.contains_key(&2));
+        assert_eq!(cache.len(), 3);
+
+        cache.set_eviction_filter(|_: &i32, _: &i32| false);
+        cache.add(5, 5);
+        assert_eq!(cache.len(), 4);
+    }
+
+    #[test]
+    fn test_stat_delta() {
+        let mut cache = Cache::new(2);
+        cache.add(1, 1);
+        cache.get(&1);
+        cache.get(&2);
+
+        let delta = cache.stat_delta();
+        assert_eq!(
+            (delta.hit_count, delta.miss_count, delta.insert_count),
+            (1, 1, 1)
+        );
+
+        cache.get(&1);
+        let delta = cache.stat_delta();
+        assert_eq!(
+            (delta.hit_count, delta.miss_count, delta.insert_count),
+            (1, 0, 0)
+        );
+        assert_eq!(delta.len, 1);
+        assert_eq!(cache.stat().hit_count, 2);
+
+        cache.reset_stats();
+        assert_eq!(cache.stat().hit_count, 0);
+        cache.get(&2);
+        assert_eq!(cache.stat_delta().miss_count, 1);
+    }
+
+    #[test]
+    fn test_recent_hit_rate() {
+        let mut cache = Cache::new(2);
+        assert_eq!(cache.recent_hit_rate(), None);
+
+        cache.set_hit_window(HitWindow::operations(10));
+        cache.add(1, 1);
+        for _ in 0..10 {
+            cache.get(&2);
+        }
+        for _ in 0..10 {
+            cache.get(&1);
+        }
+        assert_eq!(cache.recent_hit_rate(), Some(1.0));
+        assert_eq!(cache.stat().hit_ratio(), 0.5);
+    }
+
+    #[test]
+    fn test_age_tracking() {
+        let clock = ManualClock::new();
+        let mut cache = Cache::new(2);
+        assert!(cache.stat().age_at_eviction.is_none());
+
+        cache.set_age_tracking(AgeTracking::with_clock(clock.clone()));
+        cache.add(1, 1);
+        cache.add(2, 2);
+        clock.advance(Duration::from_micros(10));
+        cache.get(&1);
+        clock.advance(Duration::from_micros(10));
+        cache.add(3, 3);
+        cache.add(4, 4);
+
+        let info = cache.stat();
+        let age = info.age_at_eviction.unwrap();
+        let idle = info.idle_at_eviction.unwrap();
+        assert_eq!(age.count(), 2);
+        // 2 was never read, 1 was read halfway through its life
+        assert_eq!(age.quantile(1.0), Some(Duration::from_nanos(32767)));
+        assert_eq!(idle.quantile(0.5), Some(Duration::from_nanos(16383)));
+        assert_eq!(idle.quantile(1.0), Some(Duration::from_nanos(32767)));
+    }
+
+    #[test]
+    fn test_metadata() {
+        let clock = ManualClock::new();
+        let mut cache = Cache::new(2);
+        cache.add(0, 0);
+        cache.set_age_tracking(AgeTracking::with_clock(clock.clone()));
+        cache.set_frequency_tracking(true);
+        clock.advance(Duration::from_secs(1));
+        cache.add(1, 1);
+        clock.advance(Duration::from_secs(1));
+        cache.get(&1);
+        cache.get(&1);
+
+        let meta = cache.metadata(&1).unwrap();
+        assert_eq!(meta.inserted, Some(Duration::from_secs(1)));
+        assert_eq!(meta.last_access, Some(Duration::from_secs(2)));
+        assert_eq!(meta.access_count, Some(2));
+        assert_eq!(meta.segment, Segment::Single);
+        // stored before the tracking
+        let meta = cache.metadata(&0).unwrap();
+        assert_eq!((meta.inserted, meta.access_count), (None, None));
+        assert_eq!(cache.metadata(&2), None);
+    }
+
+    #[test]
+    fn test_remove_if() {
+        let mut cache = Cache::new(2);
+        cache.add(1, "a");
+        cache.add(2, "b");
+        assert_eq!(cache.remove_if(&1, |_, v| *v == "b"), Removal::Kept);
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.remove_if(&1, |&k, _| k == 1), Removal::Removed("a"));
+        assert_eq!(cache.len(), 1);
+        assert!(!cache.contains_key(&1));
+        assert_eq!(cache.remove_if(&1, |_, _| true), Removal::Missing);
+    }
+
+    #[test]
+    fn test_split_by() {
+        let mut cache = Cache::new(10);
+        cache.set_weigher(20, |_, v: &u32| *v);
+        for k in 0..6 {
+            cache.add(k, 2);
+        }
+        cache.get(&1);
+        let mut odd = cache.split_by(|&k, _| k % 2 == 1);
+        let keys = |cache: &Cache<u32, u32>| cache.iter().map(|(&k, _)| k).collect::<Vec<_>>();
+        assert_eq!(keys(&cache), vec![4, 2, 0]);
+        assert_eq!(keys(&odd), vec![1, 5, 3]);
+        assert_eq!((cache.weighted_size(), odd.weighted_size()), (6, 6));
+        assert_eq!((odd.max_size(), odd.max_weight()), (10, 20));
+        // the weigher came along
+        odd.add(7, 15);
+        assert_eq!(keys(&odd), vec![7, 1, 5]);
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut cache = Cache::new(4);
+        let mut other = Cache::new(4);
+        for k in 0..4 {
+            cache.add(k, "mine");
+        }
+        for k in 3..5 {
+            other.add(k, "theirs");
+        }
+        let evicted = Rc::new(RefCell::new(Vec::new()));
+        let log = evicted.clone();
+        cache.set_eviction_callback(move |k, v| log.borrow_mut().push((k, v)));
+
+        cache.merge(other);
+        let entries: Vec<_> = cache.iter().map(|(&k, &v)| (k, v)).collect();
+        assert_eq!(
+            entries,
+            vec![(3, "mine"), (4, "theirs"), (2, "mine"), (1, "mine")]
+        );
+        assert_eq!(*evicted.as_ref().borrow(), vec![(3, "theirs"), (0, "mine")]);
+        assert_eq!(cache.weighted_size(), 4);
+        let info = cache.stat();
+        assert_eq!((info.insert_count, info.replace_count), (5, 0));
+        assert_eq!(info.eviction_count, 1);
+    }
+
+    #[test]
+    fn test_add_with_priority() {
+        let mut cache = Cache::new(3);
+        cache.add_with_priority(1, "page", 10);
+        cache.add(2, "thumbnail");
+        cache.add(3, "thumbnail");
+        cache.add(4, "thumbnail");
+        cache.add(5, "thumbnail");
+        let keys: Vec<_> = cache.iter().map(|(&k, _)| k).collect();
+        assert_eq!(keys, vec![5, 4, 1]);
+
+        // a replaced entry keeps its priority
+        cache.add(1, "page");
+        assert_eq!(cache.priority(&1), Some(10));
+        assert_eq!(cache.priority(&4), Some(0));
+
+        // among equal priorities the least recently used goes
+        cache.add_with_priority(4, "page", 10);
+        cache.add_with_priority(5, "page", 10);
+        cache.add_with_priority(6, "page", 10);
+        let keys: Vec<_> = cache.iter().map(|(&k, _)| k).collect();
+        assert_eq!(keys, vec![6, 5, 4]);
+        assert_eq!(cache.priority(&1), None);
+    }
+
+    #[test]
+    fn test_priorities_of_colliding_keys() {
+        let mut cache: Cache<u32, &str, BuildHasherDefault<Zero>> =
+            Cache::with_hasher(3, Default::default());
+        cache.add_with_priority(1, "page", 10);
+        cache.add(2, "thumbnail");
+        cache.add(3, "thumbnail");
+        assert_eq!(cache.priority(&1), Some(10));
+        assert_eq!(cache.priority(&2), Some(0));
+        cache.remove(&2);
+        assert_eq!(cache.priority(&1), Some(10));
+        cache.add(4, "thumbnail");
+        cache.add(5, "thumbnail");
+        let keys: Vec<_> = cache.iter().map(|(&k, _)| k).collect();
+        assert_eq!(keys, vec![5, 4, 1]);
+    }
+
+    #[test]
+    fn test_priority_class_with_filter() {
+        let mut cache = Cache::new(5);
+        let class = PriorityClass::new("a", 0..=0, 0.4).unwrap();
+        cache.add_priority_class(class).unwrap();
+        cache.add_with_priority(0, "a", 0);
+        cache.add_with_priority(1, "a", 0);
+        cache.add_with_priority(20, "c", 7);
+        cache.add_with_priority(10, "b", 5);
+        cache.add_with_priority(11, "b", 5);
+        cache.set_eviction_filter(|&k: &i32, _: &&str| k < 10 || k == 20);
+        // "b" may not go and "a" is at its share, so "c" goes though it is
+        // more recent and of a higher priority
+        cache.add_with_priority(12, "b", 5);
+        let keys: Vec<_> = cache.iter().map(|(&k, _)| k).collect();
+        assert_eq!(keys, vec![12, 11, 10, 1, 0]);
+    }
+
+    #[test]
+    fn test_priority_class_shares() {
+        for &share in &[-0.1, 1.5, f64::NAN] {
+            assert!(PriorityClass::new("a", 0..=1, share).is_err());
+        }
+        let mut cache: Cache<i32, i32> = Cache::new(10);
+        let a = PriorityClass::new("a", 0..=1, 0.6).unwrap();
+        let b = PriorityClass::new("b", 2..=3, 0.5).unwrap();
+        assert_eq!(cache.add_priority_class(a), Ok(()));
+        assert!(matches!(
+            cache.add_priority_class(b),
+            Err(ConfigError::SharesOverOne { .. })
+        ));
+        assert_eq!(cache.priority_class_lens(), vec![("a", 0)]);
+    }
+
+    #[test]
+    fn test_priority_class() {
+        let mut cache = Cache::new(10);
+        let class = PriorityClass::new("a", 0..=1, 0.3).unwrap();
+        assert_eq!(cache.add_priority_class(class), Ok(()));
+        for k in 0..5 {
+            cache.add_with_priority(k, "a", 1);
+        }
+        // the flood of a higher priority takes all but the share of "a"
+        for k in 100..120 {
+            cache.add_with_priority(k, "b", 5);
+        }
+        assert_eq!(cache.priority_class_lens(), vec![("a", 3)]);
+        let a: Vec<_> = cache
+            .iter()
+            .filter(|(_, &v)| v == "a")
+            .map(|(&k, _)| k)
+            .collect();
+        assert_eq!(a, vec![4, 3, 2]);
+        assert_eq!(cache.len(), 10);
+    }
+
+    #[test]
+    fn test_low_water_mark() {
+        let batches = Rc::new(RefCell::new(Vec::new()));
+        let sink = batches.clone();
+        let mut cache = Cache::new(10);
+        cache.set_batch_eviction_callback(100, move |batch: Vec<(usize, usize)>| {
+            sink.borrow_mut().push(batch.len())
+        });
+        cache.set_low_water_mark(Some(6)).unwrap();
+        for k in 0..10 {
+            cache.add(k, k);
+        }
+        assert!(batches.as_ref().borrow().is_empty());
+        cache.add(10, 10);
+        assert_eq!(cache.len(), 6);
+        for k in 11..15 {
+            cache.add(k, k);
+        }
+        cache.add(15, 15);
+        assert_eq!(*batches.as_ref().borrow(), vec![5, 5]);
+        assert_eq!(
+            cache.set_low_water_mark(Some(11)),
+            Err(ConfigError::LowWaterMarkOverCapacity {
+                low: 11,
+                capacity: 10
+            })
+        );
+        assert_eq!(cache.low_water_mark(), Some(6));
+    }
+
+    #[test]
+    fn test_add_if_version() {
+        let mut cache = Cache::new(2);
+        cache.add(1, "a");
+        assert_eq!(cache.version(&1), Some(0));
+        // stored before versioning, the entry is not taken for a missing one
+        assert_eq!(cache.add_if_version(1, "b", None), Err("b"));
+        assert_eq!(cache.add_if_version(1, "b", Some(0)), Ok(Some(1)));
+        assert_eq!(cache.add_if_version(2, "c", None), Ok(Some(2)));
+        // a plain write moves the version on too
+        cache.add(1, "d");
+        assert_eq!(cache.version(&1), Some(3));
+        assert_eq!(cache.add_if_version(1, "stale", Some(1)), Err("stale"));
+        assert_eq!(cache.peek(&1), Some(&"d"));
+
+        // stored again after an eviction, a key gets a newer version
+        cache.add(3, "e");
+        assert_eq!(cache.version(&2), None);
+        assert_eq!(cache.add_if_version(2, "f", None), Ok(Some(5)));
+
+        // given versions are kept apart from those of the writes
+        assert_eq!(cache.add_with_version(2, "g", 10), Ok(()));
+        assert_eq!(cache.add_with_version(2, "g", 5), Err("g"));
+        assert_eq!(cache.version(&2), Some(6));
+        assert_eq!(cache.given_version(&2), Some(10));
+        cache.add(2, "h");
+        assert_eq!(cache.version(&2), Some(7));
+        assert_eq!(cache.given_version(&2), Some(10));
+    }
+
+    #[test]
+    fn test_versions_of_colliding_keys() {
+        let mut cache: Cache<u32, &str, BuildHasherDefault<Zero>> =
+            Cache::with_hasher(4, Default::default());
+        assert_eq!(cache.add_if_version(1, "a", None), Ok(Some(1)));
+        assert_eq!(cache.add_if_version(2, "b", None), Ok(Some(2)));
+        assert_eq!(cache.version(&1), Some(1));
+        cache.remove(&2);
+        assert_eq!(cache.version(&1), Some(1));
+        assert_eq!(cache.version(&2), None);
+    }
+
+    #[test]
+    fn test_stats_recorder() {
+        struct Log(Rc<RefCell<Vec<Event>>>);
+
+        impl StatsRecorder for Log {
+            fn record(&mut self, event: Event) {
+                self.0.borrow_mut().push(event);
+            }
+        }
+
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let mut cache = Cache::new(1);
+        cache.set_stats_recorder(Log(events.clone()));
+        cache.add(1, 1);
+        cache.add(1, 2);
+        cache.get(&1);
+        cache.get(&2);
+        cache.add(2, 2);
+        cache.set_weigher(1, |_, &v| v);
+        cache.add(3, 3);
+
+        let capacity = Event::Eviction {
+            reason: EvictionReason::Capacity,
+        };
+        let rejected = Event::Eviction {
+            reason: EvictionReason::Rejected,
+        };
+        assert_eq!(
+            *events.as_ref().borrow(),
+            vec![
+                Event::Insert,
+                Event::Replace,
+                Event::Hit,
+                Event::Miss,
+                Event::Insert,
+                capacity,
+                capacity,
+                rejected,
+            ]
+        );
+        assert_eq!(cache.stat().eviction_count, 3);
+
+        events.borrow_mut().clear();
+        cache.add(4, 1);
+        cache.expire(&4);
+        let expired = Event::Eviction {
+            reason: EvictionReason::Expired,
+        };
+        assert_eq!(*events.as_ref().borrow(), vec![Event::Insert, expired]);
+        assert_eq!(cache.stat().expiration_count, 1);
+    }
+
+    #[test]
+    fn test_latency_sampling() {
+        let mut cache = Cache::new(4);
+        assert!(cache.stat().get_latency.is_none());
+
+        cache.set_latency_sampling(LatencySampling::with_clock(2, ManualClock::new()));
+        cache.add(1, 1);
+        cache.add(2, 2);
+        for _ in 0..4 {
+            cache.get(&1);
+        }
+        let info = cache.stat();
+        assert_eq!(info.add_latency.unwrap().count(), 1);
+        assert_eq!(info.get_latency.unwrap().count(), 2);
+        assert_eq!(
+            info.get_latency.unwrap().quantile(0.99),
+            Some(Duration::from_nanos(0))
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde() {
+        let mut cache = Cache::new(3);
+        for i in 0..3 {
+            cache.add(i, i.to_string());
+        }
+        cache.get(&0);
+
+        let json = serde_json::to_string(&cache).unwrap();
+        assert_eq!(
+            json,
+            r#"{"max_size":3,"entries":[[0,"0"],[2,"2"],[1,"1"]]}"#
+        );
+        let mut restored: Cache<i32, String> = serde_json::from_str(&json).unwrap();
+        restored.add(3, "3".to_string());
+        assert_eq!(
+            restored.iter().map(|(&k, _)| k).collect::<Vec<_>>(),
+            vec![3, 0, 2]
+        );
+        assert_eq!(restored.weighted_size(), 3);
+    }
+
+    #[test]
+    fn test_warm_from_iter() {
+        let mut cache = Cache::new(3);
+        cache.add(0, 0);
+        assert_eq!(cache.warm_from_iter((0..10).map(|i| (i, i))), 2);
+        assert_eq!(
+            cache.iter().map(|(&k, _)| k).collect::<Vec<_>>(),
+            vec![0, 1, 2]
+        );
+        assert_eq!(cache.stat().insert_count, 3);
+
+        let mut cache = Cache::new(3);
+        cache.set_weigher(3, |_, &v| v);
+        assert_eq!(cache.warm_from_iter(vec![(1, 2), (2, 2), (3, 1)]), 2);
+        assert!(!cache.contains_key(&2));
+    }
+
+    #[test]
+    fn test_admission_policy() {
+        let evicted = Rc::new(RefCell::new(Vec::new()));
+        let mut cache = Cache::new(2);
+        let sink = evicted.clone();
+        cache.set_eviction_callback(move |k, _| sink.borrow_mut().push(k));
+        cache.set_admission_policy(TinyLfu::new(2));
+        cache.add(1, 1);
+        cache.add(2, 2);
+        for _ in 0..3 {
+            cache.get(&1);
+            cache.get(&2);
+        }
+
+        // seen once, so it may not displace 1
+        cache.add(3, 3);
+        assert!(!cache.contains_key(&3));
+        assert_eq!(*evicted.as_ref().borrow(), vec![3]);
+        assert_eq!(cache.stat().eviction_count, 1);
+
+        for _ in 0..5 {
+            cache.get(&3);
+        }
+        cache.add(3, 3);
+        assert!(cache.contains_key(&3));
+        assert!(!cache.contains_key(&1));
+        assert_eq!(*evicted.as_ref().borrow(), vec![3, 1]);
+    }
+
+    #[test]
+    fn test_hot_keys() {
+        let mut cache = Cache::new(4);
+        assert!(cache.hot_keys(1).is_empty());
+
+        cache.set_hot_key_tracking(8, 1000);
+        for i in 0..4 {
+            cache.add(i, i);
+        }
+        for _ in 0..3 {
+            cache.get(&2);
+        }
+        cache.get(&3);
+        cache.get(&7);
+        assert_eq!(cache.hot_keys(2), vec![(2, 4), (3, 2)]);
+
+        cache.reset_stats();
+        assert!(cache.hot_keys(1).is_empty());
+    }
+
+    #[test]
+    fn test_frequency_distribution() {
+        let mut cache = Cache::new(4);
+        assert_eq!(cache.frequency_distribution(), None);
+
+        cache.set_frequency_tracking(true);
+        for i in 0..4 {
+            cache.add(i, i);
+        }
+        cache.get(&1);
+        cache.get(&2);
+        cache.get(&2);
+        cache.add(3, 3);
+        for _ in 0..5 {
+            cache.get(&3);
+        }
+        assert_eq!(
+            cache.frequency_distribution(),
+            Some(vec![(0..=0, 1), (1..=1, 1), (2..=3, 1), (4..=7, 1)])
+        );
+
+        cache.add(4, 4);
+        assert_eq!(
+            cache.frequency_distribution(),
+            Some(vec![(0..=0, 1), (1..=1, 1), (2..=3, 1), (4..=7, 1)])
+        );
+        cache.remove(&3);
+        assert_eq!(
+            cache.frequency_distribution(),
+            Some(vec![(0..=0, 1), (1..=1, 1), (2..=3, 1)])
+        );
+    }
 }
@@ -1,62 +1,177 @@
 use std::borrow::Borrow;
-use std::collections::{hash_map::RandomState, HashMap};
-use std::hash::{BuildHasher, Hash, Hasher};
-use std::mem;
+use std::collections::hash_map::RandomState;
+#[cfg(feature = "compact")]
+use std::convert::TryFrom;
+use std::hash::{BuildHasher, Hash};
+use std::marker::PhantomData;
+use std::mem::{self, MaybeUninit};
+#[cfg(feature = "compact")]
+use std::num::NonZeroU32;
 use std::ptr::{self, NonNull};
 
-#[derive(Debug)]
-struct KeyPtr<K>(NonNull<K>);
+use hashbrown::hash_table::HashTable;
+
+use super::stats::MemoryUsage;
+
+/// Looks up `keys` in order with the `get` of a cache and returns what it
+/// found for each, all looked up once unless an entry of the cache left its
+/// node in between, as told by the sum of `LinkedHashMap::released` over
+/// its maps. The keys found before that are looked up again with `peek`, so
+/// that an entry displaced by a later lookup is reported missing.
+pub(crate) fn get_many<'c, 'a, C, Q, V>(
+    cache: &'c mut C,
+    keys: impl IntoIterator<Item = &'a Q>,
+    get: for<'x> fn(&'x mut C, &Q) -> Option<&'x V>,
+    peek: for<'x> fn(&'x C, &Q) -> Option<&'x V>,
+    released: fn(&C) -> usize,
+) -> Vec<Option<&'c V>>
+where
+    Q: 'a + ?Sized,
+{
+    let found: Vec<_> = keys
+        .into_iter()
+        .map(|k| (k, get(cache, k).map(NonNull::from), released(cache)))
+        .collect();
+    let cache = &*cache;
+    let released = released(cache);
+    found
+        .into_iter()
+        .map(|(k, v, then)| match v {
+            // SAFETY: no value left its node since `v` was found, and the
+            // cache is only borrowed immutably from here on
+            Some(v) if then == released => Some(unsafe { &*v.as_ptr() }),
+            Some(_) => peek(cache, k),
+            None => None,
+        })
+        .collect()
+}
 
-impl<K> KeyPtr<K> {
-    fn from(k: &K) -> KeyPtr<K> {
-        KeyPtr(NonNull::from(k))
+/// The entries `LinkedHashMap::merge` drops: those of the map, then those
+/// of the other.
+pub(crate) type Dropped<K, V> = (Vec<(K, V)>, Vec<(K, V)>);
+
+struct Node<K, V> {
+    next: Option<Link<K, V>>,
+    prev: Option<Link<K, V>>,
+    // the hash of `k`, so that neither rehashing the table nor taking the
+    // node out of it hashes the key again
+    hash: u64,
+    k: K,
+    v: V,
+}
+
+type NodePtr<K, V> = NonNull<Node<K, V>>;
+
+/// Refers to a node, from its neighbours and from the table: its address,
+/// or with the `compact` feature the index of its slot, which is half the
+/// size. A compact map holds at most `u32::MAX` entries.
+#[cfg(not(feature = "compact"))]
+type Link<K, V> = NodePtr<K, V>;
+
+#[cfg(feature = "compact")]
+struct Link<K, V> {
+    // one more than the index of the slot
+    index: NonZeroU32,
+    marker: PhantomData<NodePtr<K, V>>,
+}
+
+#[cfg(feature = "compact")]
+impl<K, V> Clone for Link<K, V> {
+    fn clone(&self) -> Self {
+        *self
     }
 }
 
-impl<K: PartialEq> PartialEq for KeyPtr<K> {
+#[cfg(feature = "compact")]
+impl<K, V> Copy for Link<K, V> {}
+
+#[cfg(feature = "compact")]
+impl<K, V> PartialEq for Link<K, V> {
     fn eq(&self, other: &Self) -> bool {
-        unsafe { (self.0.as_ref()).eq(other.0.as_ref()) }
+        self.index == other.index
     }
 }
 
-impl<K: Eq> Eq for KeyPtr<K> {}
+const CHUNK_SHIFT: usize = 6;
+
+/// The most nodes allocated at once. Chunk `n` holds `2^n` nodes up to this
+/// many, so that small maps allocate little and a slot is found from its
+/// index alone.
+const CHUNK_LEN: usize = 1 << CHUNK_SHIFT;
 
-impl<K: Hash> Hash for KeyPtr<K> {
-    fn hash<H: Hasher>(&self, state: &mut H) {
-        unsafe { (self.0.as_ref()).hash(state) }
+#[inline]
+fn chunk_len(n: usize) -> usize {
+    if n < CHUNK_SHIFT {
+        1 << n
+    } else {
+        CHUNK_LEN
     }
 }
 
-#[derive(Hash, PartialEq, Eq)]
-struct KeyRef<Q: ?Sized>(Q);
+/// Returns the link to slot `offset` of chunk `n`, which starts at `base`.
+#[cfg(not(feature = "compact"))]
+#[inline]
+fn link_to<K, V>(base: NodePtr<K, V>, _n: usize, offset: usize) -> Link<K, V> {
+    unsafe { NonNull::new_unchecked(base.as_ptr().add(offset)) }
+}
 
-impl<Q: ?Sized> KeyRef<Q> {
-    fn new(k: &Q) -> &Self {
-        unsafe { mem::transmute(k) }
+#[cfg(feature = "compact")]
+#[inline]
+fn link_to<K, V>(_base: NodePtr<K, V>, n: usize, offset: usize) -> Link<K, V> {
+    let start = if n < CHUNK_SHIFT {
+        (1 << n) - 1
+    } else {
+        CHUNK_LEN - 1 + (n - CHUNK_SHIFT) * CHUNK_LEN
+    };
+    match u32::try_from(start + offset + 1)
+        .ok()
+        .and_then(NonZeroU32::new)
+    {
+        Some(index) => Link {
+            index,
+            marker: PhantomData,
+        },
+        None => panic!("a compact LinkedHashMap holds at most u32::MAX entries"),
     }
 }
 
-impl<K, Q: ?Sized> Borrow<KeyRef<Q>> for KeyPtr<K>
-where
-    K: Borrow<Q>,
-{
-    fn borrow(&self) -> &KeyRef<Q> {
-        KeyRef::new(unsafe { (self.0.as_ref().borrow()) })
+/// Returns the node `link` refers to, given the start of every chunk.
+#[cfg(not(feature = "compact"))]
+#[inline]
+fn node_at<K, V>(_chunks: &[Option<NodePtr<K, V>>], link: Link<K, V>) -> NodePtr<K, V> {
+    link
+}
+
+#[cfg(feature = "compact")]
+#[inline]
+fn node_at<K, V>(chunks: &[Option<NodePtr<K, V>>], link: Link<K, V>) -> NodePtr<K, V> {
+    let i = link.index.get() as usize;
+    let (n, offset) = if i < CHUNK_LEN {
+        let n = i.ilog2() as usize;
+        (n, i - (1 << n))
+    } else {
+        let i = i - CHUNK_LEN;
+        (CHUNK_SHIFT + i / CHUNK_LEN, i % CHUNK_LEN)
+    };
+    // a link is only followed while its chunk is allocated
+    unsafe {
+        let base = chunks.get_unchecked(n).unwrap_unchecked();
+        NonNull::new_unchecked(base.as_ptr().add(offset))
     }
 }
 
-struct Node<K, V> {
-    next: Option<NonNull<Node<K, V>>>,
-    prev: Option<NonNull<Node<K, V>>>,
-    k: K,
-    v: V,
+unsafe fn free_chunk<K, V>(base: NodePtr<K, V>, n: usize) {
+    let slots =
+        ptr::slice_from_raw_parts_mut(base.as_ptr() as *mut MaybeUninit<Node<K, V>>, chunk_len(n));
+    drop(Box::from_raw(slots));
 }
 
 impl<K, V> Node<K, V> {
-    fn new(k: K, v: V) -> Self {
+    fn new(hash: u64, k: K, v: V) -> Self {
         Node {
             next: None,
             prev: None,
+            hash,
             k,
             v,
         }
@@ -64,18 +179,50 @@ impl<K, V> Node<K, V> {
 }
 
 pub struct LinkedHashMap<K, V, S = RandomState> {
-    head: Option<NonNull<Node<K, V>>>,
-    tail: Option<NonNull<Node<K, V>>>,
+    head: Option<Link<K, V>>,
+    tail: Option<Link<K, V>>,
 
-    empty: Option<NonNull<Node<K, V>>>,
+    // the free slots of the chunks, threaded through their `next` field
+    empty: Option<Link<K, V>>,
     empty_len: usize,
+    // the start of every chunk the nodes are allocated in, `None` for one
+    // freed by `shrink_to_fit` that has a chunk after it
+    chunks: Vec<Option<NodePtr<K, V>>>,
+    // the index of the first `None` in `chunks`, or its length
+    free_chunk: usize,
+
+    // indexes the nodes by the hash of their key
+    table: HashTable<Link<K, V>>,
+    hash_builder: S,
+
+    // how many entries were moved out of their node, wrapping
+    released: usize,
+}
 
-    map: HashMap<KeyPtr<K>, NonNull<Node<K, V>>, S>,
+/// Approximates the allocation of a `HashMap` of the given capacity: a
+/// power-of-two number of buckets, seven eighths of which may be used, each
+/// with a slot and a control byte.
+fn table_bytes<T>(capacity: usize) -> usize {
+    if capacity == 0 {
+        return 0;
+    }
+    let buckets = if capacity < 8 {
+        (capacity + 1).next_power_of_two()
+    } else {
+        (capacity * 8 / 7).next_power_of_two()
+    };
+    buckets * (mem::size_of::<T>() + 1)
 }
 
+/// Compares the hashes first, which spares comparing long keys that only
+/// share a bucket.
 #[inline]
-unsafe fn into_raw_non_null<T: ?Sized>(b: Box<T>) -> NonNull<T> {
-    NonNull::new_unchecked(Box::into_raw(b))
+fn matches<K, V, Q>(node: &Node<K, V>, hash: u64, k: &Q) -> bool
+where
+    K: Borrow<Q>,
+    Q: ?Sized + Eq,
+{
+    node.hash == hash && node.k.borrow() == k
 }
 
 impl<K: Hash + Eq, V> LinkedHashMap<K, V, RandomState> {
@@ -98,140 +245,249 @@ impl<K, V, S> LinkedHashMap<K, V, S> {
 
     #[inline]
     pub fn len(&self) -> usize {
-        self.map.len()
+        self.table.len()
     }
 
     #[inline]
     pub fn capacity(&self) -> usize {
-        self.map.capacity()
+        self.table.capacity()
+    }
+
+    /// Estimates the memory held by the map itself: the nodes of its
+    /// entries, the nodes kept for reuse and the hash table. Memory owned
+    /// by the keys and values is not included.
+    pub fn memory_usage(&self) -> MemoryUsage {
+        let node = mem::size_of::<Node<K, V>>();
+        MemoryUsage {
+            node_bytes: self.len() * node,
+            pooled_bytes: self.empty_len * node,
+            table_bytes: table_bytes::<Link<K, V>>(self.table.capacity()),
+            heap_bytes: None,
+        }
+    }
+
+    #[inline]
+    pub fn iter(&self) -> Iter<'_, K, V> {
+        Iter {
+            head: self.head,
+            tail: self.tail,
+            len: self.len(),
+            chunks: &self.chunks,
+            marker: PhantomData,
+        }
+    }
+
+    #[inline]
+    pub fn iter_mut(&mut self) -> IterMut<'_, K, V> {
+        IterMut {
+            head: self.head,
+            tail: self.tail,
+            len: self.len(),
+            chunks: &self.chunks,
+            marker: PhantomData,
+        }
     }
 
     #[inline]
     pub fn clear(&mut self) {
-        self.map.clear();
-        while let Some(_) = self.pop_front_node() {}
+        self.table.clear();
+        while let Some(node) = self.pop_front_node() {
+            unsafe { self.flush_node(node) };
+        }
         self.drop_empty();
     }
 
     #[inline]
-    fn push_front_node(&mut self, mut node: Box<Node<K, V>>) {
+    fn node(&self, link: Link<K, V>) -> NodePtr<K, V> {
+        node_at(&self.chunks, link)
+    }
+
+    #[inline]
+    fn push_front_node(&mut self, link: Link<K, V>) {
         unsafe {
+            let node = self.node(link).as_mut();
             node.next = self.head;
             node.prev = None;
-            let node = Some(into_raw_non_null(node));
 
             match self.head {
-                None => self.tail = node,
-                Some(mut head) => head.as_mut().prev = node,
+                None => self.tail = Some(link),
+                Some(head) => self.node(head).as_mut().prev = Some(link),
             }
 
-            self.head = node;
+            self.head = Some(link);
         }
     }
 
     #[inline]
-    fn pop_front_node(&mut self) -> Option<Box<Node<K, V>>> {
-        self.head.map(|node| unsafe {
-            let node = Box::from_raw(node.as_ptr());
-            self.head = node.next;
+    fn pop_front_node(&mut self) -> Option<Link<K, V>> {
+        self.head.inspect(|&link| unsafe {
+            self.head = self.node(link).as_ref().next;
 
             match self.head {
                 None => self.tail = None,
-                Some(mut head) => head.as_mut().prev = None,
+                Some(head) => self.node(head).as_mut().prev = None,
             }
-
-            node
         })
     }
 
     #[inline]
-    fn push_back_node(&mut self, mut node: Box<Node<K, V>>) {
+    fn push_back_node(&mut self, link: Link<K, V>) {
         unsafe {
+            let node = self.node(link).as_mut();
             node.next = None;
             node.prev = self.tail;
-            let node = Some(into_raw_non_null(node));
 
             match self.tail {
-                None => self.head = node,
-                Some(mut tail) => tail.as_mut().next = node,
+                None => self.head = Some(link),
+                Some(tail) => self.node(tail).as_mut().next = Some(link),
             }
 
-            self.tail = node;
+            self.tail = Some(link);
         }
     }
 
     #[inline]
-    fn pop_back_node(&mut self) -> Option<Box<Node<K, V>>> {
-        self.tail.map(|node| unsafe {
-            let node = Box::from_raw(node.as_ptr());
-            self.tail = node.prev;
+    fn pop_back_node(&mut self) -> Option<Link<K, V>> {
+        self.tail.inspect(|&link| unsafe {
+            self.tail = self.node(link).as_ref().prev;
 
             match self.tail {
                 None => self.head = None,
-                Some(mut tail) => tail.as_mut().next = None,
+                Some(tail) => self.node(tail).as_mut().next = None,
             }
-            node
         })
     }
 
     #[inline]
-    unsafe fn unlink_node(&mut self, mut node: NonNull<Node<K, V>>) {
-        let node = node.as_mut();
+    unsafe fn unlink_node(&mut self, link: Link<K, V>) {
+        let node = self.node(link).as_ref();
+        let (prev, next) = (node.prev, node.next);
 
-        match node.prev {
-            Some(mut prev) => prev.as_mut().next = node.next.clone(),
+        match prev {
+            Some(prev) => self.node(prev).as_mut().next = next,
             // this node is the head node
-            None => self.head = node.next.clone(),
+            None => self.head = next,
         };
 
-        match node.next {
-            Some(mut next) => next.as_mut().prev = node.prev.clone(),
+        match next {
+            Some(next) => self.node(next).as_mut().prev = prev,
             // this node is the tail node
-            None => self.tail = node.prev.clone(),
+            None => self.tail = prev,
         };
     }
 
+    /// Moves the entry out of an unlinked node and frees its slot.
     #[inline]
-    unsafe fn flush_node(&mut self, mut node: Box<Node<K, V>>) -> (K, V) {
-        node.as_mut().next = self.empty;
-        node.as_mut().prev = None;
-        let k = ptr::read(&node.k);
-        let v = ptr::read(&node.v);
-        self.empty = Some(into_raw_non_null(node));
-        self.empty_len += 1;
+    unsafe fn flush_node(&mut self, link: Link<K, V>) -> (K, V) {
+        let node = self.node(link).as_ptr();
+        let k = ptr::read(&(*node).k);
+        let v = ptr::read(&(*node).v);
+        self.push_free(link);
+        self.released = self.released.wrapping_add(1);
         (k, v)
     }
 
     #[inline]
-    fn new_node(&mut self, k: K, v: V) -> NonNull<Node<K, V>> {
-        match self.empty {
-            Some(empty) => {
-                let node = empty;
-                unsafe {
-                    self.empty = node.as_ref().next;
-                    ptr::write(node.as_ptr(), Node::new(k, v));
-                }
-                self.empty_len -= 1;
-                node
-            }
-            None => unsafe { into_raw_non_null(Box::new(Node::new(k, v))) },
-        }
+    fn new_node(&mut self, hash: u64, k: K, v: V) -> Link<K, V> {
+        let link = match self.pop_free() {
+            Some(link) => link,
+            None => self.grow(),
+        };
+        unsafe { ptr::write(self.node(link).as_ptr(), Node::new(hash, k, v)) };
+        link
     }
 
+    // only the `next` field of a free slot is initialized
     #[inline]
+    unsafe fn push_free(&mut self, link: Link<K, V>) {
+        ptr::addr_of_mut!((*self.node(link).as_ptr()).next).write(self.empty);
+        self.empty = Some(link);
+        self.empty_len += 1;
+    }
+
+    #[inline]
+    fn pop_free(&mut self) -> Option<Link<K, V>> {
+        let link = self.empty?;
+        self.empty = unsafe { ptr::addr_of!((*self.node(link).as_ptr()).next).read() };
+        self.empty_len -= 1;
+        Some(link)
+    }
+
+    /// Allocates a chunk of nodes in the first place free, returning its
+    /// first slot and freeing the others.
+    #[cold]
+    fn grow(&mut self) -> Link<K, V> {
+        let n = self.free_chunk;
+        let len = chunk_len(n);
+        let slots = Box::into_raw(Box::<[Node<K, V>]>::new_uninit_slice(len));
+        let base = unsafe { NonNull::new_unchecked(slots as *mut Node<K, V>) };
+        if n == self.chunks.len() {
+            self.chunks.push(Some(base));
+        } else {
+            self.chunks[n] = Some(base);
+        }
+        self.free_chunk = (n + 1..self.chunks.len())
+            .find(|&n| self.chunks[n].is_none())
+            .unwrap_or(self.chunks.len());
+        // freed backwards so that they are handed out in address order
+        for offset in (1..len).rev() {
+            unsafe { self.push_free(link_to(base, n, offset)) };
+        }
+        link_to(base, n, 0)
+    }
+
+    /// Frees the chunks none of whose slots are in use.
     fn drop_empty(&mut self) {
-        let mut count = 0;
-        unsafe {
-            while let Some(node) = self.empty {
-                count += 1;
-                self.empty = node.as_ref().next;
-                let Node { k, v, .. } = *Box::from_raw(node.as_ptr());
-                mem::forget(k);
-                mem::forget(v);
+        if self.head.is_none() {
+            for (n, base) in self.chunks.drain(..).enumerate() {
+                if let Some(base) = base {
+                    unsafe { free_chunk(base, n) };
+                }
+            }
+            self.empty = None;
+            self.empty_len = 0;
+            self.free_chunk = 0;
+            return;
+        }
+        let mut starts: Vec<(usize, usize)> = self
+            .chunks
+            .iter()
+            .enumerate()
+            .filter_map(|(n, base)| base.map(|base| (base.as_ptr() as usize, n)))
+            .collect();
+        starts.sort_unstable();
+        let chunk_of = |node: NodePtr<K, V>| {
+            let addr = node.as_ptr() as usize;
+            starts[starts.partition_point(|&(start, _)| start <= addr) - 1].1
+        };
+
+        let mut free = vec![0; self.chunks.len()];
+        let mut slots = Vec::with_capacity(self.empty_len);
+        while let Some(link) = self.pop_free() {
+            free[chunk_of(self.node(link))] += 1;
+            slots.push(link);
+        }
+        let unused: Vec<bool> = free
+            .iter()
+            .enumerate()
+            .map(|(n, &free)| free == chunk_len(n))
+            .collect();
+        for link in slots.into_iter().rev() {
+            if !unused[chunk_of(self.node(link))] {
+                unsafe { self.push_free(link) };
             }
         }
-        assert_eq!(count, self.empty_len);
-        self.empty_len = 0;
+        for (n, unused) in unused.into_iter().enumerate() {
+            if let (true, Some(base)) = (unused, self.chunks[n]) {
+                self.chunks[n] = None;
+                self.free_chunk = self.free_chunk.min(n);
+                unsafe { free_chunk(base, n) };
+            }
+        }
+        while let Some(None) = self.chunks.last() {
+            self.chunks.pop();
+        }
+        self.free_chunk = self.free_chunk.min(self.chunks.len());
     }
 }
 
@@ -254,18 +510,15 @@ where
 {
     #[inline]
     pub fn reserve(&mut self, additional: usize) {
-        self.map.reserve(additional);
+        let chunks = &self.chunks;
+        self.table.reserve(additional, |&link| unsafe {
+            node_at(chunks, link).as_ref().hash
+        });
     }
 
     #[inline]
     pub fn with_hasher(hash_builder: S) -> LinkedHashMap<K, V, S> {
-        LinkedHashMap {
-            head: None,
-            tail: None,
-            empty: None,
-            empty_len: 0,
-            map: HashMap::with_hasher(hash_builder),
-        }
+        LinkedHashMap::with_capacity_and_hasher(0, hash_builder)
     }
 
     pub fn with_capacity_and_hasher(capacity: usize, hash_builder: S) -> LinkedHashMap<K, V, S> {
@@ -274,176 +527,741 @@ where
             tail: None,
             empty: None,
             empty_len: 0,
-            map: HashMap::with_capacity_and_hasher(capacity, hash_builder),
+            chunks: Vec::new(),
+            free_chunk: 0,
+            table: HashTable::with_capacity(capacity),
+            hash_builder,
+            released: 0,
         }
     }
 
     pub fn hasher(&self) -> &S {
-        self.map.hasher()
+        &self.hash_builder
     }
 
-    pub fn contains_key<Q: ?Sized>(&self, k: &Q) -> bool
+    /// Counts, wrapping, the entries ever moved out of their node: those
+    /// removed, and the old ones of a key inserted again. A value stays at
+    /// its address until then.
+    pub(crate) fn released(&self) -> usize {
+        self.released
+    }
+
+    /// Returns the hash of `k` the map indexes its entry by, for the
+    /// `*_hashed` methods. Those trust the hash they are given, so they stay
+    /// within the crate.
+    #[inline]
+    pub(crate) fn hash<Q>(&self, k: &Q) -> u64
+    where
+        Q: ?Sized + Hash,
+    {
+        self.hash_builder.hash_one(k)
+    }
+
+    #[inline]
+    fn find<Q>(&self, hash: u64, k: &Q) -> Option<Link<K, V>>
     where
         K: Borrow<Q>,
-        Q: Hash + Eq,
+        Q: ?Sized + Eq,
     {
-        self.map.contains_key(KeyRef::new(k))
+        self.table
+            .find(hash, |&link| unsafe {
+                matches(self.node(link).as_ref(), hash, k)
+            })
+            .copied()
     }
 
+    /// Takes the node of `k` out of the table, leaving it linked.
     #[inline]
-    pub fn front(&self) -> Option<(&K, &V)> {
-        unsafe {
-            self.head
-                .as_ref()
-                .map(|node| (&node.as_ref().k, &node.as_ref().v))
+    fn take<Q>(&mut self, hash: u64, k: &Q) -> Option<Link<K, V>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        let chunks = &self.chunks;
+        let entry = self.table.find_entry(hash, |&link| unsafe {
+            matches(node_at(chunks, link).as_ref(), hash, k)
+        });
+        entry.ok().map(|entry| entry.remove().0)
+    }
+
+    /// Takes `node`, which must be in the table, out of it.
+    #[inline]
+    fn unindex(&mut self, link: Link<K, V>) {
+        let hash = unsafe { self.node(link).as_ref().hash };
+        if let Ok(entry) = self.table.find_entry(hash, |&other| other == link) {
+            entry.remove();
         }
     }
 
+    pub fn contains_key<Q>(&self, k: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self.contains_key_hashed(self.hash(k), k)
+    }
+
+    /// Like `contains_key`, with the hash of `k` already computed by `hash`.
+    pub(crate) fn contains_key_hashed<Q>(&self, hash: u64, k: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        self.find(hash, k).is_some()
+    }
+
+    #[inline]
+    pub fn front(&self) -> Option<(&K, &V)> {
+        self.head.map(|link| unsafe {
+            let node = &*self.node(link).as_ptr();
+            (&node.k, &node.v)
+        })
+    }
+
     #[inline]
     pub fn front_mut(&mut self) -> Option<&mut V> {
-        unsafe { self.head.as_mut().map(|node| &mut node.as_mut().v) }
+        self.head
+            .map(|link| unsafe { &mut (*self.node(link).as_ptr()).v })
     }
 
     #[inline]
     pub fn back(&self) -> Option<(&K, &V)> {
-        unsafe {
-            self.tail
-                .as_ref()
-                .map(|node| (&node.as_ref().k, &node.as_ref().v))
-        }
+        self.tail.map(|link| unsafe {
+            let node = &*self.node(link).as_ptr();
+            (&node.k, &node.v)
+        })
     }
 
     #[inline]
     pub fn back_mut(&mut self) -> Option<&mut V> {
-        unsafe { self.tail.as_mut().map(|node| &mut node.as_mut().v) }
+        self.tail
+            .map(|link| unsafe { &mut (*self.node(link).as_ptr()).v })
     }
 
     pub fn pop_front(&mut self) -> Option<(K, V)> {
         let node = self.pop_front_node()?;
-        self.map.remove(&KeyPtr::from(&node.k));
+        self.unindex(node);
         Some(unsafe { self.flush_node(node) })
     }
 
     pub fn pop_back(&mut self) -> Option<(K, V)> {
         let node = self.pop_back_node()?;
-        self.map.remove(&KeyPtr::from(&node.k));
+        self.unindex(node);
         Some(unsafe { self.flush_node(node) })
     }
 
-    pub fn get_key_value<Q: ?Sized>(&self, k: &Q) -> Option<(&K, &V)>
+    /// Removes the entry closest to the back, among at most `limit` entries
+    /// from the back, for which `f` returns true.
+    pub fn pop_back_matching<F>(&mut self, limit: usize, mut f: F) -> Option<(K, V)>
+    where
+        F: FnMut(&K, &V) -> bool,
+    {
+        let mut cur = self.tail;
+        for _ in 0..limit {
+            let node = cur?;
+            unsafe {
+                let n = self.node(node).as_ref();
+                if f(&n.k, &n.v) {
+                    self.unlink_node(node);
+                    self.unindex(node);
+                    return Some(self.flush_node(node));
+                }
+                cur = n.prev;
+            }
+        }
+        None
+    }
+
+    pub fn get_key_value<Q>(&self, k: &Q) -> Option<(&K, &V)>
     where
         K: Borrow<Q>,
-        Q: Hash + Eq,
+        Q: ?Sized + Hash + Eq,
     {
-        self.map
-            .get(KeyRef::new(k))
-            .map(|node| unsafe { (&node.as_ref().k, &node.as_ref().v) })
+        self.get_key_value_hashed(self.hash(k), k)
+    }
+
+    /// Like `get_key_value`, with the hash of `k` already computed by `hash`.
+    pub(crate) fn get_key_value_hashed<Q>(&self, hash: u64, k: &Q) -> Option<(&K, &V)>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        self.find(hash, k).map(|node| unsafe {
+            let node = &*self.node(node).as_ptr();
+            (&node.k, &node.v)
+        })
+    }
+
+    /// Iterates from the entry of `k` to the back, or returns `None` if `k`
+    /// is not in the map. Starting again from the last key seen resumes an
+    /// earlier walk, as long as that key is still there.
+    pub fn iter_from<Q>(&self, k: &Q) -> Option<IterFrom<'_, K, V>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self.walk_from(k, true)
+    }
+
+    /// Like `iter_from`, but walks from the entry of `k` to the front.
+    pub fn iter_rev_from<Q>(&self, k: &Q) -> Option<IterFrom<'_, K, V>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self.walk_from(k, false)
+    }
+
+    fn walk_from<Q>(&self, k: &Q, backward: bool) -> Option<IterFrom<'_, K, V>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let start = self.find(self.hash(k), k)?;
+        Some(IterFrom {
+            next: Some(start),
+            backward,
+            chunks: &self.chunks,
+            marker: PhantomData,
+        })
     }
 
-    pub fn get<Q: ?Sized>(&self, k: &Q) -> Option<&V>
+    pub fn get<Q>(&self, k: &Q) -> Option<&V>
     where
         K: Borrow<Q>,
-        Q: Hash + Eq,
+        Q: ?Sized + Hash + Eq,
     {
-        self.map
-            .get(KeyRef::new(k))
-            .map(|node| unsafe { &node.as_ref().v })
+        self.get_hashed(self.hash(k), k)
     }
 
-    pub fn get_mut<Q: ?Sized>(&mut self, k: &Q) -> Option<&mut V>
+    /// Like `get`, with the hash of `k` already computed by `hash`.
+    pub(crate) fn get_hashed<Q>(&self, hash: u64, k: &Q) -> Option<&V>
     where
         K: Borrow<Q>,
-        Q: Hash + Eq,
+        Q: ?Sized + Eq,
     {
-        let node = self.map.get_mut(KeyRef::new(k))?;
-        Some(unsafe { &mut node.as_mut().v })
+        self.find(hash, k)
+            .map(|node| unsafe { &(*self.node(node).as_ptr()).v })
     }
 
-    pub fn move_to_front<Q: ?Sized>(&mut self, k: &Q) -> bool
+    pub fn get_mut<Q>(&mut self, k: &Q) -> Option<&mut V>
     where
         K: Borrow<Q>,
-        Q: Hash + Eq,
+        Q: ?Sized + Hash + Eq,
     {
-        let node = match self.map.get_mut(KeyRef::new(k)) {
-            Some(node) => node.clone(),
+        self.get_mut_hashed(self.hash(k), k)
+    }
+
+    /// Like `get_mut`, with the hash of `k` already computed by `hash`.
+    pub(crate) fn get_mut_hashed<Q>(&mut self, hash: u64, k: &Q) -> Option<&mut V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        let node = self.find(hash, k)?;
+        Some(unsafe { &mut (*self.node(node).as_ptr()).v })
+    }
+
+    pub fn move_to_front<Q>(&mut self, k: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self.move_to_front_hashed(self.hash(k), k)
+    }
+
+    /// Like `move_to_front`, with the hash of `k` already computed by
+    /// `hash`.
+    pub(crate) fn move_to_front_hashed<Q>(&mut self, hash: u64, k: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        let node = match self.find(hash, k) {
+            Some(node) => node,
             None => return false,
         };
         unsafe {
             self.unlink_node(node);
-            self.push_front_node(Box::from_raw(node.as_ptr()));
+            self.push_front_node(node);
         }
         true
     }
 
-    pub fn move_to_back<Q: ?Sized>(&mut self, k: &Q) -> bool
+    /// Moves the entry of `k` to the front and returns it, locating its node
+    /// only once.
+    pub fn get_refresh<Q>(&mut self, k: &Q) -> Option<(&K, &V)>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self.get_refresh_hashed(self.hash(k), k)
+    }
+
+    /// Like `get_refresh`, with the hash of `k` already computed by `hash`.
+    pub(crate) fn get_refresh_hashed<Q>(&mut self, hash: u64, k: &Q) -> Option<(&K, &V)>
     where
         K: Borrow<Q>,
-        Q: Hash + Eq,
+        Q: ?Sized + Eq,
     {
-        let node = match self.map.get_mut(KeyRef::new(k)) {
-            Some(node) => node.clone(),
+        let node = self.find(hash, k)?;
+        unsafe {
+            self.unlink_node(node);
+            self.push_front_node(node);
+            let node = &*self.node(node).as_ptr();
+            Some((&node.k, &node.v))
+        }
+    }
+
+    pub fn move_to_back<Q>(&mut self, k: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self.move_to_back_hashed(self.hash(k), k)
+    }
+
+    /// Like `move_to_back`, with the hash of `k` already computed by `hash`.
+    pub(crate) fn move_to_back_hashed<Q>(&mut self, hash: u64, k: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        let node = match self.find(hash, k) {
+            Some(node) => node,
             None => return false,
         };
         unsafe {
             self.unlink_node(node);
-            self.push_back_node(Box::from_raw(node.as_ptr()));
+            self.push_back_node(node);
         }
         true
     }
 
     pub fn shrink_to_fit(&mut self) {
-        self.map.shrink_to_fit();
+        let chunks = &self.chunks;
+        self.table
+            .shrink_to_fit(|&link| unsafe { node_at(chunks, link).as_ref().hash });
         self.drop_empty();
     }
 
-    pub fn remove<Q: ?Sized>(&mut self, k: &Q) -> Option<V>
+    pub fn remove<Q>(&mut self, k: &Q) -> Option<V>
     where
         K: Borrow<Q>,
-        Q: Hash + Eq,
+        Q: ?Sized + Hash + Eq,
     {
-        self.map.remove(KeyRef::new(k)).map(|node| unsafe {
-            self.unlink_node(node);
-            let (_, v) = self.flush_node(Box::from_raw(node.as_ptr()));
-            v
-        })
+        self.remove_entry(k).map(|(_, v)| v)
+    }
+
+    pub fn remove_entry<Q>(&mut self, k: &Q) -> Option<(K, V)>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self.remove_entry_hashed(self.hash(k), k)
     }
 
-    pub fn remove_entry<Q: ?Sized>(&mut self, k: &Q) -> Option<(K, V)>
+    /// Like `remove_entry`, with the hash of `k` already computed by `hash`.
+    pub(crate) fn remove_entry_hashed<Q>(&mut self, hash: u64, k: &Q) -> Option<(K, V)>
     where
         K: Borrow<Q>,
-        Q: Hash + Eq,
+        Q: ?Sized + Eq,
     {
-        self.map.remove(KeyRef::new(k)).map(|node| unsafe {
+        self.take(hash, k).map(|node| unsafe {
             self.unlink_node(node);
-            self.flush_node(Box::from_raw(node.as_ptr()))
+            self.flush_node(node)
         })
     }
 
-    fn insert(&mut self, k: K, v: V) -> (Box<Node<K, V>>, Option<V>) {
-        unsafe {
-            let (node, old_v) = match self.map.get_mut(&KeyPtr::from(&k)) {
-                Some(node) => (*node, Some(ptr::replace(&mut node.as_mut().v, v))),
-                None => (self.new_node(k, v), None),
-            };
-            if old_v.is_some() {
+    /// Removes the entries for which `f` returns true and returns them,
+    /// from front to back.
+    pub fn drain_where<F>(&mut self, mut f: F) -> Vec<(K, V)>
+    where
+        F: FnMut(&K, &V) -> bool,
+    {
+        let mut drained = Vec::new();
+        let mut cur = self.head;
+        while let Some(node) = cur {
+            unsafe {
+                let n = self.node(node).as_ref();
+                cur = n.next;
+                if !f(&n.k, &n.v) {
+                    continue;
+                }
+                self.unindex(node);
                 self.unlink_node(node);
+                drained.push(self.flush_node(node));
+            }
+        }
+        drained
+    }
+
+    /// Moves the entries of `other`, given from front to back, in among the
+    /// entries of the map, interleaving the two by their relative position:
+    /// the entry a third of the way down `other` ends up next to the one a
+    /// third of the way down the map. Of a key in both, the entry nearer its
+    /// own front is kept and the other one returned, among the dropped
+    /// entries of the map or those of `other`, in that order.
+    pub fn merge(&mut self, other: Vec<(K, V)>) -> Dropped<K, V> {
+        let mine = self.drain_where(|_, _| true);
+        let (a_len, b_len) = (mine.len(), other.len());
+        let (mut a, mut b) = (mine.into_iter().peekable(), other.into_iter().peekable());
+        let (mut i, mut j) = (0, 0);
+        let (mut dropped_mine, mut dropped_theirs) = (Vec::new(), Vec::new());
+        loop {
+            // compares the midpoints of the ranks, (i + 1/2) / a_len against
+            // (j + 1/2) / b_len
+            let take_a = match (a.peek(), b.peek()) {
+                (None, None) => break,
+                (Some(_), None) => true,
+                (None, Some(_)) => false,
+                (Some(_), Some(_)) => (2 * i + 1) * b_len <= (2 * j + 1) * a_len,
+            };
+            let next = if take_a {
+                i += 1;
+                a.next()
             } else {
-                self.map.insert(KeyPtr::from(&node.as_ref().k), node);
+                j += 1;
+                b.next()
+            };
+            if let Some((k, v)) = next {
+                if !self.contains_key(&k) {
+                    self.push_back(k, v);
+                } else if take_a {
+                    dropped_mine.push((k, v));
+                } else {
+                    dropped_theirs.push((k, v));
+                }
             }
-            (Box::from_raw(node.as_ptr()), old_v)
         }
+        (dropped_mine, dropped_theirs)
+    }
+
+    /// Links a new node for `k` into the table, or moves `v` into the node
+    /// already there, comparing keys in a single lookup either way. A node
+    /// is only allocated for a new key. It is left unlinked from the list in
+    /// the first case; the node found in the second stays linked, and the
+    /// key passed in is handed back with the old value.
+    fn insert(&mut self, hash: u64, k: K, v: V) -> (Link<K, V>, Option<(K, V)>) {
+        if let Some(existing) = self.find(hash, &k) {
+            let old_v = unsafe { mem::replace(&mut self.node(existing).as_mut().v, v) };
+            return (existing, Some((k, old_v)));
+        }
+        let link = self.new_node(hash, k, v);
+        let chunks = &self.chunks;
+        // the key is known to be new, so only a free slot is looked for
+        self.table.insert_unique(hash, link, |&other| unsafe {
+            node_at(chunks, other).as_ref().hash
+        });
+        (link, None)
     }
 
     pub fn push_front(&mut self, k: K, v: V) -> Option<V> {
-        let (node, old_v) = self.insert(k, v);
-        self.push_front_node(node);
-        old_v
+        self.push_front_hashed(self.hash(&k), k, v)
+    }
+
+    /// Like `push_front`, with the hash of `k` already computed by `hash`.
+    pub(crate) fn push_front_hashed(&mut self, hash: u64, k: K, v: V) -> Option<V> {
+        let (node, replaced) = self.insert(hash, k, v);
+        unsafe {
+            if replaced.is_some() {
+                self.unlink_node(node);
+            }
+            self.push_front_node(node);
+        }
+        replaced.map(|(_, old_v)| old_v)
     }
 
     pub fn push_back(&mut self, k: K, v: V) -> Option<V> {
-        let (node, old_v) = self.insert(k, v);
-        self.push_back_node(node);
-        old_v
+        self.push_back_hashed(self.hash(&k), k, v)
+    }
+
+    /// Like `push_back`, with the hash of `k` already computed by `hash`.
+    pub(crate) fn push_back_hashed(&mut self, hash: u64, k: K, v: V) -> Option<V> {
+        let (node, replaced) = self.insert(hash, k, v);
+        unsafe {
+            if replaced.is_some() {
+                self.unlink_node(node);
+            }
+            self.push_back_node(node);
+        }
+        replaced.map(|(_, old_v)| old_v)
+    }
+
+    /// Inserts `k` at the front, or replaces the value of the entry already
+    /// there, moving it to the front only if `refresh` is set. The entry is
+    /// found with a single lookup either way. A replacement hands back the
+    /// key passed in together with the old value.
+    pub fn upsert_front(&mut self, k: K, v: V, refresh: bool) -> Option<(K, V)> {
+        self.upsert_front_hashed(self.hash(&k), k, v, refresh)
+    }
+
+    /// Like `upsert_front`, with the hash of `k` already computed by `hash`.
+    pub(crate) fn upsert_front_hashed(
+        &mut self,
+        hash: u64,
+        k: K,
+        v: V,
+        refresh: bool,
+    ) -> Option<(K, V)> {
+        let (node, replaced) = self.insert(hash, k, v);
+        unsafe {
+            match replaced {
+                Some(_) if !refresh => {}
+                Some(_) => {
+                    self.unlink_node(node);
+                    self.push_front_node(node);
+                }
+                None => self.push_front_node(node),
+            }
+        }
+        replaced
+    }
+
+    /// Replaces the value of `k` and moves its entry to the front, or hands
+    /// `v` back if there is no such entry, with a single lookup.
+    pub fn replace_refresh<Q>(&mut self, k: &Q, v: V) -> Result<V, V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self.replace_refresh_hashed(self.hash(k), k, v)
+    }
+
+    /// Like `replace_refresh`, with the hash of `k` already computed by
+    /// `hash`.
+    pub(crate) fn replace_refresh_hashed<Q>(&mut self, hash: u64, k: &Q, v: V) -> Result<V, V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        let node = match self.find(hash, k) {
+            Some(node) => node,
+            None => return Err(v),
+        };
+        unsafe {
+            let old_v = mem::replace(&mut self.node(node).as_mut().v, v);
+            self.unlink_node(node);
+            self.push_front_node(node);
+            Ok(old_v)
+        }
+    }
+}
+
+pub struct Iter<'a, K, V> {
+    head: Option<Link<K, V>>,
+    tail: Option<Link<K, V>>,
+    len: usize,
+    chunks: &'a [Option<NodePtr<K, V>>],
+    marker: PhantomData<&'a Node<K, V>>,
+}
+
+impl<'a, K, V> Iterator for Iter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    #[inline]
+    fn next(&mut self) -> Option<(&'a K, &'a V)> {
+        if self.len == 0 {
+            return None;
+        }
+        self.head.map(|node| unsafe {
+            let node = &*node_at(self.chunks, node).as_ptr();
+            self.len -= 1;
+            self.head = node.next;
+            (&node.k, &node.v)
+        })
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.len, Some(self.len))
+    }
+}
+
+impl<'a, K, V> DoubleEndedIterator for Iter<'a, K, V> {
+    #[inline]
+    fn next_back(&mut self) -> Option<(&'a K, &'a V)> {
+        if self.len == 0 {
+            return None;
+        }
+        self.tail.map(|node| unsafe {
+            let node = &*node_at(self.chunks, node).as_ptr();
+            self.len -= 1;
+            self.tail = node.prev;
+            (&node.k, &node.v)
+        })
+    }
+}
+
+impl<'a, K, V> ExactSizeIterator for Iter<'a, K, V> {}
+
+impl<'a, K, V> Default for Iter<'a, K, V> {
+    fn default() -> Self {
+        Iter {
+            head: None,
+            tail: None,
+            len: 0,
+            chunks: &[],
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<'a, K, V> Clone for Iter<'a, K, V> {
+    fn clone(&self) -> Self {
+        Iter { ..*self }
+    }
+}
+
+/// An iterator from a given entry to the back or front of a map, as
+/// returned by `iter_from` and `iter_rev_from`.
+pub struct IterFrom<'a, K, V> {
+    next: Option<Link<K, V>>,
+    // walking towards the back, following `next` links
+    backward: bool,
+    chunks: &'a [Option<NodePtr<K, V>>],
+    marker: PhantomData<&'a Node<K, V>>,
+}
+
+impl<'a, K, V> Iterator for IterFrom<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    #[inline]
+    fn next(&mut self) -> Option<(&'a K, &'a V)> {
+        self.next.map(|node| unsafe {
+            let node = &*node_at(self.chunks, node).as_ptr();
+            self.next = if self.backward { node.next } else { node.prev };
+            (&node.k, &node.v)
+        })
+    }
+}
+
+impl<'a, K, V> Default for IterFrom<'a, K, V> {
+    fn default() -> Self {
+        IterFrom {
+            next: None,
+            backward: true,
+            chunks: &[],
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<'a, K, V> Clone for IterFrom<'a, K, V> {
+    fn clone(&self) -> Self {
+        IterFrom { ..*self }
+    }
+}
+
+pub struct IterMut<'a, K, V> {
+    head: Option<Link<K, V>>,
+    tail: Option<Link<K, V>>,
+    len: usize,
+    chunks: &'a [Option<NodePtr<K, V>>],
+    marker: PhantomData<&'a mut Node<K, V>>,
+}
+
+impl<'a, K, V> Iterator for IterMut<'a, K, V> {
+    type Item = (&'a K, &'a mut V);
+
+    #[inline]
+    fn next(&mut self) -> Option<(&'a K, &'a mut V)> {
+        if self.len == 0 {
+            return None;
+        }
+        self.head.map(|node| unsafe {
+            let node = &mut *node_at(self.chunks, node).as_ptr();
+            self.len -= 1;
+            self.head = node.next;
+            (&node.k, &mut node.v)
+        })
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.len, Some(self.len))
+    }
+}
+
+impl<'a, K, V> DoubleEndedIterator for IterMut<'a, K, V> {
+    #[inline]
+    fn next_back(&mut self) -> Option<(&'a K, &'a mut V)> {
+        if self.len == 0 {
+            return None;
+        }
+        self.tail.map(|node| unsafe {
+            let node = &mut *node_at(self.chunks, node).as_ptr();
+            self.len -= 1;
+            self.tail = node.prev;
+            (&node.k, &mut node.v)
+        })
+    }
+}
+
+impl<'a, K, V> ExactSizeIterator for IterMut<'a, K, V> {}
+
+pub struct IntoIter<K, V, S = RandomState> {
+    len: usize,
+    l_map: LinkedHashMap<K, V, S>,
+}
+
+impl<K, V, S> Iterator for IntoIter<K, V, S> {
+    type Item = (K, V);
+
+    #[inline]
+    fn next(&mut self) -> Option<(K, V)> {
+        let node = self.l_map.pop_front_node()?;
+        self.len -= 1;
+        Some(unsafe { self.l_map.flush_node(node) })
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.len, Some(self.len))
+    }
+}
+
+impl<K, V, S> DoubleEndedIterator for IntoIter<K, V, S> {
+    #[inline]
+    fn next_back(&mut self) -> Option<(K, V)> {
+        let node = self.l_map.pop_back_node()?;
+        self.len -= 1;
+        Some(unsafe { self.l_map.flush_node(node) })
+    }
+}
+
+impl<K, V, S> ExactSizeIterator for IntoIter<K, V, S> {}
+
+impl<K, V, S> IntoIterator for LinkedHashMap<K, V, S> {
+    type Item = (K, V);
+    type IntoIter = IntoIter<K, V, S>;
+
+    #[inline]
+    fn into_iter(mut self) -> IntoIter<K, V, S> {
+        let len = self.len();
+        // the index only points into the nodes, so it can go first
+        self.table.clear();
+        IntoIter { len, l_map: self }
+    }
+}
+
+impl<'a, K, V, S> IntoIterator for &'a LinkedHashMap<K, V, S> {
+    type Item = (&'a K, &'a V);
+    type IntoIter = Iter<'a, K, V>;
+
+    #[inline]
+    fn into_iter(self) -> Iter<'a, K, V> {
+        self.iter()
+    }
+}
+
+impl<'a, K, V, S> IntoIterator for &'a mut LinkedHashMap<K, V, S> {
+    type Item = (&'a K, &'a mut V);
+    type IntoIter = IterMut<'a, K, V>;
+
+    #[inline]
+    fn into_iter(self) -> IterMut<'a, K, V> {
+        self.iter_mut()
     }
 }
 
@@ -453,22 +1271,36 @@ unsafe impl<K: Sync, V: Sync, S: Sync> Sync for LinkedHashMap<K, V, S> {}
 
 impl<K, V, S> Drop for LinkedHashMap<K, V, S> {
     fn drop(&mut self) {
-        while let Some(_) = self.pop_front_node() {}
+        while let Some(node) = self.pop_front_node() {
+            unsafe { self.flush_node(node) };
+        }
         self.drop_empty();
     }
 }
 
+/// Serializes as a sequence of key-value pairs from front to back.
+#[cfg(feature = "serde")]
+impl<K, V, S> serde::Serialize for LinkedHashMap<K, V, S>
+where
+    K: serde::Serialize,
+    V: serde::Serialize,
+{
+    fn serialize<T: serde::Serializer>(&self, serializer: T) -> Result<T::Ok, T::Error> {
+        serializer.collect_seq(self.iter())
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
 
     #[test]
     fn test_common() {
-        type LHM = LinkedHashMap<i32, i32>;
-        let mut m = LHM::new();
+        type Lhm = LinkedHashMap<i32, i32>;
+        let mut m = Lhm::new();
         assert_eq!(m.capacity(), 0);
         assert!(m.is_empty());
-        assert!(m.map.is_empty());
+        assert!(m.table.is_empty());
 
         assert_eq!(m.push_front(1, 1), None);
         assert_eq!(m.push_front(2, 2), None);
@@ -489,7 +1321,9 @@ mod test {
         *el = 6;
         assert_eq!(m.get(&5), Some(&6));
 
-        assert_eq!(m.empty_len, 0);
+        // chunks of 1, 2 and 4 nodes
+        assert_eq!(m.chunks.len(), 3);
+        assert_eq!(m.empty_len, 2);
         assert_eq!(m.len(), 5);
 
         assert_eq!(m.pop_back(), Some((1, 1)));
@@ -507,26 +1341,63 @@ mod test {
         assert_eq!(m.back(), Some((&3, &3)));
         assert_eq!(m.front(), Some((&3, &3)));
 
-        assert_eq!(m.empty_len, 4);
+        assert_eq!(m.empty_len, 6);
         assert!(m.empty.is_some());
         assert_eq!(m.len(), 1);
+        let memory = m.memory_usage();
+        assert_eq!(memory.node_bytes, mem::size_of::<Node<i32, i32>>());
+        assert_eq!(memory.pooled_bytes, 6 * memory.node_bytes);
+        assert!(memory.table_bytes > 0);
 
+        // the chunk of 3 is kept, with its other slot, and the place of the
+        // first one before it
         m.shrink_to_fit();
-        assert_eq!(m.empty_len, 0);
+        assert_eq!(m.chunks.len(), 2);
+        assert!(m.chunks[0].is_none());
+        assert_eq!(m.empty_len, 1);
+        assert_eq!(m.get(&3), Some(&3));
+        assert_eq!(m.push_front(6, 6), None);
         assert!(m.empty.is_none());
+        assert_eq!(m.push_front(7, 7), None);
+        assert!(m.chunks[0].is_some());
+        assert_eq!(m.get(&3), Some(&3));
 
         m.clear();
         assert!(m.is_empty());
-        assert!(m.map.is_empty());
+        assert!(m.table.is_empty());
+        assert!(m.chunks.is_empty());
+    }
+
+    #[test]
+    fn test_replace_without_node() {
+        let mut m = LinkedHashMap::new();
+        assert_eq!(m.push_back(1, 1), None);
+        assert_eq!(m.empty_len, 0);
+        // the only slot is in use, and replacing needs no other
+        assert_eq!(m.push_front(1, 2), Some(1));
+        assert_eq!(m.upsert_front(1, 3, false), Some((1, 2)));
+        assert_eq!(m.chunks.len(), 1);
+        assert_eq!(m.empty_len, 0);
+        assert_eq!(m.get(&1), Some(&3));
+    }
+
+    #[test]
+    fn test_link_size() {
+        let link = mem::size_of::<Option<Link<i32, i32>>>();
+        if cfg!(feature = "compact") {
+            assert_eq!(link, 4);
+        } else {
+            assert_eq!(link, mem::size_of::<usize>());
+        }
     }
 
     #[test]
     fn test_move_to_front() {
-        type LHM = LinkedHashMap<i32, i32>;
-        let mut m = LHM::new();
+        type Lhm = LinkedHashMap<i32, i32>;
+        let mut m = Lhm::new();
         assert_eq!(m.capacity(), 0);
         assert!(m.is_empty());
-        assert!(m.map.is_empty());
+        assert!(m.table.is_empty());
 
         assert_eq!(m.push_front(1, 1), None);
         assert_eq!(m.push_front(2, 2), None);
@@ -554,4 +1425,136 @@ mod test {
         assert_eq!(m.front(), Some((&5, &5)));
         assert_eq!(m.back(), Some((&1, &1)));
     }
+
+    #[test]
+    fn test_upsert_front() {
+        let mut m: LinkedHashMap<i32, i32> = LinkedHashMap::new();
+        assert_eq!(m.upsert_front(1, 10, false), None);
+        assert_eq!(m.upsert_front(2, 20, false), None);
+        assert_eq!(m.upsert_front(1, 11, false), Some((1, 10)));
+        assert_eq!(m.front(), Some((&2, &20)));
+        assert_eq!(m.upsert_front(1, 12, true), Some((1, 11)));
+        assert_eq!(m.front(), Some((&1, &12)));
+        assert_eq!(m.len(), 2);
+
+        assert_eq!(m.replace_refresh(&2, 21), Ok(20));
+        assert_eq!(m.front(), Some((&2, &21)));
+        assert_eq!(m.replace_refresh(&3, 30), Err(30));
+        let entries: Vec<_> = m.iter().map(|(&k, &v)| (k, v)).collect();
+        assert_eq!(entries, vec![(2, 21), (1, 12)]);
+    }
+
+    #[test]
+    fn test_get_refresh() {
+        let mut m: LinkedHashMap<i32, i32> = LinkedHashMap::new();
+        assert_eq!(m.get_refresh(&1), None);
+        m.push_front(1, 10);
+        m.push_front(2, 20);
+        m.push_front(3, 30);
+
+        assert_eq!(m.get_refresh(&1), Some((&1, &10)));
+        assert_eq!(m.front(), Some((&1, &10)));
+        assert_eq!(m.back(), Some((&2, &20)));
+        assert_eq!(m.get_refresh(&1), Some((&1, &10)));
+        assert_eq!(m.len(), 3);
+        let keys: Vec<_> = m.iter().map(|(&k, _)| k).collect();
+        assert_eq!(keys, vec![1, 3, 2]);
+    }
+
+    #[test]
+    fn test_stored_hash() {
+        let mut m: LinkedHashMap<i32, i32> = LinkedHashMap::new();
+        for i in 0..100 {
+            m.push_front(i, i);
+        }
+        for i in 0..50 {
+            m.remove(&(2 * i));
+        }
+        m.shrink_to_fit();
+        for (k, v) in m.iter() {
+            let node = m.find(m.hash(k), k).unwrap();
+            assert_eq!(unsafe { m.node(node).as_ref().hash }, m.hash(k));
+            assert_eq!(m.get(k), Some(v));
+        }
+        assert_eq!(m.len(), 50);
+    }
+
+    #[test]
+    fn test_iter_from() {
+        let mut m: LinkedHashMap<i32, i32> = LinkedHashMap::new();
+        for i in 0..5 {
+            m.push_back(i, i * 10);
+        }
+        let keys: Vec<_> = m.iter_from(&2).unwrap().map(|(&k, _)| k).collect();
+        assert_eq!(keys, vec![2, 3, 4]);
+        let keys: Vec<_> = m.iter_rev_from(&2).unwrap().map(|(&k, _)| k).collect();
+        assert_eq!(keys, vec![2, 1, 0]);
+        assert!(m.iter_from(&5).is_none());
+
+        // a paginated walk
+        let mut pages = vec![m.iter().take(2).map(|(&k, _)| k).collect::<Vec<_>>()];
+        while let Some(&last) = pages.last().unwrap().last() {
+            let page = m.iter_from(&last).unwrap().skip(1).take(2);
+            pages.push(page.map(|(&k, _)| k).collect());
+        }
+        assert_eq!(pages, vec![vec![0, 1], vec![2, 3], vec![4], vec![]]);
+    }
+
+    #[test]
+    fn test_pop_back_matching() {
+        type Lhm = LinkedHashMap<i32, i32>;
+        let mut m = Lhm::new();
+        for i in 1..6 {
+            m.push_front(i, i);
+        }
+
+        assert_eq!(m.pop_back_matching(3, |k, _| *k % 2 == 0), Some((2, 2)));
+        assert_eq!(m.pop_back_matching(2, |k, _| *k > 4), None);
+        assert_eq!(m.pop_back_matching(10, |k, _| *k > 4), Some((5, 5)));
+        assert_eq!(m.len(), 3);
+        assert_eq!(m.back(), Some((&1, &1)));
+        assert_eq!(m.front(), Some((&4, &4)));
+    }
+
+    #[test]
+    fn test_drain_where() {
+        type Lhm = LinkedHashMap<i32, i32>;
+        let mut m = Lhm::new();
+        for i in 1..7 {
+            m.push_front(i, i);
+        }
+
+        assert_eq!(
+            m.drain_where(|k, _| *k % 3 != 0),
+            vec![(5, 5), (4, 4), (2, 2), (1, 1)]
+        );
+        assert_eq!(m.len(), 2);
+        assert!(!m.contains_key(&1));
+        assert_eq!(m.iter().collect::<Vec<_>>(), vec![(&6, &6), (&3, &3)]);
+    }
+
+    #[test]
+    fn test_iter() {
+        type Lhm = LinkedHashMap<i32, i32>;
+        let mut m = Lhm::new();
+        for i in 1..6 {
+            m.push_front(i, i);
+        }
+
+        let keys: Vec<i32> = m.iter().map(|(k, _)| *k).collect();
+        assert_eq!(keys, vec![5, 4, 3, 2, 1]);
+        let keys: Vec<i32> = m.iter().rev().map(|(k, _)| *k).collect();
+        assert_eq!(keys, vec![1, 2, 3, 4, 5]);
+        assert_eq!(m.iter().len(), 5);
+
+        for (_, v) in m.iter_mut() {
+            *v *= 10;
+        }
+        assert_eq!(m.get(&3), Some(&30));
+
+        let mut it = m.into_iter();
+        assert_eq!(it.next(), Some((5, 50)));
+        assert_eq!(it.next_back(), Some((1, 10)));
+        assert_eq!(it.len(), 3);
+    }
 }
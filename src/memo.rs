@@ -0,0 +1,101 @@
+//! Memoized functions.
+//!
+//! `memoize` puts any cache in front of a function, so that it is only
+//! called for arguments whose result is not cached:
+//!
+//! ```
+//! use cache::{lru, memo};
+//!
+//! let mut square = memo::memoize(lru::Cache::new(64), |n: u64| n * n);
+//! assert_eq!(square.call(12), 144);
+//! assert_eq!(square.cache().len(), 1);
+//! ```
+//!
+//! With the `macros` feature, `#[cached]` does the same for a free
+//! function, keeping one cache per thread keyed by the tuple of its
+//! arguments. `policy` is one of `lru` (the default), `fifo`, `slru` and
+//! `q2`; `size` is the capacity of the cache.
+//!
+//! ```
+//! # #[cfg(feature = "macros")]
+//! # {
+//! use cache::memo::cached;
+//!
+//! #[cached(policy = "q2", size = 128)]
+//! fn fib(n: u64) -> u64 {
+//!     if n < 2 {
+//!         return n;
+//!     }
+//!     fib(n - 1) + fib(n - 2)
+//! }
+//!
+//! assert_eq!(fib(90), 2880067194370816120);
+//! # }
+//! ```
+
+#[cfg(feature = "macros")]
+pub use cache_derive::cached;
+
+use super::layer::Cache;
+
+/// A function whose results are cached in `C`.
+pub struct Memoized<C, F> {
+    cache: C,
+    f: F,
+}
+
+pub fn memoize<C, F>(cache: C, f: F) -> Memoized<C, F> {
+    Memoized { cache, f }
+}
+
+impl<C, F> Memoized<C, F> {
+    /// Returns the cached result for `k`, calling the function and caching
+    /// its result on a miss.
+    pub fn call<K, V>(&mut self, k: K) -> V
+    where
+        C: Cache<K, V>,
+        F: FnMut(K) -> V,
+        K: Clone,
+        V: Clone,
+    {
+        if let Some(v) = self.cache.get(&k) {
+            return v.clone();
+        }
+        let v = (self.f)(k.clone());
+        self.cache.add(k, v.clone());
+        v
+    }
+
+    pub fn cache(&self) -> &C {
+        &self.cache
+    }
+
+    pub fn cache_mut(&mut self) -> &mut C {
+        &mut self.cache
+    }
+
+    pub fn into_inner(self) -> (C, F) {
+        (self.cache, self.f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::q2;
+
+    #[test]
+    fn test_memoize() {
+        let mut calls = 0;
+        let mut len = memoize(q2::Cache::new(8), |s: String| {
+            calls += 1;
+            s.len()
+        });
+        assert_eq!(len.call("abc".to_string()), 3);
+        assert_eq!(len.call("abc".to_string()), 3);
+        assert_eq!(len.call("ab".to_string()), 2);
+        let (cache, _) = len.into_inner();
+        assert_eq!(cache.len(), 2);
+        assert_eq!(calls, 2);
+    }
+}
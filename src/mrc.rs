@@ -0,0 +1,317 @@
+//! Miss ratio curve estimation.
+//!
+//! `Estimator` implements SHARDS (Waldspurger et al., FAST '15): it keeps
+//! only the keys whose hash falls below a threshold, a fixed fraction
+//! `rate` of the key space, computes the LRU stack distance of every
+//! sampled access, and scales it back by `1 / rate`. The resulting curve
+//! gives the hit ratio an LRU cache would have reached on the same stream
+//! at every size, for a memory cost proportional to `rate` times the number
+//! of distinct keys. Rates around `0.01` are typically accurate to within a
+//! percent on real workloads with more than a million keys; smaller key
+//! spaces want higher rates.
+//!
+//! Feed it keys with `record`, or wrap a cache in `Tracked` to record
+//! every lookup.
+//!
+//! ```
+//! use cache::mrc::Estimator;
+//!
+//! let mut mrc = Estimator::new(1.0);
+//! for _ in 0..10 {
+//!     for k in 0..100 {
+//!         mrc.record(&k);
+//!     }
+//! }
+//! // a looping scan never hits in an LRU cache smaller than the loop
+//! assert_eq!(mrc.hit_ratio(99), 0.0);
+//! assert_eq!(mrc.hit_ratio(100), 0.9);
+//! ```
+
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hash};
+
+use super::hash::DefaultState;
+use super::layer::Cache;
+
+/// The resolution of the sampling threshold.
+const MODULUS: u64 = 1 << 24;
+
+pub struct Estimator<S = DefaultState> {
+    threshold: u64,
+    hash_builder: S,
+    // the time of the last access to each sampled key
+    last: HashMap<u64, usize>,
+    // one mark at the time of the last access to each sampled key
+    marks: Fenwick,
+    // the number of sampled accesses at each unscaled stack distance
+    distances: Vec<u64>,
+    sampled: u64,
+    recorded: u64,
+}
+
+impl Estimator<DefaultState> {
+    /// Creates an estimator that samples a fraction `rate` of the keys,
+    /// clamped to `(0, 1]`.
+    pub fn new(rate: f64) -> Estimator<DefaultState> {
+        Estimator::with_hasher(rate, Default::default())
+    }
+}
+
+impl<S: BuildHasher> Estimator<S> {
+    pub fn with_hasher(rate: f64, hash_builder: S) -> Estimator<S> {
+        let threshold = ((rate * MODULUS as f64) as u64).clamp(1, MODULUS);
+        Estimator {
+            threshold,
+            hash_builder,
+            last: HashMap::new(),
+            marks: Fenwick::default(),
+            distances: Vec::new(),
+            sampled: 0,
+            recorded: 0,
+        }
+    }
+
+    /// Returns the fraction of the keys that are sampled.
+    pub fn rate(&self) -> f64 {
+        self.threshold as f64 / MODULUS as f64
+    }
+
+    /// Records an access to `k`.
+    pub fn record<K: Hash + ?Sized>(&mut self, k: &K) {
+        self.recorded += 1;
+        let hash = self.hash_builder.hash_one(k);
+        if hash % MODULUS >= self.threshold {
+            return;
+        }
+        self.sampled += 1;
+        if self.marks.len() >= 2 * self.last.len() + 1024 {
+            self.compact();
+        }
+        let now = self.marks.len();
+        if let Some(then) = self.last.insert(hash, now) {
+            let distance = self.marks.count_from(then + 1);
+            self.marks.unmark(then);
+            if self.distances.len() <= distance {
+                self.distances.resize(distance + 1, 0);
+            }
+            self.distances[distance] += 1;
+        }
+        self.marks.push_mark();
+    }
+
+    // renumbers the last accesses densely, so that the marks only grow with
+    // the number of sampled keys
+    fn compact(&mut self) {
+        let mut order: Vec<(usize, u64)> = self.last.iter().map(|(&h, &t)| (t, h)).collect();
+        order.sort_unstable();
+        self.marks = Fenwick::default();
+        for (now, (_, hash)) in order.into_iter().enumerate() {
+            self.last.insert(hash, now);
+            self.marks.push_mark();
+        }
+    }
+
+    /// Returns the estimated hit ratio of an LRU cache of `size` entries.
+    pub fn hit_ratio(&self, size: usize) -> f64 {
+        if self.sampled == 0 {
+            return 0.0;
+        }
+        // an access hits when fewer than `size` other keys were used since
+        let scaled = (size as f64 * self.rate()).round() as usize;
+        let hits: u64 = self.distances.iter().take(scaled).sum();
+        hits as f64 / self.sampled as f64
+    }
+
+    pub fn miss_ratio(&self, size: usize) -> f64 {
+        1.0 - self.hit_ratio(size)
+    }
+
+    /// Returns the estimated hit ratio at each of `sizes`.
+    pub fn curve<I>(&self, sizes: I) -> Vec<(usize, f64)>
+    where
+        I: IntoIterator<Item = usize>,
+    {
+        sizes
+            .into_iter()
+            .map(|size| (size, self.hit_ratio(size)))
+            .collect()
+    }
+
+    /// Returns the sampling parameters, the counts and the curve at each of
+    /// `sizes` as JSON.
+    #[cfg(feature = "json")]
+    pub fn to_json<I>(&self, sizes: I) -> serde_json::Value
+    where
+        I: IntoIterator<Item = usize>,
+    {
+        let curve: Vec<_> = self
+            .curve(sizes)
+            .into_iter()
+            .map(|(size, hit_ratio)| serde_json::json!({ "size": size, "hit_ratio": hit_ratio }))
+            .collect();
+        serde_json::json!({
+            "rate": self.rate(),
+            "recorded": self.recorded,
+            "sampled": self.sampled,
+            "distinct_keys": self.distinct_keys(),
+            "curve": curve,
+        })
+    }
+
+    /// Returns the estimated number of distinct keys seen.
+    pub fn distinct_keys(&self) -> usize {
+        (self.last.len() as f64 / self.rate()).round() as usize
+    }
+
+    /// Returns the number of accesses recorded, sampled or not.
+    pub fn recorded(&self) -> u64 {
+        self.recorded
+    }
+
+    pub fn reset(&mut self) {
+        self.last.clear();
+        self.marks = Fenwick::default();
+        self.distances.clear();
+        self.sampled = 0;
+        self.recorded = 0;
+    }
+}
+
+/// A binary indexed tree of 0/1 marks that grows one mark at a time.
+#[derive(Default)]
+struct Fenwick {
+    tree: Vec<i64>,
+}
+
+impl Fenwick {
+    fn len(&self) -> usize {
+        self.tree.len()
+    }
+
+    // the number of marks in [0, end)
+    fn prefix(&self, end: usize) -> i64 {
+        let (mut i, mut sum) = (end, 0);
+        while i > 0 {
+            sum += self.tree[i - 1];
+            i &= i - 1;
+        }
+        sum
+    }
+
+    fn count_from(&self, start: usize) -> usize {
+        (self.prefix(self.len()) - self.prefix(start)) as usize
+    }
+
+    fn push_mark(&mut self) {
+        // node i covers the range (i - lowbit(i), i] in one-based indices
+        let i = self.tree.len() + 1;
+        let low = i - (i & i.wrapping_neg());
+        let covered = self.prefix(i - 1) - self.prefix(low);
+        self.tree.push(covered + 1);
+    }
+
+    fn unmark(&mut self, at: usize) {
+        let mut i = at + 1;
+        while i <= self.tree.len() {
+            self.tree[i - 1] -= 1;
+            i += i & i.wrapping_neg();
+        }
+    }
+}
+
+/// Records every lookup of the wrapped cache in an `Estimator`.
+pub struct Tracked<C, S = DefaultState> {
+    inner: C,
+    estimator: Estimator<S>,
+}
+
+impl<C, S: BuildHasher> Tracked<C, S> {
+    pub fn new(inner: C, estimator: Estimator<S>) -> Tracked<C, S> {
+        Tracked { inner, estimator }
+    }
+
+    pub fn inner(&self) -> &C {
+        &self.inner
+    }
+
+    pub fn estimator(&self) -> &Estimator<S> {
+        &self.estimator
+    }
+
+    pub fn estimator_mut(&mut self) -> &mut Estimator<S> {
+        &mut self.estimator
+    }
+
+    pub fn into_inner(self) -> (C, Estimator<S>) {
+        (self.inner, self.estimator)
+    }
+}
+
+impl<K: Hash, V, C: Cache<K, V>, S: BuildHasher> Cache<K, V> for Tracked<C, S> {
+    fn get(&mut self, k: &K) -> Option<&V> {
+        self.estimator.record(k);
+        self.inner.get(k)
+    }
+
+    fn peek(&self, k: &K) -> Option<&V> {
+        self.inner.peek(k)
+    }
+
+    fn contains_key(&self, k: &K) -> bool {
+        self.inner.contains_key(k)
+    }
+
+    fn add(&mut self, k: K, v: V) -> Option<V> {
+        self.inner.add(k, v)
+    }
+
+    fn remove(&mut self, k: &K) -> Option<V> {
+        self.inner.remove(k)
+    }
+
+    fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    fn purge(&mut self) {
+        self.inner.purge()
+    }
+
+    fn expire(&mut self, k: &K) -> bool {
+        self.inner.expire(k)
+    }
+
+    fn expire_where(&mut self, expired: &mut dyn FnMut(&K, &V) -> bool) -> usize {
+        self.inner.expire_where(expired)
+    }
+
+    fn run_pending_tasks(&mut self) {
+        self.inner.run_pending_tasks()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lru;
+    use crate::trace;
+    use crate::workload::Zipf;
+
+    #[test]
+    fn test_exact_against_lru() {
+        // at rate 1 the estimate is exact
+        let keys: Vec<u64> = Zipf::new(500, 0.9, 5).take(5000).collect();
+        let mut mrc = Estimator::new(1.0);
+        for k in &keys {
+            mrc.record(k);
+        }
+        assert_eq!(mrc.recorded(), 5000);
+        assert_eq!(mrc.distinct_keys(), mrc.last.len());
+        for &size in &[1, 10, 50, 200] {
+            let mut cache = Tracked::new(lru::Cache::new(size), Estimator::new(0.5));
+            let report = trace::replay(&mut cache, keys.iter().map(|&k| Ok(k))).unwrap();
+            assert_eq!(mrc.hit_ratio(size), report.hit_ratio(), "size {}", size);
+            assert_eq!(cache.estimator().recorded(), 5000);
+        }
+    }
+}
@@ -0,0 +1,478 @@
+//! An LRU cache shared by several namespaces.
+//!
+//! Entries are keyed by a namespace and a key within it. All namespaces
+//! compete for the same capacity, and a whole namespace can be dropped at
+//! once in time proportional to its own size. A namespace can also be given
+//! a quota, past which it evicts its own least recently used entries rather
+//! than those of the others.
+//!
+//! ```
+//! use cache::namespaced::{Cache, Quota};
+//!
+//! let mut cache = Cache::new(16);
+//! cache.add("users", 1, "alice");
+//! cache.add("posts", 1, "hello");
+//! cache.invalidate_namespace(&"users");
+//! assert_eq!(cache.get(&"users", &1), None);
+//! assert_eq!(cache.get(&"posts", &1), Some(&"hello"));
+//!
+//! cache.set_quota("posts", Quota::entries(1));
+//! cache.add("posts", 2, "world");
+//! assert_eq!(cache.namespace_len(&"posts"), 1);
+//! ```
+
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hash, Hasher};
+
+use super::hash::DefaultState;
+use super::lru;
+use super::map::LinkedHashMap;
+use super::stats::Info;
+
+/// A namespace and key, owned or borrowed, so that lookups need not build
+/// an owned `(N, K)` tuple.
+trait Pair<N, K> {
+    fn namespace(&self) -> &N;
+    fn key(&self) -> &K;
+}
+
+impl<N, K> Pair<N, K> for (N, K) {
+    fn namespace(&self) -> &N {
+        &self.0
+    }
+
+    fn key(&self) -> &K {
+        &self.1
+    }
+}
+
+impl<N, K> Pair<N, K> for (&N, &K) {
+    fn namespace(&self) -> &N {
+        self.0
+    }
+
+    fn key(&self) -> &K {
+        self.1
+    }
+}
+
+// hashes like the `(N, K)` tuple
+impl<N: Hash, K: Hash> Hash for dyn Pair<N, K> + '_ {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.namespace().hash(state);
+        self.key().hash(state);
+    }
+}
+
+impl<N: Eq, K: Eq> PartialEq for dyn Pair<N, K> + '_ {
+    fn eq(&self, other: &Self) -> bool {
+        self.namespace() == other.namespace() && self.key() == other.key()
+    }
+}
+
+impl<N: Eq, K: Eq> Eq for dyn Pair<N, K> + '_ {}
+
+impl<'a, N: 'a, K: 'a> Borrow<dyn Pair<N, K> + 'a> for (N, K) {
+    fn borrow(&self) -> &(dyn Pair<N, K> + 'a) {
+        self
+    }
+}
+
+type Callback<N, K, V> = Box<dyn FnMut(N, K, V)>;
+
+type Weigher<N, K, V> = Box<dyn Fn(&N, &K, &V) -> usize>;
+
+/// Limits on the entries of one namespace, on top of the capacity shared by
+/// all of them. Weights are measured by the weigher of the cache, or count
+/// one per entry without one.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Quota {
+    pub max_entries: Option<usize>,
+    pub max_weight: Option<usize>,
+}
+
+impl Quota {
+    pub fn entries(max_entries: usize) -> Quota {
+        Quota {
+            max_entries: Some(max_entries),
+            max_weight: None,
+        }
+    }
+
+    pub fn weight(max_weight: usize) -> Quota {
+        Quota {
+            max_entries: None,
+            max_weight: Some(max_weight),
+        }
+    }
+}
+
+pub struct Cache<N, K, V, S = DefaultState> {
+    inner: lru::Cache<(N, K), V, S>,
+    // the keys of each namespace, most recently used first
+    members: HashMap<N, LinkedHashMap<K, ()>>,
+    callback: Option<Callback<N, K, V>>,
+    quotas: HashMap<N, Quota>,
+    weigher: Option<Weigher<N, K, V>>,
+    // the weight of each namespace, only kept with a weigher
+    weights: HashMap<N, usize>,
+}
+
+impl<N, K, V> Cache<N, K, V, DefaultState>
+where
+    N: Hash + Eq + Clone,
+    K: Hash + Eq + Clone,
+{
+    pub fn new(max_size: usize) -> Cache<N, K, V, DefaultState> {
+        Cache::with_hasher(max_size, Default::default())
+    }
+}
+
+impl<N, K, V, S> Cache<N, K, V, S>
+where
+    N: Hash + Eq + Clone,
+    K: Hash + Eq + Clone,
+    S: BuildHasher,
+{
+    pub fn with_hasher(max_size: usize, hash_builder: S) -> Cache<N, K, V, S> {
+        Cache {
+            inner: lru::Cache::with_hasher(max_size, hash_builder),
+            members: HashMap::new(),
+            callback: None,
+            quotas: HashMap::new(),
+            weigher: None,
+            weights: HashMap::new(),
+        }
+    }
+
+    pub fn set_eviction_callback<C>(&mut self, cb: C)
+    where
+        C: FnMut(N, K, V) + 'static,
+    {
+        self.callback = Some(Box::new(cb));
+    }
+
+    /// Sets the quota of `ns`, evicting its least recently used entries
+    /// until it fits.
+    pub fn set_quota(&mut self, ns: N, quota: Quota) {
+        self.quotas.insert(ns.clone(), quota);
+        self.enforce_quota(&ns);
+    }
+
+    pub fn remove_quota(&mut self, ns: &N) -> Option<Quota> {
+        self.quotas.remove(ns)
+    }
+
+    pub fn quota(&self, ns: &N) -> Option<Quota> {
+        self.quotas.get(ns).copied()
+    }
+
+    /// Sets how the weight quotas weigh an entry. The capacity shared by all
+    /// namespaces still counts entries.
+    pub fn set_weigher<W>(&mut self, weigher: W)
+    where
+        W: Fn(&N, &K, &V) -> usize + 'static,
+    {
+        self.weights.clear();
+        for ((ns, k), v) in self.inner.iter() {
+            *self.weights.entry(ns.clone()).or_insert(0) += weigher(ns, k, v);
+        }
+        self.weigher = Some(Box::new(weigher));
+        let namespaces: Vec<N> = self.quotas.keys().cloned().collect();
+        for ns in &namespaces {
+            self.enforce_quota(ns);
+        }
+    }
+
+    /// Returns the weight of the entries of `ns`, their number without a
+    /// weigher.
+    pub fn namespace_weight(&self, ns: &N) -> usize {
+        match &self.weigher {
+            Some(_) => self.weights.get(ns).copied().unwrap_or(0),
+            None => self.namespace_len(ns),
+        }
+    }
+
+    fn over_quota(&self, ns: &N) -> bool {
+        let quota = match self.quotas.get(ns) {
+            Some(quota) => quota,
+            None => return false,
+        };
+        quota
+            .max_entries
+            .is_some_and(|max| self.namespace_len(ns) > max)
+            || quota
+                .max_weight
+                .is_some_and(|max| self.namespace_weight(ns) > max)
+    }
+
+    /// Evicts the least recently used entries of `ns` while it is over its
+    /// quota.
+    fn enforce_quota(&mut self, ns: &N) {
+        while self.over_quota(ns) {
+            if !self.evict_from(ns, None) {
+                break;
+            }
+        }
+    }
+
+    /// Evicts the least recently used entries of `ns` but that of `k` until
+    /// storing `v` under `k` keeps it within its quota. Done before the
+    /// entry is stored, a namespace at its quota evicts its own entries
+    /// rather than letting the shared capacity evict those of others.
+    fn make_room(&mut self, ns: &N, k: &K, v: &V) {
+        let quota = match self.quotas.get(ns) {
+            Some(quota) => *quota,
+            None => return,
+        };
+        let old = self.inner.peek(&(ns, k) as &dyn Pair<N, K>);
+        let entries = old.is_none() as usize;
+        let weight = match &self.weigher {
+            Some(weigher) => {
+                let old_weight = old.map_or(0, |old| weigher(ns, k, old));
+                weigher(ns, k, v).saturating_sub(old_weight)
+            }
+            None => entries,
+        };
+        loop {
+            let over = quota
+                .max_entries
+                .is_some_and(|max| self.namespace_len(ns) + entries > max)
+                || quota
+                    .max_weight
+                    .is_some_and(|max| self.namespace_weight(ns) + weight > max);
+            if !over || !self.evict_from(ns, Some(k)) {
+                break;
+            }
+        }
+    }
+
+    /// Evicts the least recently used entry of `ns` other than `spare`, and
+    /// returns whether there was one.
+    fn evict_from(&mut self, ns: &N, spare: Option<&K>) -> bool {
+        let victim = self.members.get(ns).and_then(|keys| {
+            keys.iter()
+                .rev()
+                .map(|(k, _)| k)
+                .find(|&k| Some(k) != spare)
+                .cloned()
+        });
+        let k = match victim {
+            Some(k) => k,
+            None => return false,
+        };
+        if let Some(v) = self.inner.remove(&(ns, &k) as &dyn Pair<N, K>) {
+            self.forget(ns, &k, &v);
+            if let Some(cb) = &mut self.callback {
+                cb(ns.clone(), k, v);
+            }
+        }
+        true
+    }
+
+    pub fn get(&mut self, ns: &N, k: &K) -> Option<&V> {
+        let v = self.inner.get(&(ns, k) as &dyn Pair<N, K>)?;
+        if let Some(keys) = self.members.get_mut(ns) {
+            keys.move_to_front(k);
+        }
+        Some(v)
+    }
+
+    pub fn peek(&self, ns: &N, k: &K) -> Option<&V> {
+        self.inner.peek(&(ns, k) as &dyn Pair<N, K>)
+    }
+
+    pub fn contains_key(&self, ns: &N, k: &K) -> bool {
+        self.inner.contains_key(&(ns, k) as &dyn Pair<N, K>)
+    }
+
+    pub fn add(&mut self, ns: N, k: K, v: V) -> Option<V> {
+        self.make_room(&ns, &k, &v);
+        if let Some(weigher) = &self.weigher {
+            let old = self.inner.peek(&(&ns, &k) as &dyn Pair<N, K>);
+            let old_weight = old.map_or(0, |old| weigher(&ns, &k, old));
+            let weight = self.weights.entry(ns.clone()).or_insert(0);
+            *weight = weight.saturating_sub(old_weight) + weigher(&ns, &k, &v);
+        }
+        let quota_ns = if self.quotas.contains_key(&ns) {
+            Some(ns.clone())
+        } else {
+            None
+        };
+        self.members
+            .entry(ns.clone())
+            .or_default()
+            .push_front(k.clone(), ());
+        let (old_v, evicted) = self.inner.push((ns, k), v);
+        for ((ns, k), v) in evicted {
+            self.forget(&ns, &k, &v);
+            if let Some(cb) = &mut self.callback {
+                cb(ns, k, v);
+            }
+        }
+        if let Some(ns) = quota_ns {
+            self.enforce_quota(&ns);
+        }
+        old_v
+    }
+
+    pub fn remove(&mut self, ns: &N, k: &K) -> Option<V> {
+        let v = self.inner.remove(&(ns, k) as &dyn Pair<N, K>)?;
+        self.forget(ns, k, &v);
+        Some(v)
+    }
+
+    fn forget(&mut self, ns: &N, k: &K, v: &V) {
+        if let Some(keys) = self.members.get_mut(ns) {
+            keys.remove(k);
+            if keys.is_empty() {
+                self.members.remove(ns);
+            }
+        }
+        if let Some(weigher) = &self.weigher {
+            if let Some(weight) = self.weights.get_mut(ns) {
+                *weight = weight.saturating_sub(weigher(ns, k, v));
+                if !self.members.contains_key(ns) {
+                    self.weights.remove(ns);
+                }
+            }
+        }
+    }
+
+    /// Removes every entry of `ns` without passing them to the eviction
+    /// callback, and returns how many there were.
+    pub fn invalidate_namespace(&mut self, ns: &N) -> usize {
+        let keys = match self.members.remove(ns) {
+            Some(keys) => keys,
+            None => return 0,
+        };
+        self.weights.remove(ns);
+        for (k, _) in keys.iter() {
+            self.inner.remove(&(ns, k) as &dyn Pair<N, K>);
+        }
+        keys.len()
+    }
+
+    /// Returns the number of entries cached in `ns`.
+    pub fn namespace_len(&self, ns: &N) -> usize {
+        self.members.get(ns).map_or(0, LinkedHashMap::len)
+    }
+
+    pub fn namespaces(&self) -> impl Iterator<Item = &N> {
+        self.members.keys()
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+
+    pub fn max_size(&self) -> usize {
+        self.inner.max_size()
+    }
+
+    pub fn purge(&mut self) {
+        self.inner.purge();
+        self.members.clear();
+        self.weights.clear();
+    }
+
+    pub fn stat(&self) -> Info {
+        self.inner.stat()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_shared_capacity() {
+        let evicted = Rc::new(RefCell::new(Vec::new()));
+        let sink = evicted.clone();
+        let mut cache = Cache::new(3);
+        cache.set_eviction_callback(move |ns, k, _| sink.borrow_mut().push((ns, k)));
+        cache.add("a", 1, 1);
+        cache.add("b", 1, 2);
+        cache.add("a", 2, 3);
+        cache.get(&"a", &1);
+        cache.add("c", 1, 4);
+        assert_eq!(*evicted.as_ref().borrow(), vec![("b", 1)]);
+        assert_eq!(cache.namespace_len(&"a"), 2);
+        assert_eq!(cache.namespace_len(&"b"), 0);
+        assert_eq!(cache.namespaces().count(), 2);
+    }
+
+    #[test]
+    fn test_quota() {
+        let evicted = Rc::new(RefCell::new(Vec::new()));
+        let sink = evicted.clone();
+        let mut cache = Cache::new(8);
+        cache.set_eviction_callback(move |ns, k, _| sink.borrow_mut().push((ns, k)));
+        for k in 0..3 {
+            cache.add("quiet", k, "");
+        }
+        cache.set_quota("noisy", Quota::entries(4));
+        for k in 0..10 {
+            cache.add("noisy", k, "");
+        }
+        // the noisy namespace only evicted its own entries
+        assert_eq!(cache.namespace_len(&"quiet"), 3);
+        assert_eq!(cache.namespace_len(&"noisy"), 4);
+        assert_eq!(evicted.as_ref().borrow()[..2], [("noisy", 0), ("noisy", 1)]);
+
+        cache.set_weigher(|_, _, v: &&str| v.len());
+        assert_eq!(cache.namespace_weight(&"noisy"), 0);
+        cache.set_quota("quiet", Quota::weight(5));
+        cache.add("quiet", 3, "abc");
+        cache.add("quiet", 4, "abc");
+        // the older entries of the namespace go first, however light
+        assert_eq!(cache.namespace_weight(&"quiet"), 3);
+        assert_eq!(cache.namespace_len(&"quiet"), 1);
+        assert!(cache.contains_key(&"quiet", &4));
+        cache.remove(&"quiet", &4);
+        assert_eq!(cache.namespace_weight(&"quiet"), 0);
+    }
+
+    #[test]
+    fn test_quota_at_capacity() {
+        let evicted = Rc::new(RefCell::new(Vec::new()));
+        let sink = evicted.clone();
+        let mut cache = Cache::new(8);
+        cache.set_eviction_callback(move |ns, k, _| sink.borrow_mut().push((ns, k)));
+        cache.set_quota("noisy", Quota::entries(4));
+        for k in 0..4 {
+            cache.add("quiet", k, ());
+        }
+        for k in 0..4 {
+            cache.add("noisy", k, ());
+        }
+        cache.get(&"noisy", &0);
+        // a full cache still takes the entry from the namespace at its quota
+        cache.add("noisy", 4, ());
+        assert_eq!(*evicted.as_ref().borrow(), vec![("noisy", 1)]);
+        assert_eq!(cache.namespace_len(&"quiet"), 4);
+        assert_eq!(cache.namespace_len(&"noisy"), 4);
+    }
+
+    #[test]
+    fn test_invalidate_namespace() {
+        let mut cache = Cache::new(8);
+        for k in 0..3 {
+            cache.add("a".to_string(), k, k);
+            cache.add("b".to_string(), k, k);
+        }
+        assert_eq!(cache.remove(&"a".to_string(), &0), Some(0));
+        assert_eq!(cache.invalidate_namespace(&"a".to_string()), 2);
+        assert_eq!(cache.invalidate_namespace(&"a".to_string()), 0);
+        assert_eq!(cache.len(), 3);
+        assert!(cache.contains_key(&"b".to_string(), &2));
+        assert_eq!(cache.peek(&"b".to_string(), &1), Some(&1));
+    }
+}
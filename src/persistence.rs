@@ -0,0 +1,213 @@
+//! Versioned snapshots of caches.
+//!
+//! A snapshot starts with a magic number, the format version and the policy
+//! of the cache, followed by the cache as serialized by serde in bincode.
+//! Loading checks the header first, so a snapshot written in a format this
+//! version does not read, or by a cache of another policy, is rejected
+//! without decoding its entries. Snapshots of every earlier format are
+//! still read.
+//!
+//! ```
+//! use cache::lru::Cache;
+//!
+//! let mut cache: Cache<u32, String> = Cache::new(16);
+//! cache.add(1, "a".to_string());
+//!
+//! let mut snapshot = Vec::new();
+//! cache.save_to(&mut snapshot).unwrap();
+//! let restored: Cache<u32, String> = Cache::load_from(&snapshot[..]).unwrap();
+//! assert_eq!(restored.peek(&1).map(String::as_str), Some("a"));
+//! ```
+
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read, Write};
+
+const MAGIC: [u8; 4] = *b"CSNP";
+
+/// The snapshot format written by this version of the crate. Version 2
+/// added the segment sizes of q2 caches and version 3 the size of their main
+/// segment; the other caches are written the same in all of them.
+pub const VERSION: u16 = 3;
+
+/// The policy recorded in a snapshot header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Kind {
+    Lru = 1,
+    Fifo = 2,
+    Slru = 3,
+    Q2 = 4,
+}
+
+fn kind_name(tag: u8) -> &'static str {
+    match tag {
+        1 => "lru",
+        2 => "fifo",
+        3 => "slru",
+        4 => "q2",
+        _ => "unknown",
+    }
+}
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(io::Error),
+    /// The data does not start with the snapshot magic number.
+    NotASnapshot,
+    UnsupportedVersion {
+        found: u16,
+        supported: u16,
+    },
+    /// The snapshot was taken of a cache with another policy.
+    WrongPolicy {
+        found: &'static str,
+        expected: &'static str,
+    },
+    Encoding(bincode::Error),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SnapshotError::Io(err) => write!(f, "snapshot i/o failed: {}", err),
+            SnapshotError::NotASnapshot => write!(f, "not a cache snapshot"),
+            SnapshotError::UnsupportedVersion { found, supported } => write!(
+                f,
+                "snapshot version {} is not supported, expected 1 to {}",
+                found, supported
+            ),
+            SnapshotError::WrongPolicy { found, expected } => write!(
+                f,
+                "snapshot of a {} cache cannot be loaded as {}",
+                found, expected
+            ),
+            SnapshotError::Encoding(err) => write!(f, "invalid snapshot: {}", err),
+        }
+    }
+}
+
+impl Error for SnapshotError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            SnapshotError::Io(err) => Some(err),
+            SnapshotError::Encoding(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for SnapshotError {
+    fn from(err: io::Error) -> SnapshotError {
+        SnapshotError::Io(err)
+    }
+}
+
+pub(crate) fn save<T, W>(kind: Kind, value: &T, mut writer: W) -> Result<(), SnapshotError>
+where
+    T: serde::Serialize,
+    W: Write,
+{
+    writer.write_all(&MAGIC)?;
+    writer.write_all(&VERSION.to_le_bytes())?;
+    writer.write_all(&[kind as u8])?;
+    bincode::serialize_into(&mut writer, value).map_err(SnapshotError::Encoding)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Reads a snapshot of a cache whose format is the same in every version.
+pub(crate) fn load<T, R>(kind: Kind, mut reader: R) -> Result<T, SnapshotError>
+where
+    T: serde::de::DeserializeOwned,
+    R: Read,
+{
+    read_header(kind, &mut reader)?;
+    bincode::deserialize_from(reader).map_err(SnapshotError::Encoding)
+}
+
+/// Checks the header of a snapshot of a `kind` cache and returns the
+/// version of the format the cache follows it in.
+pub(crate) fn read_header<R: Read>(kind: Kind, reader: &mut R) -> Result<u16, SnapshotError> {
+    let mut header = [0; 7];
+    reader
+        .read_exact(&mut header)
+        .map_err(|err| match err.kind() {
+            io::ErrorKind::UnexpectedEof => SnapshotError::NotASnapshot,
+            _ => SnapshotError::Io(err),
+        })?;
+    if header[..4] != MAGIC {
+        return Err(SnapshotError::NotASnapshot);
+    }
+    let version = u16::from_le_bytes([header[4], header[5]]);
+    if !(1..=VERSION).contains(&version) {
+        return Err(SnapshotError::UnsupportedVersion {
+            found: version,
+            supported: VERSION,
+        });
+    }
+    if header[6] != kind as u8 {
+        return Err(SnapshotError::WrongPolicy {
+            found: kind_name(header[6]),
+            expected: kind_name(kind as u8),
+        });
+    }
+    Ok(version)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{lru, q2};
+
+    #[test]
+    fn test_header_checks() {
+        let mut cache: q2::Cache<u32, u32> = q2::Cache::new(8);
+        cache.add(1, 1);
+        let mut snapshot = Vec::new();
+        cache.save_to(&mut snapshot).unwrap();
+        assert_eq!(&snapshot[..7], b"CSNP\x03\x00\x04");
+
+        let restored: q2::Cache<u32, u32> = q2::Cache::load_from(&snapshot[..]).unwrap();
+        assert_eq!(restored.peek(&1), Some(&1));
+
+        match lru::Cache::<u32, u32>::load_from(&snapshot[..]) {
+            Err(SnapshotError::WrongPolicy { found, expected }) => {
+                assert_eq!((found, expected), ("q2", "lru"))
+            }
+            _ => panic!("loaded a q2 snapshot as lru"),
+        }
+
+        snapshot[4] = 4;
+        match q2::Cache::<u32, u32>::load_from(&snapshot[..]) {
+            Err(SnapshotError::UnsupportedVersion { found: 4, .. }) => {}
+            _ => panic!("loaded a snapshot of an unknown version"),
+        }
+
+        match q2::Cache::<u32, u32>::load_from(&b"CS"[..]) {
+            Err(SnapshotError::NotASnapshot) => {}
+            _ => panic!("loaded a truncated snapshot"),
+        }
+    }
+
+    #[test]
+    fn test_earlier_versions() {
+        // the fields of a q2 cache up to the ghost sequence, then the sizes
+        // of in and out in version 2
+        let fields = (8usize, 0.75f64, 0.5f64, vec![(1u32, 1u32)]);
+        let rest = (Vec::<(u32, u32)>::new(), Vec::<(u64, u64)>::new(), 0u64);
+        for version in 1..=2u16 {
+            let mut snapshot = b"CSNP".to_vec();
+            snapshot.extend_from_slice(&version.to_le_bytes());
+            snapshot.push(Kind::Q2 as u8);
+            bincode::serialize_into(&mut snapshot, &(&fields, &rest)).unwrap();
+            if version == 2 {
+                bincode::serialize_into(&mut snapshot, &(Some(3usize), Some(4usize))).unwrap();
+            }
+            let restored: q2::Cache<u32, u32> = q2::Cache::load_from(&snapshot[..]).unwrap();
+            assert_eq!(restored.peek(&1), Some(&1));
+            assert_eq!(restored.max_size(), 8);
+            let expected = if version == 1 { (2, 6, 4) } else { (3, 5, 4) };
+            assert_eq!(restored.segment_max_lens(), expected);
+        }
+    }
+}
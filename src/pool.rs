@@ -0,0 +1,388 @@
+//! LRU caches that share one capacity.
+//!
+//! Every cache created from a `CapacityPool` counts its entries, or their
+//! weight, against the pool. When an insertion takes the pool over its
+//! capacity, the least recently used entry across all of its caches is
+//! evicted, whichever cache holds it. A cache whose values are borrowed
+//! through `get` or `peek` is passed over while they are; if no other cache
+//! has an entry to give, the pool stays over its capacity until a later
+//! insertion finds the borrows released.
+//!
+//! ```
+//! use cache::pool::CapacityPool;
+//!
+//! let pool = CapacityPool::new(2);
+//! let mut users = pool.cache();
+//! let mut posts = pool.cache();
+//! users.add(1, "alice");
+//! posts.add(1, "hello");
+//! users.get(&1);
+//! posts.add(2, "world");
+//! // the post was the coldest entry in the pool
+//! assert!(users.contains_key(&1));
+//! assert!(!posts.contains_key(&1));
+//! ```
+
+use std::borrow::Borrow;
+use std::cell::{Cell, Ref, RefCell};
+use std::hash::{BuildHasher, Hash};
+use std::rc::{Rc, Weak};
+
+use super::hash::DefaultState;
+use super::map::LinkedHashMap;
+use super::stats::{EvictionReason, Info, Stats};
+use super::weight::Weigher;
+
+/// A cache as seen by its pool.
+trait Member {
+    /// Returns the access tick of the least recently used entry, or `None`
+    /// if the cache is empty or borrowed.
+    fn coldest(&self) -> Option<u64>;
+
+    /// Evicts the least recently used entry and returns its weight, 0 if
+    /// the cache is empty or borrowed.
+    fn evict_coldest(&self) -> usize;
+}
+
+struct Shared {
+    capacity: usize,
+    used: Cell<usize>,
+    tick: Cell<u64>,
+    members: RefCell<Vec<Weak<dyn Member>>>,
+}
+
+impl Shared {
+    fn tick(&self) -> u64 {
+        let tick = self.tick.get() + 1;
+        self.tick.set(tick);
+        tick
+    }
+
+    fn evict_until_within_capacity(&self) {
+        while self.used.get() > self.capacity {
+            let mut members = self.members.borrow_mut();
+            members.retain(|member| member.strong_count() > 0);
+            let coldest = members
+                .iter()
+                .filter_map(|member| member.upgrade())
+                .filter_map(|member| member.coldest().map(|tick| (tick, member)))
+                .min_by_key(|&(tick, _)| tick);
+            drop(members);
+            match coldest {
+                Some((_, member)) => {
+                    let freed = member.evict_coldest();
+                    self.used.set(self.used.get().saturating_sub(freed));
+                }
+                None => break,
+            }
+        }
+    }
+}
+
+/// A handle to a shared capacity. Clones refer to the same pool.
+#[derive(Clone)]
+pub struct CapacityPool {
+    shared: Rc<Shared>,
+}
+
+impl CapacityPool {
+    /// Creates a pool bounding the total weight of the entries of its
+    /// caches, which is their count unless they have a weigher.
+    pub fn new(capacity: usize) -> CapacityPool {
+        CapacityPool {
+            shared: Rc::new(Shared {
+                capacity,
+                used: Cell::new(0),
+                tick: Cell::new(0),
+                members: RefCell::new(Vec::new()),
+            }),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.shared.capacity
+    }
+
+    /// Returns the total weight of the entries of all caches in the pool.
+    pub fn used(&self) -> usize {
+        self.shared.used.get()
+    }
+
+    pub fn cache<K, V>(&self) -> Cache<K, V, DefaultState>
+    where
+        K: Hash + Eq + 'static,
+        V: 'static,
+    {
+        self.cache_with_hasher(Default::default())
+    }
+
+    pub fn cache_with_hasher<K, V, S>(&self, hash_builder: S) -> Cache<K, V, S>
+    where
+        K: Hash + Eq + 'static,
+        V: 'static,
+        S: BuildHasher + 'static,
+    {
+        let inner = Rc::new(RefCell::new(Inner {
+            map: LinkedHashMap::with_hasher(hash_builder),
+            weigher: Weigher::unit(),
+            weight: 0,
+            stats: Stats::default(),
+        }));
+        let member: Rc<dyn Member> = inner.clone();
+        self.shared
+            .members
+            .borrow_mut()
+            .push(Rc::downgrade(&member));
+        Cache {
+            shared: self.shared.clone(),
+            inner,
+        }
+    }
+}
+
+struct Inner<K, V, S> {
+    map: LinkedHashMap<K, (V, u64), S>,
+    weigher: Weigher<K, V>,
+    weight: usize,
+    stats: Stats,
+}
+
+impl<K, V, S> Member for RefCell<Inner<K, V, S>>
+where
+    K: Hash + Eq,
+    S: BuildHasher,
+{
+    fn coldest(&self) -> Option<u64> {
+        // a cache that cannot be borrowed mutably cannot give up an entry
+        let inner = self.try_borrow_mut().ok()?;
+        inner.map.back().map(|(_, &(_, tick))| tick)
+    }
+
+    fn evict_coldest(&self) -> usize {
+        let mut inner = match self.try_borrow_mut() {
+            Ok(inner) => inner,
+            Err(_) => return 0,
+        };
+        match inner.map.pop_back() {
+            Some((k, (v, _))) => {
+                inner.stats.evict(EvictionReason::Capacity);
+                let weight = inner.weigher.weigh(&k, &v);
+                inner.weight = inner.weight.saturating_sub(weight);
+                weight
+            }
+            None => 0,
+        }
+    }
+}
+
+/// An LRU cache whose capacity is that of its pool.
+pub struct Cache<K, V, S = DefaultState> {
+    shared: Rc<Shared>,
+    inner: Rc<RefCell<Inner<K, V, S>>>,
+}
+
+impl<K, V, S> Cache<K, V, S>
+where
+    K: Hash + Eq,
+    S: BuildHasher,
+{
+    /// Weighs the entries of this cache with `weigher` instead of counting
+    /// them. Entries already cached are weighed again.
+    pub fn set_weigher<W>(&mut self, weigher: W)
+    where
+        W: Fn(&K, &V) -> u32 + 'static,
+    {
+        {
+            let mut inner = self.inner.borrow_mut();
+            let weigher = Weigher::new(weigher);
+            let weight = inner
+                .map
+                .iter()
+                .map(|(k, (v, _))| weigher.weigh(k, v))
+                .sum();
+            let used = self.shared.used.get() - inner.weight + weight;
+            self.shared.used.set(used);
+            inner.weigher = weigher;
+            inner.weight = weight;
+        }
+        self.shared.evict_until_within_capacity();
+    }
+
+    pub fn contains_key<Q>(&self, k: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        self.inner.as_ref().borrow().map.contains_key(k)
+    }
+
+    pub fn get<Q>(&mut self, k: &Q) -> Option<Ref<'_, V>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        {
+            let mut inner = self.inner.borrow_mut();
+            let tick = self.shared.tick();
+            match inner.map.get_mut(k) {
+                Some(entry) => entry.1 = tick,
+                None => {
+                    inner.stats.miss();
+                    return None;
+                }
+            }
+            inner.stats.hit();
+            inner.map.move_to_front(k);
+        }
+        self.peek(k)
+    }
+
+    pub fn peek<Q>(&self, k: &Q) -> Option<Ref<'_, V>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        Ref::filter_map(self.inner.as_ref().borrow(), |inner| {
+            inner.map.get(k).map(|(v, _)| v)
+        })
+        .ok()
+    }
+
+    pub fn add(&mut self, k: K, v: V) -> Option<V> {
+        let old_v = {
+            let mut inner = self.inner.borrow_mut();
+            let inner = &mut *inner;
+            let tick = self.shared.tick();
+            let mut used = self.shared.used.get() + inner.weigher.weigh(&k, &v);
+            let old_v = inner.map.remove(&k).map(|(old_v, _)| old_v);
+            match &old_v {
+                Some(old_v) => {
+                    inner.stats.replace();
+                    used -= inner.weigher.weigh(&k, old_v);
+                }
+                None => inner.stats.insert(),
+            }
+            inner.weight = inner.weight + used - self.shared.used.get();
+            self.shared.used.set(used);
+            inner.map.push_front(k, (v, tick));
+            old_v
+        };
+        self.shared.evict_until_within_capacity();
+        old_v
+    }
+
+    pub fn remove<Q>(&mut self, k: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        let mut inner = self.inner.borrow_mut();
+        let (k, (v, _)) = inner.map.remove_entry(k)?;
+        let weight = inner.weigher.weigh(&k, &v);
+        inner.weight -= weight;
+        self.shared.used.set(self.shared.used.get() - weight);
+        Some(v)
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.as_ref().borrow().map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.as_ref().borrow().map.is_empty()
+    }
+
+    /// Returns the total weight of the entries of this cache.
+    pub fn weighted_size(&self) -> usize {
+        self.inner.as_ref().borrow().weight
+    }
+
+    pub fn purge(&mut self) {
+        let mut inner = self.inner.borrow_mut();
+        self.shared.used.set(self.shared.used.get() - inner.weight);
+        inner.map.clear();
+        inner.weight = 0;
+    }
+
+    pub fn stat(&self) -> Info {
+        let inner = self.inner.as_ref().borrow();
+        let memory = inner.map.memory_usage();
+        Info::new(&inner.stats.counters, inner.map.len(), memory, &inner.stats)
+    }
+}
+
+impl<K, V, S> Drop for Cache<K, V, S> {
+    fn drop(&mut self) {
+        let weight = self.inner.as_ref().borrow().weight;
+        self.shared
+            .used
+            .set(self.shared.used.get().saturating_sub(weight));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_global_lru() {
+        let pool = CapacityPool::new(3);
+        let mut a = pool.cache();
+        let mut b = pool.cache();
+        a.add(1, 1);
+        b.add(1, 1);
+        a.add(2, 2);
+        b.get(&1);
+        assert_eq!(a.add(2, 3), Some(2));
+        assert_eq!(pool.used(), 3);
+
+        b.add(2, 2);
+        assert!(!a.contains_key(&1));
+        assert_eq!((a.len(), b.len()), (1, 2));
+        assert_eq!(a.stat().eviction_count, 1);
+        assert_eq!(*b.get(&1).unwrap(), 1);
+
+        drop(b);
+        assert_eq!(pool.used(), 1);
+        a.add(3, 3);
+        a.add(4, 4);
+        assert_eq!(a.len(), 3);
+    }
+
+    #[test]
+    fn test_weigher() {
+        let pool = CapacityPool::new(10);
+        let mut small = pool.cache();
+        let mut large: Cache<u32, String> = pool.cache();
+        large.set_weigher(|_, v: &String| v.len() as u32);
+        small.add(1, 1);
+        large.add(1, "abcdef".to_owned());
+        assert_eq!(pool.used(), 7);
+        large.add(2, "abcd".to_owned());
+        assert!(!small.contains_key(&1));
+        assert_eq!(pool.used(), 10);
+        assert_eq!(large.remove(&1).as_deref(), Some("abcdef"));
+        assert_eq!(pool.used(), 4);
+    }
+
+    #[test]
+    fn test_borrowed_member() {
+        let pool = CapacityPool::new(1);
+        let mut a = pool.cache();
+        let mut b = pool.cache();
+        b.add(1, 1);
+        let v = b.peek(&1).unwrap();
+        // b is passed over while its value is borrowed, so the entry of a
+        // goes instead
+        a.add(2, 2);
+        assert_eq!(*v, 1);
+        assert!(!a.contains_key(&2));
+        assert_eq!(pool.used(), 1);
+        drop(v);
+
+        a.add(3, 3);
+        assert_eq!(pool.used(), 1);
+        assert!(!b.contains_key(&1));
+        assert!(a.contains_key(&3));
+    }
+}
@@ -0,0 +1,181 @@
+//! Python bindings, built with pyo3.
+//!
+//! The `cache` extension module exposes `LruCache`, `SlruCache` and
+//! `Q2Cache`, which evict exactly like their Rust counterparts. Keys are
+//! `bytes` or `str`, values any Python object. Build the crate as a
+//! `cdylib` with the `python` feature, e.g. with maturin, to import it:
+//!
+//! ```python
+//! from cache import Q2Cache
+//!
+//! cache = Q2Cache(1024)
+//! cache.set_eviction_callback(lambda k, v: print("evicted", k))
+//! cache.put("answer", 42)
+//! assert cache.get("answer") == 42
+//! ```
+
+use pyo3::exceptions::{PyTypeError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::{PyBytes, PyString};
+
+use super::{lru, q2, slru};
+
+/// A cache key, which Python sees as `bytes` or `str`. The two never compare
+/// equal, like in Python.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Key {
+    Bytes(Vec<u8>),
+    Str(String),
+}
+
+impl<'py> FromPyObject<'py> for Key {
+    fn extract_bound(ob: &Bound<'py, PyAny>) -> PyResult<Key> {
+        if let Ok(bytes) = ob.downcast::<PyBytes>() {
+            return Ok(Key::Bytes(bytes.as_bytes().to_vec()));
+        }
+        if let Ok(s) = ob.downcast::<PyString>() {
+            return Ok(Key::Str(s.to_str()?.to_owned()));
+        }
+        Err(PyTypeError::new_err("cache keys must be bytes or str"))
+    }
+}
+
+impl IntoPy<PyObject> for Key {
+    fn into_py(self, py: Python<'_>) -> PyObject {
+        match self {
+            Key::Bytes(bytes) => PyBytes::new_bound(py, &bytes).into_py(py),
+            Key::Str(s) => s.into_py(py),
+        }
+    }
+}
+
+macro_rules! py_cache {
+    ($name:ident, $py_name:literal, $policy:ident, { $($new:tt)* }) => {
+        #[pyclass(unsendable, name = $py_name)]
+        pub struct $name {
+            inner: $policy::Cache<Key, PyObject>,
+        }
+
+        #[pymethods]
+        impl $name {
+            $($new)*
+
+            fn get(&mut self, py: Python<'_>, key: Key) -> Option<PyObject> {
+                self.inner.get(&key).map(|v| v.clone_ref(py))
+            }
+
+            /// Looks up a key without counting it as a use.
+            fn peek(&self, py: Python<'_>, key: Key) -> Option<PyObject> {
+                self.inner.peek(&key).map(|v| v.clone_ref(py))
+            }
+
+            /// Caches a value, returning the one it replaces.
+            fn put(&mut self, key: Key, value: PyObject) -> Option<PyObject> {
+                self.inner.add(key, value)
+            }
+
+            fn remove(&mut self, key: Key) -> Option<PyObject> {
+                self.inner.remove(&key)
+            }
+
+            fn clear(&mut self) {
+                self.inner.purge();
+            }
+
+            /// Calls `callback(key, value)` with every evicted entry.
+            /// Exceptions it raises are reported as unraisable.
+            fn set_eviction_callback(&mut self, callback: PyObject) {
+                self.inner.set_eviction_callback(move |k: Key, v: PyObject| {
+                    Python::with_gil(|py| {
+                        if let Err(err) = callback.call1(py, (k, v)) {
+                            err.write_unraisable_bound(py, None);
+                        }
+                    })
+                });
+            }
+
+            #[getter]
+            fn max_size(&self) -> usize {
+                self.inner.max_size()
+            }
+
+            fn __len__(&self) -> usize {
+                self.inner.len()
+            }
+
+            fn __contains__(&self, key: Key) -> bool {
+                self.inner.contains_key(&key)
+            }
+        }
+    };
+}
+
+py_cache!(PyLruCache, "LruCache", lru, {
+    #[new]
+    fn new(max_size: usize) -> PyLruCache {
+        PyLruCache {
+            inner: lru::Cache::new(max_size),
+        }
+    }
+});
+
+py_cache!(PySlruCache, "SlruCache", slru, {
+    #[new]
+    #[pyo3(signature = (max_size, main_cache_factor = slru::DEFAULT_MAIN_CF))]
+    fn new(max_size: usize, main_cache_factor: f64) -> PyResult<PySlruCache> {
+        let inner = slru::Cache::try_with_params(max_size, main_cache_factor)
+            .map_err(|err| PyValueError::new_err(err.to_string()))?;
+        Ok(PySlruCache { inner })
+    }
+});
+
+py_cache!(PyQ2Cache, "Q2Cache", q2, {
+    #[new]
+    #[pyo3(signature = (
+        max_size,
+        main_cache_factor = q2::DEFAULT_MAIN_CF,
+        out_cache_factor = q2::DEFAULT_OUT_CF,
+    ))]
+    fn new(max_size: usize, main_cache_factor: f64, out_cache_factor: f64) -> PyResult<PyQ2Cache> {
+        let inner = q2::Cache::try_with_params(max_size, main_cache_factor, out_cache_factor)
+            .map_err(|err| PyValueError::new_err(err.to_string()))?;
+        Ok(PyQ2Cache { inner })
+    }
+});
+
+#[pymodule]
+fn cache(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyLruCache>()?;
+    m.add_class::<PySlruCache>()?;
+    m.add_class::<PyQ2Cache>()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pyo3::types::{PyDict, PyList};
+
+    #[test]
+    fn test_eviction_callback() {
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let evicted = PyList::empty_bound(py);
+            let mut cache = PyLruCache::new(1);
+            let globals = PyDict::new_bound(py);
+            globals.set_item("evicted", &evicted).unwrap();
+            let callback = py
+                .eval_bound("lambda k, v: evicted.append((k, v))", Some(&globals), None)
+                .unwrap();
+            cache.set_eviction_callback(callback.unbind());
+            let key = |s: &str| Key::Str(s.to_owned());
+            cache.put(key("a"), 1.into_py(py));
+            cache.put(Key::Bytes(b"a".to_vec()), 2.into_py(py));
+            assert!(cache.__contains__(Key::Bytes(b"a".to_vec())));
+            assert!(!cache.__contains__(key("a")));
+            assert_eq!(evicted.len(), 1);
+            let (k, v): (String, i32) = evicted.get_item(0).unwrap().extract().unwrap();
+            assert_eq!((k.as_str(), v), ("a", 1));
+        });
+    }
+}
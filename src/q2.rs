@@ -1,206 +1,635 @@
 use std::borrow::Borrow;
-use std::collections::hash_map::RandomState;
-use std::hash::{BuildHasher, Hash, Hasher};
-use std::ptr;
+use std::hash::{BuildHasher, Hash};
+use std::mem;
 
-use super::map::LinkedHashMap;
+use super::admission::{Doorkeeper, PromotionLimit};
+use super::bloom::RotatingBloom;
+use super::core::{self, ReplacementPolicy, Store};
+use super::error::{self, ConfigError};
+use super::hash::{DefaultState, SeededState};
+use super::map::{self, LinkedHashMap};
+#[cfg(feature = "persistence")]
+use super::persistence::{self, SnapshotError};
+pub use super::stats::Info;
+use super::stats::{MemoryUsage, Segment};
 
-const DEFAULT_MAIN_CF: f64 = 0.75;
-const DEFAULT_OUT_CF: f64 = 0.50;
+pub(crate) const DEFAULT_MAIN_CF: f64 = 0.75;
+pub(crate) const DEFAULT_OUT_CF: f64 = 0.50;
+const MIN_SIZE: usize = 2;
 
-pub struct Cache<K, V, S = RandomState> {
+/// Keeps new entries in the in segment and those read again in main,
+/// remembering the keys recently evicted from in so that they go straight
+/// to main when added again: a `core::Cache` with the `Q2` policy.
+pub type Cache<K, V, S = DefaultState> = core::Cache<K, V, Q2, S>;
+
+/// The 2Q policy: a hit in the in segment, or the return of a key of the
+/// ghost queue, moves the entry to main. The in segment is evicted first
+/// while it is over its share, main otherwise.
+pub struct Q2 {
     max_size: usize,
     max_size_in: usize,
+    max_size_main: usize,
     max_size_out: usize,
+    main_cache_factor: f64,
+    out_cache_factor: f64,
 
-    hit_count: usize,
-    miss_count: usize,
+    max_weight: usize,
+    max_weight_in: usize,
 
-    hash_builder: S,
+    doorkeeper: Option<Doorkeeper>,
 
-    callback: Option<Box<dyn Fn(K, V)>>,
+    /// Hashes of keys recently evicted from in, each stamped with the value
+    /// of `ghost_seq` when it was added.
+    out: LinkedHashMap<u64, u64, DefaultState>,
+    /// Replaces `out` in compact mode.
+    ghost_filter: Option<Box<RotatingBloom>>,
+    ghost_seq: u64,
+    max_ghost_age: Option<u64>,
 
-    in_: LinkedHashMap<K, V, S>,
-    out: LinkedHashMap<u64, (), S>,
-    main: LinkedHashMap<K, V, S>,
+    adaptive: bool,
+    /// Hashes of keys recently evicted from main, only kept in adaptive mode.
+    main_out: LinkedHashMap<u64, (), DefaultState>,
 }
 
-impl<K: Hash + Eq, V, S> Cache<K, V, S>
-where
-    K: Hash + Eq,
-    S: BuildHasher + Clone,
-{
-    pub fn with_hasher(size: usize, hash_builder: S) -> Cache<K, V, S> {
-        Cache::with_param_and_hasher(size, DEFAULT_MAIN_CF, DEFAULT_OUT_CF, hash_builder)
-    }
-
-    pub fn with_param_and_hasher(
-        size: usize,
+impl Q2 {
+    fn new(
+        (max_size, max_size_in, max_size_main, max_size_out): (usize, usize, usize, usize),
         main_cache_factor: f64,
         out_cache_factor: f64,
-        hash_builder: S,
-    ) -> Cache<K, V, S> {
-        let max_size = if size < 2 { 2 } else { size };
-
-        let max_size_main = (max_size as f64 * main_cache_factor) as usize;
-        let max_size_in = (max_size as f64 * (1 as f64 - main_cache_factor)) as usize;
-        let max_size_out = (max_size as f64 * out_cache_factor) as usize;
-
-        Cache {
+    ) -> Q2 {
+        Q2 {
             max_size,
             max_size_in,
+            max_size_main,
             max_size_out,
+            main_cache_factor,
+            out_cache_factor,
+            max_weight: max_size,
+            max_weight_in: max_size_in,
+            doorkeeper: None,
+            out: LinkedHashMap::with_capacity_and_hasher(max_size_out, Default::default()),
+            ghost_filter: None,
+            ghost_seq: 0,
+            max_ghost_age: None,
+            adaptive: false,
+            main_out: LinkedHashMap::with_hasher(Default::default()),
+        }
+    }
 
-            hit_count: 0,
-            miss_count: 0,
+    /// Returns a policy of the same configuration, without a doorkeeper,
+    /// that remembers no keys.
+    fn split_off(&self) -> Q2 {
+        let sizes = (
+            self.max_size,
+            self.max_size_in,
+            self.max_size_main,
+            self.max_size_out,
+        );
+        let mut other = Q2::new(sizes, self.main_cache_factor, self.out_cache_factor);
+        other.max_weight = self.max_weight;
+        other.max_weight_in = self.max_weight_in;
+        other.adaptive = self.adaptive;
+        other.max_ghost_age = self.max_ghost_age;
+        if self.ghost_filter.is_some() {
+            other.ghost_filter = Some(Box::new(RotatingBloom::new(self.max_size_out)));
+        }
+        other
+    }
 
-            hash_builder: hash_builder.clone(),
+    /// Records a new key in the doorkeeper, if there is one, and returns
+    /// whether it may be cached.
+    fn passes_doorkeeper(&mut self, hash: u64) -> bool {
+        let doorkeeper = match &mut self.doorkeeper {
+            Some(doorkeeper) => doorkeeper,
+            None => return true,
+        };
+        let ghost = match &self.ghost_filter {
+            Some(filter) => filter.contains(hash),
+            None => self.out.contains_key(&hash),
+        };
+        if ghost || self.main_out.contains_key(&hash) {
+            return true;
+        }
+        doorkeeper.record(hash)
+    }
+
+    /// Remembers the key of `hash` as evicted from in.
+    fn push_ghost<K, V, S>(&mut self, store: &mut Store<K, V, S>, hash: u64)
+    where
+        K: Hash + Eq,
+        S: BuildHasher,
+    {
+        self.ghost_seq += 1;
+        if let Some(filter) = &mut self.ghost_filter {
+            filter.insert(hash);
+            return;
+        }
+        if self.out.len() + 1 > self.max_size_out && self.out.pop_back().is_some() {
+            store.count_ghost_drop();
+        }
+        self.out.push_front(hash, self.ghost_seq);
+        self.drop_old_ghosts(store);
+    }
 
-            callback: None,
+    /// Forgets the key of `hash` as a ghost, if it was one, and returns its
+    /// age, always 0 in compact mode.
+    fn take_ghost(&mut self, hash: u64) -> Option<u64> {
+        match &self.ghost_filter {
+            Some(filter) => Some(0).filter(|_| filter.contains(hash)),
+            None => self.out.remove(&hash).map(|stamp| self.ghost_seq - stamp),
+        }
+    }
 
-            in_: LinkedHashMap::with_capacity_and_hasher(max_size_in, hash_builder.clone()),
-            out: LinkedHashMap::with_capacity_and_hasher(max_size_out, hash_builder.clone()),
-            main: LinkedHashMap::with_capacity_and_hasher(max_size_main, hash_builder),
+    fn ghost_len(&self) -> usize {
+        match &self.ghost_filter {
+            Some(filter) => filter.len(),
+            None => self.out.len(),
         }
     }
 
-    pub fn set_eviction_callback<C>(&mut self, cb: C)
+    fn drop_old_ghosts<K, V, S>(&mut self, store: &mut Store<K, V, S>)
     where
-        C: Fn(K, V) + 'static,
+        K: Hash + Eq,
+        S: BuildHasher,
     {
-        self.callback = Some(Box::new(cb));
+        let max_age = match self.max_ghost_age {
+            Some(max_age) => max_age,
+            None => return,
+        };
+        while let Some((_, &stamp)) = self.out.back() {
+            if self.ghost_seq - stamp <= max_age {
+                break;
+            }
+            self.out.pop_back();
+            store.count_ghost_drop();
+        }
+    }
+
+    /// Moves the split between the segments so that in gets `delta` more
+    /// entries, or fewer if negative, keeping at least one in each.
+    fn rebalance(&mut self, delta: isize) {
+        if self.max_size < MIN_SIZE {
+            return;
+        }
+        let max_in = self.max_size - 1;
+        let in_size = if delta < 0 {
+            self.max_size_in.saturating_sub(delta.unsigned_abs()).max(1)
+        } else {
+            (self.max_size_in + delta as usize).min(max_in)
+        };
+        self.max_size_in = in_size;
+        self.max_size_main = self.max_size - in_size;
+        self.main_cache_factor = self.max_size_main as f64 / self.max_size as f64;
+        self.max_weight_in = (self.max_weight as f64 * (1.0 - self.main_cache_factor)) as usize;
     }
 
-    pub fn contains_key<Q: ?Sized>(&self, key: &Q) -> bool
+    /// Evicts from the in segment, remembering the key as a ghost.
+    fn evict_in<K, V, S>(&mut self, store: &mut Store<K, V, S>) -> Option<(K, V)>
     where
-        K: Borrow<Q>,
-        Q: Eq + Hash,
+        K: Hash + Eq,
+        S: BuildHasher,
     {
-        self.main.contains_key(key) || self.in_.contains_key(key)
+        let (k, v) = store.pop_victim_from(Segment::In)?;
+        let hash = store.hash(&k);
+        self.push_ghost(store, hash);
+        Some((k, v))
     }
+}
+
+impl<K: Hash + Eq, V, S: BuildHasher> ReplacementPolicy<K, V, S> for Q2 {
+    const MIN_SIZE: usize = MIN_SIZE;
 
-    pub fn get<Q: ?Sized>(&mut self, key: &Q) -> Option<&V>
+    fn on_hit<'a, Q>(&mut self, store: &'a mut Store<K, V, S>, k: &Q) -> Option<(&'a K, &'a V)>
     where
         K: Borrow<Q>,
-        Q: Eq + Hash,
+        Q: ?Sized + Eq + Hash,
     {
-        if self.main.contains_key(key) {
-            self.hit_count += 1;
-            self.main.move_to_front(key);
-            return self.main.get(key);
+        if store.segment_of(k)? == Segment::Main {
+            return store.get_refresh(k);
         }
-
-        if let Some((k, v)) = self.in_.remove_entry(key) {
-            self.hit_count += 1;
-            self.main.push_front(k, v);
-            return self.main.get(key);
+        // over the promotion limit a hit leaves the entry in place
+        if store.may_promote() {
+            store.protect(k);
         }
-        self.miss_count += 1;
-        None
+        store.get_key_value(k)
     }
 
-    pub fn add(&mut self, key: K, value: V) -> Option<V> {
-        if let Some(v) = self.main.get_mut(&key) {
-            let old_v = unsafe { ptr::replace(v, value) };
-            self.main.move_to_front(&key);
-            return Some(old_v);
+    fn on_insert(&mut self, store: &mut Store<K, V, S>, k: K, v: V) -> Option<(K, V)> {
+        match store.segment_of(&k) {
+            Some(Segment::Main) => return store.upsert_protected(k, v),
+            // over the promotion limit a replaced entry stays in place
+            Some(_) if !store.may_promote() => return store.upsert_front(k, v, false),
+            Some(_) => return store.upsert_protected(k, v),
+            None => {}
         }
+        let hash = store.hash(&k);
+        let ghost_age = self.take_ghost(hash);
+        let returning = if ghost_age.is_some() {
+            if self.adaptive {
+                let delta = (self.main_out.len() / (self.ghost_len() + 1)).max(1);
+                self.rebalance(delta as isize);
+            }
+            true
+        } else if self.adaptive && self.main_out.remove(&hash).is_some() {
+            let delta = (self.ghost_len() / (self.main_out.len() + 1)).max(1);
+            self.rebalance(-(delta as isize));
+            true
+        } else {
+            false
+        };
+        // over the promotion limit a returning key starts over in the in
+        // segment
+        if returning && store.may_promote() {
+            // only a ghost that gets its key into main counts as hit
+            if let Some(age) = ghost_age {
+                store.count_ghost_hit(age as usize);
+            }
+            return store.upsert_protected(k, v);
+        }
+        store.upsert_front(k, v, false)
+    }
 
-        if let Some(v) = self.in_.remove(&key) {
-            self.main.push_front(key, value);
-            return Some(v);
+    fn admits(&mut self, store: &Store<K, V, S>, k: &K) -> bool {
+        store.contains_key(k) || self.passes_doorkeeper(store.hash(k))
+    }
+
+    fn on_remove(&mut self, hash: u64) {
+        self.out.remove(&hash);
+        self.main_out.remove(&hash);
+    }
+
+    fn choose_victim(&mut self, store: &mut Store<K, V, S>) -> Option<(K, V)> {
+        if store.segment_weight(Segment::In) > self.max_weight_in {
+            if let Some(entry) = self.evict_in(store) {
+                return Some(entry);
+            }
+        }
+        match store.pop_victim_from(Segment::Main) {
+            Some((k, v)) => {
+                if self.adaptive && self.max_size_out > 0 {
+                    if self.main_out.len() >= self.max_size_out {
+                        self.main_out.pop_back();
+                    }
+                    self.main_out.push_front(store.hash(&k), ());
+                }
+                Some((k, v))
+            }
+            None => self.evict_in(store),
         }
+    }
 
-        let mut s = self.hash_builder.build_hasher();
-        key.hash(&mut s);
-        if self.out.remove(&s.finish()).is_some() {
-            self.ensure_space(true);
-            self.main.push_front(key, value);
-            return None;
+    fn on_resize(&mut self, store: &mut Store<K, V, S>, max_size: usize, max_weight: usize) {
+        if max_size != self.max_size {
+            self.max_size = max_size;
+            self.max_size_main = (max_size as f64 * self.main_cache_factor) as usize;
+            self.max_size_in = (max_size as f64 * (1.0 - self.main_cache_factor)) as usize;
+            self.max_size_out = (max_size as f64 * self.out_cache_factor) as usize;
+            while self.out.len() > self.max_size_out && self.out.pop_back().is_some() {
+                store.count_ghost_drop();
+            }
+            if let Some(filter) = &mut self.ghost_filter {
+                filter.resize(self.max_size_out);
+            }
+            while self.main_out.len() > self.max_size_out {
+                self.main_out.pop_back();
+            }
         }
+        self.max_weight = max_weight;
+        self.max_weight_in = if store.is_unweighted() {
+            self.max_size_in
+        } else {
+            (max_weight as f64 * (1.0 - self.main_cache_factor)) as usize
+        };
+    }
 
-        self.ensure_space(false);
-        self.in_.push_front(key, value);
-        None
+    fn on_purge(&mut self) {
+        self.out.clear();
+        if let Some(filter) = &mut self.ghost_filter {
+            filter.clear();
+        }
+        self.main_out.clear();
     }
 
-    fn ensure_space(&mut self, recent_exict: bool) {
-        let in_len = self.in_.len();
-        let main_len = self.main.len();
-        if in_len + main_len < self.max_size {
+    fn on_merge(&mut self, store: &mut Store<K, V, S>, _other: Q2) {
+        // cached again, the keys of the other cache are no longer ghosts
+        if self.out.is_empty() && self.main_out.is_empty() {
             return;
         }
-
-        let (k, v) = if in_len > 0
-            && (in_len > self.max_size_in || (in_len == self.max_size_in && !recent_exict))
+        for (k, _) in store
+            .iter_segment(Segment::Main)
+            .chain(store.iter_segment(Segment::In))
         {
-            let (k, v) = self.in_.pop_back().unwrap();
-            if self.out.len() + 1 > self.max_size_out {
-                self.out.pop_back();
-            }
-            let mut s = self.hash_builder.build_hasher();
-            k.hash(&mut s);
-            self.out.push_front(s.finish(), ());
-            (k, v)
+            let hash = store.hash(k);
+            self.out.remove(&hash);
+            self.main_out.remove(&hash);
+        }
+    }
+
+    fn warm_segment(&mut self, store: &Store<K, V, S>, weight: usize) -> Option<Segment> {
+        let max_weight_main = self.max_weight - self.max_weight_in;
+        if store.segment_weight(Segment::Main) + weight <= max_weight_main {
+            Some(Segment::Main)
+        } else if store.segment_weight(Segment::In) + weight <= self.max_weight_in {
+            Some(Segment::In)
         } else {
-            self.main.pop_back().unwrap()
-        };
-        self.callback.as_ref().map(|cb| cb(k, v));
+            None
+        }
     }
 
-    pub fn remove(&mut self, key: &K) -> Option<V> {
-        let mut s = self.hash_builder.build_hasher();
-        key.hash(&mut s);
-        self.out.remove(&s.finish());
-        self.main.remove(key).or_else(|| self.in_.remove(key))
+    fn memory_usage(&self) -> MemoryUsage {
+        let mut memory = self.out.memory_usage() + self.main_out.memory_usage();
+        if let Some(doorkeeper) = &self.doorkeeper {
+            memory.table_bytes += doorkeeper.heap_bytes();
+        }
+        if let Some(filter) = &self.ghost_filter {
+            memory.table_bytes += filter.heap_bytes();
+        }
+        memory
     }
 
-    pub fn purge(&mut self) {
-        self.main.clear();
-        self.in_.clear();
-        self.out.clear();
+    fn shrink_to_fit(&mut self) {
+        self.out.shrink_to_fit();
+        self.main_out.shrink_to_fit();
     }
+}
 
-    pub fn len(&self) -> usize {
-        self.main.len() + self.in_.len()
+impl<K, V, S> Cache<K, V, S>
+where
+    K: Hash + Eq,
+    S: BuildHasher + Clone,
+{
+    pub fn with_hasher(size: usize, hash_builder: S) -> Cache<K, V, S> {
+        Cache::with_param_and_hasher(size, DEFAULT_MAIN_CF, DEFAULT_OUT_CF, hash_builder)
     }
 
-    pub fn is_empty(&self) -> bool {
-        self.main.is_empty() && self.in_.is_empty()
+    pub fn with_param_and_hasher(
+        size: usize,
+        main_cache_factor: f64,
+        out_cache_factor: f64,
+        hash_builder: S,
+    ) -> Cache<K, V, S> {
+        let max_size = if size < MIN_SIZE { MIN_SIZE } else { size };
+
+        let max_size_main = (max_size as f64 * main_cache_factor) as usize;
+        let max_size_in = (max_size as f64 * (1.0 - main_cache_factor)) as usize;
+        let max_size_out = (max_size as f64 * out_cache_factor) as usize;
+        Cache::with_sizes(
+            (max_size, max_size_in, max_size_main, max_size_out),
+            main_cache_factor,
+            out_cache_factor,
+            hash_builder,
+        )
+    }
+
+    /// Creates a cache with exact segment sizes, in entries, instead of
+    /// factors of the total size. `in_size` and `main_size` are raised to
+    /// one; an `out_size` of zero disables the ghost queue. The factors
+    /// reported by `main_cache_factor` and `out_cache_factor`, and used by
+    /// `resize`, are derived from the sizes.
+    pub fn with_segment_sizes_and_hasher(
+        in_size: usize,
+        main_size: usize,
+        out_size: usize,
+        hash_builder: S,
+    ) -> Cache<K, V, S> {
+        let (in_size, main_size) = (in_size.max(1), main_size.max(1));
+        let max_size = in_size + main_size;
+        Cache::with_sizes(
+            (max_size, in_size, main_size, out_size),
+            main_size as f64 / max_size as f64,
+            out_size as f64 / max_size as f64,
+            hash_builder,
+        )
+    }
+
+    /// Creates a cache with a capacity of zero: every `add` hands the entry
+    /// straight to the eviction callback and every `get` misses.
+    pub fn disabled_with_hasher(hash_builder: S) -> Cache<K, V, S> {
+        Cache::with_sizes((0, 0, 0, 0), DEFAULT_MAIN_CF, DEFAULT_OUT_CF, hash_builder)
+    }
+
+    fn with_sizes(
+        sizes: (usize, usize, usize, usize),
+        main_cache_factor: f64,
+        out_cache_factor: f64,
+        hash_builder: S,
+    ) -> Cache<K, V, S> {
+        let (max_size, max_size_in, max_size_main, _) = sizes;
+        let policy = Q2::new(sizes, main_cache_factor, out_cache_factor);
+        core::Cache::with_segments(policy, max_size, (max_size_in, max_size_main), hash_builder)
+    }
+
+    /// Like `with_param_and_hasher`, but rejects a configuration that would
+    /// otherwise be clamped or leave a segment empty.
+    pub fn try_with_param_and_hasher(
+        size: usize,
+        main_cache_factor: f64,
+        out_cache_factor: f64,
+        hash_builder: S,
+    ) -> Result<Cache<K, V, S>, ConfigError> {
+        error::check_size(size, MIN_SIZE)?;
+        error::check_factor("main_cache_factor", main_cache_factor)?;
+        error::check_factor("out_cache_factor", out_cache_factor)?;
+
+        let cache =
+            Cache::with_param_and_hasher(size, main_cache_factor, out_cache_factor, hash_builder);
+        let (in_size, main_size, out_size) = cache.segment_max_lens();
+        error::check_segment("in", in_size)?;
+        error::check_segment("main", main_size)?;
+        error::check_segment("out", out_size)?;
+        Ok(cache)
+    }
+
+    /// Moves the entries for which `f` returns true into a new cache and
+    /// returns it, keeping their relative order in both caches. The new
+    /// cache has the limits, weigher and hasher of this one, but none of its
+    /// callbacks, policies or trackers.
+    pub fn split_by<F>(&mut self, f: F) -> Cache<K, V, S>
+    where
+        F: FnMut(&K, &V) -> bool,
+    {
+        let policy = self.policy().split_off();
+        self.split_with(policy, f)
+    }
+}
+
+impl<K, V, S> Cache<K, V, S>
+where
+    K: Hash + Eq,
+    S: BuildHasher,
+{
+    /// Installs a doorkeeper in front of the in segment: a new key is only
+    /// cached the second time it is added, and turned down to the eviction
+    /// callback the first. Keys remembered by a ghost queue are let in.
+    pub fn set_doorkeeper(&mut self, doorkeeper: Doorkeeper) {
+        self.policy_mut().doorkeeper = Some(doorkeeper);
+    }
+
+    pub fn doorkeeper(&self) -> Option<&Doorkeeper> {
+        self.policy().doorkeeper.as_ref()
     }
 
-    pub fn peek(&self, key: &K) -> Option<&V> {
-        if let Some(v) = self.main.get(key) {
-            return Some(v);
+    /// Turns on or off the compact mode of the ghost queue. Instead of the
+    /// exact hashes of the keys recently evicted from in, the cache then
+    /// remembers them in two bloom filters used in turn, at about 20 bits
+    /// per ghost. They hold the last `out_size` to twice as many keys, with
+    /// up to about two percent of false positives. Ghosts can then no longer be
+    /// forgotten one by one, listed by `iter_ghosts` or saved, and their
+    /// ages are neither bounded nor counted. Turning the mode on carries
+    /// the ghosts over; turning it off forgets them.
+    pub fn set_compact_ghosts(&mut self, compact: bool) {
+        let policy = self.policy_mut();
+        if compact == policy.ghost_filter.is_some() {
+            return;
+        }
+        if !compact {
+            policy.ghost_filter = None;
+            return;
+        }
+        let mut filter = RotatingBloom::new(policy.max_size_out);
+        // the exact queue is no longer used, so its table is freed
+        let out = mem::replace(
+            &mut policy.out,
+            LinkedHashMap::with_hasher(Default::default()),
+        );
+        for (hash, _) in out.iter().rev() {
+            filter.insert(*hash);
         }
-        self.in_.get(key)
+        policy.ghost_filter = Some(Box::new(filter));
     }
 
-    pub fn shrink_to_fit(&mut self) {
-        self.in_.shrink_to_fit();
-        self.out.shrink_to_fit();
-        self.main.shrink_to_fit();
+    pub fn has_compact_ghosts(&self) -> bool {
+        self.policy().ghost_filter.is_some()
     }
 
-    pub fn stat(&self) -> Info {
-        Info {
-            hit_count: self.hit_count,
-            miss_count: self.miss_count,
+    /// Bounds the age of the ghosts, counted in insertions into the ghost
+    /// queue rather than in time: a key that saw more than `age` other keys
+    /// enter the queue after it is forgotten, even if the queue has room,
+    /// and no longer goes straight to main when added again. The ages are
+    /// those summed in `Info::ghost_age_insertions_total`. `None`, the
+    /// default, only bounds the queue by its length.
+    pub fn set_max_ghost_age_insertions(&mut self, age: Option<usize>) {
+        let (policy, store) = self.parts_mut();
+        policy.max_ghost_age = age.map(|age| age as u64);
+        policy.drop_old_ghosts(store);
+        self.flush_evicted();
+    }
+
+    pub fn max_ghost_age_insertions(&self) -> Option<usize> {
+        self.policy().max_ghost_age.map(|age| age as usize)
+    }
+
+    /// Turns on or off the adaptive balancing of the segments. In adaptive
+    /// mode the cache also remembers as many keys recently evicted from main
+    /// as the ghost queue holds, as ARC does. Adding a key remembered from
+    /// the in segment shows that it was too small and moves the split
+    /// towards it; adding one remembered from main moves it the other way.
+    /// Each step is larger the rarer that kind of ghost is.
+    /// `main_cache_factor` follows the split, which `resize` and
+    /// `set_weigher` keep; turning the mode off freezes it.
+    pub fn set_adaptive(&mut self, adaptive: bool) {
+        let policy = self.policy_mut();
+        policy.adaptive = adaptive;
+        if !adaptive {
+            policy.main_out.clear();
         }
     }
-}
 
-pub struct Info {
-    pub hit_count: usize,
-    pub miss_count: usize,
+    pub fn is_adaptive(&self) -> bool {
+        self.policy().adaptive
+    }
+
+    /// Bounds the number of entries moved to main per interval, by a hit in
+    /// the in segment or by the return of a key evicted recently. Over the
+    /// limit hits leave the entry in place and returning keys start over in
+    /// the in segment.
+    pub fn set_promotion_limit(&mut self, limit: PromotionLimit) {
+        self.store_mut().set_promotion_limit(limit);
+    }
+
+    pub fn promotion_limit(&self) -> Option<&PromotionLimit> {
+        self.store().promotion_limit()
+    }
+
+    pub fn main_cache_factor(&self) -> f64 {
+        self.policy().main_cache_factor
+    }
+
+    pub fn out_cache_factor(&self) -> f64 {
+        self.policy().out_cache_factor
+    }
+
+    /// Returns the current `(in, main, out)` segment lengths.
+    pub fn segment_lens(&self) -> (usize, usize, usize) {
+        let store = self.store();
+        let ghosts = self.policy().ghost_len();
+        (
+            store.segment_len(Segment::In),
+            store.segment_len(Segment::Main),
+            ghosts,
+        )
+    }
+
+    /// Returns the configured `(in, main, out)` segment maximums.
+    pub fn segment_max_lens(&self) -> (usize, usize, usize) {
+        let policy = self.policy();
+        (
+            policy.max_size_in,
+            policy.max_size_main,
+            policy.max_size_out,
+        )
+    }
+
+    /// Iterates over the in segment, from the most to the least recently
+    /// added entry.
+    pub fn iter_in(&self) -> map::Iter<'_, K, V> {
+        self.store().iter_segment(Segment::In)
+    }
+
+    /// Iterates over the main segment, from the most to the least recently
+    /// used entry.
+    pub fn iter_main(&self) -> map::Iter<'_, K, V> {
+        self.store().iter_segment(Segment::Main)
+    }
+
+    /// Iterates over the hashes of the keys in the ghost queue, from the most
+    /// recently evicted from the in segment to the next to be forgotten.
+    pub fn iter_ghosts(&self) -> impl DoubleEndedIterator<Item = u64> + ExactSizeIterator + '_ {
+        self.policy().out.iter().map(|(&hash, _)| hash)
+    }
+
+    /// Writes a versioned snapshot of the cache to `writer`. Only what
+    /// serde serializes is saved.
+    #[cfg(feature = "persistence")]
+    pub fn save_to<W>(&self, writer: W) -> Result<(), SnapshotError>
+    where
+        K: serde::Serialize,
+        V: serde::Serialize,
+        W: std::io::Write,
+    {
+        persistence::save(persistence::Kind::Q2, self, writer)
+    }
+
+    /// Reads a cache written by `save_to`.
+    #[cfg(feature = "persistence")]
+    pub fn load_from<R>(mut reader: R) -> Result<Cache<K, V, S>, SnapshotError>
+    where
+        K: serde::de::DeserializeOwned,
+        V: serde::de::DeserializeOwned,
+        S: Clone + Default,
+        R: std::io::Read,
+    {
+        let version = persistence::read_header(persistence::Kind::Q2, &mut reader)?;
+        Cache::decode(version, reader).map_err(SnapshotError::Encoding)
+    }
 }
 
-impl<K: Hash + Eq, V> Cache<K, V, RandomState> {
+impl<K: Hash + Eq, V> Cache<K, V, DefaultState> {
     pub fn with_params(
         size: usize,
         main_cache_factor: f64,
         out_cache_factor: f64,
-    ) -> Cache<K, V, RandomState> {
+    ) -> Cache<K, V, DefaultState> {
         Cache::with_param_and_hasher(
             size,
             main_cache_factor,
@@ -209,9 +638,193 @@ impl<K: Hash + Eq, V> Cache<K, V, RandomState> {
         )
     }
 
-    pub fn new(size: usize) -> Cache<K, V, RandomState> {
+    pub fn with_segment_sizes(
+        in_size: usize,
+        main_size: usize,
+        out_size: usize,
+    ) -> Cache<K, V, DefaultState> {
+        Cache::with_segment_sizes_and_hasher(in_size, main_size, out_size, Default::default())
+    }
+
+    pub fn try_with_params(
+        size: usize,
+        main_cache_factor: f64,
+        out_cache_factor: f64,
+    ) -> Result<Cache<K, V, DefaultState>, ConfigError> {
+        Cache::try_with_param_and_hasher(
+            size,
+            main_cache_factor,
+            out_cache_factor,
+            Default::default(),
+        )
+    }
+
+    pub fn new(size: usize) -> Cache<K, V, DefaultState> {
         Cache::with_params(size, DEFAULT_MAIN_CF, DEFAULT_OUT_CF)
     }
+
+    pub fn disabled() -> Cache<K, V, DefaultState> {
+        Cache::disabled_with_hasher(Default::default())
+    }
+}
+
+impl<K: Hash + Eq, V> Cache<K, V, SeededState> {
+    /// Creates a cache that hashes, and so behaves, the same on every run
+    /// given the same `seed`.
+    pub fn with_seed(size: usize, seed: u64) -> Cache<K, V, SeededState> {
+        Cache::with_hasher(size, SeededState::new(seed))
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<K, V, S> serde::Serialize for Cache<K, V, S>
+where
+    K: Hash + Eq + serde::Serialize,
+    V: serde::Serialize,
+    S: BuildHasher,
+{
+    /// Serializes the configuration, the entries of each segment from the
+    /// most to the least recently used, and the ghost queue. The ghosts are
+    /// key hashes, so they only carry over to a cache whose hasher hashes
+    /// the same way. Callbacks, weighers and statistics are not serialized.
+    fn serialize<T: serde::Serializer>(&self, serializer: T) -> Result<T::Ok, T::Error> {
+        use serde::ser::SerializeStruct;
+
+        let (store, policy) = (self.store(), self.policy());
+        let mut state = serializer.serialize_struct("Cache", 10)?;
+        state.serialize_field("max_size", &self.max_size())?;
+        state.serialize_field("main_cache_factor", &policy.main_cache_factor)?;
+        state.serialize_field("out_cache_factor", &policy.out_cache_factor)?;
+        state.serialize_field("in", &core::SegmentEntries(store, Segment::In))?;
+        state.serialize_field("main", &core::SegmentEntries(store, Segment::Main))?;
+        state.serialize_field("out", &policy.out)?;
+        state.serialize_field("ghost_seq", &policy.ghost_seq)?;
+        state.serialize_field("in_size", &Some(policy.max_size_in))?;
+        state.serialize_field("out_size", &Some(policy.max_size_out))?;
+        state.serialize_field("main_size", &Some(policy.max_size_main))?;
+        state.end()
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de, K, V, S> serde::Deserialize<'de> for Cache<K, V, S>
+where
+    K: Hash + Eq + serde::Deserialize<'de>,
+    V: serde::Deserialize<'de>,
+    S: BuildHasher + Clone + Default,
+{
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let repr = Repr::deserialize(deserializer)?;
+        Cache::from_repr(repr).map_err(serde::de::Error::custom)
+    }
+}
+
+/// A serialized cache, the fields of the latest version last.
+#[cfg(feature = "serde")]
+#[derive(serde::Deserialize)]
+struct Repr<K, V> {
+    max_size: usize,
+    main_cache_factor: f64,
+    out_cache_factor: f64,
+    #[serde(rename = "in")]
+    in_: Vec<(K, V)>,
+    main: Vec<(K, V)>,
+    out: Vec<(u64, u64)>,
+    ghost_seq: u64,
+    // absent from serializations made before segment sizes could be set
+    // exactly; the factors give them back up to rounding
+    #[serde(default)]
+    in_size: Option<usize>,
+    #[serde(default)]
+    out_size: Option<usize>,
+    // absent from those made before main could be smaller than the rest
+    #[serde(default)]
+    main_size: Option<usize>,
+}
+
+#[cfg(feature = "serde")]
+impl<K, V, S> Cache<K, V, S>
+where
+    K: Hash + Eq,
+    S: BuildHasher + Clone + Default,
+{
+    /// Rebuilds a cache from its serialized form, which must not have a key
+    /// in both segments.
+    fn from_repr(repr: Repr<K, V>) -> Result<Cache<K, V, S>, &'static str> {
+        let mut cache = if repr.max_size == 0 {
+            Cache::disabled_with_hasher(S::default())
+        } else if let (Some(in_size), Some(out_size)) = (repr.in_size, repr.out_size) {
+            let main_size = repr
+                .main_size
+                .unwrap_or_else(|| repr.max_size.saturating_sub(in_size));
+            Cache::with_sizes(
+                (repr.max_size, in_size, main_size, out_size),
+                repr.main_cache_factor,
+                repr.out_cache_factor,
+                S::default(),
+            )
+        } else {
+            Cache::with_param_and_hasher(
+                repr.max_size,
+                repr.main_cache_factor,
+                repr.out_cache_factor,
+                S::default(),
+            )
+        };
+        for (k, v) in repr.in_ {
+            cache.restore(k, v, false);
+        }
+        for (k, v) in repr.main {
+            if cache.contains_key(&k) {
+                return Err("a key is in both segments");
+            }
+            cache.restore(k, v, true);
+        }
+        let policy = cache.policy_mut();
+        for (hash, stamp) in repr.out.into_iter().take(policy.max_size_out) {
+            policy.out.push_back(hash, stamp);
+        }
+        policy.ghost_seq = repr.ghost_seq;
+        cache.restored();
+        Ok(cache)
+    }
+
+    /// Reads a cache in the snapshot format of `version`, the fields it
+    /// lacks left out of the `Repr`.
+    #[cfg(feature = "persistence")]
+    fn decode<R>(version: u16, mut reader: R) -> bincode::Result<Cache<K, V, S>>
+    where
+        K: serde::de::DeserializeOwned,
+        V: serde::de::DeserializeOwned,
+        R: std::io::Read,
+    {
+        fn field<T: serde::de::DeserializeOwned, R: std::io::Read>(
+            reader: &mut R,
+        ) -> bincode::Result<T> {
+            bincode::deserialize_from(reader)
+        }
+
+        if version >= 3 {
+            return bincode::deserialize_from(reader);
+        }
+        let mut repr = Repr {
+            max_size: field(&mut reader)?,
+            main_cache_factor: field(&mut reader)?,
+            out_cache_factor: field(&mut reader)?,
+            in_: field(&mut reader)?,
+            main: field(&mut reader)?,
+            out: field(&mut reader)?,
+            ghost_seq: field(&mut reader)?,
+            in_size: None,
+            out_size: None,
+            main_size: None,
+        };
+        if version == 2 {
+            repr.in_size = field(&mut reader)?;
+            repr.out_size = field(&mut reader)?;
+        }
+        Cache::from_repr(repr).map_err(serde::de::Error::custom)
+    }
 }
 
 #[cfg(test)]
@@ -242,7 +855,7 @@ mod tests {
                 }
                 _ => {}
             }
-            assert!(cache.main.len() + cache.in_.len() <= size);
+            assert!(cache.segment_lens().1 + cache.segment_lens().0 <= size);
         }
     }
 
@@ -254,14 +867,14 @@ mod tests {
             cache.add(i, i);
         }
 
-        assert_eq!(cache.in_.len(), 128);
-        assert_eq!(cache.main.len(), 0);
+        assert_eq!(cache.segment_lens().0, 128);
+        assert_eq!(cache.segment_lens().1, 0);
 
         for i in 0usize..size {
             assert!(cache.get(&i).is_some());
         }
-        assert_eq!(cache.in_.len(), 0);
-        assert_eq!(cache.main.len(), 128);
+        assert_eq!(cache.segment_lens().0, 0);
+        assert_eq!(cache.segment_lens().1, 128);
     }
 
     #[test]
@@ -270,19 +883,18 @@ mod tests {
         let mut cache: Cache<usize, usize> = Cache::new(size);
 
         cache.add(1, 1);
-        assert_eq!(cache.in_.len(), 1);
-        assert_eq!(cache.main.len(), 0);
+        assert_eq!(cache.segment_lens().0, 1);
+        assert_eq!(cache.segment_lens().1, 0);
 
         cache.add(1, 1);
-        assert_eq!(cache.in_.len(), 0);
-        assert_eq!(cache.main.len(), 1);
+        assert_eq!(cache.segment_lens().0, 0);
+        assert_eq!(cache.segment_lens().1, 1);
 
         cache.add(1, 1);
-        assert_eq!(cache.in_.len(), 0);
-        assert_eq!(cache.main.len(), 1);
+        assert_eq!(cache.segment_lens().0, 0);
+        assert_eq!(cache.segment_lens().1, 1);
     }
 
-
     use std::cell::RefCell;
     use std::rc::Rc;
     #[test]
@@ -301,20 +913,20 @@ mod tests {
         cache.add(3, 3);
         cache.add(4, 4);
         cache.add(5, 5);
-        assert_eq!(cache.in_.len(), 4);
-        assert_eq!(cache.out.len(), 1);
-        assert_eq!(cache.main.len(), 0);
+        assert_eq!(cache.segment_lens().0, 4);
+        assert_eq!(cache.segment_lens().2, 1);
+        assert_eq!(cache.segment_lens().1, 0);
         assert_eq!(*e_count.as_ref().borrow(), 1);
 
         cache.add(1, 1);
-        assert_eq!(cache.in_.len(), 3);
-        assert_eq!(cache.out.len(), 1);
-        assert_eq!(cache.main.len(), 1);
+        assert_eq!(cache.segment_lens().0, 3);
+        assert_eq!(cache.segment_lens().2, 1);
+        assert_eq!(cache.segment_lens().1, 1);
 
         cache.add(6, 6);
-        assert_eq!(cache.in_.len(), 3);
-        assert_eq!(cache.out.len(), 2);
-        assert_eq!(cache.main.len(), 1);
+        assert_eq!(cache.segment_lens().0, 3);
+        assert_eq!(cache.segment_lens().2, 2);
+        assert_eq!(cache.segment_lens().1, 1);
     }
 
     #[test]
@@ -343,6 +955,45 @@ mod tests {
         assert!(cache.get(&200).is_none());
     }
 
+    #[test]
+    fn test_get_many() {
+        let mut cache: Cache<usize, usize> = Cache::new(16);
+        for i in 0..4 {
+            cache.add(i, i * 10);
+        }
+        // each hit in in moves its entry to main
+        assert_eq!(
+            cache.get_many(&[0, 1, 9, 0]),
+            vec![Some(&0), Some(&10), None, Some(&0)]
+        );
+        assert_eq!(cache.segment_lens().1, 2);
+        let info = cache.stat();
+        assert_eq!((info.hit_count, info.miss_count), (3, 1));
+    }
+
+    #[test]
+    fn test_get_mut() {
+        let mut cache: Cache<usize, Vec<usize>> = Cache::new(4);
+        cache.add(0, vec![]);
+        cache.add(1, vec![]);
+        cache.peek_mut(&0).unwrap().push(1);
+        assert_eq!(cache.segment_lens(), (2, 0, 0));
+        cache.get_mut(&0).unwrap().push(2);
+        assert_eq!(cache.segment_lens(), (1, 1, 0));
+        cache.peek_mut(&0).unwrap().push(3);
+        assert_eq!(cache.peek(&0), Some(&vec![1, 2, 3]));
+        assert!(cache.get_mut(&2).is_none());
+        assert!(cache.peek_mut(&2).is_none());
+        let info = cache.stat();
+        assert_eq!((info.hit_count, info.miss_count), (1, 1));
+
+        // over the promotion limit a hit leaves the entry in in
+        cache.set_promotion_limit(PromotionLimit::per_operations(0, 1000));
+        cache.get_mut(&1).unwrap().push(4);
+        assert_eq!(cache.segment_lens(), (1, 1, 0));
+        assert_eq!(cache.peek(&1), Some(&vec![4]));
+    }
+
     #[test]
     fn test_contains() {
         let mut cache: Cache<usize, usize> = Cache::new(2);
@@ -362,4 +1013,482 @@ mod tests {
         cache.add(3, 3);
         assert!(!cache.contains_key(&1));
     }
+
+    #[test]
+    fn test_segment_lens() {
+        let mut cache: Cache<usize, usize> = Cache::new(4);
+        assert_eq!(cache.segment_max_lens(), (1, 3, 2));
+        assert_eq!(cache.max_size(), 4);
+        assert_eq!(cache.main_cache_factor(), 0.75);
+        assert_eq!(cache.out_cache_factor(), 0.5);
+        assert!(cache.capacity() >= 4);
+
+        for i in 0usize..5 {
+            cache.add(i, i);
+        }
+        cache.add(0, 0);
+        assert_eq!(cache.segment_lens(), (3, 1, 1));
+        // 0 came back from the ghost queue straight into main
+        let in_: Vec<_> = cache.iter_in().map(|(&k, _)| k).collect();
+        assert_eq!(in_, vec![4, 3, 2]);
+        let main: Vec<_> = cache.iter_main().map(|(&k, _)| k).collect();
+        assert_eq!(main, vec![0]);
+        let ghosts: Vec<_> = cache.iter_ghosts().collect();
+        assert_eq!(ghosts, vec![cache.hasher().hash_one(1usize)]);
+    }
+
+    #[test]
+    fn test_stat() {
+        let mut cache: Cache<usize, usize> = Cache::new(4);
+        cache.add(1, 1);
+        cache.add(2, 2);
+
+        assert!(cache.get(&1).is_some());
+        assert!(cache.get(&1).is_some());
+        assert!(cache.get(&3).is_none());
+        assert!(cache.peek(&2).is_some());
+
+        cache.add(2, 2);
+        for i in 3usize..7 {
+            cache.add(i, i);
+        }
+
+        let info = cache.stat();
+        assert_eq!(info.hit_count, 2);
+        assert_eq!(info.miss_count, 1);
+        assert_eq!(info.insert_count, 6);
+        assert_eq!(info.replace_count, 1);
+        assert_eq!(info.eviction_count, 2);
+        assert_eq!(info.len, 4);
+        assert_eq!(info.in_hit_count, 1);
+        assert_eq!(info.main_hit_count, 1);
+        assert_eq!(info.ghost_hit_count, 0);
+
+        cache.add(3, 3);
+        assert_eq!(cache.stat().ghost_hit_count, 1);
+    }
+
+    #[test]
+    fn test_ghost_stats() {
+        let mut cache: Cache<usize, usize> = Cache::with_params(4, 0.5, 0.5);
+        for i in 0usize..8 {
+            cache.add(i, i);
+        }
+        // 0..4 went through the ghost queue of length 2, 0 and 1 fell out
+        assert_eq!(cache.segment_lens(), (4, 0, 2));
+        let info = cache.stat();
+        assert_eq!(info.ghost_drop_count, 2);
+        assert_eq!(info.ghost_hit_count, 0);
+
+        // 2 entered the ghost queue before 3
+        cache.add(2, 2);
+        let info = cache.stat();
+        assert_eq!(info.ghost_hit_count, 1);
+        assert_eq!(info.avg_ghost_age_insertions(), 1.0);
+        assert_eq!(info.ghost_admission_ratio(), 1.0 / 9.0);
+    }
+
+    #[test]
+    fn test_add_with_priority() {
+        let mut cache = Cache::new(4);
+        cache.add_with_priority(1, "page", 10);
+        for k in 2..8 {
+            cache.add(k, "thumbnail");
+        }
+        // the in segment evicts the thumbnails and remembers them
+        assert!(cache.contains_key(&1));
+        assert_eq!(cache.segment_lens(), (4, 0, 2));
+
+        // a returning key goes to main at priority 0
+        cache.add(6, "thumbnail");
+        assert_eq!(cache.priority(&6), Some(0));
+        assert_eq!(cache.iter_main().next(), Some((&6, &"thumbnail")));
+        assert_eq!(cache.priority(&1), Some(10));
+    }
+
+    #[test]
+    fn test_promotion_limit() {
+        let mut cache: Cache<usize, usize> = Cache::new(8);
+        cache.set_promotion_limit(PromotionLimit::per_operations(1, 1000));
+        cache.add(0, 0);
+        cache.add(1, 1);
+        assert_eq!(cache.get(&0), Some(&0));
+        assert_eq!(cache.get(&1), Some(&1));
+        assert_eq!(cache.segment_lens().0, 1);
+        assert_eq!(cache.add(1, 10), Some(1));
+        assert!(cache.iter_in().any(|(&k, &v)| (k, v) == (1, 10)));
+
+        // a key back from the ghosts over the limit starts over in in
+        let mut cache: Cache<usize, usize> = Cache::new(8);
+        for i in 0..20 {
+            cache.add(i, i);
+        }
+        let ghost = cache.iter_ghosts().next().unwrap();
+        let key = (0..20)
+            .find(|k| cache.hasher().hash_one(k) == ghost)
+            .unwrap();
+        cache.set_promotion_limit(PromotionLimit::per_operations(0, 1000));
+        cache.add(key, key);
+        assert!(cache.iter_in().any(|(&k, _)| k == key));
+        assert_eq!(cache.iter_main().count(), 0);
+        assert_eq!(cache.stat().ghost_hit_count, 0);
+    }
+
+    #[test]
+    fn test_doorkeeper() {
+        let mut cache: Cache<usize, usize> = Cache::new(8);
+        cache.set_doorkeeper(Doorkeeper::new(100));
+        let rejected = std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
+        let log = rejected.clone();
+        cache.set_eviction_callback(move |k, _| log.borrow_mut().push(k));
+
+        assert_eq!(cache.add(1, 1), None);
+        assert!(!cache.contains_key(&1));
+        cache.add(1, 1);
+        assert_eq!(cache.peek(&1), Some(&1));
+        assert_eq!(*rejected.as_ref().borrow(), vec![1]);
+
+        // a key remembered by the ghost queue is let in at once
+        let mut cache: Cache<usize, usize> = Cache::new(8);
+        for i in 0..20 {
+            cache.add(i, i);
+        }
+        cache.set_doorkeeper(Doorkeeper::new(100));
+        let ghost = cache.iter_ghosts().next().unwrap();
+        let key = (0..20)
+            .find(|k| cache.hasher().hash_one(k) == ghost)
+            .unwrap();
+        cache.add(key, key);
+        assert!(cache.iter_main().any(|(&k, _)| k == key));
+        cache.add(100, 100);
+        assert!(!cache.contains_key(&100));
+    }
+
+    #[test]
+    fn test_compact_ghosts() {
+        let mut cache: Cache<usize, usize> = Cache::new(8);
+        for i in 0..20 {
+            cache.add(i, i);
+        }
+        // the ghosts carry over
+        assert_eq!(cache.segment_lens(), (8, 0, 4));
+        cache.set_compact_ghosts(true);
+        assert_eq!(cache.iter_ghosts().count(), 0);
+        assert_eq!(cache.segment_lens(), (8, 0, 4));
+        cache.add(9, 9);
+        assert_eq!(
+            cache.iter_main().map(|(&k, _)| k).collect::<Vec<_>>(),
+            vec![9]
+        );
+
+        // seeded, as a false positive would send a new key to main
+        let mut cache: Cache<usize, usize, SeededState> = Cache::with_seed(8, 0);
+        cache.set_compact_ghosts(true);
+        for i in 0..20 {
+            cache.add(i, i);
+        }
+        // 12 keys were evicted from in and the filters hold the last 4
+        assert_eq!(cache.segment_lens().2, 4);
+        cache.add(11, 11);
+        cache.add(0, 0);
+        assert_eq!(
+            cache.iter_main().map(|(&k, _)| k).collect::<Vec<_>>(),
+            vec![11]
+        );
+        assert_eq!(cache.stat().ghost_hit_count, 1);
+
+        // and so do they when the filters are resized
+        cache.resize(16);
+        cache.add(10, 10);
+        assert!(cache.iter_main().any(|(&k, _)| k == 10));
+        assert_eq!(cache.stat().ghost_hit_count, 2);
+
+        cache.purge();
+        assert_eq!(cache.segment_lens(), (0, 0, 0));
+    }
+
+    #[test]
+    fn test_low_water_mark() {
+        let mut cache: Cache<usize, usize> = Cache::new(8);
+        cache.set_low_water_mark(Some(4)).unwrap();
+        for k in 0..8 {
+            cache.add(k, k);
+        }
+        assert_eq!(cache.len(), 8);
+        cache.add(8, 8);
+        assert_eq!(cache.len(), 4);
+        assert_eq!(cache.segment_lens().2, 4);
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut cache = Cache::new(16);
+        let mut other = Cache::new(16);
+        for k in 0..3 {
+            cache.add(k, "mine");
+        }
+        assert!(cache.get(&0).is_some());
+        for k in [2, 3, 0] {
+            other.add(k, "theirs");
+        }
+        assert!(other.get(&2).is_some());
+        let evicted = Rc::new(RefCell::new(Vec::new()));
+        let log = evicted.clone();
+        cache.set_eviction_callback(move |k, v| log.borrow_mut().push((k, v)));
+
+        // of a key in both, the entry in main is kept
+        cache.merge(other);
+        let mut entries: Vec<_> = cache.iter().map(|(&k, &v)| (k, v)).collect();
+        entries.sort();
+        assert_eq!(
+            entries,
+            vec![(0, "mine"), (1, "mine"), (2, "theirs"), (3, "theirs")]
+        );
+        assert_eq!(cache.segment_lens().1, 2);
+        assert_eq!(*evicted.as_ref().borrow(), vec![(2, "mine"), (0, "theirs")]);
+        let info = cache.stat();
+        assert_eq!((info.insert_count, info.replace_count), (4, 1));
+        assert_eq!(info.eviction_count, 0);
+        assert_eq!(cache.weighted_size(), 4);
+    }
+
+    #[test]
+    fn test_add_if_version() {
+        let mut cache: Cache<usize, usize> = Cache::new(8);
+        assert_eq!(cache.add_if_version(0, 0, None), Ok(Some(1)));
+        // promoted into main by the replacement
+        assert_eq!(cache.add_if_version(0, 1, Some(1)), Ok(Some(2)));
+        assert_eq!(cache.segment_lens().1, 1);
+        assert_eq!(cache.add_if_version(0, 2, Some(1)), Err(2));
+        cache.remove(&0);
+        assert_eq!(cache.version(&0), None);
+        assert_eq!(cache.add_if_version(0, 3, None), Ok(Some(3)));
+        // a write through a reference moves the version on
+        *cache.peek_mut(&0).unwrap() = 4;
+        assert_eq!(cache.version(&0), Some(4));
+        *cache.get_mut(&0).unwrap() = 5;
+        assert_eq!(cache.version(&0), Some(5));
+    }
+
+    #[test]
+    fn test_max_ghost_age_insertions() {
+        let mut cache: Cache<usize, usize> = Cache::with_params(8, 0.5, 1.0);
+        cache.set_max_ghost_age_insertions(Some(2));
+        for i in 0usize..12 {
+            cache.add(i, i);
+        }
+        // 0..4 entered the ghost queue, 0 is three insertions old
+        assert_eq!(cache.segment_lens(), (8, 0, 3));
+        assert_eq!(cache.stat().ghost_drop_count, 1);
+
+        cache.add(1, 1);
+        cache.add(0, 0);
+        assert_eq!(cache.stat().ghost_hit_count, 1);
+        let main: Vec<_> = cache.iter_main().map(|(&k, _)| k).collect();
+        assert_eq!(main, vec![1]);
+
+        cache.set_max_ghost_age_insertions(Some(0));
+        assert_eq!(cache.max_ghost_age_insertions(), Some(0));
+        assert_eq!(cache.segment_lens().2, 1);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde() {
+        use std::collections::hash_map::DefaultHasher;
+        use std::hash::BuildHasherDefault;
+
+        type Fixed = BuildHasherDefault<DefaultHasher>;
+        let mut cache: Cache<usize, usize, Fixed> =
+            Cache::with_param_and_hasher(4, 0.5, 0.5, Fixed::default());
+        for i in 0usize..8 {
+            cache.add(i, i);
+        }
+        cache.add(3, 3);
+
+        let json = serde_json::to_string(&cache).unwrap();
+        let mut restored: Cache<usize, usize, Fixed> = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.segment_lens(), cache.segment_lens());
+        assert_eq!(
+            restored.iter().collect::<Vec<_>>(),
+            cache.iter().collect::<Vec<_>>()
+        );
+
+        // 2 is still remembered as a ghost
+        restored.add(2, 2);
+        assert_eq!(restored.stat().ghost_hit_count, 1);
+        assert_eq!(serde_json::to_string(&restored).unwrap(), {
+            cache.add(2, 2);
+            serde_json::to_string(&cache).unwrap()
+        });
+
+        let json = r#"{"max_size":4,"main_cache_factor":0.5,"out_cache_factor":0.5,
+            "in":[[1,1]],"main":[[1,2]],"out":[],"ghost_seq":0}"#;
+        let err = serde_json::from_str::<Cache<usize, usize>>(json)
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("a key is in both segments"));
+    }
+
+    #[test]
+    fn test_warm_from_iter() {
+        let mut cache: Cache<usize, usize> = Cache::with_params(4, 0.5, 0.5);
+        assert_eq!(cache.warm_from_iter((0..6).map(|i| (i, i))), 4);
+        assert_eq!(cache.segment_lens(), (2, 2, 0));
+        assert_eq!(
+            cache.iter().map(|(&k, _)| k).collect::<Vec<_>>(),
+            vec![0, 1, 2, 3]
+        );
+        cache.get(&0);
+        assert_eq!(cache.stat().main_hit_count, 1);
+    }
+
+    #[test]
+    fn test_adaptive() {
+        let mut cache: Cache<usize, usize> = Cache::new(10);
+        cache.set_adaptive(true);
+        assert_eq!(cache.segment_max_lens(), (2, 7, 5));
+
+        // keys coming back just after leaving in grow it
+        for i in 0..20 {
+            cache.add(i, i);
+        }
+        for i in 5..10 {
+            cache.add(i, i);
+        }
+        assert_eq!(cache.segment_max_lens(), (7, 3, 5));
+        assert_eq!(cache.main_cache_factor(), 0.3);
+
+        // keys coming back just after leaving main shrink it again
+        for i in 100..120 {
+            cache.add(i, i);
+            cache.add(i, i);
+        }
+        assert_eq!(cache.policy().main_out.len(), 5);
+        for i in 115..120 {
+            cache.add(i, i);
+        }
+        assert_eq!(cache.segment_max_lens(), (5, 5, 5));
+        assert_eq!(cache.segment_lens(), (5, 5, 5));
+
+        cache.set_adaptive(false);
+        assert!(cache.policy().main_out.is_empty());
+        cache.resize(20);
+        assert_eq!(cache.segment_max_lens(), (10, 10, 10));
+    }
+
+    #[test]
+    fn test_segment_sizes() {
+        // factors close to a 1 + 2 split truncate the in segment to nothing
+        let cache: Cache<usize, usize> = Cache::with_params(3, 0.7, 0.34);
+        assert_eq!(cache.segment_max_lens(), (0, 2, 1));
+        #[cfg(feature = "serde")]
+        {
+            let json = serde_json::to_string(&cache).unwrap();
+            let restored: Cache<usize, usize> = serde_json::from_str(&json).unwrap();
+            assert_eq!(restored.segment_max_lens(), (0, 2, 1));
+            assert_eq!(restored.max_size(), 3);
+            assert_eq!(restored.main_cache_factor(), 0.7);
+            assert_eq!(restored.out_cache_factor(), 0.34);
+        }
+        #[cfg(feature = "persistence")]
+        {
+            let mut snapshot = Vec::new();
+            cache.save_to(&mut snapshot).unwrap();
+            let restored: Cache<usize, usize> = Cache::load_from(&snapshot[..]).unwrap();
+            assert_eq!(restored.segment_max_lens(), (0, 2, 1));
+            assert_eq!(restored.max_size(), 3);
+        }
+
+        let mut cache: Cache<usize, usize> = Cache::with_segment_sizes(1, 2, 1);
+        assert_eq!(cache.segment_max_lens(), (1, 2, 1));
+        assert_eq!(cache.max_size(), 3);
+        for i in 0..4 {
+            cache.add(i, i);
+        }
+        assert_eq!(cache.segment_lens(), (3, 0, 1));
+        cache.add(0, 0);
+        assert_eq!(cache.segment_lens(), (2, 1, 1));
+
+        #[cfg(feature = "serde")]
+        {
+            let json = serde_json::to_string(&cache).unwrap();
+            let restored: Cache<usize, usize> = serde_json::from_str(&json).unwrap();
+            assert_eq!(restored.segment_max_lens(), (1, 2, 1));
+        }
+    }
+
+    #[test]
+    fn test_try_with_params() {
+        assert!(Cache::<usize, usize>::try_with_params(128, 0.75, 0.5).is_ok());
+        assert_eq!(
+            Cache::<usize, usize>::try_with_params(1, 0.75, 0.5).err(),
+            Some(ConfigError::SizeTooSmall { size: 1, min: 2 })
+        );
+        assert_eq!(
+            Cache::<usize, usize>::try_with_params(128, 1.5, 0.5).err(),
+            Some(ConfigError::FactorOutOfRange {
+                name: "main_cache_factor",
+                value: 1.5
+            })
+        );
+        assert_eq!(
+            Cache::<usize, usize>::try_with_params(4, 0.9, 0.5).err(),
+            Some(ConfigError::EmptySegment { segment: "in" })
+        );
+    }
+
+    #[test]
+    fn test_disabled() {
+        let mut cache: Cache<usize, usize> = Cache::disabled();
+
+        let e_count = Rc::new(RefCell::new(0));
+        let count = e_count.clone();
+        cache.set_eviction_callback(move |_, _| {
+            *count.borrow_mut() += 1;
+        });
+
+        assert!(cache.is_disabled());
+        assert_eq!(cache.add(1, 1), None);
+        assert_eq!(cache.add(1, 1), None);
+        assert_eq!(*e_count.as_ref().borrow(), 2);
+        assert!(cache.get(&1).is_none());
+        assert!(cache.is_empty());
+        assert_eq!(cache.segment_lens(), (0, 0, 0));
+    }
+
+    #[test]
+    fn test_weigher() {
+        let mut cache: Cache<usize, usize> = Cache::new(8);
+        cache.set_weigher(100, |_, v| *v as u32);
+
+        for i in 1usize..6 {
+            cache.add(i, 20);
+        }
+        assert_eq!(cache.weighted_size(), 100);
+        cache.add(6, 20);
+        assert_eq!(cache.weighted_size(), 100);
+        assert_eq!(cache.segment_lens(), (5, 0, 1));
+
+        cache.add(1, 50);
+        assert_eq!(cache.weighted_size(), 90);
+        assert_eq!(cache.segment_lens(), (2, 1, 3));
+
+        cache.add(9, 101);
+        assert!(!cache.contains_key(&9));
+        assert_eq!(cache.weighted_size(), 90);
+    }
+
+    #[test]
+    fn test_eviction_filter() {
+        let mut cache: Cache<usize, usize> = Cache::new(4);
+        cache.set_eviction_filter(|k, _| *k != 1);
+
+        for i in 1usize..6 {
+            cache.add(i, i);
+        }
+        assert!(cache.contains_key(&1));
+        assert!(!cache.contains_key(&2));
+        assert_eq!(cache.len(), 4);
+    }
 }
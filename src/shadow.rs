@@ -0,0 +1,293 @@
+//! What-if comparison of policies on a live key stream.
+//!
+//! `ShadowCache` wraps a real cache and replays every operation on its keys
+//! into ghost caches, which store no values and so cost little more than
+//! their keys. Their statistics tell how another policy or size would have
+//! done on the same traffic.
+//!
+//! A ghost cache fills itself on its own misses, as if the application read
+//! through it; otherwise it would only learn the keys the real cache missed.
+//! The `add` that reads through the real cache after such a miss is then
+//! not passed on to the ghost cache, which already stored the key.
+//!
+//! ```
+//! use cache::any::Policy;
+//! use cache::layer::Cache;
+//! use cache::lru;
+//! use cache::shadow::ShadowCache;
+//!
+//! let mut cache = ShadowCache::new(lru::Cache::new(100));
+//! cache.add_shadow("q2", Policy::q2(), 100);
+//! cache.add_shadow("lru x2", Policy::Lru, 200);
+//! for k in 0..1000u32 {
+//!     if cache.get(&(k % 150)).is_none() {
+//!         cache.add(k % 150, k);
+//!     }
+//! }
+//! for shadow in cache.shadows() {
+//!     println!("{}: {:.2}", shadow.label, shadow.stat().hit_ratio());
+//! }
+//! assert_eq!(cache.shadow("lru x2").unwrap().stat().hit_count, 850);
+//! ```
+
+use std::hash::{BuildHasher, Hash};
+
+use super::any::{AnyCache, Policy};
+use super::hash::DefaultState;
+use super::layer::Cache;
+use super::stats::Info;
+
+/// A ghost cache run by a `ShadowCache`.
+pub struct Shadow<K, S = DefaultState> {
+    pub label: String,
+    cache: AnyCache<K, (), S>,
+    // the key the last `get` stored on a miss, whose `add` is skipped
+    filled: Option<K>,
+}
+
+impl<K, S> Shadow<K, S>
+where
+    K: Hash + Eq,
+    S: BuildHasher + Clone,
+{
+    pub fn policy(&self) -> Policy {
+        self.cache.policy()
+    }
+
+    pub fn max_size(&self) -> usize {
+        self.cache.max_size()
+    }
+
+    /// Returns the statistics of the ghost cache, whose hits and misses are
+    /// those the real cache would have had with its policy and size.
+    pub fn stat(&self) -> Info {
+        self.cache.stat()
+    }
+}
+
+pub struct ShadowCache<C, K, S = DefaultState> {
+    inner: C,
+    shadows: Vec<Shadow<K, S>>,
+    hash_builder: S,
+}
+
+impl<C, K: Hash + Eq> ShadowCache<C, K, DefaultState> {
+    pub fn new(inner: C) -> ShadowCache<C, K, DefaultState> {
+        ShadowCache::with_hasher(inner, Default::default())
+    }
+}
+
+impl<C, K, S> ShadowCache<C, K, S>
+where
+    K: Hash + Eq,
+    S: BuildHasher + Clone,
+{
+    pub fn with_hasher(inner: C, hash_builder: S) -> ShadowCache<C, K, S> {
+        ShadowCache {
+            inner,
+            shadows: Vec::new(),
+            hash_builder,
+        }
+    }
+
+    /// Starts a ghost cache with `policy` and `size`. It only sees the
+    /// operations from now on, so it starts cold even if the real cache is
+    /// warm.
+    pub fn add_shadow<L: Into<String>>(&mut self, label: L, policy: Policy, size: usize) {
+        self.shadows.push(Shadow {
+            label: label.into(),
+            cache: AnyCache::with_hasher(policy, size, self.hash_builder.clone()),
+            filled: None,
+        });
+    }
+
+    /// Stops and returns the ghost cache with `label`.
+    pub fn remove_shadow(&mut self, label: &str) -> Option<Shadow<K, S>> {
+        let i = self.shadows.iter().position(|s| s.label == label)?;
+        Some(self.shadows.remove(i))
+    }
+
+    pub fn shadow(&self, label: &str) -> Option<&Shadow<K, S>> {
+        self.shadows.iter().find(|s| s.label == label)
+    }
+
+    pub fn shadows(&self) -> &[Shadow<K, S>] {
+        &self.shadows
+    }
+
+    /// Returns the configuration and statistics of every ghost cache as
+    /// JSON.
+    #[cfg(feature = "json")]
+    pub fn to_json(&self) -> serde_json::Value {
+        let shadows: Vec<_> = self
+            .shadows
+            .iter()
+            .map(|shadow| {
+                serde_json::json!({
+                    "label": shadow.label,
+                    "policy": shadow.policy().to_json(),
+                    "max_size": shadow.max_size(),
+                    "stats": shadow.stat().to_json(),
+                })
+            })
+            .collect();
+        serde_json::Value::Array(shadows)
+    }
+
+    /// Resets the statistics of every ghost cache, keeping their contents.
+    pub fn reset_shadow_stats(&mut self) {
+        for shadow in &mut self.shadows {
+            shadow.cache.reset_stats();
+        }
+    }
+
+    pub fn inner(&self) -> &C {
+        &self.inner
+    }
+
+    pub fn inner_mut(&mut self) -> &mut C {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> C {
+        self.inner
+    }
+}
+
+impl<K, V, C, S> Cache<K, V> for ShadowCache<C, K, S>
+where
+    K: Hash + Eq + Clone,
+    C: Cache<K, V>,
+    S: BuildHasher + Clone,
+{
+    fn get(&mut self, k: &K) -> Option<&V> {
+        for shadow in &mut self.shadows {
+            shadow.filled = None;
+            if shadow.cache.get(k).is_none() {
+                shadow.cache.add(k.clone(), ());
+                shadow.filled = Some(k.clone());
+            }
+        }
+        self.inner.get(k)
+    }
+
+    fn peek(&self, k: &K) -> Option<&V> {
+        self.inner.peek(k)
+    }
+
+    fn contains_key(&self, k: &K) -> bool {
+        self.inner.contains_key(k)
+    }
+
+    fn add(&mut self, k: K, v: V) -> Option<V> {
+        for shadow in &mut self.shadows {
+            // a second insertion would count as a replacement, which
+            // segmented policies take for a hit
+            if shadow.filled.take().as_ref() != Some(&k) {
+                shadow.cache.add(k.clone(), ());
+            }
+        }
+        self.inner.add(k, v)
+    }
+
+    fn remove(&mut self, k: &K) -> Option<V> {
+        for shadow in &mut self.shadows {
+            shadow.filled = None;
+            shadow.cache.remove(k);
+        }
+        self.inner.remove(k)
+    }
+
+    fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    fn purge(&mut self) {
+        for shadow in &mut self.shadows {
+            shadow.filled = None;
+            shadow.cache.purge();
+        }
+        self.inner.purge()
+    }
+
+    fn expire(&mut self, k: &K) -> bool {
+        self.inner.expire(k)
+    }
+
+    fn expire_where(&mut self, expired: &mut dyn FnMut(&K, &V) -> bool) -> usize {
+        self.inner.expire_where(expired)
+    }
+
+    fn run_pending_tasks(&mut self) {
+        self.inner.run_pending_tasks()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{lru, q2, slru};
+
+    #[test]
+    fn test_shadows_mirror_the_inner_cache() {
+        let mut cache = ShadowCache::new(lru::Cache::new(2));
+        cache.add_shadow("same", Policy::Lru, 2);
+        cache.add_shadow("fifo", Policy::Fifo, 2);
+        for &k in &[1, 2, 1, 3, 1, 2] {
+            if cache.get(&k).is_none() {
+                cache.add(k, k * 10);
+            }
+        }
+        cache.remove(&1);
+        let info = cache.inner().stat();
+        let same = cache.shadow("same").unwrap().stat();
+        assert_eq!(
+            (same.hit_count, same.miss_count),
+            (info.hit_count, info.miss_count)
+        );
+        assert_eq!(cache.shadow("fifo").unwrap().stat().hit_count, 1);
+        assert!(!cache.shadow("same").unwrap().cache.contains_key(&1));
+
+        assert!(cache.remove_shadow("fifo").is_some());
+        assert_eq!(cache.shadows().len(), 1);
+    }
+
+    /// Reads through `cache` with a mix of hot and cold keys, replacing a
+    /// cached key now and then.
+    fn run<C: Cache<u32, u32>>(cache: &mut C) {
+        for i in 0..2000u32 {
+            let k = if i % 3 == 0 { i % 7 } else { (i * 7919) % 61 };
+            if cache.get(&k).is_none() || i % 5 == 0 {
+                cache.add(k, i);
+            }
+        }
+    }
+
+    #[test]
+    fn test_segmented_shadows_match() {
+        fn counters(info: &Info) -> [usize; 8] {
+            [
+                info.hit_count,
+                info.miss_count,
+                info.insert_count,
+                info.replace_count,
+                info.eviction_count,
+                info.in_hit_count,
+                info.main_hit_count,
+                info.ghost_hit_count,
+            ]
+        }
+
+        let mut cache = ShadowCache::new(slru::Cache::new(16));
+        cache.add_shadow("same", Policy::slru(), 16);
+        run(&mut cache);
+        let same = cache.shadow("same").unwrap().stat();
+        assert_eq!(counters(&same), counters(&cache.inner().stat()));
+
+        let mut cache = ShadowCache::new(q2::Cache::new(16));
+        cache.add_shadow("same", Policy::q2(), 16);
+        run(&mut cache);
+        let same = cache.shadow("same").unwrap().stat();
+        assert_eq!(counters(&same), counters(&cache.inner().stat()));
+    }
+}
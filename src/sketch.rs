@@ -0,0 +1,314 @@
+//! Frequency estimation in bounded memory.
+//!
+//! A `CountMinSketch` counts occurrences of keys, identified by their hash,
+//! in `depth` rows of `width` counters. Every key maps to one counter per
+//! row and its estimate is the smallest of them: collisions can only make
+//! it larger, so a key is never counted less often than it occurred. With
+//! `n` occurrences in total, an estimate is off by more than `e * n / width`
+//! with a probability of at most `exp(-depth)`.
+//!
+//! Conservative update only raises the counters of a key that are at its
+//! current estimate, which keeps the estimates of colliding keys lower, at
+//! the price of no longer supporting decrements. A decay period halves all
+//! counters periodically, so that the estimates follow recent traffic.
+//! Counters that saturate at 255 or below take a byte each.
+//!
+//! ```
+//! use std::hash::BuildHasher;
+//!
+//! use cache::hash::FixedState;
+//! use cache::sketch::CountMinSketch;
+//!
+//! let hasher = FixedState::default();
+//! let mut sketch = CountMinSketch::new(1024, 4);
+//! sketch.set_conservative_update(true);
+//! for path in &["/", "/about", "/", "/"] {
+//!     sketch.increment(hasher.hash_one(path));
+//! }
+//! assert_eq!(sketch.estimate(hasher.hash_one("/")), 3);
+//! assert_eq!(sketch.estimate(hasher.hash_one("/about")), 1);
+//! ```
+
+use std::mem;
+
+use super::error::{self, ConfigError};
+
+/// The counters of a sketch, as narrow as its maximum count allows.
+#[derive(Clone)]
+enum Counters {
+    Narrow(Vec<u8>),
+    Wide(Vec<u32>),
+}
+
+impl Counters {
+    fn get(&self, i: usize) -> u32 {
+        match self {
+            Counters::Narrow(counters) => u32::from(counters[i]),
+            Counters::Wide(counters) => counters[i],
+        }
+    }
+
+    fn set(&mut self, i: usize, count: u32) {
+        match self {
+            Counters::Narrow(counters) => counters[i] = count as u8,
+            Counters::Wide(counters) => counters[i] = count,
+        }
+    }
+
+    /// Applies `f` to every counter.
+    fn update(&mut self, f: impl Fn(u32) -> u32) {
+        match self {
+            Counters::Narrow(counters) => {
+                for count in counters.iter_mut() {
+                    *count = f(u32::from(*count)) as u8;
+                }
+            }
+            Counters::Wide(counters) => {
+                for count in counters.iter_mut() {
+                    *count = f(*count);
+                }
+            }
+        }
+    }
+
+    /// Stores the counters in bytes if `narrow`, in `u32`s otherwise. The
+    /// counts must fit.
+    fn set_narrow(&mut self, narrow: bool) {
+        *self = match self {
+            Counters::Wide(counters) if narrow => {
+                Counters::Narrow(counters.iter().map(|&count| count as u8).collect())
+            }
+            Counters::Narrow(counters) if !narrow => {
+                Counters::Wide(counters.iter().map(|&count| u32::from(count)).collect())
+            }
+            _ => return,
+        };
+    }
+
+    fn heap_bytes(&self) -> usize {
+        match self {
+            Counters::Narrow(counters) => counters.capacity(),
+            Counters::Wide(counters) => counters.capacity() * mem::size_of::<u32>(),
+        }
+    }
+}
+
+/// Counts occurrences of hashed keys in `depth` rows of `width` counters.
+#[derive(Clone)]
+pub struct CountMinSketch {
+    counters: Counters,
+    mask: usize,
+    depth: usize,
+    max_count: u32,
+    conservative: bool,
+    // the number of additions after which all counters are halved, or 0
+    decay_period: usize,
+    additions: usize,
+}
+
+impl CountMinSketch {
+    /// Creates a sketch with `depth` rows of `width` counters. The width is
+    /// rounded up to a power of two; both are at least one.
+    pub fn new(width: usize, depth: usize) -> CountMinSketch {
+        let width = width.max(1).next_power_of_two();
+        let depth = depth.max(1);
+        CountMinSketch {
+            counters: Counters::Wide(vec![0; width * depth]),
+            mask: width - 1,
+            depth,
+            max_count: u32::MAX,
+            conservative: false,
+            decay_period: 0,
+            additions: 0,
+        }
+    }
+
+    /// Creates a sketch whose estimates exceed the true counts by at most
+    /// `epsilon` times the total count, with a probability of at least
+    /// `1 - delta`. Both must lie within (0, 1), and an `epsilon` so small
+    /// that the counters would not fit in memory is rejected.
+    pub fn with_error(epsilon: f64, delta: f64) -> Result<CountMinSketch, ConfigError> {
+        error::check_factor("epsilon", epsilon)?;
+        error::check_factor("delta", delta)?;
+        // saturates for the tiniest epsilons, which the check then rejects
+        let width = (std::f64::consts::E / epsilon).ceil() as usize;
+        let depth = ((1.0 / delta).ln().ceil() as usize).max(1);
+        error::check_sketch_size(epsilon, width, depth)?;
+        Ok(CountMinSketch::new(width, depth))
+    }
+
+    pub fn width(&self) -> usize {
+        self.mask + 1
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Turns on or off conservative update, off by default.
+    pub fn set_conservative_update(&mut self, conservative: bool) {
+        self.conservative = conservative;
+    }
+
+    /// Sets the value at which counters saturate, `u32::MAX` by default.
+    /// Up to 255, the counters are stored in a byte each.
+    pub fn set_max_count(&mut self, max_count: u32) {
+        self.max_count = max_count;
+        self.counters.update(|count| count.min(max_count));
+        self.counters.set_narrow(max_count <= u32::from(u8::MAX));
+    }
+
+    /// Sets the number of additions after which the counters are aged, or
+    /// disables aging if `additions` is 0, the default.
+    pub fn set_decay_period(&mut self, additions: usize) {
+        self.decay_period = additions;
+        self.additions = 0;
+    }
+
+    fn index(&self, hash: u64, row: usize) -> usize {
+        // a different mix of the hash for every row
+        let mut h = hash.wrapping_add((row as u64 + 1).wrapping_mul(0x9e37_79b9_7f4a_7c15));
+        h = (h ^ (h >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        h = (h ^ (h >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        row * self.width() + ((h ^ (h >> 31)) as usize & self.mask)
+    }
+
+    pub fn increment(&mut self, hash: u64) {
+        self.add(hash, 1);
+    }
+
+    /// Counts `count` occurrences of the key with hash `hash`.
+    pub fn add(&mut self, hash: u64, count: u32) {
+        let target = self
+            .estimate(hash)
+            .saturating_add(count)
+            .min(self.max_count);
+        for row in 0..self.depth {
+            let i = self.index(hash, row);
+            let counter = self.counters.get(i);
+            let counter = if self.conservative {
+                counter.max(target)
+            } else {
+                counter.saturating_add(count).min(self.max_count)
+            };
+            self.counters.set(i, counter);
+        }
+
+        if self.decay_period > 0 {
+            self.additions = self.additions.saturating_add(count as usize);
+            if self.additions >= self.decay_period {
+                self.age();
+            }
+        }
+    }
+
+    /// Returns the estimated number of occurrences of the key with hash
+    /// `hash`, never less than the true number since the last aging.
+    pub fn estimate(&self, hash: u64) -> u32 {
+        (0..self.depth)
+            .map(|row| self.counters.get(self.index(hash, row)))
+            .min()
+            .unwrap_or(0)
+    }
+
+    /// Halves all counters at once, as happens at the end of every decay
+    /// period.
+    pub fn age(&mut self) {
+        self.counters.update(|count| count / 2);
+        self.additions /= 2;
+    }
+
+    pub fn clear(&mut self) {
+        self.counters.update(|_| 0);
+        self.additions = 0;
+    }
+
+    /// Returns the memory held by the counters.
+    pub fn heap_bytes(&self) -> usize {
+        self.counters.heap_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::workload::Zipf;
+    use std::collections::HashMap;
+
+    fn counts(keys: &[u64]) -> HashMap<u64, u32> {
+        let mut counts = HashMap::new();
+        for &k in keys {
+            *counts.entry(k).or_insert(0) += 1;
+        }
+        counts
+    }
+
+    #[test]
+    fn test_never_underestimates() {
+        let keys: Vec<u64> = Zipf::new(5000, 0.9, 3).take(20_000).collect();
+        let exact = counts(&keys);
+        let mut plain = CountMinSketch::new(256, 4);
+        let mut conservative = CountMinSketch::new(256, 4);
+        conservative.set_conservative_update(true);
+        for &k in &keys {
+            plain.increment(k);
+            conservative.increment(k);
+        }
+
+        let (mut plain_error, mut conservative_error) = (0, 0);
+        for (&k, &count) in &exact {
+            assert!(conservative.estimate(k) >= count);
+            assert!(plain.estimate(k) >= conservative.estimate(k));
+            plain_error += plain.estimate(k) - count;
+            conservative_error += conservative.estimate(k) - count;
+        }
+        assert!(conservative_error < plain_error);
+    }
+
+    #[test]
+    fn test_with_error() {
+        let mut sketch = CountMinSketch::with_error(0.01, 0.01).unwrap();
+        assert_eq!((sketch.width(), sketch.depth()), (512, 5));
+        assert_eq!(sketch.heap_bytes(), 512 * 5 * 4);
+        sketch.set_max_count(15);
+        assert_eq!(sketch.heap_bytes(), 512 * 5);
+
+        assert_eq!(
+            CountMinSketch::with_error(0.0, 0.1).err(),
+            Some(ConfigError::FactorOutOfRange {
+                name: "epsilon",
+                value: 0.0
+            })
+        );
+        assert!(CountMinSketch::with_error(0.1, 1.0).is_err());
+        assert_eq!(
+            CountMinSketch::with_error(1e-300, 0.1).err(),
+            Some(ConfigError::SketchTooWide { epsilon: 1e-300 })
+        );
+    }
+
+    #[test]
+    fn test_saturation_and_aging() {
+        let mut sketch = CountMinSketch::new(64, 2);
+        sketch.set_max_count(15);
+        sketch.add(1, 20);
+        sketch.add(3, 300);
+        sketch.increment(2);
+        assert_eq!((sketch.estimate(1), sketch.estimate(3)), (15, 15));
+        sketch.age();
+        assert_eq!((sketch.estimate(1), sketch.estimate(2)), (7, 0));
+
+        sketch.clear();
+        sketch.set_decay_period(10);
+        sketch.add(1, 9);
+        assert_eq!(sketch.estimate(1), 9);
+        // the tenth addition halves the counts
+        sketch.increment(1);
+        assert_eq!(sketch.estimate(1), 5);
+        // and leaves the period half elapsed
+        sketch.add(1, 4);
+        assert_eq!(sketch.estimate(1), 9);
+        sketch.increment(1);
+        assert_eq!(sketch.estimate(1), 5);
+    }
+}
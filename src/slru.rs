@@ -1,27 +1,222 @@
 use std::borrow::Borrow;
-use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
 use std::hash::{BuildHasher, Hash};
-use std::ptr;
 
-use super::map::LinkedHashMap;
+use super::admission::PromotionLimit;
+use super::core::{self, ReplacementPolicy, Store};
+use super::error::{self, ConfigError};
+use super::hash::{DefaultState, SeededState};
+use super::map;
+#[cfg(feature = "persistence")]
+use super::persistence::{self, SnapshotError};
+pub use super::stats::Info;
+use super::stats::Segment;
 
-const DEFAULT_MAIN_CF: f64 = 0.75;
+pub(crate) const DEFAULT_MAIN_CF: f64 = 0.75;
+const MIN_SIZE: usize = 2;
 
-pub struct Cache<K, V, S = RandomState> {
-    max_size: usize,
+/// Keeps entries read again in a protected main segment and new ones on
+/// probation in the in segment, which takes the evictions: a `core::Cache`
+/// with the `Slru` policy.
+pub type Cache<K, V, S = DefaultState> = core::Cache<K, V, Slru, S>;
+
+/// Promotes an entry of the in segment to main when it is read, demoting
+/// the least recently used entries of a full main to the front of the in
+/// segment.
+#[derive(Debug)]
+pub struct Slru {
     max_size_in: usize,
     max_size_main: usize,
+    main_cache_factor: f64,
+
+    max_weight_in: usize,
+    max_weight_main: usize,
+
+    refresh_interval: u32,
+    // hits since an entry was last moved to the front
+    unrefreshed_hits: u32,
+
+    promotion_threshold: u32,
+    // hits so far of the entries of in that have not been promoted yet, by
+    // key hash; only kept with a threshold above one
+    probation_hits: HashMap<u64, u32>,
+}
+
+impl Slru {
+    fn new(max_size: usize, main_cache_factor: f64) -> Slru {
+        let max_size_main = (max_size as f64 * main_cache_factor) as usize;
+        let max_size_in = (max_size as f64 * (1.0 - main_cache_factor)) as usize;
+        Slru {
+            max_size_in,
+            max_size_main,
+            main_cache_factor,
+            max_weight_in: max_size_in,
+            max_weight_main: max_size_main,
+            refresh_interval: 1,
+            unrefreshed_hits: 0,
+            promotion_threshold: 1,
+            probation_hits: HashMap::new(),
+        }
+    }
+
+    /// Returns a policy of the same configuration that knows of no hits.
+    fn split_off(&self) -> Slru {
+        Slru {
+            unrefreshed_hits: 0,
+            probation_hits: HashMap::new(),
+            ..*self
+        }
+    }
+
+    /// Counts a hit and returns whether it is due to move its entry to the
+    /// front, by the refresh interval.
+    fn refresh_due(&mut self) -> bool {
+        let due = self.unrefreshed_hits + 1 >= self.refresh_interval;
+        self.unrefreshed_hits = if due { 0 } else { self.unrefreshed_hits + 1 };
+        due
+    }
+
+    /// Counts a hit on `key` if it is in the in segment and returns whether
+    /// it stays there, short of the promotion threshold or over the
+    /// promotion limit.
+    fn on_probation<K, V, S, Q>(&mut self, store: &mut Store<K, V, S>, key: &Q) -> bool
+    where
+        K: Hash + Eq + Borrow<Q>,
+        S: BuildHasher,
+        Q: ?Sized + Eq + Hash,
+    {
+        let counted = self.promotion_threshold > 1;
+        if (!counted && store.promotion_limit().is_none())
+            || store.segment_of(key) != Some(Segment::In)
+        {
+            return false;
+        }
+        let hash = store.hash(key);
+        if counted {
+            let hits = self.probation_hits.entry(hash).or_insert(0);
+            *hits = hits.saturating_add(1);
+            if *hits < self.promotion_threshold {
+                return true;
+            }
+        }
+        if !store.may_promote() {
+            return true;
+        }
+        self.probation_hits.remove(&hash);
+        false
+    }
+
+    fn forget_hits(&mut self, hash: u64) {
+        if !self.probation_hits.is_empty() {
+            self.probation_hits.remove(&hash);
+        }
+    }
+
+    /// Demotes the least recently used entries of main until it is within
+    /// its share, leaving at least `keep` of them.
+    fn demote_overflow<K, V, S>(&self, store: &mut Store<K, V, S>, keep: usize)
+    where
+        K: Hash + Eq,
+        S: BuildHasher,
+    {
+        while store.segment_weight(Segment::Main) > self.max_weight_main
+            && store.segment_len(Segment::Main) > keep
+        {
+            store.demote();
+        }
+    }
+}
+
+impl<K: Hash + Eq, V, S: BuildHasher> ReplacementPolicy<K, V, S> for Slru {
+    const MIN_SIZE: usize = MIN_SIZE;
+
+    fn on_hit<'a, Q>(&mut self, store: &'a mut Store<K, V, S>, k: &Q) -> Option<(&'a K, &'a V)>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        let segment = store.segment_of(k)?;
+        if segment != Segment::Main && !self.on_probation(store, k) {
+            store.protect(k);
+            self.demote_overflow(store, 1);
+            return store.get_key_value(k);
+        }
+        if self.refresh_due() {
+            store.get_refresh(k)
+        } else {
+            store.get_key_value(k)
+        }
+    }
 
-    hit_count: usize,
-    miss_count: usize,
+    fn on_insert(&mut self, store: &mut Store<K, V, S>, k: K, v: V) -> Option<(K, V)> {
+        match store.segment_of(&k) {
+            Some(Segment::Main) => {
+                let old = store.upsert_protected(k, v);
+                self.demote_overflow(store, 0);
+                old
+            }
+            // a replacement counts as a hit towards the promotion threshold
+            Some(_) if self.on_probation(store, &k) => store.upsert_front(k, v, true),
+            Some(_) => {
+                let old = store.upsert_protected(k, v);
+                self.demote_overflow(store, 1);
+                old
+            }
+            None => store.upsert_front(k, v, false),
+        }
+    }
 
-    callback: Option<Box<dyn Fn(K, V)>>,
+    fn on_remove(&mut self, hash: u64) {
+        self.forget_hits(hash);
+    }
 
-    in_: LinkedHashMap<K, V, S>,
-    main: LinkedHashMap<K, V, S>,
+    fn choose_victim(&mut self, store: &mut Store<K, V, S>) -> Option<(K, V)> {
+        let (k, v) = store.pop_victim()?;
+        self.forget_hits(store.hash(&k));
+        Some((k, v))
+    }
+
+    fn on_resize(&mut self, store: &mut Store<K, V, S>, max_size: usize, max_weight: usize) {
+        let factor = self.main_cache_factor;
+        self.max_size_main = (max_size as f64 * factor) as usize;
+        self.max_size_in = (max_size as f64 * (1.0 - factor)) as usize;
+        if store.is_unweighted() {
+            self.max_weight_main = self.max_size_main;
+            self.max_weight_in = self.max_size_in;
+        } else {
+            self.max_weight_main = (max_weight as f64 * factor) as usize;
+            self.max_weight_in = (max_weight as f64 * (1.0 - factor)) as usize;
+        }
+        self.demote_overflow(store, 0);
+    }
+
+    fn on_purge(&mut self) {
+        self.probation_hits.clear();
+    }
+
+    fn on_merge(&mut self, store: &mut Store<K, V, S>, other: Slru) {
+        // the hits counted towards promotion carry over; a key counted in
+        // both caches keeps our count, whichever of its entries was kept
+        for (hash, hits) in other.probation_hits {
+            self.probation_hits.entry(hash).or_insert(hits);
+        }
+        for (k, _) in store.iter_segment(Segment::Main) {
+            self.forget_hits(store.hash(k));
+        }
+    }
+
+    fn warm_segment(&mut self, store: &Store<K, V, S>, weight: usize) -> Option<Segment> {
+        if store.segment_weight(Segment::Main) + weight <= self.max_weight_main {
+            Some(Segment::Main)
+        } else if store.segment_weight(Segment::In) + weight <= self.max_weight_in {
+            Some(Segment::In)
+        } else {
+            None
+        }
+    }
 }
 
-impl<K: Hash + Eq, V, S> Cache<K, V, S>
+impl<K, V, S> Cache<K, V, S>
 where
     K: Hash + Eq,
     S: BuildHasher + Clone,
@@ -35,150 +230,266 @@ where
         main_cache_factor: f64,
         hash_builder: S,
     ) -> Cache<K, V, S> {
-        let max_size = if size < 2 { 2 } else { size };
+        let max_size = if size < MIN_SIZE { MIN_SIZE } else { size };
+        Cache::with_main_factor(max_size, main_cache_factor, hash_builder)
+    }
 
-        let max_size_main = (max_size as f64 * main_cache_factor) as usize;
-        let max_size_in = (max_size as f64 * (1 as f64 - main_cache_factor)) as usize;
-        Cache {
-            max_size,
-            max_size_in,
-            max_size_main,
+    /// Creates a cache with a capacity of zero: every `add` hands the entry
+    /// straight to the eviction callback and every `get` misses.
+    pub fn disabled_with_hasher(hash_builder: S) -> Cache<K, V, S> {
+        Cache::with_main_factor(0, DEFAULT_MAIN_CF, hash_builder)
+    }
 
-            callback: None,
+    fn with_main_factor(
+        max_size: usize,
+        main_cache_factor: f64,
+        hash_builder: S,
+    ) -> Cache<K, V, S> {
+        let policy = Slru::new(max_size, main_cache_factor);
+        let capacities = (policy.max_size_in, policy.max_size_main);
+        core::Cache::with_segments(policy, max_size, capacities, hash_builder)
+    }
 
-            hit_count: 0,
-            miss_count: 0,
+    /// Like `with_param_and_hasher`, but rejects a configuration that would
+    /// otherwise be clamped or leave a segment empty.
+    pub fn try_with_param_and_hasher(
+        size: usize,
+        main_cache_factor: f64,
+        hash_builder: S,
+    ) -> Result<Cache<K, V, S>, ConfigError> {
+        error::check_size(size, MIN_SIZE)?;
+        error::check_factor("main_cache_factor", main_cache_factor)?;
 
-            in_: LinkedHashMap::with_capacity_and_hasher(max_size_in, hash_builder.clone()),
-            main: LinkedHashMap::with_capacity_and_hasher(max_size_main, hash_builder),
-        }
+        let cache = Cache::with_param_and_hasher(size, main_cache_factor, hash_builder);
+        error::check_segment("in", cache.policy().max_size_in)?;
+        error::check_segment("main", cache.policy().max_size_main)?;
+        Ok(cache)
     }
 
-    pub fn set_eviction_callback<C>(&mut self, cb: C)
+    /// Moves the entries for which `f` returns true into a new cache and
+    /// returns it, keeping their relative order in both caches. The new
+    /// cache has the limits, weigher and hasher of this one, but none of its
+    /// callbacks, policies or trackers.
+    pub fn split_by<F>(&mut self, f: F) -> Cache<K, V, S>
     where
-        C: Fn(K, V) + 'static,
+        F: FnMut(&K, &V) -> bool,
     {
-        self.callback = Some(Box::new(cb));
+        let policy = self.policy().split_off();
+        // taken before the moved keys are forgotten here
+        let hits = self.policy().probation_hits.clone();
+        let mut other = self.split_with(policy, f);
+        if !hits.is_empty() {
+            let (policy, store) = other.parts_mut();
+            for (k, _) in store.iter_segment(Segment::In) {
+                let hash = store.hash(k);
+                if let Some(&hits) = hits.get(&hash) {
+                    policy.probation_hits.insert(hash, hits);
+                }
+            }
+        }
+        other
     }
+}
 
-    pub fn contains_key<Q: ?Sized>(&self, key: &Q) -> bool
+impl<K, V, S> Cache<K, V, S>
+where
+    K: Hash + Eq,
+    S: BuildHasher,
+{
+    /// Registers a listener that sees every entry demoted from main back to
+    /// the front of the in segment to make room there. Demoted entries stay
+    /// cached, so they never reach the eviction callback or observer unless
+    /// they are evicted later on.
+    pub fn set_demotion_listener<D>(&mut self, listener: D)
     where
-        K: Borrow<Q>,
-        Q: Eq + Hash,
+        D: FnMut(&K, &V) + 'static,
     {
-        self.main.contains_key(key) || self.in_.contains_key(key)
+        self.store_mut().set_demotion_listener(Box::new(listener));
     }
 
-    pub fn get<Q: ?Sized>(&mut self, key: &Q) -> Option<&V>
-    where
-        K: Borrow<Q>,
-        Q: Eq + Hash,
-    {
-        if self.main.contains_key(key) {
-            self.hit_count += 1;
-            self.main.move_to_front(key);
-            return self.main.get(key);
-        }
+    /// Moves an entry to the front of its segment on only one hit in
+    /// `every`, sparing the relinking on the others. Promotions from the in
+    /// segment to main are not affected. The default of 1 refreshes on
+    /// every hit.
+    pub fn set_refresh_interval(&mut self, every: u32) {
+        let policy = self.policy_mut();
+        policy.refresh_interval = every.max(1);
+        policy.unrefreshed_hits = 0;
+    }
 
-        if let Some((k, v)) = self.in_.remove_entry(key) {
-            self.hit_count += 1;
-            self.ensure_space(true);
-            self.main.push_front(k, v);
-            return self.main.get(key);
-        }
-        self.miss_count += 1;
-        None
+    pub fn refresh_interval(&self) -> u32 {
+        self.policy().refresh_interval
     }
 
-    pub fn add(&mut self, key: K, value: V) -> Option<V> {
-        if let Some(v) = self.main.get_mut(&key) {
-            let old_v = unsafe { ptr::replace(v, value) };
-            self.main.move_to_front(&key);
-            return Some(old_v);
+    /// Sets the number of hits an entry of the in segment needs before it is
+    /// promoted to main. The default of 1 promotes on the first hit; a higher
+    /// threshold keeps keys touched only a few times by a scan from
+    /// flushing the main segment. Hits below the threshold move the entry to
+    /// the front of the in segment.
+    pub fn set_promotion_threshold(&mut self, hits: u32) {
+        let policy = self.policy_mut();
+        policy.promotion_threshold = hits.max(1);
+        if policy.promotion_threshold == 1 {
+            policy.probation_hits.clear();
         }
+    }
 
-        if let Some(v) = self.in_.remove(&key) {
-            self.ensure_space(true);
-            self.main.push_front(key, value);
-            return Some(v);
-        }
+    pub fn promotion_threshold(&self) -> u32 {
+        self.policy().promotion_threshold
+    }
 
-        self.ensure_space(false);
-        self.in_.push_front(key, value);
-        None
+    /// Bounds the number of entries promoted to main per interval. Entries
+    /// over the limit stay in the in segment, moved to its front, until a
+    /// later hit finds the limit has room again.
+    pub fn set_promotion_limit(&mut self, limit: PromotionLimit) {
+        self.store_mut().set_promotion_limit(limit);
     }
 
-    fn ensure_space(&mut self, main: bool) {
-        if main && self.main.len() >= self.max_size_main {
-            if let Some((k, v)) = self.main.pop_back() {
-                self.in_.push_front(k, v);
-            }
-        }
+    pub fn promotion_limit(&self) -> Option<&PromotionLimit> {
+        self.store().promotion_limit()
+    }
 
-        let in_len = self.in_.len();
-        let main_len = self.main.len();
-        if in_len + main_len < self.max_size {
-            return;
-        }
+    pub fn main_cache_factor(&self) -> f64 {
+        self.policy().main_cache_factor
+    }
 
-        if in_len > 0 && (in_len > self.max_size_in || (in_len == self.max_size_in && !main)) {
-            if let Some((k, v)) = self.in_.pop_back() {
-                self.callback.as_ref().map(|cb| cb(k, v));
-            }
-        }
+    /// Returns the current `(in, main)` segment lengths.
+    pub fn segment_lens(&self) -> (usize, usize) {
+        let store = self.store();
+        (
+            store.segment_len(Segment::In),
+            store.segment_len(Segment::Main),
+        )
     }
 
-    pub fn remove(&mut self, key: &K) -> Option<V> {
-        self.main
-            .remove(key)
-            .or_else(|| self.in_.remove(key))
+    /// Returns the configured `(in, main)` segment maximums.
+    pub fn segment_max_lens(&self) -> (usize, usize) {
+        (self.policy().max_size_in, self.policy().max_size_main)
     }
 
-    pub fn purge(&mut self) {
-        self.main.clear();
-        self.in_.clear();
+    /// Iterates over the in segment, from the most to the least recently
+    /// added entry.
+    pub fn iter_in(&self) -> map::Iter<'_, K, V> {
+        self.store().iter_segment(Segment::In)
     }
 
-    pub fn len(&self) -> usize {
-        self.main.len() + self.in_.len()
+    /// Iterates over the main segment, from the most to the least recently
+    /// used entry.
+    pub fn iter_main(&self) -> map::Iter<'_, K, V> {
+        self.store().iter_segment(Segment::Main)
     }
 
-    pub fn is_empty(&self) -> bool {
-        self.main.is_empty() && self.in_.is_empty()
+    /// Writes a versioned snapshot of the cache to `writer`. Only what
+    /// serde serializes is saved.
+    #[cfg(feature = "persistence")]
+    pub fn save_to<W>(&self, writer: W) -> Result<(), SnapshotError>
+    where
+        K: serde::Serialize,
+        V: serde::Serialize,
+        W: std::io::Write,
+    {
+        persistence::save(persistence::Kind::Slru, self, writer)
     }
 
-    pub fn peek(&self, key: &K) -> Option<&V> {
-        if let Some(v) = self.main.get(key) {
-            return Some(v);
-        }
-        self.in_.get(key)
+    /// Reads a cache written by `save_to`.
+    #[cfg(feature = "persistence")]
+    pub fn load_from<R>(reader: R) -> Result<Cache<K, V, S>, SnapshotError>
+    where
+        K: serde::de::DeserializeOwned,
+        V: serde::de::DeserializeOwned,
+        S: Clone + Default,
+        R: std::io::Read,
+    {
+        persistence::load(persistence::Kind::Slru, reader)
     }
+}
 
-    pub fn shrink_to_fit(&mut self) {
-        self.in_.shrink_to_fit();
-        self.main.shrink_to_fit();
+impl<K: Hash + Eq, V> Cache<K, V, DefaultState> {
+    pub fn with_params(size: usize, main_cache_factor: f64) -> Cache<K, V, DefaultState> {
+        Cache::with_param_and_hasher(size, main_cache_factor, Default::default())
     }
 
-    pub fn stat(&self) -> Info {
-        Info {
-            hit_count: self.hit_count,
-            miss_count: self.miss_count,
-        }
+    pub fn try_with_params(
+        size: usize,
+        main_cache_factor: f64,
+    ) -> Result<Cache<K, V, DefaultState>, ConfigError> {
+        Cache::try_with_param_and_hasher(size, main_cache_factor, Default::default())
+    }
+
+    pub fn new(size: usize) -> Cache<K, V, DefaultState> {
+        Cache::with_params(size, DEFAULT_MAIN_CF)
+    }
+
+    pub fn disabled() -> Cache<K, V, DefaultState> {
+        Cache::disabled_with_hasher(Default::default())
     }
 }
 
-pub struct Info {
-    pub hit_count: usize,
-    pub miss_count: usize,
+impl<K: Hash + Eq, V> Cache<K, V, SeededState> {
+    /// Creates a cache that hashes, and so behaves, the same on every run
+    /// given the same `seed`.
+    pub fn with_seed(size: usize, seed: u64) -> Cache<K, V, SeededState> {
+        Cache::with_hasher(size, SeededState::new(seed))
+    }
 }
 
-impl<K: Hash + Eq, V> Cache<K, V, RandomState> {
-    pub fn with_params(size: usize, main_cache_factor: f64) -> Cache<K, V, RandomState> {
-        Cache::with_param_and_hasher(size, main_cache_factor, Default::default())
+#[cfg(feature = "serde")]
+impl<K, V, S> serde::Serialize for Cache<K, V, S>
+where
+    K: Hash + Eq + serde::Serialize,
+    V: serde::Serialize,
+    S: BuildHasher,
+{
+    /// Serializes the configuration and the entries of each segment from
+    /// the most to the least recently used. Callbacks, weighers and
+    /// statistics are not serialized.
+    fn serialize<T: serde::Serializer>(&self, serializer: T) -> Result<T::Ok, T::Error> {
+        use serde::ser::SerializeStruct;
+
+        let store = self.store();
+        let mut state = serializer.serialize_struct("Cache", 4)?;
+        state.serialize_field("max_size", &self.max_size())?;
+        state.serialize_field("main_cache_factor", &self.main_cache_factor())?;
+        state.serialize_field("in", &core::SegmentEntries(store, Segment::In))?;
+        state.serialize_field("main", &core::SegmentEntries(store, Segment::Main))?;
+        state.end()
     }
+}
 
-    pub fn new(size: usize) -> Cache<K, V, RandomState> {
-        Cache::with_params(size, DEFAULT_MAIN_CF)
+#[cfg(feature = "serde")]
+impl<'de, K, V, S> serde::Deserialize<'de> for Cache<K, V, S>
+where
+    K: Hash + Eq + serde::Deserialize<'de>,
+    V: serde::Deserialize<'de>,
+    S: BuildHasher + Clone + Default,
+{
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(serde::Deserialize)]
+        struct Repr<K, V> {
+            max_size: usize,
+            main_cache_factor: f64,
+            #[serde(rename = "in")]
+            in_: Vec<(K, V)>,
+            main: Vec<(K, V)>,
+        }
+
+        let repr = Repr::deserialize(deserializer)?;
+        let mut cache = if repr.max_size == 0 {
+            Cache::disabled_with_hasher(S::default())
+        } else {
+            Cache::with_param_and_hasher(repr.max_size, repr.main_cache_factor, S::default())
+        };
+        for (k, v) in repr.in_ {
+            cache.restore(k, v, false);
+        }
+        for (k, v) in repr.main {
+            if cache.contains_key(&k) {
+                return Err(serde::de::Error::custom("a key is in both segments"));
+            }
+            cache.restore(k, v, true);
+        }
+        cache.restored();
+        Ok(cache)
     }
 }
 
@@ -187,6 +498,44 @@ mod tests {
     use super::*;
     use rand::{self, Rng};
 
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde() {
+        let mut cache: Cache<usize, usize> = Cache::with_params(4, 0.5);
+        for i in 0..4 {
+            cache.add(i, i);
+        }
+        cache.get(&1);
+        let json = serde_json::to_string(&cache).unwrap();
+        let restored: Cache<usize, usize> = serde_json::from_str(&json).unwrap();
+        assert_eq!(
+            restored.iter().collect::<Vec<_>>(),
+            cache.iter().collect::<Vec<_>>()
+        );
+
+        let json = r#"{"max_size":4,"main_cache_factor":0.5,"in":[[1,1]],"main":[[1,2]]}"#;
+        let err = serde_json::from_str::<Cache<usize, usize>>(json)
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("a key is in both segments"));
+    }
+
+    #[test]
+    fn test_export_and_warm() {
+        let mut cache: Cache<usize, usize> = Cache::with_params(4, 0.5);
+        for i in 0..4 {
+            cache.add(i, i);
+        }
+        cache.get(&1);
+        cache.get(&2);
+        let entries = cache.to_vec();
+        assert_eq!(entries, vec![(0, 0), (3, 3), (1, 1), (2, 2)]);
+
+        let mut restored: Cache<usize, usize> = Cache::with_params(4, 0.5);
+        restored.warm_from_iter(cache.export().into_iter().rev());
+        assert_eq!(restored.to_vec(), entries);
+    }
+
     #[test]
     fn test_random_ops() {
         let size = 128;
@@ -210,7 +559,7 @@ mod tests {
                 }
                 _ => {}
             }
-            assert!(cache.main.len() + cache.in_.len() <= size);
+            assert!(cache.segment_lens().1 + cache.segment_lens().0 <= size);
         }
     }
 
@@ -222,15 +571,19 @@ mod tests {
             cache.add(i, i);
         }
 
-        assert_eq!(cache.in_.len(), 128);
-        assert_eq!(cache.main.len(), 0);
+        assert_eq!(cache.segment_lens().0, 128);
+        assert_eq!(cache.segment_lens().1, 0);
 
         for i in 0usize..size {
             assert!(cache.get(&i).is_some());
         }
 
-        assert_eq!(cache.in_.len(), 32);
-        assert_eq!(cache.main.len(), 96);
+        assert_eq!(cache.segment_lens().0, 32);
+        assert_eq!(cache.segment_lens().1, 96);
+        assert_eq!(cache.stat().in_hit_count, 128);
+
+        assert!(cache.get(&127).is_some());
+        assert_eq!(cache.stat().main_hit_count, 1);
     }
 
     #[test]
@@ -239,22 +592,21 @@ mod tests {
         let mut cache: Cache<usize, usize> = Cache::new(size);
 
         cache.add(1, 1);
-        assert_eq!(cache.in_.len(), 1);
-        assert_eq!(cache.main.len(), 0);
+        assert_eq!(cache.segment_lens().0, 1);
+        assert_eq!(cache.segment_lens().1, 0);
 
         cache.add(1, 1);
-        assert_eq!(cache.in_.len(), 0);
-        assert_eq!(cache.main.len(), 1);
+        assert_eq!(cache.segment_lens().0, 0);
+        assert_eq!(cache.segment_lens().1, 1);
 
         cache.add(1, 1);
-        assert_eq!(cache.in_.len(), 0);
-        assert_eq!(cache.main.len(), 1);
+        assert_eq!(cache.segment_lens().0, 0);
+        assert_eq!(cache.segment_lens().1, 1);
     }
 
     use std::cell::RefCell;
     use std::rc::Rc;
 
-
     #[test]
     fn test_back_to_in_front() {
         let size = 4;
@@ -271,33 +623,33 @@ mod tests {
         cache.add(3, 3);
         cache.add(4, 4);
         cache.add(5, 5);
-        assert_eq!(cache.in_.len(), 4);
-        assert_eq!(cache.main.len(), 0);
+        assert_eq!(cache.segment_lens().0, 4);
+        assert_eq!(cache.segment_lens().1, 0);
         assert_eq!(*e_count.as_ref().borrow(), 1);
 
         assert!(cache.get(&4).is_some());
         assert!(cache.get(&3).is_some());
         assert!(cache.get(&5).is_some());
-        assert_eq!(cache.in_.len(), 1);
-        assert_eq!(cache.main.len(), 3);
-        assert_eq!(cache.main.front(), Some((&5, &5)));
+        assert_eq!(cache.segment_lens().0, 1);
+        assert_eq!(cache.segment_lens().1, 3);
+        assert_eq!(cache.iter_main().next(), Some((&5, &5)));
 
         assert!(cache.get(&2).is_some());
-        assert_eq!(cache.in_.len(), 1);
-        assert_eq!(cache.main.len(), 3);
+        assert_eq!(cache.segment_lens().0, 1);
+        assert_eq!(cache.segment_lens().1, 3);
 
-        assert_eq!(cache.in_.front(), Some((&4, &4)));
-        assert_eq!(cache.main.back(), Some((&3, &3)));
-        assert_eq!(cache.main.front(), Some((&2, &2)));
+        assert_eq!(cache.iter_in().next(), Some((&4, &4)));
+        assert_eq!(cache.iter_main().next_back(), Some((&3, &3)));
+        assert_eq!(cache.iter_main().next(), Some((&2, &2)));
 
         cache.add(6, 6);
         assert!(cache.get(&6).is_some());
-        assert_eq!(cache.main.back(), Some((&5, &5)));
-        assert_eq!(cache.main.front(), Some((&6, &6)));
-        assert_eq!(cache.in_.front(), Some((&3, &3)));
+        assert_eq!(cache.iter_main().next_back(), Some((&5, &5)));
+        assert_eq!(cache.iter_main().next(), Some((&6, &6)));
+        assert_eq!(cache.iter_in().next(), Some((&3, &3)));
 
-        assert_eq!(cache.in_.len(), 1);
-        assert_eq!(cache.main.len(), 3);
+        assert_eq!(cache.segment_lens().0, 1);
+        assert_eq!(cache.segment_lens().1, 3);
     }
 
     #[test]
@@ -326,6 +678,27 @@ mod tests {
         assert!(cache.get(&200).is_none());
     }
 
+    #[test]
+    fn test_get_mut() {
+        let mut cache: Cache<usize, Vec<usize>> = Cache::new(8);
+        cache.add(0, vec![]);
+        cache.add(1, vec![]);
+        cache.peek_mut(&0).unwrap().push(1);
+        assert_eq!(cache.segment_lens(), (2, 0));
+        cache.get_mut(&0).unwrap().push(2);
+        assert_eq!(cache.segment_lens(), (1, 1));
+
+        cache.set_promotion_threshold(2);
+        cache.get_mut(&1).unwrap().push(1);
+        assert_eq!(cache.segment_lens(), (1, 1));
+        cache.get_mut(&1).unwrap().push(2);
+        assert_eq!(cache.segment_lens(), (0, 2));
+        assert_eq!(cache.peek(&0), Some(&vec![1, 2]));
+        assert_eq!(cache.peek(&1), Some(&vec![1, 2]));
+        assert!(cache.get_mut(&2).is_none());
+        assert!(cache.peek_mut(&2).is_none());
+    }
+
     #[test]
     fn test_contains() {
         let mut cache: Cache<usize, usize> = Cache::new(2);
@@ -345,4 +718,364 @@ mod tests {
         cache.add(3, 3);
         assert!(!cache.contains_key(&1));
     }
+
+    #[test]
+    fn test_into_iter() {
+        let mut cache: Cache<usize, usize> = Cache::new(4);
+        cache.add(1, 1);
+        cache.add(2, 2);
+        cache.add(3, 3);
+        cache.get(&2);
+
+        let keys: Vec<usize> = cache.iter().map(|(k, _)| *k).collect();
+        assert_eq!(keys, vec![2, 3, 1]);
+
+        let entries: Vec<(usize, usize)> = cache.into_iter().collect();
+        assert_eq!(entries, vec![(2, 2), (3, 3), (1, 1)]);
+    }
+
+    #[test]
+    fn test_segment_lens() {
+        let mut cache: Cache<usize, usize> = Cache::new(8);
+        assert_eq!(cache.segment_max_lens(), (2, 6));
+
+        for i in 0usize..8 {
+            cache.add(i, i);
+        }
+        cache.get(&0);
+        cache.get(&1);
+        assert_eq!(cache.segment_lens(), (6, 2));
+        let main: Vec<_> = cache.iter_main().map(|(&k, _)| k).collect();
+        assert_eq!(main, vec![1, 0]);
+        let in_: Vec<_> = cache.iter_in().map(|(&k, _)| k).collect();
+        assert_eq!(in_, vec![7, 6, 5, 4, 3, 2]);
+    }
+
+    #[test]
+    fn test_demotion_listener() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let demoted = Rc::new(RefCell::new(Vec::new()));
+        let evicted = Rc::new(RefCell::new(Vec::new()));
+        let mut cache: Cache<usize, usize> = Cache::with_params(4, 0.5);
+        let log = demoted.clone();
+        cache.set_demotion_listener(move |&k, _| log.borrow_mut().push(k));
+        let log = evicted.clone();
+        cache.set_eviction_callback(move |k, _| log.borrow_mut().push(k));
+
+        for i in 0..4 {
+            cache.add(i, i);
+            cache.get(&i);
+        }
+        // promoting 2 and 3 pushed 0 and 1 back to in
+        assert_eq!(*demoted.as_ref().borrow(), vec![0, 1]);
+        assert!(evicted.as_ref().borrow().is_empty());
+        cache.add(4, 4);
+        assert_eq!(*evicted.as_ref().borrow(), vec![0]);
+        assert_eq!(demoted.as_ref().borrow().len(), 2);
+    }
+
+    #[test]
+    fn test_split_by() {
+        let mut cache: Cache<usize, usize> = Cache::with_params(8, 0.5);
+        for i in 0..8 {
+            cache.add(i, i);
+        }
+        for i in 0..4 {
+            cache.get(&i);
+        }
+        let mut even = cache.split_by(|&k, _| k % 2 == 0);
+        let main: Vec<_> = even.iter_main().map(|(&k, _)| k).collect();
+        let in_: Vec<_> = even.iter_in().map(|(&k, _)| k).collect();
+        assert_eq!((main, in_), (vec![2, 0], vec![6, 4]));
+        assert_eq!(cache.segment_lens(), (2, 2));
+        assert_eq!(cache.weighted_size() + even.weighted_size(), 8);
+        assert_eq!(even.segment_max_lens(), cache.segment_max_lens());
+        for i in 8..12 {
+            even.add(i, i);
+        }
+        assert_eq!(even.len(), 8);
+    }
+
+    #[test]
+    fn test_iter_from() {
+        let mut cache: Cache<usize, usize> = Cache::with_params(4, 0.5);
+        for i in 0..4 {
+            cache.add(i, i);
+        }
+        cache.get(&0);
+        let order: Vec<_> = cache.iter().map(|(&k, _)| k).collect();
+        let from = |k| {
+            let iter = cache.iter_from(&k).unwrap();
+            iter.map(|(&k, _)| k).collect::<Vec<_>>()
+        };
+        assert_eq!(from(0), order);
+        assert_eq!(from(order[2]), order[2..]);
+        assert!(cache.iter_from(&4).is_none());
+    }
+
+    #[test]
+    fn test_panicking_demotion_listener() {
+        use std::panic::{self, AssertUnwindSafe};
+
+        let mut cache: Cache<usize, usize> = Cache::with_params(4, 0.5);
+        cache.set_demotion_listener(|_, _| panic!("listener panic"));
+        for i in 0..4 {
+            cache.add(i, i);
+        }
+        cache.get(&0);
+        cache.get(&1);
+        // promoting 2 demotes 0, whose listener panics mid-promotion
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            cache.get(&2);
+        }));
+        assert!(result.is_err());
+        assert_eq!(cache.len(), 4);
+        assert_eq!(cache.segment_lens(), (2, 2));
+        assert_eq!(cache.weighted_size(), 4);
+        assert_eq!(cache.peek(&0), Some(&0));
+    }
+
+    #[test]
+    fn test_promotion_threshold() {
+        let mut cache: Cache<usize, usize> = Cache::new(8);
+        cache.set_promotion_threshold(2);
+        assert_eq!(cache.promotion_threshold(), 2);
+        cache.add(0, 0);
+        cache.add(1, 1);
+        assert_eq!(cache.get(&0), Some(&0));
+        assert_eq!(cache.segment_lens(), (2, 0));
+        let in_: Vec<_> = cache.iter_in().map(|(&k, _)| k).collect();
+        assert_eq!(in_, vec![0, 1]);
+        assert_eq!(cache.get(&0), Some(&0));
+        assert_eq!(cache.segment_lens(), (1, 1));
+
+        // a scan touching every key once leaves main alone
+        for i in 2..10 {
+            cache.add(i, i);
+            cache.get(&i);
+        }
+        assert!(cache.contains_key(&0));
+        assert_eq!(cache.segment_lens(), (7, 1));
+
+        // counters do not survive the entry; a replacement counts as a hit
+        cache.remove(&9);
+        cache.add(9, 9);
+        assert_eq!(cache.add(9, 10), Some(9));
+        assert_eq!(cache.segment_lens(), (7, 1));
+        cache.get(&9);
+        assert_eq!(cache.segment_lens(), (6, 2));
+        assert_eq!(cache.stat().in_hit_count, 11);
+    }
+
+    #[test]
+    fn test_add_with_priority() {
+        let mut cache = Cache::new(4);
+        cache.add_with_priority(1, "page", 10);
+        for k in 2..8 {
+            cache.add(k, "thumbnail");
+        }
+        // never read, the page still outlives the thumbnails
+        assert!(cache.contains_key(&1));
+        assert_eq!(cache.segment_lens(), (4, 0));
+
+        // a promoted entry keeps its priority, and demoted in turn it
+        // outlives the thumbnails again
+        cache.get(&1);
+        cache.get(&7);
+        assert_eq!(cache.segment_lens(), (2, 2));
+        for k in 8..12 {
+            cache.add(k, "thumbnail");
+            cache.get(&k);
+        }
+        assert_eq!(cache.priority(&1), Some(10));
+        assert_eq!(cache.iter_in().next_back(), Some((&1, &"page")));
+    }
+
+    #[test]
+    fn test_promotion_limit() {
+        let mut cache: Cache<usize, usize> = Cache::new(10);
+        cache.set_promotion_limit(PromotionLimit::per_operations(2, 100));
+        for i in 0..4 {
+            cache.add(i, i);
+        }
+        for i in 0..4 {
+            assert_eq!(cache.get(&i), Some(&i));
+        }
+        assert_eq!(cache.segment_lens(), (2, 2));
+        let in_: Vec<_> = cache.iter_in().map(|(&k, _)| k).collect();
+        assert_eq!(in_, vec![3, 2]);
+        assert_eq!(
+            cache.promotion_limit().map(PromotionLimit::remaining),
+            Some(0)
+        );
+
+        // the next interval lets them through
+        for _ in 0..92 {
+            cache.get(&0);
+        }
+        cache.get(&2);
+        cache.add(3, 30);
+        assert_eq!(cache.segment_lens(), (0, 4));
+        assert_eq!(cache.peek(&3), Some(&30));
+    }
+
+    #[test]
+    fn test_metadata() {
+        let mut cache: Cache<usize, usize> = Cache::new(8);
+        cache.set_frequency_tracking(true);
+        cache.add(0, 0);
+        cache.add(1, 1);
+        cache.get(&1);
+        assert_eq!(cache.metadata(&0).map(|m| m.segment), Some(Segment::In));
+        let meta = cache.metadata(&1).unwrap();
+        assert_eq!((meta.segment, meta.access_count), (Segment::Main, Some(1)));
+        assert_eq!(meta.inserted, None);
+        assert_eq!(cache.metadata(&2), None);
+    }
+
+    #[test]
+    fn test_refresh_interval() {
+        let mut cache: Cache<usize, usize> = Cache::new(8);
+        cache.set_refresh_interval(3);
+        for i in 0..3 {
+            cache.add(i, i);
+            cache.get(&i);
+        }
+        // promotions are not sampled, but moves within main are
+        assert_eq!(cache.segment_lens(), (0, 3));
+        let main =
+            |cache: &Cache<usize, usize>| cache.iter_main().map(|(&k, _)| k).collect::<Vec<_>>();
+        assert_eq!(main(&cache), vec![2, 1, 0]);
+        cache.get(&0);
+        cache.get(&0);
+        assert_eq!(main(&cache), vec![2, 1, 0]);
+        cache.get(&0);
+        assert_eq!(main(&cache), vec![0, 2, 1]);
+    }
+
+    #[test]
+    fn test_try_with_params() {
+        assert!(Cache::<usize, usize>::try_with_params(128, 0.75).is_ok());
+        assert_eq!(
+            Cache::<usize, usize>::try_with_params(0, 0.75).err(),
+            Some(ConfigError::SizeTooSmall { size: 0, min: 2 })
+        );
+        assert_eq!(
+            Cache::<usize, usize>::try_with_params(128, 0.0).err(),
+            Some(ConfigError::FactorOutOfRange {
+                name: "main_cache_factor",
+                value: 0.0
+            })
+        );
+        assert_eq!(
+            Cache::<usize, usize>::try_with_params(2, 0.25).err(),
+            Some(ConfigError::EmptySegment { segment: "main" })
+        );
+    }
+
+    #[test]
+    fn test_weigher() {
+        let mut cache: Cache<usize, usize> = Cache::new(8);
+        cache.set_weigher(100, |_, v| *v as u32);
+        assert_eq!(cache.max_weight(), 100);
+
+        for i in 1usize..6 {
+            cache.add(i, 20);
+        }
+        assert_eq!(cache.weighted_size(), 100);
+        cache.add(6, 20);
+        assert_eq!(cache.weighted_size(), 100);
+        assert!(!cache.contains_key(&1));
+
+        assert!(cache.get(&2).is_some());
+        assert_eq!(cache.segment_lens(), (4, 1));
+
+        cache.add(7, 90);
+        assert_eq!(cache.weighted_size(), 90);
+        assert_eq!(cache.len(), 1);
+
+        cache.add(8, 101);
+        assert!(!cache.contains_key(&8));
+        assert_eq!(cache.weighted_size(), 90);
+    }
+
+    #[test]
+    fn test_low_water_mark() {
+        let mut cache: Cache<usize, usize> = Cache::new(8);
+        cache.set_low_water_mark(Some(4)).unwrap();
+        for k in 0..8 {
+            cache.add(k, k);
+        }
+        for k in 0..4 {
+            assert!(cache.get(&k).is_some());
+        }
+        assert_eq!(cache.len(), 8);
+        cache.add(8, 8);
+        // the in segment empties before main gives up its least recent entry
+        let keys: Vec<_> = cache.iter().map(|(&k, _)| k).collect();
+        assert_eq!(keys, vec![3, 2, 1, 8]);
+
+        // an entry heavier than the in segment leaves main to the second loop
+        let mut cache: Cache<usize, usize> = Cache::new(8);
+        cache.set_weigher(8, |_: &usize, v: &usize| *v as u32);
+        cache.set_low_water_mark(Some(3)).unwrap();
+        for k in 0..4 {
+            cache.add(k, 1);
+            assert!(cache.get(&k).is_some());
+        }
+        cache.add(4, 1);
+        cache.add(5, 5);
+        assert_eq!(cache.weighted_size(), 5);
+        assert_eq!(cache.len(), 1);
+        assert!(cache.set_low_water_mark(Some(9)).is_err());
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut cache = Cache::new(16);
+        let mut other = Cache::new(16);
+        for k in 0..3 {
+            cache.add(k, "mine");
+        }
+        assert!(cache.get(&0).is_some());
+        for k in [2, 3, 0] {
+            other.add(k, "theirs");
+        }
+        assert!(other.get(&2).is_some());
+        let evicted = Rc::new(RefCell::new(Vec::new()));
+        let log = evicted.clone();
+        cache.set_eviction_callback(move |k, v| log.borrow_mut().push((k, v)));
+
+        // of a key in both, the entry in main is kept
+        cache.merge(other);
+        let mut entries: Vec<_> = cache.iter().map(|(&k, &v)| (k, v)).collect();
+        entries.sort();
+        assert_eq!(
+            entries,
+            vec![(0, "mine"), (1, "mine"), (2, "theirs"), (3, "theirs")]
+        );
+        assert_eq!(cache.segment_lens().1, 2);
+        assert_eq!(*evicted.as_ref().borrow(), vec![(2, "mine"), (0, "theirs")]);
+        let info = cache.stat();
+        assert_eq!((info.insert_count, info.replace_count), (4, 1));
+        assert_eq!(info.eviction_count, 0);
+        assert_eq!(cache.weighted_size(), 4);
+    }
+
+    #[test]
+    fn test_add_if_version() {
+        let mut cache: Cache<usize, usize> = Cache::new(8);
+        assert_eq!(cache.add_if_version(0, 0, None), Ok(Some(1)));
+        // writes through a reference move the version on, in either segment
+        *cache.peek_mut(&0).unwrap() = 1;
+        assert_eq!(cache.version(&0), Some(2));
+        *cache.get_mut(&0).unwrap() = 2;
+        assert_eq!(cache.segment_lens(), (0, 1));
+        assert_eq!(cache.version(&0), Some(3));
+        assert_eq!(cache.add_if_version(0, 3, Some(1)), Err(3));
+        cache.remove(&0);
+        assert_eq!(cache.version(&0), None);
+    }
 }
@@ -0,0 +1,1058 @@
+//! Statistics shared by every policy.
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::ops::{Add, RangeInclusive};
+use std::time::Duration;
+
+use super::clock::{Clock, DefaultClock};
+
+mod hot_keys;
+#[cfg(feature = "metrics")]
+pub(crate) mod metrics;
+#[cfg(feature = "prometheus")]
+pub mod prometheus;
+
+pub(crate) use self::hot_keys::HotKeys;
+
+/// Number of buckets a `HitWindow` divides its span into.
+const WINDOW_BUCKETS: usize = 10;
+
+/// One bucket for zero and one for every bit length of a `u64`.
+const HISTOGRAM_BUCKETS: usize = 65;
+
+/// Likewise for the `u32` access counts.
+const FREQUENCY_BUCKETS: usize = 33;
+
+/// Raw event counters.
+///
+/// Only lookups that may change the state of a cache (`get`) are counted as
+/// hits or misses. `peek`, `contains_key` and the insertion paths never touch
+/// those two counters.
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct Counters {
+    pub hit_count: usize,
+    pub miss_count: usize,
+    pub eviction_count: usize,
+    pub expiration_count: usize,
+    pub insert_count: usize,
+    pub replace_count: usize,
+    pub main_hit_count: usize,
+    pub in_hit_count: usize,
+    pub ghost_hit_count: usize,
+    pub ghost_drop_count: usize,
+    pub ghost_age_insertions_total: usize,
+    pub age_at_eviction: Histogram,
+    pub idle_at_eviction: Histogram,
+    pub get_latency: Histogram,
+    pub add_latency: Histogram,
+}
+
+impl Counters {
+    /// Returns the counters accumulated since `mark` was taken.
+    pub fn since(&self, mark: &Counters) -> Counters {
+        Counters {
+            hit_count: self.hit_count.saturating_sub(mark.hit_count),
+            miss_count: self.miss_count.saturating_sub(mark.miss_count),
+            eviction_count: self.eviction_count.saturating_sub(mark.eviction_count),
+            expiration_count: self.expiration_count.saturating_sub(mark.expiration_count),
+            insert_count: self.insert_count.saturating_sub(mark.insert_count),
+            replace_count: self.replace_count.saturating_sub(mark.replace_count),
+            main_hit_count: self.main_hit_count.saturating_sub(mark.main_hit_count),
+            in_hit_count: self.in_hit_count.saturating_sub(mark.in_hit_count),
+            ghost_hit_count: self.ghost_hit_count.saturating_sub(mark.ghost_hit_count),
+            ghost_drop_count: self.ghost_drop_count.saturating_sub(mark.ghost_drop_count),
+            ghost_age_insertions_total: self
+                .ghost_age_insertions_total
+                .saturating_sub(mark.ghost_age_insertions_total),
+            age_at_eviction: self.age_at_eviction.since(&mark.age_at_eviction),
+            idle_at_eviction: self.idle_at_eviction.since(&mark.idle_at_eviction),
+            get_latency: self.get_latency.since(&mark.get_latency),
+            add_latency: self.add_latency.since(&mark.add_latency),
+        }
+    }
+}
+
+/// Why an entry left a cache through the eviction path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EvictionReason {
+    /// Evicted to keep the cache within its size or weight bound.
+    Capacity,
+    /// Never stored, because the cache is disabled or the entry alone
+    /// exceeds the weight budget.
+    Rejected,
+    /// Dropped because it expired.
+    Expired,
+    /// Dropped by `merge` for the entry of the same key in the other cache.
+    Superseded,
+}
+
+/// An insertion or eviction, as taken from the event queue of a cache.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EntryEvent<K, V> {
+    Inserted(K),
+    /// An entry left the cache; expirations have the `Expired` reason.
+    Evicted {
+        key: K,
+        value: V,
+        reason: EvictionReason,
+    },
+}
+
+/// What `remove_if` did.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Removal<V> {
+    /// The predicate held and the entry was removed.
+    Removed(V),
+    /// The predicate did not hold and the entry stays.
+    Kept,
+    /// There was no such entry.
+    Missing,
+}
+
+/// The part of a cache an entry is in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Segment {
+    /// The only segment of an LRU or FIFO cache.
+    Single,
+    /// The probationary segment of an SLRU or 2Q cache.
+    In,
+    /// The protected segment of an SLRU or 2Q cache.
+    Main,
+}
+
+/// What a cache knows about one of its entries, as returned by `metadata`.
+/// Times are readings of the clock of the age tracking; like the access
+/// count, they are only known for entries stored while their tracking was
+/// enabled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EntryMeta {
+    pub inserted: Option<Duration>,
+    /// When the entry was last read or replaced.
+    pub last_access: Option<Duration>,
+    /// How often the entry was read or replaced.
+    pub access_count: Option<u32>,
+    pub segment: Segment,
+}
+
+/// A statistics event, as passed to a `StatsRecorder`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Event {
+    Hit,
+    Miss,
+    Eviction { reason: EvictionReason },
+    Insert,
+    Replace,
+}
+
+/// Receives the statistics events of a cache, so that they can be counted
+/// in an external metrics registry. The cache's own counters, which back
+/// `stat`, are the built-in recorder and keep counting either way.
+pub trait StatsRecorder {
+    fn record(&mut self, event: Event);
+}
+
+impl StatsRecorder for Counters {
+    #[inline]
+    fn record(&mut self, event: Event) {
+        match event {
+            Event::Hit => self.hit_count += 1,
+            Event::Miss => self.miss_count += 1,
+            Event::Eviction {
+                reason: EvictionReason::Expired,
+            } => self.expiration_count += 1,
+            Event::Eviction { .. } => self.eviction_count += 1,
+            Event::Insert => self.insert_count += 1,
+            Event::Replace => self.replace_count += 1,
+        }
+    }
+}
+
+/// The operations whose latency can be sampled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Operation {
+    Get,
+    Add,
+}
+
+/// The ghost hits and drops counted by a replacement policy, which has no
+/// access to the statistics, until the cache takes them.
+#[derive(Debug, Default)]
+pub(crate) struct GhostCounts {
+    hits: usize,
+    age_insertions: usize,
+    drops: usize,
+}
+
+impl GhostCounts {
+    /// Counts an insertion whose key was found in a ghost queue, `age`
+    /// ghost insertions after the key entered it.
+    #[inline]
+    pub fn hit(&mut self, age: usize) {
+        self.hits += 1;
+        self.age_insertions += age;
+    }
+
+    /// Counts a ghost pushed out of its queue without ever being hit.
+    #[inline]
+    pub fn drop_one(&mut self) {
+        self.drops += 1;
+    }
+}
+
+/// The counters of a cache together with the optional trackers built on
+/// top of them.
+#[derive(Default)]
+pub(crate) struct Stats {
+    pub counters: Counters,
+    mark: Counters,
+    window: Option<HitWindow>,
+    ages: Option<AgeTracking>,
+    /// Access counts of the cached entries by key hash.
+    frequencies: Option<HashMap<u64, u32>>,
+    /// The counters as of the last `flush_metrics`.
+    #[cfg(feature = "metrics")]
+    flushed: Counters,
+    recorder: Option<Box<dyn StatsRecorder>>,
+    latency: Option<LatencySampling>,
+}
+
+impl Stats {
+    /// Counts an event and passes it on to the trackers and the external
+    /// recorder.
+    #[inline]
+    pub fn record(&mut self, event: Event) {
+        self.counters.record(event);
+        if let Some(window) = &mut self.window {
+            match event {
+                Event::Hit => window.record(true),
+                Event::Miss => window.record(false),
+                _ => {}
+            }
+        }
+        if let Some(recorder) = &mut self.recorder {
+            recorder.record(event);
+        }
+    }
+
+    #[inline]
+    pub fn hit(&mut self) {
+        self.record(Event::Hit);
+    }
+
+    #[inline]
+    pub fn miss(&mut self) {
+        self.record(Event::Miss);
+    }
+
+    /// Counts a hit served from the protected (main) segment.
+    #[inline]
+    pub fn hit_main(&mut self) {
+        self.hit();
+        self.counters.main_hit_count += 1;
+    }
+
+    /// Counts a hit served from the probation (in) segment.
+    #[inline]
+    pub fn hit_in(&mut self) {
+        self.hit();
+        self.counters.in_hit_count += 1;
+    }
+
+    /// Takes over the ghost hits and drops a policy counted since the last
+    /// call.
+    #[inline]
+    pub fn add_ghosts(&mut self, ghosts: &mut GhostCounts) {
+        self.counters.ghost_hit_count += ghosts.hits;
+        self.counters.ghost_age_insertions_total += ghosts.age_insertions;
+        self.counters.ghost_drop_count += ghosts.drops;
+        *ghosts = GhostCounts::default();
+    }
+
+    /// Counts an entry handed to the eviction callback.
+    #[inline]
+    pub fn evict(&mut self, reason: EvictionReason) {
+        self.record(Event::Eviction { reason });
+    }
+
+    /// Counts a new key being stored.
+    #[inline]
+    pub fn insert(&mut self) {
+        self.record(Event::Insert);
+    }
+
+    /// Counts the value of an already cached key being replaced.
+    #[inline]
+    pub fn replace(&mut self) {
+        self.record(Event::Replace);
+    }
+
+    pub fn set_recorder(&mut self, recorder: Option<Box<dyn StatsRecorder>>) {
+        self.recorder = recorder;
+    }
+
+    /// Returns the start time of the operation about to run if it is one of
+    /// the sampled ones.
+    #[inline]
+    pub fn start_sample(&mut self) -> Option<Duration> {
+        let latency = self.latency.as_mut()?;
+        latency.skipped += 1;
+        if latency.skipped < latency.every {
+            return None;
+        }
+        latency.skipped = 0;
+        Some(latency.clock.now())
+    }
+
+    /// Records the latency of a sampled operation started at `start`.
+    #[inline]
+    pub fn end_sample(&mut self, op: Operation, start: Option<Duration>) {
+        if let (Some(start), Some(latency)) = (start, &self.latency) {
+            let elapsed = latency.clock.now().checked_sub(start).unwrap_or_default();
+            match op {
+                Operation::Get => self.counters.get_latency.record(elapsed),
+                Operation::Add => self.counters.add_latency.record(elapsed),
+            }
+        }
+    }
+
+    pub fn set_latency_sampling(&mut self, latency: Option<LatencySampling>) {
+        self.latency = latency;
+    }
+
+    /// Notes that the entry with the given key hash was stored. The hash is
+    /// only computed when age or frequency tracking is enabled.
+    #[inline]
+    pub fn stamp_insert<F>(&mut self, hash: F)
+    where
+        F: FnOnce() -> u64,
+    {
+        if !self.tracks_entries() {
+            return;
+        }
+        let hash = hash();
+        if let Some(ages) = &mut self.ages {
+            let now = ages.clock.now();
+            ages.stamps.insert(hash, (now, now));
+        }
+        if let Some(frequencies) = &mut self.frequencies {
+            frequencies.insert(hash, 0);
+        }
+    }
+
+    /// Notes that the entry with the given key hash was read or replaced.
+    #[inline]
+    pub fn stamp_access<F>(&mut self, hash: F)
+    where
+        F: FnOnce() -> u64,
+    {
+        if !self.tracks_entries() {
+            return;
+        }
+        let hash = hash();
+        if let Some(ages) = &mut self.ages {
+            let now = ages.clock.now();
+            if let Some(stamp) = ages.stamps.get_mut(&hash) {
+                stamp.1 = now;
+            }
+        }
+        if let Some(count) = self.frequencies.as_mut().and_then(|f| f.get_mut(&hash)) {
+            *count = count.saturating_add(1);
+        }
+    }
+
+    /// Notes that the value of the entry with the given key hash was
+    /// replaced.
+    #[inline]
+    pub fn stamp_replace<F>(&mut self, hash: F)
+    where
+        F: FnOnce() -> u64,
+    {
+        self.stamp_access(hash);
+    }
+
+    /// Forgets the entry with the given key hash, which left the cache
+    /// without being evicted.
+    #[inline]
+    pub fn unstamp<F>(&mut self, hash: F)
+    where
+        F: FnOnce() -> u64,
+    {
+        if !self.tracks_entries() {
+            return;
+        }
+        let hash = hash();
+        if let Some(ages) = &mut self.ages {
+            ages.stamps.remove(&hash);
+        }
+        if let Some(frequencies) = &mut self.frequencies {
+            frequencies.remove(&hash);
+        }
+    }
+
+    /// Records the age and idle time of an evicted entry. Entries stored
+    /// before tracking was enabled, or never stored at all, are skipped.
+    #[inline]
+    pub fn evict_stamped<F>(&mut self, hash: F)
+    where
+        F: FnOnce() -> u64,
+    {
+        if !self.tracks_entries() {
+            return;
+        }
+        let hash = hash();
+        if let Some(ages) = &mut self.ages {
+            if let Some((inserted, accessed)) = ages.stamps.remove(&hash) {
+                let now = ages.clock.now();
+                let counters = &mut self.counters;
+                counters
+                    .age_at_eviction
+                    .record(now.checked_sub(inserted).unwrap_or_default());
+                counters
+                    .idle_at_eviction
+                    .record(now.checked_sub(accessed).unwrap_or_default());
+            }
+        }
+        if let Some(frequencies) = &mut self.frequencies {
+            frequencies.remove(&hash);
+        }
+    }
+
+    pub fn unstamp_all(&mut self) {
+        if let Some(ages) = &mut self.ages {
+            ages.stamps.clear();
+        }
+        if let Some(frequencies) = &mut self.frequencies {
+            frequencies.clear();
+        }
+    }
+
+    /// Returns what the trackers know about the entry with the given key
+    /// hash, which is in `segment`.
+    pub fn entry_meta(&self, hash: u64, segment: Segment) -> EntryMeta {
+        let stamps = self.ages.as_ref().and_then(|ages| ages.stamps.get(&hash));
+        EntryMeta {
+            inserted: stamps.map(|&(inserted, _)| inserted),
+            last_access: stamps.map(|&(_, accessed)| accessed),
+            access_count: self
+                .frequencies
+                .as_ref()
+                .and_then(|f| f.get(&hash).copied()),
+            segment,
+        }
+    }
+
+    fn tracks_entries(&self) -> bool {
+        self.ages.is_some() || self.frequencies.is_some()
+    }
+
+    pub fn set_age_tracking(&mut self, ages: Option<AgeTracking>) {
+        self.ages = ages;
+    }
+
+    pub fn set_frequency_tracking(&mut self, enabled: bool) {
+        if !enabled {
+            self.frequencies = None;
+        } else if self.frequencies.is_none() {
+            self.frequencies = Some(HashMap::new());
+        }
+    }
+
+    /// Buckets the tracked entries by their access count: bucket `i` holds
+    /// the entries read or replaced less than `2^i` but at least `2^(i - 1)`
+    /// times. Empty buckets are left out.
+    pub fn frequency_distribution(&self) -> Option<Vec<(RangeInclusive<u32>, usize)>> {
+        let frequencies = self.frequencies.as_ref()?;
+        let mut counts = [0usize; FREQUENCY_BUCKETS];
+        for &count in frequencies.values() {
+            counts[(32 - count.leading_zeros()) as usize] += 1;
+        }
+        let distribution = counts
+            .iter()
+            .enumerate()
+            .filter(|&(_, &entries)| entries > 0)
+            .map(|(i, &entries)| (frequency_range(i), entries))
+            .collect();
+        Some(distribution)
+    }
+
+    /// Returns the counters accumulated since the previous call.
+    pub fn take_delta(&mut self) -> Counters {
+        let delta = self.counters.since(&self.mark);
+        self.mark = self.counters;
+        delta
+    }
+
+    /// Like `take_delta`, but against the mark of the metrics flushes.
+    #[cfg(feature = "metrics")]
+    pub fn take_flushed(&mut self) -> Counters {
+        let delta = self.counters.since(&self.flushed);
+        self.flushed = self.counters;
+        delta
+    }
+
+    pub fn reset(&mut self) {
+        self.counters = Counters::default();
+        self.mark = Counters::default();
+        #[cfg(feature = "metrics")]
+        {
+            self.flushed = Counters::default();
+        }
+        if let Some(window) = &mut self.window {
+            window.clear();
+        }
+    }
+
+    pub fn set_hit_window(&mut self, window: Option<HitWindow>) {
+        self.window = window;
+    }
+
+    pub fn recent_hit_rate(&self) -> Option<f64> {
+        self.window.as_ref().and_then(HitWindow::hit_rate)
+    }
+}
+
+/// A snapshot of the statistics of a cache, as returned by `stat` and
+/// `stat_delta`. Counters that a policy never touches stay zero.
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Info {
+    pub hit_count: usize,
+    pub miss_count: usize,
+    pub eviction_count: usize,
+    /// Entries dropped because they expired; zero for caches without
+    /// expiration.
+    pub expiration_count: usize,
+    pub insert_count: usize,
+    pub replace_count: usize,
+    pub len: usize,
+    /// Estimated memory held by the cache.
+    pub memory: MemoryUsage,
+    /// Time since insertion of the entries evicted while age tracking was
+    /// enabled, or `None` if it never was.
+    pub age_at_eviction: Option<Histogram>,
+    /// Time since the last access of the same entries.
+    pub idle_at_eviction: Option<Histogram>,
+    /// Latency of the sampled `get` calls, or `None` if latency sampling
+    /// was never enabled.
+    pub get_latency: Option<Histogram>,
+    /// Latency of the sampled `add` calls.
+    pub add_latency: Option<Histogram>,
+    /// Hits served from the main segment of a segmented cache.
+    pub main_hit_count: usize,
+    /// Hits served from the in segment, each of which promoted the entry.
+    pub in_hit_count: usize,
+    /// Insertions admitted straight to main because the key was found in
+    /// the ghost queue.
+    pub ghost_hit_count: usize,
+    /// Ghosts pushed out of the ghost queue without ever being hit.
+    pub ghost_drop_count: usize,
+    /// Sum over all ghost hits of the number of keys that entered the ghost
+    /// queue while the hit key was waiting there: a count of insertions,
+    /// not a time.
+    pub ghost_age_insertions_total: usize,
+}
+
+impl Info {
+    pub(crate) fn new(counters: &Counters, len: usize, memory: MemoryUsage, stats: &Stats) -> Info {
+        let tracked = |histogram: Histogram| stats.ages.as_ref().map(|_| histogram);
+        let sampled = |histogram: Histogram| stats.latency.as_ref().map(|_| histogram);
+        Info {
+            hit_count: counters.hit_count,
+            miss_count: counters.miss_count,
+            eviction_count: counters.eviction_count,
+            expiration_count: counters.expiration_count,
+            insert_count: counters.insert_count,
+            replace_count: counters.replace_count,
+            len,
+            memory,
+            age_at_eviction: tracked(counters.age_at_eviction),
+            idle_at_eviction: tracked(counters.idle_at_eviction),
+            get_latency: sampled(counters.get_latency),
+            add_latency: sampled(counters.add_latency),
+            main_hit_count: counters.main_hit_count,
+            in_hit_count: counters.in_hit_count,
+            ghost_hit_count: counters.ghost_hit_count,
+            ghost_drop_count: counters.ghost_drop_count,
+            ghost_age_insertions_total: counters.ghost_age_insertions_total,
+        }
+    }
+
+    pub fn hit_ratio(&self) -> f64 {
+        hit_ratio(self.hit_count, self.miss_count)
+    }
+
+    /// Fraction of insertions that were admitted to main by a ghost hit.
+    pub fn ghost_admission_ratio(&self) -> f64 {
+        if self.insert_count == 0 {
+            return 0.0;
+        }
+        self.ghost_hit_count as f64 / self.insert_count as f64
+    }
+
+    /// Average age of a key in the ghost queue when it was hit, counted in
+    /// the insertions into the queue meanwhile rather than in time. A value
+    /// close to the ghost queue's maximum length suggests the queue is too
+    /// short; a small one combined with many drops suggests it is longer
+    /// than it needs to be.
+    pub fn avg_ghost_age_insertions(&self) -> f64 {
+        if self.ghost_hit_count == 0 {
+            return 0.0;
+        }
+        self.ghost_age_insertions_total as f64 / self.ghost_hit_count as f64
+    }
+
+    /// Returns the counters, memory usage and histograms as JSON, with the
+    /// derived ratios alongside. Histograms are lists of `[upper bound in
+    /// nanoseconds, count]` pairs.
+    #[cfg(feature = "json")]
+    pub fn to_json(&self) -> serde_json::Value {
+        let mut json = serde_json::to_value(self).expect("stats serialize to JSON");
+        json["hit_ratio"] = self.hit_ratio().into();
+        json["ghost_admission_ratio"] = self.ghost_admission_ratio().into();
+        json["avg_ghost_age_insertions"] = self.avg_ghost_age_insertions().into();
+        json
+    }
+}
+
+/// Estimated bytes held by a cache, broken down by what holds them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct MemoryUsage {
+    /// Nodes holding the cached entries, including the inline part of
+    /// their keys and values.
+    pub node_bytes: usize,
+    /// Nodes of removed entries kept for reuse.
+    pub pooled_bytes: usize,
+    /// Hash tables, from their capacity.
+    pub table_bytes: usize,
+    /// Heap memory owned by the cached keys and values as measured by
+    /// `HeapSize`, or `None` unless the cache is bounded by
+    /// `set_max_heap_size`.
+    pub heap_bytes: Option<usize>,
+}
+
+impl MemoryUsage {
+    pub fn total(&self) -> usize {
+        self.node_bytes + self.pooled_bytes + self.table_bytes + self.heap_bytes.unwrap_or(0)
+    }
+}
+
+impl Add for MemoryUsage {
+    type Output = MemoryUsage;
+
+    fn add(self, other: MemoryUsage) -> MemoryUsage {
+        let heap_bytes = match (self.heap_bytes, other.heap_bytes) {
+            (None, None) => None,
+            (a, b) => Some(a.unwrap_or(0) + b.unwrap_or(0)),
+        };
+        MemoryUsage {
+            node_bytes: self.node_bytes + other.node_bytes,
+            pooled_bytes: self.pooled_bytes + other.pooled_bytes,
+            table_bytes: self.table_bytes + other.table_bytes,
+            heap_bytes,
+        }
+    }
+}
+
+/// Returns `hits / (hits + misses)`, or zero before the first lookup.
+pub(crate) fn hit_ratio(hit_count: usize, miss_count: usize) -> f64 {
+    let total = hit_count + miss_count;
+    if total == 0 {
+        return 0.0;
+    }
+    hit_count as f64 / total as f64
+}
+
+enum Span {
+    Operations {
+        per_bucket: usize,
+        in_bucket: usize,
+    },
+    Time {
+        per_bucket: Duration,
+        bucket_start: Duration,
+        clock: Box<dyn Clock>,
+    },
+}
+
+/// Hit rate over a sliding window of recent lookups.
+///
+/// The window is split into ten buckets of hit and miss counts; the oldest
+/// bucket is dropped as a new one starts, so the covered span varies between
+/// nine and ten tenths of the configured one.
+pub struct HitWindow {
+    span: Span,
+    buckets: Vec<(usize, usize)>,
+    current: usize,
+}
+
+impl HitWindow {
+    /// Tracks roughly the last `ops` lookups.
+    pub fn operations(ops: usize) -> HitWindow {
+        let per_bucket = (ops / WINDOW_BUCKETS).max(1);
+        HitWindow::with_span(Span::Operations {
+            per_bucket,
+            in_bucket: 0,
+        })
+    }
+
+    /// Tracks the lookups of roughly the last `span` of time.
+    pub fn duration(span: Duration) -> HitWindow {
+        HitWindow::duration_with_clock(span, DefaultClock::default())
+    }
+
+    pub fn duration_with_clock<C>(span: Duration, clock: C) -> HitWindow
+    where
+        C: Clock + 'static,
+    {
+        let per_bucket = (span / WINDOW_BUCKETS as u32).max(Duration::from_nanos(1));
+        let bucket_start = clock.now();
+        HitWindow::with_span(Span::Time {
+            per_bucket,
+            bucket_start,
+            clock: Box::new(clock),
+        })
+    }
+
+    fn with_span(span: Span) -> HitWindow {
+        HitWindow {
+            span,
+            buckets: vec![(0, 0); WINDOW_BUCKETS],
+            current: 0,
+        }
+    }
+
+    fn record(&mut self, hit: bool) {
+        let expired = match &mut self.span {
+            Span::Operations {
+                per_bucket,
+                in_bucket,
+            } => {
+                if *in_bucket < *per_bucket {
+                    *in_bucket += 1;
+                    0
+                } else {
+                    *in_bucket = 1;
+                    1
+                }
+            }
+            Span::Time {
+                per_bucket,
+                bucket_start,
+                clock,
+            } => {
+                let now = clock.now();
+                let expired = expired_buckets(now, *bucket_start, *per_bucket);
+                if expired > 0 {
+                    // the start of the bucket `now` falls in, which is less
+                    // than a bucket back
+                    let elapsed = now - *bucket_start;
+                    let into_bucket = elapsed.as_nanos() % per_bucket.as_nanos();
+                    *bucket_start = now - Duration::from_nanos(into_bucket as u64);
+                }
+                expired
+            }
+        };
+        for _ in 0..expired.min(WINDOW_BUCKETS) {
+            self.current = (self.current + 1) % WINDOW_BUCKETS;
+            self.buckets[self.current] = (0, 0);
+        }
+
+        let bucket = &mut self.buckets[self.current];
+        if hit {
+            bucket.0 += 1;
+        } else {
+            bucket.1 += 1;
+        }
+    }
+
+    fn hit_rate(&self) -> Option<f64> {
+        let expired = match &self.span {
+            Span::Operations { .. } => 0,
+            Span::Time {
+                per_bucket,
+                bucket_start,
+                clock,
+            } => expired_buckets(clock.now(), *bucket_start, *per_bucket),
+        };
+        if expired >= WINDOW_BUCKETS {
+            return None;
+        }
+
+        // the `expired` oldest buckets would be recycled by the next lookup
+        let (hits, misses) = (0..WINDOW_BUCKETS - expired)
+            .map(|age| self.buckets[(self.current + WINDOW_BUCKETS - age) % WINDOW_BUCKETS])
+            .fold((0, 0), |(h, m), (bh, bm)| (h + bh, m + bm));
+        if hits + misses == 0 {
+            return None;
+        }
+        Some(hit_ratio(hits, misses))
+    }
+
+    fn clear(&mut self) {
+        for bucket in &mut self.buckets {
+            *bucket = (0, 0);
+        }
+    }
+}
+
+/// Tracks when each cached entry was stored and last accessed so that its
+/// age and idle time can be recorded when it is evicted.
+///
+/// Entries are identified by the hash of their key; on the rare collision
+/// the two entries share a stamp.
+pub struct AgeTracking {
+    clock: Box<dyn Clock>,
+    stamps: HashMap<u64, (Duration, Duration)>,
+}
+
+impl AgeTracking {
+    pub fn new() -> AgeTracking {
+        AgeTracking::with_clock(DefaultClock::default())
+    }
+
+    pub fn with_clock<C>(clock: C) -> AgeTracking
+    where
+        C: Clock + 'static,
+    {
+        AgeTracking {
+            clock: Box::new(clock),
+            stamps: HashMap::new(),
+        }
+    }
+}
+
+impl Default for AgeTracking {
+    fn default() -> AgeTracking {
+        AgeTracking::new()
+    }
+}
+
+/// Measures the latency of every `every`th `get` or `add` call.
+pub struct LatencySampling {
+    every: usize,
+    skipped: usize,
+    clock: Box<dyn Clock>,
+}
+
+impl LatencySampling {
+    pub fn new(every: usize) -> LatencySampling {
+        LatencySampling::with_clock(every, DefaultClock::default())
+    }
+
+    pub fn with_clock<C>(every: usize, clock: C) -> LatencySampling
+    where
+        C: Clock + 'static,
+    {
+        LatencySampling {
+            every: every.max(1),
+            skipped: 0,
+            clock: Box::new(clock),
+        }
+    }
+}
+
+/// A histogram of durations with power-of-two buckets: bucket `i` counts
+/// durations of less than `2^i` but at least `2^(i - 1)` nanoseconds, so a
+/// reading is accurate to within a factor of two.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Histogram {
+    counts: [u64; HISTOGRAM_BUCKETS],
+}
+
+impl Histogram {
+    pub fn new() -> Histogram {
+        Histogram {
+            counts: [0; HISTOGRAM_BUCKETS],
+        }
+    }
+
+    pub fn record(&mut self, value: Duration) {
+        let nanos = value.as_nanos().min(u128::from(u64::MAX)) as u64;
+        self.counts[(64 - nanos.leading_zeros()) as usize] += 1;
+    }
+
+    /// Returns the number of recorded durations.
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count() == 0
+    }
+
+    /// Iterates over the non-empty buckets as `(upper bound, count)` pairs,
+    /// shortest durations first. The upper bound is inclusive.
+    pub fn buckets(&self) -> impl Iterator<Item = (Duration, u64)> + '_ {
+        self.counts
+            .iter()
+            .enumerate()
+            .filter(|&(_, &count)| count > 0)
+            .map(|(i, &count)| (bucket_upper_bound(i), count))
+    }
+
+    /// Returns the upper bound of the bucket holding the `q`-quantile, e.g.
+    /// `0.5` for the median, or `None` if nothing was recorded.
+    pub fn quantile(&self, q: f64) -> Option<Duration> {
+        let total = self.count();
+        if total == 0 {
+            return None;
+        }
+        let rank = ((q.clamp(0.0, 1.0) * total as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (i, &count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Some(bucket_upper_bound(i));
+            }
+        }
+        None
+    }
+
+    /// Returns the durations recorded since `mark` was taken.
+    pub fn since(&self, mark: &Histogram) -> Histogram {
+        let mut delta = *self;
+        for (count, marked) in delta.counts.iter_mut().zip(mark.counts.iter()) {
+            *count = count.saturating_sub(*marked);
+        }
+        delta
+    }
+}
+
+/// Serialized as the list of non-empty buckets, each an `(upper bound in
+/// nanoseconds, count)` pair.
+#[cfg(feature = "serde")]
+impl serde::Serialize for Histogram {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(
+            self.buckets()
+                .map(|(bound, count)| (bound.as_nanos() as u64, count)),
+        )
+    }
+}
+
+impl Default for Histogram {
+    fn default() -> Histogram {
+        Histogram::new()
+    }
+}
+
+fn frequency_range(bucket: usize) -> RangeInclusive<u32> {
+    match bucket {
+        0 => 0..=0,
+        32 => 1 << 31..=u32::MAX,
+        _ => 1 << (bucket - 1)..=(1 << bucket) - 1,
+    }
+}
+
+fn bucket_upper_bound(bucket: usize) -> Duration {
+    let nanos = if bucket == 64 {
+        u64::MAX
+    } else {
+        (1u64 << bucket) - 1
+    };
+    Duration::from_nanos(nanos)
+}
+
+fn expired_buckets(now: Duration, bucket_start: Duration, per_bucket: Duration) -> usize {
+    let elapsed = now.checked_sub(bucket_start).unwrap_or_default();
+    usize::try_from(elapsed.as_nanos() / per_bucket.as_nanos()).unwrap_or(usize::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    #[test]
+    fn test_operations_window() {
+        let mut window = HitWindow::operations(10);
+        assert_eq!(window.hit_rate(), None);
+
+        for _ in 0..10 {
+            window.record(false);
+        }
+        assert_eq!(window.hit_rate(), Some(0.0));
+
+        for _ in 0..5 {
+            window.record(true);
+        }
+        assert_eq!(window.hit_rate(), Some(0.5));
+    }
+
+    #[test]
+    fn test_histogram() {
+        let mut histogram = Histogram::new();
+        assert_eq!(histogram.quantile(0.5), None);
+
+        histogram.record(Duration::from_nanos(0));
+        histogram.record(Duration::from_nanos(5));
+        histogram.record(Duration::from_nanos(6));
+        histogram.record(Duration::from_nanos(100));
+        assert_eq!(histogram.count(), 4);
+        assert_eq!(
+            histogram.buckets().collect::<Vec<_>>(),
+            vec![
+                (Duration::from_nanos(0), 1),
+                (Duration::from_nanos(7), 2),
+                (Duration::from_nanos(127), 1),
+            ]
+        );
+        assert_eq!(histogram.quantile(0.5), Some(Duration::from_nanos(7)));
+        assert_eq!(histogram.quantile(1.0), Some(Duration::from_nanos(127)));
+
+        let mark = histogram;
+        histogram.record(Duration::from_secs(1));
+        assert_eq!(histogram.since(&mark).count(), 1);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serialize_info() {
+        let mut counters = Counters {
+            hit_count: 3,
+            ..Default::default()
+        };
+        counters.age_at_eviction.record(Duration::from_nanos(5));
+        let mut stats = Stats::default();
+        stats.set_age_tracking(Some(AgeTracking::new()));
+        let info = Info::new(&counters, 2, MemoryUsage::default(), &stats);
+
+        let json = serde_json::to_value(&info).unwrap();
+        assert_eq!(json["hit_count"], 3);
+        assert_eq!(json["len"], 2);
+        assert_eq!(json["age_at_eviction"], serde_json::json!([[7, 1]]));
+        assert_eq!(json["idle_at_eviction"], serde_json::json!([]));
+        assert_eq!(json["memory"]["heap_bytes"], serde_json::Value::Null);
+        let info = Info::new(&counters, 2, MemoryUsage::default(), &Stats::default());
+        assert_eq!(info.age_at_eviction, None);
+    }
+
+    #[test]
+    fn test_duration_window() {
+        let clock = ManualClock::new();
+        let mut window = HitWindow::duration_with_clock(Duration::from_secs(10), clock.clone());
+
+        window.record(false);
+        window.record(false);
+        clock.advance(Duration::from_secs(5));
+        window.record(true);
+        assert_eq!(window.hit_rate(), Some(1.0 / 3.0));
+
+        clock.advance(Duration::from_secs(6));
+        assert_eq!(window.hit_rate(), Some(1.0));
+
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(window.hit_rate(), None);
+        window.record(false);
+        assert_eq!(window.hit_rate(), Some(0.0));
+
+        // more buckets expire at once than a u32 counts
+        let span = Duration::from_nanos(WINDOW_BUCKETS as u64);
+        let mut window = HitWindow::duration_with_clock(span, clock.clone());
+        clock.advance(Duration::from_secs(5));
+        window.record(true);
+        assert_eq!(window.hit_rate(), Some(1.0));
+    }
+}
@@ -0,0 +1,151 @@
+use std::cmp::Reverse;
+use std::collections::btree_map::{self, BTreeMap};
+use std::collections::HashMap;
+use std::hash::Hash;
+
+use crate::hash::DefaultState;
+use crate::map::LinkedHashMap;
+
+/// Finds the most frequently accessed keys with the space-saving algorithm.
+///
+/// At most `capacity` keys are monitored. An unmonitored key replaces the
+/// one with the lowest count and inherits that count plus one, so counts
+/// overestimate by at most the count they inherited, and any key accessed
+/// more often than `1 / capacity` of the time is guaranteed to be
+/// monitored. Every `window` accesses all counts are halved, which makes
+/// the ranking follow recent traffic.
+///
+/// The keys are also filed by count, the oldest of each count at the back,
+/// so that the key to replace is found without a scan.
+pub(crate) struct HotKeys<K> {
+    capacity: usize,
+    window: usize,
+    seen: usize,
+    counts: HashMap<K, u64>,
+    by_count: BTreeMap<u64, LinkedHashMap<K, (), DefaultState>>,
+    clone: fn(&K) -> K,
+}
+
+impl<K: Hash + Eq> HotKeys<K> {
+    pub fn new(capacity: usize, window: usize) -> HotKeys<K>
+    where
+        K: Clone,
+    {
+        HotKeys {
+            capacity: capacity.max(1),
+            window: window.max(1),
+            seen: 0,
+            counts: HashMap::with_capacity(capacity),
+            by_count: BTreeMap::new(),
+            clone: K::clone,
+        }
+    }
+
+    pub fn record(&mut self, k: &K) {
+        if let Some(count) = self.counts.get_mut(k) {
+            let old = *count;
+            *count += 1;
+            self.unfile(k, old);
+            self.file(k, old + 1);
+        } else if self.counts.len() < self.capacity {
+            self.counts.insert((self.clone)(k), 1);
+            self.file(k, 1);
+        } else if let Some(mut min) = self.by_count.first_entry() {
+            let min_count = *min.key();
+            let (min_k, _) = min.get_mut().pop_back().expect("buckets are never empty");
+            if min.get().is_empty() {
+                min.remove();
+            }
+            self.counts.remove(&min_k);
+            self.counts.insert((self.clone)(k), min_count + 1);
+            self.file(k, min_count + 1);
+        }
+
+        self.seen += 1;
+        if self.seen >= self.window {
+            self.seen = 0;
+            for count in self.counts.values_mut() {
+                *count /= 2;
+            }
+            self.counts.retain(|_, count| *count > 0);
+            // halving merges counts, so the buckets are filed anew
+            self.by_count.clear();
+            let counts: Vec<(K, u64)> = self
+                .counts
+                .iter()
+                .map(|(k, &count)| ((self.clone)(k), count))
+                .collect();
+            for (k, count) in counts {
+                self.file(&k, count);
+            }
+        }
+    }
+
+    fn file(&mut self, k: &K, count: u64) {
+        self.by_count
+            .entry(count)
+            .or_insert_with(|| LinkedHashMap::with_hasher(Default::default()))
+            .push_front((self.clone)(k), ());
+    }
+
+    fn unfile(&mut self, k: &K, count: u64) {
+        if let btree_map::Entry::Occupied(mut bucket) = self.by_count.entry(count) {
+            bucket.get_mut().remove(k);
+            if bucket.get().is_empty() {
+                bucket.remove();
+            }
+        }
+    }
+
+    /// Returns up to `n` keys with their estimated counts, most frequent
+    /// first.
+    pub fn top(&self, n: usize) -> Vec<(K, u64)> {
+        let mut top: Vec<(&K, u64)> = self.counts.iter().map(|(k, &count)| (k, count)).collect();
+        top.sort_by_key(|&(_, count)| Reverse(count));
+        top.into_iter()
+            .take(n)
+            .map(|(k, count)| ((self.clone)(k), count))
+            .collect()
+    }
+
+    pub fn clear(&mut self) {
+        self.seen = 0;
+        self.counts.clear();
+        self.by_count.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_space_saving() {
+        let mut hot = HotKeys::new(2, 100);
+        for _ in 0..5 {
+            hot.record(&1);
+        }
+        hot.record(&2);
+        hot.record(&3);
+        // 3 took over the slot of 2 and its count
+        assert_eq!(hot.top(2), vec![(1, 5), (3, 2)]);
+        assert_eq!(hot.top(1), vec![(1, 5)]);
+    }
+
+    #[test]
+    fn test_window() {
+        let mut hot = HotKeys::new(4, 10);
+        for _ in 0..9 {
+            hot.record(&1);
+        }
+        hot.record(&2);
+        assert_eq!(hot.top(4), vec![(1, 4)]);
+
+        // the least recently counted of the rarest keys is replaced
+        for k in 2..6 {
+            hot.record(&k);
+        }
+        assert_eq!(hot.top(2), vec![(1, 4), (5, 2)]);
+        assert!(hot.top(4).iter().all(|&(k, _)| k != 2));
+    }
+}
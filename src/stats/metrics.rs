@@ -0,0 +1,117 @@
+//! Reports cache statistics through the `metrics` facade.
+
+use ::metrics::{counter, gauge};
+
+use super::Counters;
+
+/// Increments the counters of the cache called `name` by `delta` and sets
+/// its size gauges.
+pub(crate) fn flush(name: &str, delta: &Counters, len: usize, weighted_size: usize) {
+    let counters = [
+        ("cache_hits_total", delta.hit_count),
+        ("cache_misses_total", delta.miss_count),
+        ("cache_evictions_total", delta.eviction_count),
+        ("cache_expirations_total", delta.expiration_count),
+        ("cache_inserts_total", delta.insert_count),
+        ("cache_replaces_total", delta.replace_count),
+    ];
+    for &(metric, value) in counters.iter() {
+        counter!(metric, "cache" => name.to_owned()).increment(value as u64);
+    }
+    gauge!("cache_entries", "cache" => name.to_owned()).set(len as f64);
+    gauge!("cache_weighted_size", "cache" => name.to_owned()).set(weighted_size as f64);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    use ::metrics::{
+        Counter, CounterFn, Gauge, GaugeFn, Histogram, Key, KeyName, Metadata, Recorder,
+        SharedString, Unit,
+    };
+
+    use crate::lru::Cache;
+
+    #[derive(Default)]
+    struct Cell(AtomicU64);
+
+    impl CounterFn for Cell {
+        fn increment(&self, value: u64) {
+            self.0.fetch_add(value, Ordering::SeqCst);
+        }
+
+        fn absolute(&self, value: u64) {
+            self.0.store(value, Ordering::SeqCst);
+        }
+    }
+
+    impl GaugeFn for Cell {
+        fn increment(&self, _: f64) {}
+
+        fn decrement(&self, _: f64) {}
+
+        fn set(&self, value: f64) {
+            self.0.store(value as u64, Ordering::SeqCst);
+        }
+    }
+
+    /// Keeps every metric in a map keyed by name and `cache` label.
+    #[derive(Default)]
+    struct TestRecorder(Mutex<HashMap<String, Arc<Cell>>>);
+
+    impl TestRecorder {
+        fn cell(&self, key: &Key) -> Arc<Cell> {
+            let label = key.labels().find(|l| l.key() == "cache").unwrap();
+            let name = format!("{}{{{}}}", key.name(), label.value());
+            self.0.lock().unwrap().entry(name).or_default().clone()
+        }
+
+        fn get(&self, name: &str) -> u64 {
+            self.0.lock().unwrap()[name].0.load(Ordering::SeqCst)
+        }
+    }
+
+    impl Recorder for TestRecorder {
+        fn describe_counter(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+        fn describe_gauge(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+        fn describe_histogram(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+        fn register_counter(&self, key: &Key, _: &Metadata<'_>) -> Counter {
+            Counter::from_arc(self.cell(key))
+        }
+
+        fn register_gauge(&self, key: &Key, _: &Metadata<'_>) -> Gauge {
+            Gauge::from_arc(self.cell(key))
+        }
+
+        fn register_histogram(&self, _: &Key, _: &Metadata<'_>) -> Histogram {
+            Histogram::noop()
+        }
+    }
+
+    #[test]
+    fn test_flush_metrics() {
+        let recorder = TestRecorder::default();
+        let mut cache = Cache::new(2);
+        ::metrics::with_local_recorder(&recorder, || {
+            cache.add(1, 1);
+            cache.get(&1);
+            cache.get(&2);
+            cache.flush_metrics("users");
+            cache.add(2, 2);
+            cache.add(3, 3);
+            cache.get(&1);
+            cache.flush_metrics("users");
+        });
+        assert_eq!(recorder.get("cache_hits_total{users}"), 1);
+        assert_eq!(recorder.get("cache_misses_total{users}"), 2);
+        assert_eq!(recorder.get("cache_inserts_total{users}"), 3);
+        assert_eq!(recorder.get("cache_evictions_total{users}"), 1);
+        assert_eq!(recorder.get("cache_entries{users}"), 2);
+    }
+}
@@ -0,0 +1,230 @@
+//! Renders cache statistics in the Prometheus text exposition format.
+//!
+//! ```
+//! use cache::lru::Cache;
+//! use cache::stats::prometheus::Exporter;
+//!
+//! let mut cache: Cache<u32, u32> = Cache::new(16);
+//! cache.add(1, 1);
+//!
+//! let exporter = Exporter::new("app_cache").label("name", "users");
+//! let text = exporter.render(&cache.stat());
+//! assert!(text.contains("app_cache_inserts_total{name=\"users\"} 1\n"));
+//! ```
+//!
+//! Several caches share one response body through `render_many`, which
+//! writes the `# HELP` and `# TYPE` lines of each metric once, followed by
+//! a sample per cache.
+
+use std::fmt::Write;
+
+use super::Info;
+
+/// The kind of a metric, which decides its `# TYPE` line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Counter,
+    Gauge,
+}
+
+/// A single sample: its name without the prefix, help text, kind and value.
+pub type Sample = (&'static str, &'static str, Kind, f64);
+
+/// Statistics that can be rendered by an `Exporter`.
+pub trait Metrics {
+    fn samples(&self) -> Vec<Sample>;
+}
+
+/// Formats `Metrics` with a common name prefix and set of labels.
+#[derive(Debug, Clone)]
+pub struct Exporter {
+    prefix: String,
+    labels: Vec<(String, String)>,
+}
+
+impl Exporter {
+    /// Creates an exporter whose metric names start with `prefix` followed
+    /// by an underscore.
+    pub fn new<P: Into<String>>(prefix: P) -> Exporter {
+        Exporter {
+            prefix: prefix.into(),
+            labels: Vec::new(),
+        }
+    }
+
+    /// Adds a label attached to every sample.
+    pub fn label<N, V>(mut self, name: N, value: V) -> Exporter
+    where
+        N: Into<String>,
+        V: Into<String>,
+    {
+        self.labels.push((name.into(), value.into()));
+        self
+    }
+
+    pub fn render<M: Metrics + ?Sized>(&self, metrics: &M) -> String {
+        let mut out = String::new();
+        self.render_into(metrics, &mut out);
+        out
+    }
+
+    /// Appends the rendered metrics to `out`. A body may describe each
+    /// metric only once, so several caches go through `render_many`.
+    pub fn render_into<M: Metrics + ?Sized>(&self, metrics: &M, out: &mut String) {
+        let labels = self.format_labels(None);
+        for (name, help, kind, value) in metrics.samples() {
+            self.write_header(out, name, help, kind);
+            let _ = writeln!(out, "{}_{}{} {}", self.prefix, name, labels, value);
+        }
+    }
+
+    /// Renders the metrics of several caches, telling them apart by the
+    /// label `label`, whose value is given with each cache. Every metric is
+    /// described once, followed by the sample of each cache.
+    pub fn render_many<M: Metrics + ?Sized>(&self, label: &str, caches: &[(&str, &M)]) -> String {
+        let caches: Vec<(String, Vec<Sample>)> = caches
+            .iter()
+            .map(|(value, metrics)| (self.format_labels(Some((label, value))), metrics.samples()))
+            .collect();
+        let mut out = String::new();
+        let families = match caches.first() {
+            Some((_, samples)) => samples.clone(),
+            None => return out,
+        };
+        for (name, help, kind, _) in families {
+            self.write_header(&mut out, name, help, kind);
+            for (labels, samples) in &caches {
+                if let Some((_, _, _, value)) = samples.iter().find(|sample| sample.0 == name) {
+                    let _ = writeln!(out, "{}_{}{} {}", self.prefix, name, labels, value);
+                }
+            }
+        }
+        out
+    }
+
+    fn write_header(&self, out: &mut String, name: &str, help: &str, kind: Kind) {
+        let kind = match kind {
+            Kind::Counter => "counter",
+            Kind::Gauge => "gauge",
+        };
+        let _ = writeln!(out, "# HELP {}_{} {}", self.prefix, name, help);
+        let _ = writeln!(out, "# TYPE {}_{} {}", self.prefix, name, kind);
+    }
+
+    /// Formats the labels of the exporter, followed by `extra` if given.
+    fn format_labels(&self, extra: Option<(&str, &str)>) -> String {
+        let labels: Vec<String> = self
+            .labels
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+            .chain(extra)
+            .map(|(name, value)| format!("{}=\"{}\"", name, escape(value)))
+            .collect();
+        if labels.is_empty() {
+            return String::new();
+        }
+        format!("{{{}}}", labels.join(","))
+    }
+}
+
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn counter(name: &'static str, help: &'static str, value: usize) -> Sample {
+    (name, help, Kind::Counter, value as f64)
+}
+
+fn gauge(name: &'static str, help: &'static str, value: f64) -> Sample {
+    (name, help, Kind::Gauge, value)
+}
+
+impl Metrics for Info {
+    fn samples(&self) -> Vec<Sample> {
+        vec![
+            counter("hits_total", "Lookups that found an entry.", self.hit_count),
+            counter(
+                "misses_total",
+                "Lookups that found nothing.",
+                self.miss_count,
+            ),
+            counter(
+                "evictions_total",
+                "Entries evicted for room.",
+                self.eviction_count,
+            ),
+            counter(
+                "expirations_total",
+                "Entries that expired.",
+                self.expiration_count,
+            ),
+            counter("inserts_total", "New keys stored.", self.insert_count),
+            counter("replaces_total", "Values replaced.", self.replace_count),
+            gauge("entries", "Entries currently cached.", self.len as f64),
+            gauge("hit_ratio", "Hits over all lookups.", self.hit_ratio()),
+            gauge(
+                "memory_bytes",
+                "Estimated bytes held by the cache.",
+                self.memory.total() as f64,
+            ),
+            counter(
+                "main_hits_total",
+                "Hits served from main.",
+                self.main_hit_count,
+            ),
+            counter("in_hits_total", "Hits served from in.", self.in_hit_count),
+            counter(
+                "ghost_hits_total",
+                "Admissions by ghost hit.",
+                self.ghost_hit_count,
+            ),
+            counter(
+                "ghost_drops_total",
+                "Ghosts dropped without a hit.",
+                self.ghost_drop_count,
+            ),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lru;
+
+    #[test]
+    fn test_render() {
+        let mut cache: lru::Cache<usize, usize> = lru::Cache::new(1);
+        cache.add(1, 1);
+        cache.add(2, 2);
+        cache.get(&2);
+
+        let exporter = Exporter::new("cache").label("name", "a \"b\"");
+        let text = exporter.render(&cache.stat());
+        assert!(text.contains("# TYPE cache_evictions_total counter\n"));
+        assert!(text.contains("cache_evictions_total{name=\"a \\\"b\\\"\"} 1\n"));
+        assert!(text.contains("cache_hit_ratio{name=\"a \\\"b\\\"\"} 1\n"));
+
+        let text = Exporter::new("cache").render(&cache.stat());
+        assert!(text.contains("cache_entries 1\n"));
+
+        let other: lru::Cache<usize, usize> = lru::Cache::new(1);
+        let exporter = Exporter::new("cache").label("host", "h");
+        let text = exporter.render_many("name", &[("a", &cache.stat()), ("b", &other.stat())]);
+        assert_eq!(text.matches("# TYPE cache_entries gauge\n").count(), 1);
+        assert!(text.contains(
+            "# TYPE cache_entries gauge\n\
+             cache_entries{host=\"h\",name=\"a\"} 1\n\
+             cache_entries{host=\"h\",name=\"b\"} 0\n"
+        ));
+    }
+}
@@ -0,0 +1,449 @@
+//! Differential testing of the policies against reference models.
+//!
+//! Each model keeps its entries in plain `Vec`s, most recently used first,
+//! and spells out its policy with linear scans, so that it can be checked by
+//! reading it. `check` applies the same random operations to a cache and to
+//! a model and panics at the first operation after which they disagree on a
+//! returned value, the length, or the value of any key.
+//!
+//! The models are also available to other crates behind the `testing`
+//! feature, to check wrappers and custom policies:
+//!
+//! ```
+//! use cache::any::Policy;
+//! use cache::testing;
+//!
+//! testing::check_policy(Policy::q2(), 8, 500, 1);
+//! ```
+
+use super::any::{AnyCache, Policy};
+use super::layer::Cache;
+use super::workload::Rng;
+
+fn position<K: Eq, V>(entries: &[(K, V)], k: &K) -> Option<usize> {
+    entries.iter().position(|(key, _)| key == k)
+}
+
+fn find<'a, K: Eq, V>(entries: &'a [(K, V)], k: &K) -> Option<&'a V> {
+    entries.iter().find(|(key, _)| key == k).map(|(_, v)| v)
+}
+
+/// Least recently used: hits and replacements move an entry to the front,
+/// and the back is evicted.
+pub struct Lru<K, V> {
+    max_size: usize,
+    entries: Vec<(K, V)>,
+}
+
+impl<K, V> Lru<K, V> {
+    pub fn new(max_size: usize) -> Lru<K, V> {
+        Lru {
+            max_size: max_size.max(1),
+            entries: Vec::new(),
+        }
+    }
+}
+
+impl<K: Eq, V> Cache<K, V> for Lru<K, V> {
+    fn get(&mut self, k: &K) -> Option<&V> {
+        let entry = self.entries.remove(position(&self.entries, k)?);
+        self.entries.insert(0, entry);
+        Some(&self.entries[0].1)
+    }
+
+    fn peek(&self, k: &K) -> Option<&V> {
+        find(&self.entries, k)
+    }
+
+    fn contains_key(&self, k: &K) -> bool {
+        position(&self.entries, k).is_some()
+    }
+
+    fn add(&mut self, k: K, v: V) -> Option<V> {
+        let old_v = position(&self.entries, &k).map(|i| self.entries.remove(i).1);
+        self.entries.insert(0, (k, v));
+        self.entries.truncate(self.max_size);
+        old_v
+    }
+
+    fn remove(&mut self, k: &K) -> Option<V> {
+        Some(self.entries.remove(position(&self.entries, k)?).1)
+    }
+
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    fn purge(&mut self) {
+        self.entries.clear();
+    }
+}
+
+/// First in, first out: entries keep their place until evicted, even when
+/// they are hit or replaced.
+pub struct Fifo<K, V> {
+    max_size: usize,
+    entries: Vec<(K, V)>,
+}
+
+impl<K, V> Fifo<K, V> {
+    pub fn new(max_size: usize) -> Fifo<K, V> {
+        Fifo {
+            max_size: max_size.max(1),
+            entries: Vec::new(),
+        }
+    }
+}
+
+impl<K: Eq, V> Cache<K, V> for Fifo<K, V> {
+    fn get(&mut self, k: &K) -> Option<&V> {
+        find(&self.entries, k)
+    }
+
+    fn peek(&self, k: &K) -> Option<&V> {
+        find(&self.entries, k)
+    }
+
+    fn contains_key(&self, k: &K) -> bool {
+        position(&self.entries, k).is_some()
+    }
+
+    fn add(&mut self, k: K, v: V) -> Option<V> {
+        if let Some(i) = position(&self.entries, &k) {
+            return Some(std::mem::replace(&mut self.entries[i].1, v));
+        }
+        self.entries.insert(0, (k, v));
+        self.entries.truncate(self.max_size);
+        None
+    }
+
+    fn remove(&mut self, k: &K) -> Option<V> {
+        Some(self.entries.remove(position(&self.entries, k)?).1)
+    }
+
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    fn purge(&mut self) {
+        self.entries.clear();
+    }
+}
+
+/// Segmented LRU: new entries go to the probationary `in_` segment, and a
+/// second use moves them to the protected `main` segment. An overfull main
+/// segment demotes its back to the front of `in_`; the cache evicts from
+/// the back of `in_`, and from `main` only when `in_` is empty.
+pub struct Slru<K, V> {
+    max_size: usize,
+    max_main: usize,
+    in_: Vec<(K, V)>,
+    main: Vec<(K, V)>,
+}
+
+impl<K, V> Slru<K, V> {
+    pub fn new(size: usize, main_cache_factor: f64) -> Slru<K, V> {
+        let max_size = size.max(2);
+        Slru {
+            max_size,
+            max_main: (max_size as f64 * main_cache_factor) as usize,
+            in_: Vec::new(),
+            main: Vec::new(),
+        }
+    }
+
+    // makes room for `incoming` more entries, in `main` if `to_main`
+    fn make_room(&mut self, to_main: bool, incoming: usize) {
+        if to_main {
+            while self.main.len() + incoming > self.max_main && !self.main.is_empty() {
+                let demoted = self.main.pop().unwrap();
+                self.in_.insert(0, demoted);
+            }
+        }
+        while self.in_.len() + self.main.len() + incoming > self.max_size {
+            if self.in_.pop().is_none() && self.main.pop().is_none() {
+                break;
+            }
+        }
+    }
+}
+
+impl<K: Eq, V> Cache<K, V> for Slru<K, V> {
+    fn get(&mut self, k: &K) -> Option<&V> {
+        if let Some(i) = position(&self.main, k) {
+            let entry = self.main.remove(i);
+            self.main.insert(0, entry);
+            return Some(&self.main[0].1);
+        }
+        let entry = self.in_.remove(position(&self.in_, k)?);
+        self.make_room(true, 1);
+        self.main.insert(0, entry);
+        Some(&self.main[0].1)
+    }
+
+    fn peek(&self, k: &K) -> Option<&V> {
+        find(&self.main, k).or_else(|| find(&self.in_, k))
+    }
+
+    fn contains_key(&self, k: &K) -> bool {
+        self.peek(k).is_some()
+    }
+
+    fn add(&mut self, k: K, v: V) -> Option<V> {
+        if let Some(i) = position(&self.main, &k) {
+            let (_, old_v) = self.main.remove(i);
+            self.main.insert(0, (k, v));
+            self.make_room(true, 0);
+            return Some(old_v);
+        }
+        if let Some(i) = position(&self.in_, &k) {
+            let (_, old_v) = self.in_.remove(i);
+            self.make_room(true, 1);
+            self.main.insert(0, (k, v));
+            return Some(old_v);
+        }
+        self.make_room(false, 1);
+        self.in_.insert(0, (k, v));
+        None
+    }
+
+    fn remove(&mut self, k: &K) -> Option<V> {
+        if let Some(i) = position(&self.main, k) {
+            return Some(self.main.remove(i).1);
+        }
+        Some(self.in_.remove(position(&self.in_, k)?).1)
+    }
+
+    fn len(&self) -> usize {
+        self.in_.len() + self.main.len()
+    }
+
+    fn purge(&mut self) {
+        self.in_.clear();
+        self.main.clear();
+    }
+}
+
+/// 2Q: new entries go to `in_`, whose evicted keys are remembered in the
+/// `out` ghost queue. A hit in `in_`, or the return of a key remembered in
+/// `out`, puts the entry in `main`.
+pub struct Q2<K, V> {
+    max_size: usize,
+    max_in: usize,
+    max_out: usize,
+    in_: Vec<(K, V)>,
+    main: Vec<(K, V)>,
+    out: Vec<K>,
+}
+
+impl<K, V> Q2<K, V> {
+    pub fn new(size: usize, main_cache_factor: f64, out_cache_factor: f64) -> Q2<K, V> {
+        let max_size = size.max(2);
+        Q2 {
+            max_size,
+            max_in: (max_size as f64 * (1.0 - main_cache_factor)) as usize,
+            max_out: (max_size as f64 * out_cache_factor) as usize,
+            in_: Vec::new(),
+            main: Vec::new(),
+            out: Vec::new(),
+        }
+    }
+
+    fn evict_in(&mut self) -> bool {
+        let (k, _) = match self.in_.pop() {
+            Some(entry) => entry,
+            None => return false,
+        };
+        if self.out.len() + 1 > self.max_out {
+            self.out.pop();
+        }
+        self.out.insert(0, k);
+        true
+    }
+
+    // makes room for `incoming` more entries; an overfull `in_` is evicted
+    // from first, and so is a full one unless the entry goes to `main`
+    fn make_room(&mut self, to_main: bool, incoming: usize) {
+        while self.in_.len() + self.main.len() + incoming > self.max_size {
+            let in_full = self.in_.len() > self.max_in
+                || (self.in_.len() + incoming > self.max_in && !to_main);
+            if in_full && self.evict_in() {
+                continue;
+            }
+            if self.main.pop().is_none() && !self.evict_in() {
+                break;
+            }
+        }
+    }
+}
+
+impl<K: Eq, V> Cache<K, V> for Q2<K, V> {
+    fn get(&mut self, k: &K) -> Option<&V> {
+        if let Some(i) = position(&self.main, k) {
+            let entry = self.main.remove(i);
+            self.main.insert(0, entry);
+            return Some(&self.main[0].1);
+        }
+        let entry = self.in_.remove(position(&self.in_, k)?);
+        self.main.insert(0, entry);
+        Some(&self.main[0].1)
+    }
+
+    fn peek(&self, k: &K) -> Option<&V> {
+        find(&self.main, k).or_else(|| find(&self.in_, k))
+    }
+
+    fn contains_key(&self, k: &K) -> bool {
+        self.peek(k).is_some()
+    }
+
+    fn add(&mut self, k: K, v: V) -> Option<V> {
+        if let Some(i) = position(&self.main, &k) {
+            let (_, old_v) = self.main.remove(i);
+            self.main.insert(0, (k, v));
+            self.make_room(true, 0);
+            return Some(old_v);
+        }
+        if let Some(i) = position(&self.in_, &k) {
+            let (_, old_v) = self.in_.remove(i);
+            self.main.insert(0, (k, v));
+            self.make_room(true, 0);
+            return Some(old_v);
+        }
+        if let Some(i) = self.out.iter().position(|key| *key == k) {
+            self.out.remove(i);
+            self.make_room(true, 1);
+            self.main.insert(0, (k, v));
+            return None;
+        }
+        self.make_room(false, 1);
+        self.in_.insert(0, (k, v));
+        None
+    }
+
+    fn remove(&mut self, k: &K) -> Option<V> {
+        self.out.retain(|key| key != k);
+        if let Some(i) = position(&self.main, k) {
+            return Some(self.main.remove(i).1);
+        }
+        Some(self.in_.remove(position(&self.in_, k)?).1)
+    }
+
+    fn len(&self) -> usize {
+        self.in_.len() + self.main.len()
+    }
+
+    fn purge(&mut self) {
+        self.in_.clear();
+        self.main.clear();
+        self.out.clear();
+    }
+}
+
+/// An operation applied by `check`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Get(u64),
+    Peek(u64),
+    Add(u64, u64),
+    Remove(u64),
+    Purge,
+}
+
+/// Applies `ops` random operations on the keys `0..keys` to `cache` and
+/// `model`, and panics as soon as they disagree. The same `seed` replays
+/// the same operations.
+pub fn check<C, M>(mut cache: C, mut model: M, keys: u64, ops: usize, seed: u64)
+where
+    C: Cache<u64, u64>,
+    M: Cache<u64, u64>,
+{
+    let keys = keys.max(1);
+    let mut rng = Rng::new(seed);
+    for i in 0..ops {
+        let k = rng.below(keys);
+        let op = match rng.below(100) {
+            0..=39 => Op::Get(k),
+            40..=79 => Op::Add(k, i as u64),
+            80..=94 => Op::Remove(k),
+            95..=98 => Op::Peek(k),
+            _ => Op::Purge,
+        };
+        let (got, want) = match op {
+            Op::Get(k) => (cache.get(&k).copied(), model.get(&k).copied()),
+            Op::Peek(k) => (cache.peek(&k).copied(), model.peek(&k).copied()),
+            Op::Add(k, v) => (cache.add(k, v), model.add(k, v)),
+            Op::Remove(k) => (cache.remove(&k), model.remove(&k)),
+            Op::Purge => {
+                cache.purge();
+                model.purge();
+                (None, None)
+            }
+        };
+        let at = format!("op {} ({:?}) with seed {}", i, op, seed);
+        assert_eq!(got, want, "{}: different results", at);
+        assert_eq!(cache.len(), model.len(), "{}: different lengths", at);
+        for k in 0..keys {
+            assert_eq!(
+                cache.peek(&k),
+                model.peek(&k),
+                "{}: different values for key {}",
+                at,
+                k
+            );
+        }
+    }
+}
+
+/// Checks an `AnyCache` of `policy` and `size` against its model, on twice
+/// as many keys as it holds.
+pub fn check_policy(policy: Policy, size: usize, ops: usize, seed: u64) {
+    let cache = AnyCache::new(policy, size);
+    let keys = 2 * size as u64 + 3;
+    match policy {
+        Policy::Lru => check(cache, Lru::new(size), keys, ops, seed),
+        Policy::Fifo => check(cache, Fifo::new(size), keys, ops, seed),
+        Policy::Slru { main_cache_factor } => {
+            check(cache, Slru::new(size, main_cache_factor), keys, ops, seed)
+        }
+        Policy::Q2 {
+            main_cache_factor,
+            out_cache_factor,
+        } => check(
+            cache,
+            Q2::new(size, main_cache_factor, out_cache_factor),
+            keys,
+            ops,
+            seed,
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_policies_match_their_models() {
+        let policies = [
+            Policy::Lru,
+            Policy::Fifo,
+            Policy::slru(),
+            Policy::Slru {
+                main_cache_factor: 0.5,
+            },
+            Policy::q2(),
+            Policy::Q2 {
+                main_cache_factor: 0.5,
+                out_cache_factor: 1.0,
+            },
+        ];
+        for &policy in &policies {
+            for &size in &[1, 2, 3, 5, 8, 16] {
+                for seed in 0..4 {
+                    check_policy(policy, size, 1000, seed);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,719 @@
+//! Replaying access traces.
+//!
+//! A trace is read as a sequence of `u64` keys and replayed against any
+//! `layer::Cache`, which is filled on every miss. The supported formats are
+//! those of the traces commonly used to evaluate replacement policies:
+//!
+//! - `Arc`: the ARC traces, whose lines read `start count ignored request`,
+//!   standing for the `count` blocks from `start` on;
+//! - `Lirs`: one block number per line, as in the LIRS traces;
+//! - `Csv`: the first column of each line. Keys that are not integers are
+//!   hashed.
+//!
+//! ```
+//! use cache::lru;
+//! use cache::trace::{self, Format};
+//!
+//! let trace = "1\n2\n1\n3\n1\n";
+//! let keys = trace::read(trace.as_bytes(), Format::Lirs);
+//! let report = trace::replay(&mut lru::Cache::new(2), keys).unwrap();
+//! assert_eq!((report.requests, report.hits), (5, 2));
+//! ```
+//!
+//! Traces can also be captured from a running program: a cache wrapped in
+//! `Recorded` appends each of its lookups, insertions, removals, expirations
+//! and purges, with the hash of the key and the time, to a compact binary
+//! log. `read_log`
+//! reads it back and `replay_log` runs it against another cache, to tune a
+//! policy offline on real access patterns.
+//!
+//! ```
+//! use cache::layer::Cache;
+//! use cache::trace::{self, Recorded, Recorder};
+//! use cache::{lru, q2};
+//!
+//! let mut cache = Recorded::new(lru::Cache::new(2), Recorder::new(Vec::new()));
+//! for k in &["a", "b", "a", "c", "a"] {
+//!     if cache.get(k).is_none() {
+//!         cache.add(k, ());
+//!     }
+//! }
+//! let (_, recorder) = cache.into_inner();
+//! let log = recorder.finish().unwrap();
+//!
+//! let report = trace::replay_log(&mut q2::Cache::new(2), trace::read_log(&log[..])).unwrap();
+//! assert_eq!((report.requests, report.hits), (5, 2));
+//! ```
+
+use std::collections::hash_map::DefaultHasher;
+use std::error::Error;
+use std::fmt;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::io::{self, BufRead, Read, Write};
+use std::str::FromStr;
+use std::time::Duration;
+
+use super::bench_harness::Op;
+use super::clock::{Clock, DefaultClock};
+use super::hash::FixedState;
+use super::layer::Cache;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Arc,
+    Lirs,
+    Csv,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseFormatError(String);
+
+impl fmt::Display for ParseFormatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "unknown trace format {:?}, expected arc, lirs or csv",
+            self.0
+        )
+    }
+}
+
+impl Error for ParseFormatError {}
+
+impl FromStr for Format {
+    type Err = ParseFormatError;
+
+    fn from_str(s: &str) -> Result<Format, ParseFormatError> {
+        match s {
+            "arc" => Ok(Format::Arc),
+            "lirs" => Ok(Format::Lirs),
+            "csv" => Ok(Format::Csv),
+            _ => Err(ParseFormatError(s.to_owned())),
+        }
+    }
+}
+
+/// Reads the keys of a trace lazily. Blank lines and lines starting with
+/// `#` are skipped; a malformed line yields an `InvalidData` error.
+pub fn read<R: BufRead>(reader: R, format: Format) -> Keys<R> {
+    Keys {
+        lines: reader.lines(),
+        format,
+        line: 0,
+        pending: 0..0,
+    }
+}
+
+pub struct Keys<R> {
+    lines: io::Lines<R>,
+    format: Format,
+    line: usize,
+    // the blocks of an ARC line not yet yielded
+    pending: std::ops::Range<u64>,
+}
+
+impl<R: BufRead> Keys<R> {
+    fn invalid(&self, what: &str) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("line {}: {}", self.line, what),
+        )
+    }
+
+    fn parse(&mut self, line: &str) -> io::Result<u64> {
+        match self.format {
+            Format::Lirs => line
+                .parse()
+                .map_err(|_| self.invalid("expected a block number")),
+            Format::Csv => {
+                let field = line.split(',').next().unwrap_or("").trim();
+                Ok(field.parse().unwrap_or_else(|_| {
+                    let mut hasher = DefaultHasher::new();
+                    field.hash(&mut hasher);
+                    hasher.finish()
+                }))
+            }
+            Format::Arc => {
+                let mut fields = line.split_whitespace().map(str::parse::<u64>);
+                match (fields.next(), fields.next()) {
+                    (Some(Ok(start)), Some(Ok(count))) if count > 0 => {
+                        self.pending = start + 1..start + count;
+                        Ok(start)
+                    }
+                    _ => Err(self.invalid("expected a start block and a block count")),
+                }
+            }
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for Keys<R> {
+    type Item = io::Result<u64>;
+
+    fn next(&mut self) -> Option<io::Result<u64>> {
+        if let Some(key) = self.pending.next() {
+            return Some(Ok(key));
+        }
+        loop {
+            let line = match self.lines.next()? {
+                Ok(line) => line,
+                Err(err) => return Some(Err(err)),
+            };
+            self.line += 1;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            return Some(self.parse(line));
+        }
+    }
+}
+
+/// The outcome of a replay.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Report {
+    pub requests: u64,
+    pub hits: u64,
+    /// The entries inserted on a miss that are no longer cached at the
+    /// end, whether evicted or refused admission.
+    pub evictions: u64,
+}
+
+impl Report {
+    pub fn misses(&self) -> u64 {
+        self.requests - self.hits
+    }
+
+    pub fn hit_ratio(&self) -> f64 {
+        if self.requests == 0 {
+            return 0.0;
+        }
+        self.hits as f64 / self.requests as f64
+    }
+
+    #[cfg(feature = "json")]
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "requests": self.requests,
+            "hits": self.hits,
+            "misses": self.misses(),
+            "hit_ratio": self.hit_ratio(),
+            "evictions": self.evictions,
+        })
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "requests: {}, hits: {}, hit ratio: {:.4}, evictions: {}",
+            self.requests,
+            self.hits,
+            self.hit_ratio(),
+            self.evictions
+        )
+    }
+}
+
+/// Looks up every key of `keys` in `cache`, adding the missing ones, and
+/// stops at the first error. The cache is expected to start empty.
+pub fn replay<C, I>(cache: &mut C, keys: I) -> io::Result<Report>
+where
+    C: Cache<u64, ()>,
+    I: IntoIterator<Item = io::Result<u64>>,
+{
+    let mut report = Report::default();
+    for key in keys {
+        let key = key?;
+        report.requests += 1;
+        if cache.get(&key).is_some() {
+            report.hits += 1;
+        } else {
+            cache.add(key, ());
+        }
+    }
+    report.evictions = report.misses().saturating_sub(cache.len() as u64);
+    Ok(report)
+}
+
+/// The first bytes of a recorded log, the last one being the version of
+/// the format.
+const LOG_HEADER: [u8; 5] = *b"CREC\x01";
+
+/// One operation of a recorded log, keyed by the hash of its key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Record {
+    pub op: Op,
+    /// The time of the operation on the clock of the recorder, to the
+    /// microsecond.
+    pub at: Duration,
+}
+
+/// Appends operations to a log, each as a tag byte, the microseconds since
+/// the previous operation as a LEB128 varint and the key hash in eight
+/// bytes, 0 for a purge.
+///
+/// Keys are hashed with a `FixedState` unless given another hash builder,
+/// so that logs recorded by different runs of a build agree. A write error
+/// stops the recording and is returned by `finish`.
+pub struct Recorder<W: Write, S = FixedState> {
+    writer: W,
+    hash_builder: S,
+    clock: Box<dyn Clock>,
+    // the time of the last record, in microseconds
+    last: u64,
+    records: u64,
+    error: Option<io::Error>,
+}
+
+impl<W: Write> Recorder<W, FixedState> {
+    pub fn new(writer: W) -> Recorder<W, FixedState> {
+        Recorder::with_hasher(writer, FixedState::default())
+    }
+}
+
+impl<W: Write, S: BuildHasher> Recorder<W, S> {
+    pub fn with_hasher(writer: W, hash_builder: S) -> Recorder<W, S> {
+        let mut recorder = Recorder {
+            writer,
+            hash_builder,
+            clock: Box::new(DefaultClock::default()),
+            last: 0,
+            records: 0,
+            error: None,
+        };
+        if let Err(err) = recorder.writer.write_all(&LOG_HEADER) {
+            recorder.error = Some(err);
+        }
+        recorder
+    }
+
+    /// Takes the timestamps from `clock` instead of the time since the
+    /// recorder was created.
+    pub fn set_clock<C>(&mut self, clock: C)
+    where
+        C: Clock + 'static,
+    {
+        self.clock = Box::new(clock);
+        self.last = 0;
+    }
+
+    /// Returns the hash the operations on `k` are recorded under.
+    pub fn hash<K: Hash + ?Sized>(&self, k: &K) -> u64 {
+        self.hash_builder.hash_one(k)
+    }
+
+    /// Records `op`, whose key is a hash as returned by `hash`.
+    pub fn record(&mut self, op: Op) {
+        if self.error.is_some() {
+            return;
+        }
+        if let Err(err) = self.write(op) {
+            self.error = Some(err);
+        }
+    }
+
+    fn write(&mut self, op: Op) -> io::Result<()> {
+        let (tag, hash) = match op {
+            Op::Get(hash) => (0, hash),
+            Op::Add(hash) => (1, hash),
+            Op::Remove(hash) => (2, hash),
+            Op::Access(hash) => (3, hash),
+            Op::Purge => (4, 0),
+        };
+        let now = self.clock.now().as_micros() as u64;
+        // a clock that went backwards records no time between the two
+        let mut delta = now.saturating_sub(self.last);
+        self.last = self.last.max(now);
+
+        let mut buf = [0; 1 + 10 + 8];
+        buf[0] = tag;
+        let mut len = 1;
+        loop {
+            buf[len] = (delta & 0x7f) as u8;
+            len += 1;
+            delta >>= 7;
+            if delta == 0 {
+                break;
+            }
+            buf[len - 1] |= 0x80;
+        }
+        buf[len..len + 8].copy_from_slice(&hash.to_le_bytes());
+        self.writer.write_all(&buf[..len + 8])?;
+        self.records += 1;
+        Ok(())
+    }
+
+    /// Returns the number of operations recorded.
+    pub fn records(&self) -> u64 {
+        self.records
+    }
+
+    /// Flushes the log and returns its writer, or the first error met.
+    pub fn finish(mut self) -> io::Result<W> {
+        if let Some(err) = self.error.take() {
+            return Err(err);
+        }
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+/// Records every lookup, insertion and removal of the wrapped cache. The
+/// entries it expires are recorded as removals, and a purge as such.
+/// `peek` and `contains_key` do not count as accesses and are left out.
+pub struct Recorded<C, W: Write, S = FixedState> {
+    inner: C,
+    recorder: Recorder<W, S>,
+}
+
+impl<C, W: Write, S: BuildHasher> Recorded<C, W, S> {
+    pub fn new(inner: C, recorder: Recorder<W, S>) -> Recorded<C, W, S> {
+        Recorded { inner, recorder }
+    }
+
+    pub fn inner(&self) -> &C {
+        &self.inner
+    }
+
+    pub fn recorder(&self) -> &Recorder<W, S> {
+        &self.recorder
+    }
+
+    pub fn into_inner(self) -> (C, Recorder<W, S>) {
+        (self.inner, self.recorder)
+    }
+}
+
+impl<K: Hash, V, C: Cache<K, V>, W: Write, S: BuildHasher> Cache<K, V> for Recorded<C, W, S> {
+    fn get(&mut self, k: &K) -> Option<&V> {
+        let hash = self.recorder.hash(k);
+        self.recorder.record(Op::Get(hash));
+        self.inner.get(k)
+    }
+
+    fn peek(&self, k: &K) -> Option<&V> {
+        self.inner.peek(k)
+    }
+
+    fn contains_key(&self, k: &K) -> bool {
+        self.inner.contains_key(k)
+    }
+
+    fn add(&mut self, k: K, v: V) -> Option<V> {
+        let hash = self.recorder.hash(&k);
+        self.recorder.record(Op::Add(hash));
+        self.inner.add(k, v)
+    }
+
+    fn remove(&mut self, k: &K) -> Option<V> {
+        let hash = self.recorder.hash(k);
+        self.recorder.record(Op::Remove(hash));
+        self.inner.remove(k)
+    }
+
+    fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    fn purge(&mut self) {
+        self.recorder.record(Op::Purge);
+        self.inner.purge()
+    }
+
+    fn expire(&mut self, k: &K) -> bool {
+        let hash = self.recorder.hash(k);
+        self.recorder.record(Op::Remove(hash));
+        self.inner.expire(k)
+    }
+
+    fn expire_where(&mut self, expired: &mut dyn FnMut(&K, &V) -> bool) -> usize {
+        let recorder = &self.recorder;
+        let mut hashes = Vec::new();
+        let count = self.inner.expire_where(&mut |k, v| {
+            let expire = expired(k, v);
+            if expire {
+                hashes.push(recorder.hash(k));
+            }
+            expire
+        });
+        for hash in hashes {
+            self.recorder.record(Op::Remove(hash));
+        }
+        count
+    }
+
+    fn run_pending_tasks(&mut self) {
+        self.inner.run_pending_tasks()
+    }
+}
+
+/// Reads the operations of a log written by a `Recorder` lazily. A log cut
+/// short within an operation, or with another header, yields an
+/// `InvalidData` error, after which the iterator ends.
+pub fn read_log<R: Read>(reader: R) -> Records<R> {
+    Records {
+        reader,
+        started: false,
+        done: false,
+        at: 0,
+    }
+}
+
+pub struct Records<R> {
+    reader: R,
+    // whether the header was read
+    started: bool,
+    // set at the end of the log or after an error
+    done: bool,
+    at: u64,
+}
+
+fn invalid_log(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, what.to_owned())
+}
+
+impl<R: Read> Records<R> {
+    fn byte(&mut self) -> io::Result<Option<u8>> {
+        let mut byte = [0];
+        loop {
+            match self.reader.read(&mut byte) {
+                Ok(0) => return Ok(None),
+                Ok(_) => return Ok(Some(byte[0])),
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    fn read_record(&mut self) -> io::Result<Option<Record>> {
+        if !self.started {
+            let mut header = [0; LOG_HEADER.len()];
+            self.reader
+                .read_exact(&mut header)
+                .map_err(|_| invalid_log("not a recorded log"))?;
+            if header != LOG_HEADER {
+                return Err(invalid_log("not a recorded log"));
+            }
+            self.started = true;
+        }
+        let tag = match self.byte()? {
+            Some(tag) => tag,
+            None => return Ok(None),
+        };
+        let truncated = || invalid_log("truncated operation");
+        let mut delta = 0u64;
+        let mut shift = 0;
+        loop {
+            let byte = self.byte()?.ok_or_else(truncated)?;
+            // a u64 takes at most 10 bytes, the last holding a single bit
+            if shift == 63 && byte > 1 {
+                return Err(invalid_log("overlong delta"));
+            }
+            delta |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                break;
+            }
+            shift += 7;
+        }
+        let mut hash = [0; 8];
+        self.reader.read_exact(&mut hash).map_err(|_| truncated())?;
+        let hash = u64::from_le_bytes(hash);
+        let op = match tag {
+            0 => Op::Get(hash),
+            1 => Op::Add(hash),
+            2 => Op::Remove(hash),
+            3 => Op::Access(hash),
+            4 => Op::Purge,
+            _ => return Err(invalid_log("unknown operation")),
+        };
+        self.at = self.at.saturating_add(delta);
+        Ok(Some(Record {
+            op,
+            at: Duration::from_micros(self.at),
+        }))
+    }
+}
+
+impl<R: Read> Iterator for Records<R> {
+    type Item = io::Result<Record>;
+
+    fn next(&mut self) -> Option<io::Result<Record>> {
+        if self.done {
+            return None;
+        }
+        let record = self.read_record().transpose();
+        self.done = !matches!(record, Some(Ok(_)));
+        record
+    }
+}
+
+/// Runs the operations of a recorded log against `cache` as they were
+/// recorded, and stops at the first error. Lookups count as requests; an
+/// `Access` also adds the key on a miss. The cache is expected to start
+/// empty.
+pub fn replay_log<C, I>(cache: &mut C, records: I) -> io::Result<Report>
+where
+    C: Cache<u64, ()>,
+    I: IntoIterator<Item = io::Result<Record>>,
+{
+    let mut report = Report::default();
+    // the keys inserted and removed, for the number evicted
+    let (mut inserted, mut removed) = (0u64, 0u64);
+    for record in records {
+        let (hit, add) = match record?.op {
+            Op::Get(k) => (Some(cache.get(&k).is_some()), None),
+            Op::Add(k) => (None, Some(k)),
+            Op::Remove(k) => {
+                removed += cache.remove(&k).is_some() as u64;
+                (None, None)
+            }
+            Op::Access(k) => {
+                let hit = cache.get(&k).is_some();
+                (Some(hit), if hit { None } else { Some(k) })
+            }
+            Op::Purge => {
+                removed += cache.len() as u64;
+                cache.purge();
+                (None, None)
+            }
+        };
+        if let Some(hit) = hit {
+            report.requests += 1;
+            report.hits += hit as u64;
+        }
+        if let Some(k) = add {
+            inserted += cache.add(k, ()).is_none() as u64;
+        }
+    }
+    report.evictions = inserted.saturating_sub(removed + cache.len() as u64);
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::{lru, q2};
+
+    fn keys(trace: &str, format: Format) -> Vec<u64> {
+        read(trace.as_bytes(), format)
+            .collect::<io::Result<_>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_formats() {
+        assert_eq!(keys("# comment\n5\n\n7\n", Format::Lirs), vec![5, 7]);
+        assert_eq!(
+            keys("10 3 0 1\n4 1 0 2\n", Format::Arc),
+            vec![10, 11, 12, 4]
+        );
+        let csv = keys("42,get\nuser:1,get\nuser:1,set\n", Format::Csv);
+        assert_eq!(csv[0], 42);
+        assert_eq!(csv[1], csv[2]);
+
+        let err = read("1\nx\n".as_bytes(), Format::Lirs)
+            .nth(1)
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(err.to_string(), "line 2: expected a block number");
+    }
+
+    #[test]
+    fn test_replay() {
+        let trace = "1 2 0 0\n1 1 0 1\n3 1 0 2\n2 1 0 3\n";
+        let mut cache = q2::Cache::new(4);
+        let report = replay(&mut cache, read(trace.as_bytes(), Format::Arc)).unwrap();
+        assert_eq!((report.requests, report.hits, report.misses()), (5, 2, 3));
+        assert_eq!(report.evictions, 0);
+        assert_eq!(Format::from_str("arc"), Ok(Format::Arc));
+        assert!(Format::from_str("umass").is_err());
+    }
+
+    #[test]
+    fn test_record_and_replay() {
+        let clock = ManualClock::new();
+        let mut recorder = Recorder::new(Vec::new());
+        recorder.set_clock(clock.clone());
+        let mut cache = Recorded::new(lru::Cache::new(2), recorder);
+        cache.add("a", ());
+        clock.advance(Duration::from_secs(1));
+        cache.get(&"a");
+        cache.peek(&"a");
+        clock.advance(Duration::from_micros(5));
+        cache.add("b", ());
+        cache.add("c", ());
+        cache.remove(&"b");
+        cache.get(&"a");
+        assert_eq!(cache.recorder().records(), 6);
+
+        let (_, recorder) = cache.into_inner();
+        let a = recorder.hash("a");
+        let log = recorder.finish().unwrap();
+        // a varint of one byte for the gaps under 128 microseconds
+        assert_eq!(log.len(), LOG_HEADER.len() + 6 * 10 + 2);
+        let records: Vec<_> = read_log(&log[..]).collect::<io::Result<_>>().unwrap();
+        assert_eq!(records[0].op, Op::Add(a));
+        assert_eq!(records[1].op, Op::Get(a));
+        assert_eq!(records[1].at, Duration::from_secs(1));
+        assert_eq!(records[5].at, Duration::from_micros(1_000_005));
+
+        let report = replay_log(&mut lru::Cache::new(2), records.into_iter().map(Ok)).unwrap();
+        assert_eq!((report.requests, report.hits, report.evictions), (2, 1, 1));
+        let report = replay_log(&mut lru::Cache::new(1), read_log(&log[..])).unwrap();
+        assert_eq!((report.requests, report.hits, report.evictions), (2, 1, 2));
+
+        let err = read_log(&log[..log.len() - 1]).last().unwrap().unwrap_err();
+        assert_eq!(err.to_string(), "truncated operation");
+        let mut records = read_log("1\n2\n".as_bytes());
+        let err = records.next().unwrap().unwrap_err();
+        assert_eq!(err.to_string(), "not a recorded log");
+        assert!(records.next().is_none());
+
+        let mut overlong = LOG_HEADER.to_vec();
+        overlong.push(0);
+        overlong.extend(&[0xff; 10]);
+        overlong.extend(&[0; 9]);
+        let mut records = read_log(&overlong[..]);
+        let err = records.next().unwrap().unwrap_err();
+        assert_eq!(err.to_string(), "overlong delta");
+        assert!(records.next().is_none());
+    }
+
+    #[test]
+    fn test_record_expirations() {
+        let mut cache = Recorded::new(lru::Cache::new(4), Recorder::new(Vec::new()));
+        for k in 0..4 {
+            cache.add(k, ());
+        }
+        assert!(cache.expire(&0));
+        assert_eq!(cache.expire_where(&mut |&k, _| k == 1), 1);
+        cache.get(&2);
+        cache.purge();
+        cache.get(&3);
+
+        let (_, recorder) = cache.into_inner();
+        let hash = |k: i32| recorder.hash(&k);
+        let (h0, h1, h2, h3) = (hash(0), hash(1), hash(2), hash(3));
+        let log = recorder.finish().unwrap();
+        let ops: Vec<_> = read_log(&log[..])
+            .map(|record| record.unwrap().op)
+            .collect();
+        assert_eq!(
+            ops[4..],
+            [
+                Op::Remove(h0),
+                Op::Remove(h1),
+                Op::Get(h2),
+                Op::Purge,
+                Op::Get(h3)
+            ]
+        );
+
+        // the purged keys miss on replay as they did
+        let report = replay_log(&mut lru::Cache::new(4), read_log(&log[..])).unwrap();
+        assert_eq!((report.requests, report.hits, report.evictions), (2, 1, 0));
+    }
+}
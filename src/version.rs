@@ -0,0 +1,96 @@
+//! The versions `add_if_version` and `add_with_version` compare writes
+//! against, shared by the caches that offer them.
+
+use std::borrow::Borrow;
+use std::hash::Hash;
+
+use super::hash::DefaultState;
+use super::map::LinkedHashMap;
+
+/// The versions of an entry: that of its last write, and the last one a
+/// caller gave it.
+struct Version {
+    write: u64,
+    given: Option<u64>,
+}
+
+/// The versions of the cached entries by key. Write versions are handed out
+/// by one counter, so a key stored again after it left the cache gets a
+/// newer version than any it had before. The versions callers give are
+/// kept apart and never move the counter.
+///
+/// The keys are looked up by the hashes the cache computed for its own
+/// map, so versioning costs no second hash; the hasher of `current` is
+/// never used.
+pub(crate) struct Versions<K> {
+    next: u64,
+    current: LinkedHashMap<K, Version, DefaultState>,
+    // copies the key of an entry first versioned by a write that does not
+    // require `K: Clone`
+    clone: fn(&K) -> K,
+}
+
+impl<K: Hash + Eq> Versions<K> {
+    pub(crate) fn new(clone: fn(&K) -> K) -> Versions<K> {
+        Versions {
+            next: 1,
+            current: LinkedHashMap::with_hasher(Default::default()),
+            clone,
+        }
+    }
+
+    /// Returns the write version of the entry of `k`, or 0 if it was not
+    /// written since versioning began.
+    pub(crate) fn get<Q>(&self, hash: u64, k: &Q) -> u64
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        self.current
+            .get_hashed(hash, k)
+            .map_or(0, |version| version.write)
+    }
+
+    /// Returns the last version given to the entry of `k`, if any.
+    pub(crate) fn given<Q>(&self, hash: u64, k: &Q) -> Option<u64>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        self.current.get_hashed(hash, k)?.given
+    }
+
+    /// Gives the entry of `k`, which was just written, a new write version.
+    pub(crate) fn bump(&mut self, hash: u64, k: &K) {
+        let write = self.next;
+        self.next += 1;
+        match self.current.get_mut_hashed(hash, k) {
+            Some(version) => version.write = write,
+            None => {
+                let version = Version { write, given: None };
+                self.current
+                    .push_front_hashed(hash, (self.clone)(k), version);
+            }
+        }
+    }
+
+    /// Records `given` as the version a caller gave the entry of `k`, which
+    /// its write already bumped.
+    pub(crate) fn give(&mut self, hash: u64, k: &K, given: u64) {
+        if let Some(version) = self.current.get_mut_hashed(hash, k) {
+            version.given = Some(given);
+        }
+    }
+
+    pub(crate) fn remove<Q>(&mut self, hash: u64, k: &Q)
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        self.current.remove_entry_hashed(hash, k);
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.current.clear();
+    }
+}
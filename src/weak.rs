@@ -0,0 +1,147 @@
+//! An LRU cache of weak references.
+//!
+//! The cache holds `Weak<V>` handles, so it never keeps a value alive by
+//! itself: it indexes values owned elsewhere for as long as they live.
+//! Entries whose value has been dropped are removed when looked up, or in
+//! bulk by `sweep`.
+//!
+//! ```
+//! use std::sync::Arc;
+//! use cache::weak::Cache;
+//!
+//! let mut cache = Cache::new(16);
+//! let session = Arc::new("alice");
+//! cache.add(1, &session);
+//! assert_eq!(cache.get(&1).as_deref(), Some(&"alice"));
+//! drop(session);
+//! assert_eq!(cache.get(&1), None);
+//! assert!(cache.is_empty());
+//! ```
+
+use std::borrow::Borrow;
+use std::hash::{BuildHasher, Hash};
+use std::sync::{Arc, Weak};
+
+use super::hash::DefaultState;
+use super::lru;
+use super::stats::Info;
+
+pub struct Cache<K, V, S = DefaultState> {
+    inner: lru::Cache<K, Weak<V>, S>,
+}
+
+impl<K: Hash + Eq, V> Cache<K, V, DefaultState> {
+    pub fn new(max_size: usize) -> Cache<K, V, DefaultState> {
+        Cache::with_hasher(max_size, Default::default())
+    }
+}
+
+impl<K, V, S> Cache<K, V, S>
+where
+    K: Hash + Eq,
+    S: BuildHasher,
+{
+    pub fn with_hasher(max_size: usize, hash_builder: S) -> Cache<K, V, S> {
+        Cache {
+            inner: lru::Cache::with_hasher(max_size, hash_builder),
+        }
+    }
+
+    /// Returns the value of `k` if it is still alive. An entry whose value
+    /// has been dropped is removed, and the lookup counts as a miss.
+    pub fn get<Q>(&mut self, k: &Q) -> Option<Arc<V>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        if self.inner.peek(k).is_some_and(|v| v.strong_count() == 0) {
+            self.inner.remove(k);
+        }
+        self.inner.get(k).and_then(Weak::upgrade)
+    }
+
+    pub fn peek<Q>(&self, k: &Q) -> Option<Arc<V>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        self.inner.peek(k).and_then(Weak::upgrade)
+    }
+
+    /// Returns whether `k` is cached and its value still alive.
+    pub fn contains_key<Q>(&self, k: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        self.inner.peek(k).is_some_and(|v| v.strong_count() > 0)
+    }
+
+    /// Caches a weak reference to `v` under `k`, returning the value it
+    /// replaces if that is still alive.
+    pub fn add(&mut self, k: K, v: &Arc<V>) -> Option<Arc<V>> {
+        self.inner
+            .add(k, Arc::downgrade(v))
+            .and_then(|old| old.upgrade())
+    }
+
+    pub fn remove<Q>(&mut self, k: &Q) -> Option<Arc<V>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        self.inner.remove(k).and_then(|v| v.upgrade())
+    }
+
+    /// Removes every entry whose value has been dropped and returns how
+    /// many there were.
+    pub fn sweep(&mut self) -> usize {
+        self.inner.retain(|_, v| v.strong_count() > 0)
+    }
+
+    /// Returns the number of entries, including those whose value has been
+    /// dropped since the last `sweep`.
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+
+    pub fn max_size(&self) -> usize {
+        self.inner.max_size()
+    }
+
+    pub fn purge(&mut self) {
+        self.inner.purge();
+    }
+
+    pub fn stat(&self) -> Info {
+        self.inner.stat()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sweep() {
+        let mut cache = Cache::new(8);
+        let values: Vec<_> = (0..4).map(Arc::new).collect();
+        for v in &values {
+            cache.add(**v, v);
+        }
+        let mut values = values.into_iter();
+        let kept = values.next().unwrap();
+        drop(values);
+        assert_eq!(cache.len(), 4);
+        assert!(!cache.contains_key(&1));
+        assert_eq!(cache.peek(&0).as_ref(), Some(&kept));
+
+        assert_eq!(cache.sweep(), 3);
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.stat().eviction_count, 0);
+    }
+}
@@ -0,0 +1,68 @@
+use std::mem;
+use std::rc::Rc;
+
+/// Measures entries against a cache's weight budget.
+///
+/// Without a user supplied weigher every entry weighs one, which makes a
+/// weight budget equivalent to the entry count limit. Weights are computed
+/// again whenever an entry leaves a cache, so a weigher must return the same
+/// weight for an entry for as long as it is cached.
+pub(crate) struct Weigher<K, V> {
+    weigh: Option<WeighFn<K, V>>,
+    /// Set when the weights are the byte sizes of `heap_size::entry_size`.
+    pub in_bytes: bool,
+}
+
+type WeighFn<K, V> = Rc<dyn Fn(&K, &V) -> u32>;
+
+// shared by the caches split from one another
+impl<K, V> Clone for Weigher<K, V> {
+    fn clone(&self) -> Weigher<K, V> {
+        Weigher {
+            weigh: self.weigh.clone(),
+            in_bytes: self.in_bytes,
+        }
+    }
+}
+
+impl<K, V> Weigher<K, V> {
+    pub fn unit() -> Weigher<K, V> {
+        Weigher {
+            weigh: None,
+            in_bytes: false,
+        }
+    }
+
+    pub fn new<W>(weigher: W) -> Weigher<K, V>
+    where
+        W: Fn(&K, &V) -> u32 + 'static,
+    {
+        Weigher {
+            weigh: Some(Rc::new(weigher)),
+            in_bytes: false,
+        }
+    }
+
+    /// Returns whether every entry weighs one.
+    pub fn is_unit(&self) -> bool {
+        self.weigh.is_none()
+    }
+
+    #[inline]
+    pub fn weigh(&self, k: &K, v: &V) -> usize {
+        match &self.weigh {
+            Some(weigher) => weigher(k, v) as usize,
+            None => 1,
+        }
+    }
+
+    /// Returns the heap bytes owned by `len` entries of total weight
+    /// `weight`, or `None` unless the weights are byte sizes.
+    pub fn heap_bytes(&self, weight: usize, len: usize) -> Option<usize> {
+        if !self.in_bytes {
+            return None;
+        }
+        let inline = len * (mem::size_of::<K>() + mem::size_of::<V>());
+        Some(weight.saturating_sub(inline))
+    }
+}
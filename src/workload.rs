@@ -0,0 +1,223 @@
+//! Synthetic key streams for benchmarks and simulations.
+//!
+//! Every generator is an endless `Iterator<Item = u64>` seeded explicitly,
+//! so that two runs with the same seed see the same keys. Uniform keys
+//! flatter recency-based policies, since no key is worth keeping more than
+//! another; `Zipf` and `Hotspot` give the skewed popularity of real
+//! workloads, and `ScanMixed` interleaves the one-off scans that pollute a
+//! plain LRU cache.
+//!
+//! ```
+//! use cache::workload::{ScanMixed, Zipf};
+//!
+//! let keys = ScanMixed::new(Zipf::new(1000, 0.99, 7), 50, 0.01, 7);
+//! let trace: Vec<u64> = keys.take(10_000).collect();
+//! assert_eq!(trace.len(), 10_000);
+//! ```
+
+/// A small, fast generator (SplitMix64). It is not cryptographically
+/// secure.
+#[derive(Debug, Clone)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        Rng { state: seed }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Returns a float uniformly distributed in `[0, 1)`.
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Returns an integer uniformly distributed in `[0, n)`. `n` must not be
+    /// zero.
+    pub fn below(&mut self, n: u64) -> u64 {
+        ((self.next_u64() as u128 * n as u128) >> 64) as u64
+    }
+}
+
+/// Keys drawn uniformly from `[0, n)`.
+#[derive(Debug, Clone)]
+pub struct Uniform {
+    n: u64,
+    rng: Rng,
+}
+
+impl Uniform {
+    pub fn new(n: u64, seed: u64) -> Uniform {
+        Uniform {
+            n: n.max(1),
+            rng: Rng::new(seed),
+        }
+    }
+}
+
+impl Iterator for Uniform {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<u64> {
+        Some(self.rng.below(self.n))
+    }
+}
+
+/// Keys from `[0, n)` where key `i` is drawn with a probability
+/// proportional to `1 / (i + 1)^exponent`, so that `0` is the most popular.
+/// Exponents around `1` match most measured web and storage workloads.
+///
+/// The cumulative distribution is precomputed, which takes `8 * n` bytes.
+#[derive(Debug, Clone)]
+pub struct Zipf {
+    cdf: Vec<f64>,
+    rng: Rng,
+}
+
+impl Zipf {
+    pub fn new(n: u64, exponent: f64, seed: u64) -> Zipf {
+        let mut cdf = Vec::with_capacity(n.max(1) as usize);
+        let mut total = 0.0;
+        for i in 0..n.max(1) {
+            total += 1.0 / ((i + 1) as f64).powf(exponent);
+            cdf.push(total);
+        }
+        for p in &mut cdf {
+            *p /= total;
+        }
+        Zipf {
+            cdf,
+            rng: Rng::new(seed),
+        }
+    }
+}
+
+impl Iterator for Zipf {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<u64> {
+        let u = self.rng.next_f64();
+        let i = self.cdf.partition_point(|&p| p <= u);
+        Some(i.min(self.cdf.len() - 1) as u64)
+    }
+}
+
+/// Keys from `[0, n)` where a fraction `hot_probability` of the requests
+/// goes uniformly to the first `hot_fraction` of the keys, and the rest
+/// uniformly to the others.
+#[derive(Debug, Clone)]
+pub struct Hotspot {
+    n: u64,
+    hot: u64,
+    hot_probability: f64,
+    rng: Rng,
+}
+
+impl Hotspot {
+    pub fn new(n: u64, hot_fraction: f64, hot_probability: f64, seed: u64) -> Hotspot {
+        let n = n.max(1);
+        let hot = ((n as f64 * hot_fraction) as u64).clamp(1, n);
+        Hotspot {
+            n,
+            hot,
+            hot_probability,
+            rng: Rng::new(seed),
+        }
+    }
+}
+
+impl Iterator for Hotspot {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<u64> {
+        if self.hot == self.n || self.rng.next_f64() < self.hot_probability {
+            return Some(self.rng.below(self.hot));
+        }
+        Some(self.hot + self.rng.below(self.n - self.hot))
+    }
+}
+
+/// The keys of `inner`, interrupted with probability `scan_probability`
+/// before each of them by a scan of `scan_len` keys never seen before.
+///
+/// Scan keys count up from `SCAN_START`, so they never collide with those
+/// of `inner` as long as these stay below it.
+#[derive(Debug, Clone)]
+pub struct ScanMixed<G> {
+    inner: G,
+    scan_len: u64,
+    scan_probability: f64,
+    next_scan_key: u64,
+    remaining: u64,
+    rng: Rng,
+}
+
+pub const SCAN_START: u64 = 1 << 63;
+
+impl<G> ScanMixed<G> {
+    pub fn new(inner: G, scan_len: u64, scan_probability: f64, seed: u64) -> ScanMixed<G> {
+        ScanMixed {
+            inner,
+            scan_len,
+            scan_probability,
+            next_scan_key: SCAN_START,
+            remaining: 0,
+            rng: Rng::new(seed),
+        }
+    }
+}
+
+impl<G: Iterator<Item = u64>> Iterator for ScanMixed<G> {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<u64> {
+        if self.remaining == 0 && self.rng.next_f64() < self.scan_probability {
+            self.remaining = self.scan_len;
+        }
+        if self.remaining == 0 {
+            return self.inner.next();
+        }
+        self.remaining -= 1;
+        let key = self.next_scan_key;
+        self.next_scan_key = self.next_scan_key.wrapping_add(1);
+        Some(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generators() {
+        let a: Vec<u64> = Zipf::new(100, 1.0, 1).take(1000).collect();
+        let b: Vec<u64> = Zipf::new(100, 1.0, 1).take(1000).collect();
+        assert_eq!(a, b);
+        assert!(a.iter().all(|&k| k < 100));
+        let top = a.iter().filter(|&&k| k < 10).count();
+        assert!(top > 500, "{} of the keys are in the top 10", top);
+
+        assert!(Uniform::new(10, 2).take(1000).all(|k| k < 10));
+
+        let hot = Hotspot::new(1000, 0.1, 0.9, 3)
+            .take(1000)
+            .filter(|&k| k < 100)
+            .count();
+        assert!(hot > 850 && hot < 950, "{} hot keys", hot);
+
+        let mixed: Vec<u64> = ScanMixed::new(Uniform::new(10, 4), 20, 0.05, 4)
+            .take(2000)
+            .collect();
+        let scanned: Vec<u64> = mixed.into_iter().filter(|&k| k >= SCAN_START).collect();
+        assert!(!scanned.is_empty());
+        assert!(scanned.windows(2).all(|w| w[1] == w[0] + 1));
+    }
+}
@@ -0,0 +1,552 @@
+//! An LRU cache in front of a backing `Store`.
+//!
+//! In write-through mode every `add` is written to the store before it is
+//! cached. In write-back mode entries are only marked dirty, and are written
+//! when they are evicted, when `flush` is called or when the cache is
+//! dropped, so repeated updates of a key cost a single write.
+//!
+//! A `Store` is synchronous: a write blocks the caller until it is done.
+//! With the `async` feature, a cache can also be created over an
+//! `AsyncStore` with `new_async`, and is then written to through the
+//! futures returned by `add_async` and `flush_async`, run by the caller's
+//! executor. Such a cache cannot write from `drop`, so its dirty entries
+//! are lost unless flushed beforehand.
+//!
+//! ```
+//! use std::collections::HashMap;
+//! use cache::writeback::{Cache, Mode};
+//!
+//! let mut cache = Cache::new(1, HashMap::new(), Mode::WriteBack);
+//! cache.add(1, "a").unwrap();
+//! assert!(cache.store().is_empty());
+//! // evicting the dirty entry writes it
+//! cache.add(2, "b").unwrap();
+//! assert_eq!(cache.store()[&1], "a");
+//! ```
+
+use std::borrow::Borrow;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+#[cfg(feature = "async")]
+use std::future::Future;
+use std::hash::{BuildHasher, Hash};
+
+use super::hash::DefaultState;
+use super::lru;
+use super::stats::Info;
+
+/// Where a cache persists its entries.
+pub trait Store<K, V> {
+    type Error;
+
+    fn write(&mut self, k: &K, v: &V) -> Result<(), Self::Error>;
+
+    /// Writes several entries at once. Defaults to one `write` per entry,
+    /// stopping at the first error.
+    fn write_batch(&mut self, entries: &[(&K, &V)]) -> Result<(), Self::Error> {
+        for &(k, v) in entries {
+            self.write(k, v)?;
+        }
+        Ok(())
+    }
+}
+
+impl<K, V, S> Store<K, V> for HashMap<K, V, S>
+where
+    K: Hash + Eq + Clone,
+    V: Clone,
+    S: BuildHasher,
+{
+    type Error = std::convert::Infallible;
+
+    fn write(&mut self, k: &K, v: &V) -> Result<(), Self::Error> {
+        self.insert(k.clone(), v.clone());
+        Ok(())
+    }
+}
+
+/// Where a cache persists its entries, through futures. Available with
+/// the `async` feature.
+#[cfg(feature = "async")]
+pub trait AsyncStore<K, V> {
+    type Error;
+
+    fn write(&mut self, k: &K, v: &V) -> impl Future<Output = Result<(), Self::Error>>;
+
+    /// Writes several entries at once. Defaults to one `write` per entry,
+    /// stopping at the first error.
+    fn write_batch(
+        &mut self,
+        entries: &[(&K, &V)],
+    ) -> impl Future<Output = Result<(), Self::Error>> {
+        async move {
+            for &(k, v) in entries {
+                self.write(k, v).await?;
+            }
+            Ok(())
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+impl<K, V, S> AsyncStore<K, V> for HashMap<K, V, S>
+where
+    K: Hash + Eq + Clone,
+    V: Clone,
+    S: BuildHasher,
+{
+    type Error = std::convert::Infallible;
+
+    fn write(&mut self, k: &K, v: &V) -> impl Future<Output = Result<(), Self::Error>> {
+        self.insert(k.clone(), v.clone());
+        std::future::ready(Ok(()))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    WriteThrough,
+    WriteBack,
+}
+
+/// A failed write, together with the entries that could not be written so
+/// that they are not lost.
+#[derive(Debug)]
+pub struct WriteError<K, V, E> {
+    pub error: E,
+    pub unwritten: Vec<(K, V)>,
+}
+
+impl<K, V, E: fmt::Display> fmt::Display for WriteError<K, V, E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "failed to write {} entries: {}",
+            self.unwritten.len(),
+            self.error
+        )
+    }
+}
+
+impl<K: fmt::Debug, V: fmt::Debug, E: Error> Error for WriteError<K, V, E> {}
+
+impl<K, V, E> WriteError<K, V, E> {
+    /// The error of the write of `failed`, which leaves the entries after
+    /// it unwritten too.
+    fn unwritten<I>(error: E, failed: (K, V), rest: I) -> WriteError<K, V, E>
+    where
+        I: Iterator<Item = (K, V)>,
+    {
+        let mut unwritten = vec![failed];
+        unwritten.extend(rest);
+        WriteError { error, unwritten }
+    }
+}
+
+struct Slot<V> {
+    value: V,
+    dirty: Cell<bool>,
+}
+
+fn dirty<K, V, S>(slots: &lru::Cache<K, Slot<V>, S>) -> Vec<(&K, &V)>
+where
+    K: Hash + Eq,
+    S: BuildHasher,
+{
+    slots
+        .iter()
+        .filter(|(_, slot)| slot.dirty.get())
+        .map(|(k, slot)| (k, &slot.value))
+        .collect()
+}
+
+type Flush<K, V, T, S> = fn(&mut Cache<K, V, T, S>);
+
+pub struct Cache<K, V, T, S = DefaultState> {
+    mode: Mode,
+    store: T,
+    inner: lru::Cache<K, Slot<V>, S>,
+    // writes the dirty entries from `drop`, which only a synchronous store
+    // can do
+    flush_on_drop: Option<Flush<K, V, T, S>>,
+}
+
+impl<K, V, T> Cache<K, V, T, DefaultState>
+where
+    K: Hash + Eq,
+    T: Store<K, V>,
+{
+    pub fn new(max_size: usize, store: T, mode: Mode) -> Cache<K, V, T, DefaultState> {
+        Cache::with_hasher(max_size, store, mode, Default::default())
+    }
+}
+
+#[cfg(feature = "async")]
+impl<K, V, T> Cache<K, V, T, DefaultState>
+where
+    K: Hash + Eq,
+    T: AsyncStore<K, V>,
+{
+    /// Creates a cache over an asynchronous store, written to by
+    /// `add_async` and `flush_async`.
+    pub fn new_async(max_size: usize, store: T, mode: Mode) -> Cache<K, V, T, DefaultState> {
+        Cache::with_hasher_async(max_size, store, mode, Default::default())
+    }
+}
+
+impl<K, V, T, S> Cache<K, V, T, S>
+where
+    K: Hash + Eq,
+    T: Store<K, V>,
+    S: BuildHasher,
+{
+    pub fn with_hasher(
+        max_size: usize,
+        store: T,
+        mode: Mode,
+        hash_builder: S,
+    ) -> Cache<K, V, T, S> {
+        Cache {
+            mode,
+            store,
+            inner: lru::Cache::with_hasher(max_size, hash_builder),
+            flush_on_drop: Some(|cache| {
+                // there is no one left to report a failure to
+                let _ = cache.flush();
+            }),
+        }
+    }
+
+    /// Caches `v` under `k`, writing it first in write-through mode, and
+    /// writes the dirty entries it displaces. If a write fails the entries
+    /// concerned are handed back in the error; those already written stay
+    /// written.
+    pub fn add(&mut self, k: K, v: V) -> Result<Option<V>, WriteError<K, V, T::Error>> {
+        if self.mode == Mode::WriteThrough {
+            if let Err(error) = self.store.write(&k, &v) {
+                return Err(WriteError {
+                    error,
+                    unwritten: vec![(k, v)],
+                });
+            }
+        }
+        let (old, victims) = self.push(k, v);
+        let mut victims = victims.into_iter();
+        while let Some((k, v)) = victims.next() {
+            if let Err(error) = self.store.write(&k, &v) {
+                return Err(WriteError::unwritten(error, (k, v), victims));
+            }
+        }
+        Ok(old)
+    }
+
+    /// Writes every dirty entry in one batch and marks them clean. The
+    /// entries stay dirty if the batch fails.
+    pub fn flush(&mut self) -> Result<(), T::Error> {
+        let dirty = dirty(&self.inner);
+        if dirty.is_empty() {
+            return Ok(());
+        }
+        self.store.write_batch(&dirty)?;
+        self.mark_clean();
+        Ok(())
+    }
+}
+
+#[cfg(feature = "async")]
+impl<K, V, T, S> Cache<K, V, T, S>
+where
+    K: Hash + Eq,
+    T: AsyncStore<K, V>,
+    S: BuildHasher,
+{
+    pub fn with_hasher_async(
+        max_size: usize,
+        store: T,
+        mode: Mode,
+        hash_builder: S,
+    ) -> Cache<K, V, T, S> {
+        Cache {
+            mode,
+            store,
+            inner: lru::Cache::with_hasher(max_size, hash_builder),
+            flush_on_drop: None,
+        }
+    }
+
+    /// Like `add`, writing to the asynchronous store. The entry is only
+    /// cached once a write-through has completed; the displaced dirty
+    /// entries are written after.
+    pub async fn add_async(&mut self, k: K, v: V) -> Result<Option<V>, WriteError<K, V, T::Error>> {
+        if self.mode == Mode::WriteThrough {
+            if let Err(error) = self.store.write(&k, &v).await {
+                return Err(WriteError {
+                    error,
+                    unwritten: vec![(k, v)],
+                });
+            }
+        }
+        let (old, victims) = self.push(k, v);
+        let mut victims = victims.into_iter();
+        while let Some((k, v)) = victims.next() {
+            if let Err(error) = self.store.write(&k, &v).await {
+                return Err(WriteError::unwritten(error, (k, v), victims));
+            }
+        }
+        Ok(old)
+    }
+
+    /// Like `flush`, writing to the asynchronous store.
+    pub async fn flush_async(&mut self) -> Result<(), T::Error> {
+        let dirty = dirty(&self.inner);
+        if dirty.is_empty() {
+            return Ok(());
+        }
+        self.store.write_batch(&dirty).await?;
+        self.mark_clean();
+        Ok(())
+    }
+}
+
+impl<K, V, T, S> Cache<K, V, T, S>
+where
+    K: Hash + Eq,
+    S: BuildHasher,
+{
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    pub fn store(&self) -> &T {
+        &self.store
+    }
+
+    pub fn store_mut(&mut self) -> &mut T {
+        &mut self.store
+    }
+
+    pub fn get<Q>(&mut self, k: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        self.inner.get(k).map(|slot| &slot.value)
+    }
+
+    pub fn peek<Q>(&self, k: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        self.inner.peek(k).map(|slot| &slot.value)
+    }
+
+    pub fn contains_key<Q>(&self, k: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        self.inner.contains_key(k)
+    }
+
+    /// Returns whether `k` is cached with changes not yet written.
+    pub fn is_dirty<Q>(&self, k: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        self.inner.peek(k).is_some_and(|slot| slot.dirty.get())
+    }
+
+    /// Caches `v` under `k`, dirty in write-back mode, and returns the
+    /// value it replaced and the dirty entries it displaced.
+    fn push(&mut self, k: K, v: V) -> (Option<V>, Vec<(K, V)>) {
+        let slot = Slot {
+            value: v,
+            dirty: Cell::new(self.mode == Mode::WriteBack),
+        };
+        let (old, displaced) = self.inner.push(k, slot);
+        let dirty = displaced
+            .into_iter()
+            .filter(|(_, slot)| slot.dirty.get())
+            .map(|(k, slot)| (k, slot.value))
+            .collect();
+        (old.map(|slot| slot.value), dirty)
+    }
+
+    /// Removes `k` without writing it, returning its value even if dirty.
+    pub fn remove<Q>(&mut self, k: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        self.inner.remove(k).map(|slot| slot.value)
+    }
+
+    fn mark_clean(&self) {
+        for (_, slot) in self.inner.iter() {
+            slot.dirty.set(false);
+        }
+    }
+
+    /// Returns the number of cached entries not yet written.
+    pub fn dirty_len(&self) -> usize {
+        self.inner
+            .iter()
+            .filter(|(_, slot)| slot.dirty.get())
+            .count()
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+
+    pub fn max_size(&self) -> usize {
+        self.inner.max_size()
+    }
+
+    pub fn stat(&self) -> Info {
+        self.inner.stat()
+    }
+}
+
+impl<K, V, T, S> Drop for Cache<K, V, T, S> {
+    /// Flushes the dirty entries to a synchronous store. A failure is
+    /// ignored, as there is no one left to report it to; call `flush`
+    /// beforehand to see it.
+    fn drop(&mut self) {
+        if let Some(flush) = self.flush_on_drop {
+            flush(self);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    #[cfg(feature = "async")]
+    use std::task::{Context, Poll, Waker};
+
+    /// Polls `future` until it completes, which the futures of the test
+    /// stores do at once.
+    #[cfg(feature = "async")]
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = std::pin::pin!(future);
+        let mut cx = Context::from_waker(Waker::noop());
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+        }
+    }
+
+    /// Records the writes and fails those of odd values.
+    #[derive(Default)]
+    struct Log(Vec<(u32, u32)>);
+
+    impl Store<u32, u32> for Log {
+        type Error = u32;
+
+        fn write(&mut self, &k: &u32, &v: &u32) -> Result<(), u32> {
+            if v % 2 == 1 {
+                return Err(v);
+            }
+            self.0.push((k, v));
+            Ok(())
+        }
+    }
+
+    #[cfg(feature = "async")]
+    impl AsyncStore<u32, u32> for Log {
+        type Error = u32;
+
+        fn write(&mut self, k: &u32, v: &u32) -> impl Future<Output = Result<(), u32>> {
+            std::future::ready(Store::write(self, k, v))
+        }
+    }
+
+    /// Shares its writes with the test, which outlives the cache.
+    struct Shared(Rc<RefCell<Vec<(u32, u32)>>>);
+
+    impl Store<u32, u32> for Shared {
+        type Error = ();
+
+        fn write(&mut self, &k: &u32, &v: &u32) -> Result<(), ()> {
+            self.0.borrow_mut().push((k, v));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_write_through() {
+        let mut cache = Cache::new(2, Log::default(), Mode::WriteThrough);
+        assert_eq!(cache.add(1, 2).unwrap(), None);
+        assert_eq!(cache.add(1, 4).unwrap(), Some(2));
+        assert!(!cache.is_dirty(&1));
+        let err = cache.add(2, 3).unwrap_err();
+        assert_eq!((err.error, err.unwritten), (3, vec![(2, 3)]));
+        assert!(!cache.contains_key(&2));
+        assert_eq!(cache.store().0, vec![(1, 2), (1, 4)]);
+    }
+
+    #[test]
+    fn test_write_back() {
+        let mut cache = Cache::new(2, Log::default(), Mode::WriteBack);
+        cache.add(1, 2).unwrap();
+        cache.add(1, 4).unwrap();
+        cache.add(2, 6).unwrap();
+        assert_eq!(cache.dirty_len(), 2);
+        assert!(cache.store().0.is_empty());
+
+        cache.add(3, 8).unwrap();
+        assert_eq!(cache.store().0, vec![(1, 4)]);
+
+        cache.flush().unwrap();
+        assert_eq!(cache.dirty_len(), 0);
+        assert_eq!(cache.store().0, vec![(1, 4), (3, 8), (2, 6)]);
+
+        // clean victims are not written again, failed ones are handed back
+        cache.add(4, 5).unwrap();
+        cache.add(5, 10).unwrap();
+        let err = cache.add(6, 12).unwrap_err();
+        assert_eq!(err.unwritten, vec![(4, 5)]);
+        assert_eq!(cache.store().0.len(), 3);
+    }
+
+    #[test]
+    fn test_flush_on_drop() {
+        let writes = Rc::new(RefCell::new(Vec::new()));
+        let mut cache = Cache::new(4, Shared(writes.clone()), Mode::WriteBack);
+        cache.add(1, 2).unwrap();
+        cache.add(3, 4).unwrap();
+        assert!(writes.as_ref().borrow().is_empty());
+        drop(cache);
+        assert_eq!(*writes.as_ref().borrow(), vec![(3, 4), (1, 2)]);
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_async_store() {
+        let mut cache = Cache::new_async(2, Log::default(), Mode::WriteBack);
+        block_on(cache.add_async(1, 2)).unwrap();
+        block_on(cache.add_async(2, 4)).unwrap();
+        block_on(cache.add_async(3, 6)).unwrap();
+        assert_eq!(cache.store().0, vec![(1, 2)]);
+        block_on(cache.flush_async()).unwrap();
+        assert_eq!(cache.dirty_len(), 0);
+        assert_eq!(cache.store().0, vec![(1, 2), (3, 6), (2, 4)]);
+
+        let mut cache = Cache::new_async(2, Log::default(), Mode::WriteThrough);
+        let err = block_on(cache.add_async(1, 3)).unwrap_err();
+        assert_eq!((err.error, err.unwritten), (3, vec![(1, 3)]));
+        assert!(cache.is_empty());
+    }
+}